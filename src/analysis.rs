@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use mdbook::book::{Book, BookItem};
+
+use crate::compat::undefined_command;
+use crate::engine::Engine;
+use crate::macros::replace_command;
+use crate::scan::{copied_length, offset, Span};
+use crate::ChapterRenderer;
+
+// an equation can't use more unsupported commands than this, to bound retries
+const MAX_UNSUPPORTED_PER_EQUATION: usize = 32;
+
+// where an unsupported command was first found, and how often it is used
+#[derive(Clone, Debug, PartialEq)]
+pub struct Usage {
+    pub count: usize,
+    pub first_chapter: String,
+    pub first_equation: String,
+}
+
+// commands used in a book that katex doesn't support, by name
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub unsupported: BTreeMap<String, Usage>,
+    pub equations: usize,
+}
+
+impl Report {
+    fn record(&mut self, name: String, chapter: &str, equation: &str) {
+        self.unsupported
+            .entry(name)
+            .and_modify(|usage| usage.count += 1)
+            .or_insert_with(|| Usage {
+                count: 1,
+                first_chapter: String::from(chapter),
+                first_equation: String::from(equation),
+            });
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.unsupported.is_empty() {
+            return writeln!(
+                f,
+                "All commands in {} equations are supported by KaTeX.",
+                self.equations
+            );
+        }
+        writeln!(
+            f,
+            "{} commands unsupported by KaTeX in {} equations:",
+            self.unsupported.len(),
+            self.equations
+        )?;
+        for (name, usage) in &self.unsupported {
+            writeln!(
+                f,
+                "  {} ({} uses), first in {}: `{}`",
+                name, usage.count, usage.first_chapter, usage.first_equation
+            )?;
+        }
+        Ok(())
+    }
+}
+
+// find the commands unsupported by katex in every equation of a book
+pub fn analyze_book(renderer: &ChapterRenderer, book: &Book) -> Report {
+    let mut report = Report::default();
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            let name = match &chapter.path {
+                Some(path) => path.display().to_string(),
+                None => chapter.name.clone(),
+            };
+            analyze_chapter(renderer, &chapter.content, &name, &mut report);
+        }
+    }
+    report
+}
+
+// find the commands unsupported by katex in the equations of a chapter
+pub fn analyze_chapter(
+    renderer: &ChapterRenderer,
+    content: &str,
+    chapter: &str,
+    report: &mut Report,
+) {
+    // with `throw-on-error = false`, katex renders unsupported commands in red
+    // rather than failing on them
+    let inline_opts = renderer.inline_opts.clone().with_throw_on_error(true);
+    let display_opts = renderer.display_opts.clone().with_throw_on_error(true);
+    let engine = Engine::new(&inline_opts, &display_opts);
+    for span in renderer.scanner.scan(content, &mut |_| {}) {
+        if let Span::Math { tex, display, .. } = span {
+            report.equations += 1;
+            let tex = renderer.prepare(tex);
+            for name in unsupported_commands(renderer, &engine, &tex, display) {
+                report.record(name, chapter, &tex);
+            }
+        }
+    }
+}
+
+// katex stops at the first undefined command, so each one found is removed
+// and the equation rendered again, until it renders or fails for another reason
+fn unsupported_commands(
+    renderer: &ChapterRenderer,
+    engine: &Engine,
+    tex: &str,
+    display: bool,
+) -> Vec<String> {
+    let mut tex = String::from(tex);
+    let mut found = Vec::new();
+    while found.len() < MAX_UNSUPPORTED_PER_EQUATION {
+        let error = match engine.render(&renderer.after_preamble(&tex), display) {
+            Ok(_) => break,
+            Err(error) => error,
+        };
+        let name = match undefined_command(&error) {
+            Some(name) if !found.contains(&name) => name,
+            _ => break,
+        };
+        tex = replace_command(&tex, &name, "{}");
+        found.push(name);
+    }
+    found
+}
+
+// what a dollar sign of a chapter is taken for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DollarKind {
+    MathOpen,
+    MathClose,
+    // left as text, or opening an equation that looks like an amount, as in
+    // `$5 and $10`
+    CurrencySuspect,
+    InsideCode,
+}
+
+impl fmt::Display for DollarKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            DollarKind::MathOpen => "math-open",
+            DollarKind::MathClose => "math-close",
+            DollarKind::CurrencySuspect => "currency-suspect",
+            DollarKind::InsideCode => "inside-code",
+        })
+    }
+}
+
+// a `$` or `$$` of a chapter, with its line and column counted from 1
+#[derive(Clone, Debug, PartialEq)]
+pub struct Dollar {
+    pub kind: DollarKind,
+    pub marker: String,
+    pub chapter: String,
+    pub line: usize,
+    pub column: usize,
+    // the line it's on
+    pub context: String,
+}
+
+// the dollar signs of a book, in reading order
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DollarReport {
+    pub dollars: Vec<Dollar>,
+}
+
+impl DollarReport {
+    pub fn count(&self, kind: DollarKind) -> usize {
+        self.dollars
+            .iter()
+            .filter(|dollar| dollar.kind == kind)
+            .count()
+    }
+}
+
+impl fmt::Display for DollarReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.dollars.is_empty() {
+            return writeln!(f, "No dollar signs found.");
+        }
+        for dollar in &self.dollars {
+            writeln!(
+                f,
+                "{}:{}:{}: {} `{}`: {}",
+                dollar.chapter,
+                dollar.line,
+                dollar.column,
+                dollar.kind,
+                dollar.marker,
+                dollar.context.trim()
+            )?;
+        }
+        let kinds = [
+            DollarKind::MathOpen,
+            DollarKind::MathClose,
+            DollarKind::CurrencySuspect,
+            DollarKind::InsideCode,
+        ];
+        let counts: Vec<String> = kinds
+            .iter()
+            .map(|kind| format!("{} {}", self.count(*kind), kind))
+            .collect();
+        writeln!(
+            f,
+            "{} dollar signs: {}",
+            self.dollars.len(),
+            counts.join(", ")
+        )
+    }
+}
+
+// line starts of a chapter, to locate its dollar signs
+struct Lines<'a> {
+    content: &'a str,
+    starts: Vec<usize>,
+}
+
+impl<'a> Lines<'a> {
+    fn new(content: &'a str) -> Self {
+        let starts = std::iter::once(0)
+            .chain(content.match_indices('\n').map(|(start, _)| start + 1))
+            .collect();
+        Lines { content, starts }
+    }
+
+    fn dollar(&self, kind: DollarKind, marker: &str, chapter: &str, position: usize) -> Dollar {
+        let line = self.starts.partition_point(|start| *start <= position);
+        let start = self.starts[line - 1];
+        let end = self.content[start..]
+            .find('\n')
+            .map_or(self.content.len(), |end| start + end);
+        Dollar {
+            kind,
+            marker: String::from(marker),
+            chapter: String::from(chapter),
+            line,
+            column: self.content[start..position].chars().count() + 1,
+            context: String::from(&self.content[start..end]),
+        }
+    }
+}
+
+// list the dollar signs of every chapter of a book, rendered or not
+pub fn catalog_dollars(renderer: &ChapterRenderer, book: &Book) -> DollarReport {
+    let mut report = DollarReport::default();
+    for item in book.iter() {
+        if let BookItem::Chapter(chapter) = item {
+            let name = match &chapter.path {
+                Some(path) => path.display().to_string(),
+                None => chapter.name.clone(),
+            };
+            catalog_chapter(renderer, &chapter.content, &name, &mut report);
+        }
+    }
+    report
+}
+
+// list the dollar signs of a chapter, as the scanner sees them; escaped ones
+// and those inside equations or data blocks are left out
+pub fn catalog_chapter(
+    renderer: &ChapterRenderer,
+    content: &str,
+    chapter: &str,
+    report: &mut DollarReport,
+) {
+    let lines = Lines::new(content);
+    let mut text_start = 0;
+    for span in renderer.scanner.scan(content, &mut |_| {}) {
+        let (start, end) = match span {
+            Span::Math { source, .. } | Span::Data { open: source, .. } => {
+                match offset(content, source) {
+                    Some(start) => (start, start + span_len(&span)),
+                    None => continue,
+                }
+            }
+            Span::Text(_) => continue,
+        };
+        catalog_text(&lines, content, text_start, start, chapter, report);
+        text_start = end;
+        if let Span::Math {
+            source,
+            tex,
+            display,
+        } = span
+        {
+            let tex_start = offset(source, tex).unwrap_or(0);
+            let (open, close) = (&source[..tex_start], &source[tex_start + tex.len()..]);
+            if !open.contains('$') {
+                continue;
+            }
+            let (open_kind, close_kind) = if !display && looks_like_amount(tex, &content[end..]) {
+                (DollarKind::CurrencySuspect, DollarKind::CurrencySuspect)
+            } else {
+                (DollarKind::MathOpen, DollarKind::MathClose)
+            };
+            report
+                .dollars
+                .push(lines.dollar(open_kind, open, chapter, start));
+            report
+                .dollars
+                .push(lines.dollar(close_kind, close, chapter, end - close.len()));
+        }
+    }
+    catalog_text(&lines, content, text_start, content.len(), chapter, report);
+}
+
+fn span_len(span: &Span) -> usize {
+    match span {
+        Span::Text(text) => text.len(),
+        Span::Math { source, .. } => source.len(),
+        Span::Data {
+            open,
+            content,
+            close,
+        } => open.len() + content.len() + close.len(),
+    }
+}
+
+// an inline equation starting with a digit, and ending with a space or right
+// before another digit, as in `$5 and $10` or `$5-$10`, is most likely prices
+fn looks_like_amount(tex: &str, after: &str) -> bool {
+    let starts_with_digit = tex.starts_with(|c: char| c.is_ascii_digit());
+    let ends_with_space = tex.ends_with(char::is_whitespace);
+    let before_digit = after.starts_with(|c: char| c.is_ascii_digit());
+    starts_with_digit && (ends_with_space || before_digit)
+}
+
+// the dollar signs between equations, in code or left as text
+fn catalog_text(
+    lines: &Lines,
+    content: &str,
+    start: usize,
+    end: usize,
+    chapter: &str,
+    report: &mut DollarReport,
+) {
+    let mut position = start;
+    while let Some(c) = content[position..end].chars().next() {
+        if let Some(length) = copied_length(content, position) {
+            let code_end = (position + length).min(end);
+            let mut code = position;
+            while let Some(found) = content[code..code_end].find('$') {
+                let marker = dollars_at(&content[code + found..code_end]);
+                let dollar = lines.dollar(DollarKind::InsideCode, marker, chapter, code + found);
+                report.dollars.push(dollar);
+                code += found + marker.len();
+            }
+            position = code_end;
+            continue;
+        }
+        // escaped characters, dollar signs included, are text
+        if c == '\\' {
+            let escaped = content[position + 1..end].chars().next();
+            position += 1 + escaped.map_or(0, char::len_utf8);
+            continue;
+        }
+        if c == '$' {
+            let marker = dollars_at(&content[position..end]);
+            let dollar = lines.dollar(DollarKind::CurrencySuspect, marker, chapter, position);
+            report.dollars.push(dollar);
+            position += marker.len();
+            continue;
+        }
+        position += c.len_utf8();
+    }
+}
+
+// the `$` or `$$` at the start of some text
+fn dollars_at(text: &str) -> &str {
+    if text.starts_with("$$") {
+        &text[..2]
+    } else {
+        &text[..1]
+    }
+}
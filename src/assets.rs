@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Context;
+use mdbook::errors::Error;
+
+// where `static-css` finds the katex stylesheet and fonts: the `dist`
+// directory of the katex npm package, relative to the book root
+pub const DEFAULT_DIST: &str = "node_modules/katex/dist";
+
+// where `static-css` puts the stylesheet, relative to the source directory,
+// with the fonts next to it
+pub const DEFAULT_STYLESHEET: &str = "katex/katex.min.css";
+
+// copy a file unless the destination already has the same content, so that
+// `mdbook serve`, which watches the source directory, doesn't rebuild again
+fn copy_if_changed(from: &Path, to: &Path) -> Result<bool, Error> {
+    let content = fs::read(from).with_context(|| format!("unable to read {}", from.display()))?;
+    if fs::read(to).is_ok_and(|existing| existing == content) {
+        return Ok(false);
+    }
+    to.parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(to, &content))
+        .with_context(|| format!("unable to write {}", to.display()))?;
+    Ok(true)
+}
+
+// copy `katex.min.css` and the fonts of a katex distribution to `stylesheet`
+// and the `fonts` directory next to it, returning the number of files copied
+pub fn install(dist: &Path, stylesheet: &Path) -> Result<usize, Error> {
+    let source = dist.join("katex.min.css");
+    if !source.is_file() {
+        return Err(Error::msg(format!(
+            "no KaTeX stylesheet in {}, install KaTeX {} there with `npm install katex@{}`, \
+             or set `katex-dist` to the `dist` directory of a KaTeX release",
+            dist.display(),
+            crate::KATEX_VERSION,
+            crate::KATEX_VERSION
+        )));
+    }
+    let mut copied = usize::from(copy_if_changed(&source, stylesheet)?);
+    let fonts = stylesheet.with_file_name("fonts");
+    let entries = fs::read_dir(dist.join("fonts"))
+        .with_context(|| format!("unable to read the KaTeX fonts in {}", dist.display()))?;
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_file() {
+            copied += usize::from(copy_if_changed(
+                &entry.path(),
+                &fonts.join(entry.file_name()),
+            )?);
+        }
+    }
+    Ok(copied)
+}
@@ -0,0 +1,425 @@
+use std::collections::HashMap;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use mdbook::errors::Error;
+
+use crate::diagnostics;
+
+// environment variable pointing to a shared cache directory, e.g. a CI cache mount
+pub const CACHE_DIR_VAR: &str = "MDBOOK_KATEX_CACHE_DIR";
+
+// store of rendered content, addressed by content hash
+pub trait Cache: Send + Sync {
+    fn get(&self, namespace: &str, key: u64) -> Option<String>;
+
+    // store a value; failing to store it only means a cache miss next time
+    fn put(&self, namespace: &str, key: u64, value: &str);
+}
+
+// on-disk cache
+//
+// mdbook runs the preprocessor as a new process on every rebuild, and the
+// html renderer empties the build directory, so the cache lives in its own
+// directory to survive across the rebuilds of a `mdbook serve` session.
+pub struct FsCache {
+    dir: PathBuf,
+}
+
+impl FsCache {
+    pub fn new(dir: &Path) -> Self {
+        FsCache {
+            dir: dir.to_path_buf(),
+        }
+    }
+
+    // default location for the book at `root`: the directory in
+    // `MDBOOK_KATEX_CACHE_DIR` if set, or one of its own in the cache directory
+    // of the current user, so that clearing or exporting it leaves other books
+    pub fn default_dir(root: &Path) -> PathBuf {
+        match std::env::var_os(CACHE_DIR_VAR) {
+            Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+            _ => {
+                let root = root.canonicalize().unwrap_or_else(|_| root.to_path_buf());
+                let mut hasher = StableHasher::default();
+                root.to_string_lossy().hash(&mut hasher);
+                user_cache_dir()
+                    .unwrap_or_else(std::env::temp_dir)
+                    .join("mdbook-katex")
+                    .join(format!("{:016x}", hasher.finish()))
+            }
+        }
+    }
+
+    fn path(&self, namespace: &str, key: u64) -> PathBuf {
+        self.dir.join(namespace).join(format!("{:016x}", key))
+    }
+}
+
+impl Cache for FsCache {
+    fn get(&self, namespace: &str, key: u64) -> Option<String> {
+        fs::read_to_string(self.path(namespace, key)).ok()
+    }
+
+    fn put(&self, namespace: &str, key: u64, value: &str) {
+        let path = self.path(namespace, key);
+        if let Some(parent) = path.parent() {
+            if create_dir(parent).is_err() {
+                return;
+            }
+        }
+        if let Err(why) = write_entry(&path, value.as_bytes()) {
+            diagnostics::warn(&format!(
+                "couldn't write cache entry {}: {}",
+                path.display(),
+                why
+            ));
+        }
+    }
+}
+
+// the cache directory of the current user, as set up by the platform
+fn user_cache_dir() -> Option<PathBuf> {
+    let var = |name| {
+        std::env::var_os(name)
+            .filter(|value| !value.is_empty())
+            .map(PathBuf::from)
+    };
+    if cfg!(windows) {
+        var("LOCALAPPDATA")
+    } else if cfg!(target_os = "macos") {
+        var("HOME").map(|home| home.join("Library").join("Caches"))
+    } else {
+        var("XDG_CACHE_HOME").or_else(|| var("HOME").map(|home| home.join(".cache")))
+    }
+}
+
+// create a cache directory, only readable by the current user on unix, so
+// that other users can't read or plant entries, should it be shared
+fn create_dir(dir: &Path) -> std::io::Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)
+}
+
+// write a cache entry to a file next to it, moved in place once complete, so
+// that builds reading the cache meanwhile never see part of it
+fn write_entry(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temporary = path.with_file_name(format!(
+        ".{}.{}-{}.tmp",
+        name,
+        std::process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ));
+    fs::write(&temporary, content)
+        .and_then(|_| fs::rename(&temporary, path))
+        .inspect_err(|_| {
+            let _ = fs::remove_file(&temporary);
+        })
+}
+
+// a cache shared between renderers
+impl<T: Cache + ?Sized> Cache for Arc<T> {
+    fn get(&self, namespace: &str, key: u64) -> Option<String> {
+        (**self).get(namespace, key)
+    }
+
+    fn put(&self, namespace: &str, key: u64, value: &str) {
+        (**self).put(namespace, key, value)
+    }
+}
+
+// cache living in memory, for library users rendering several books, or the
+// same book several times, from one process
+#[derive(Default)]
+pub struct MemoryCache {
+    entries: Mutex<HashMap<(String, u64), String>>,
+}
+
+impl MemoryCache {
+    pub fn new() -> Self {
+        MemoryCache::default()
+    }
+}
+
+impl Cache for MemoryCache {
+    fn get(&self, namespace: &str, key: u64) -> Option<String> {
+        let entries = self.entries.lock().unwrap();
+        entries.get(&(String::from(namespace), key)).cloned()
+    }
+
+    fn put(&self, namespace: &str, key: u64, value: &str) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert((String::from(namespace), key), String::from(value));
+    }
+}
+
+// hash used as a cache key, including the crate and katex versions so that
+// cached output of other versions is never reused
+pub fn hash<T: Hash + ?Sized>(value: &T) -> u64 {
+    let mut hasher = StableHasher::default();
+    env!("CARGO_PKG_VERSION").hash(&mut hasher);
+    crate::KATEX_VERSION.hash(&mut hasher);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+// 64-bit FNV-1a, for keys persisted across builds and machines, unlike those
+// of `DefaultHasher`, whose algorithm may change with any release of rust;
+// integers are hashed in little endian and `usize` as 64 bits on every platform
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        StableHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for StableHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i16(&mut self, i: i16) {
+        self.write_u16(i as u16);
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write_u32(i as u32);
+    }
+
+    fn write_i64(&mut self, i: i64) {
+        self.write_u64(i as u64);
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write_u128(i as u128);
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64);
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+// archives of the on-disk cache, in the tar format, so that CI pipelines can
+// persist the cache between runs as a single file
+const BLOCK: usize = 512;
+
+// an archive entry name, as `chapters/00000000deadbeef`, if it's a cache entry
+fn entry_name(name: &str) -> Option<(&str, &str)> {
+    let (namespace, key) = name.trim_start_matches("./").split_once('/')?;
+    let valid_namespace = !namespace.is_empty()
+        && namespace
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    let valid_key = key.len() == 16 && key.chars().all(|c| c.is_ascii_hexdigit());
+    if valid_namespace && valid_key {
+        Some((namespace, key))
+    } else {
+        None
+    }
+}
+
+// write a field of a tar header as a NUL terminated octal number
+fn octal(field: &mut [u8], value: u64) {
+    let width = field.len() - 1;
+    let digits = format!("{:0width$o}", value, width = width);
+    field[..width].copy_from_slice(digits.as_bytes());
+}
+
+fn header(name: &str, size: u64) -> [u8; BLOCK] {
+    let mut header = [0; BLOCK];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut header[100..108], 0o644);
+    octal(&mut header[108..116], 0);
+    octal(&mut header[116..124], 0);
+    octal(&mut header[124..136], size);
+    // no modification time, so that archives of the same cache are identical
+    octal(&mut header[136..148], 0);
+    header[156] = b'0';
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // the checksum is computed with its own field filled with spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u64 = header.iter().map(|&b| u64::from(b)).sum();
+    octal(&mut header[148..155], checksum);
+    header
+}
+
+fn padding(size: usize) -> usize {
+    (BLOCK - size % BLOCK) % BLOCK
+}
+
+// write every entry of the cache in `dir` to an archive, returning the number
+// of entries; a missing cache directory is an empty cache
+pub fn export(dir: &Path, writer: &mut dyn Write) -> Result<usize, Error> {
+    let mut names = Vec::new();
+    if dir.exists() {
+        let namespaces =
+            fs::read_dir(dir).with_context(|| format!("unable to read {}", dir.display()))?;
+        for namespace in namespaces {
+            let namespace = namespace?;
+            if !namespace.file_type()?.is_dir() {
+                continue;
+            }
+            for entry in fs::read_dir(namespace.path())? {
+                let name = format!(
+                    "{}/{}",
+                    namespace.file_name().to_string_lossy(),
+                    entry?.file_name().to_string_lossy()
+                );
+                if entry_name(&name).is_some() {
+                    names.push(name);
+                }
+            }
+        }
+    }
+    names.sort();
+    for name in &names {
+        let path = dir.join(name);
+        let content =
+            fs::read(&path).with_context(|| format!("unable to read {}", path.display()))?;
+        writer.write_all(&header(name, content.len() as u64))?;
+        writer.write_all(&content)?;
+        writer.write_all(&[0; BLOCK][..padding(content.len())])?;
+    }
+    // the end of the archive
+    writer.write_all(&[0; 2 * BLOCK])?;
+    writer.flush()?;
+    Ok(names.len())
+}
+
+// remove every entry of the cache in `dir`, returning the number of entries;
+// other files are left alone
+pub fn clear(dir: &Path) -> Result<usize, Error> {
+    let mut count = 0;
+    if !dir.exists() {
+        return Ok(count);
+    }
+    let namespaces =
+        fs::read_dir(dir).with_context(|| format!("unable to read {}", dir.display()))?;
+    for namespace in namespaces {
+        let namespace = namespace?;
+        if !namespace.file_type()?.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(namespace.path())? {
+            let entry = entry?;
+            let name = format!(
+                "{}/{}",
+                namespace.file_name().to_string_lossy(),
+                entry.file_name().to_string_lossy()
+            );
+            if entry_name(&name).is_some() {
+                fs::remove_file(entry.path())
+                    .with_context(|| format!("unable to remove {}", entry.path().display()))?;
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = std::str::from_utf8(field).ok()?;
+    let digits = digits.trim_matches(|c: char| c == '\0' || c == ' ');
+    u64::from_str_radix(digits, 8).ok()
+}
+
+fn parse_name(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+// size of the largest entry read from an archive, far above that of a chapter,
+// so that a corrupted size doesn't exhaust memory
+const MAX_ENTRY_SIZE: u64 = 64 << 20;
+
+// restore the entries of an archive made by `export` to the cache in `dir`,
+// returning the number of entries; other files in the archive are skipped
+pub fn import(dir: &Path, reader: &mut dyn Read) -> Result<usize, Error> {
+    let truncated = || Error::msg("the cache archive is truncated");
+    let mut count = 0;
+    let mut header = [0; BLOCK];
+    loop {
+        reader.read_exact(&mut header).map_err(|_| truncated())?;
+        if header.iter().all(|&b| b == 0) {
+            return Ok(count);
+        }
+        let mut name = parse_name(&header[..100]);
+        let prefix = parse_name(&header[345..500]);
+        if !prefix.is_empty() {
+            name = format!("{}/{}", prefix, name);
+        }
+        let size = parse_octal(&header[124..136]).ok_or_else(|| {
+            Error::msg(format!("invalid size for `{}` in the cache archive", name))
+        })?;
+        if size > MAX_ENTRY_SIZE {
+            return Err(Error::msg(format!(
+                "`{}` in the cache archive is too large, with {} bytes",
+                name, size
+            )));
+        }
+        let mut content = Vec::new();
+        (&mut *reader).take(size).read_to_end(&mut content)?;
+        let padding = padding(size as usize) as u64;
+        let skipped = std::io::copy(&mut (&mut *reader).take(padding), &mut std::io::sink())?;
+        if content.len() as u64 != size || skipped != padding {
+            return Err(truncated());
+        }
+        let is_file = header[156] == b'0' || header[156] == 0;
+        match entry_name(&name) {
+            Some((namespace, key)) if is_file => {
+                let path = dir.join(namespace).join(key.to_ascii_lowercase());
+                create_dir(&dir.join(namespace))
+                    .and_then(|_| write_entry(&path, &content))
+                    .with_context(|| format!("unable to write {}", path.display()))?;
+                count += 1;
+            }
+            _ if !is_file => {}
+            _ => diagnostics::warn(&format!(
+                "`{}` in the cache archive isn't a cache entry, skipping it",
+                name
+            )),
+        }
+    }
+}
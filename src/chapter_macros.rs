@@ -0,0 +1,186 @@
+// macros defined inside the equations of chapters, as in a
+// `$$ \newcommand{\R}{\mathbb{R}} $$` block at the top of a chapter, for the
+// equations after them
+//
+// Chapters are rewritten before they're rendered: equations holding only
+// definitions are removed, and the equations after them start with the
+// `\gdef` of each macro they use, which katex takes anywhere in an equation.
+use std::path::Path;
+
+use anyhow::Error;
+use mdbook::book::{Book, Chapter};
+
+use crate::macros::{DuplicatePolicy, Location, MacroMap};
+use crate::scan::{offset, Span};
+use crate::{diagnostics, pipeline, ChapterRenderer};
+
+// the equations macros defined in a chapter apply to, with `chapter-macros`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MacroScope {
+    // the rest of the chapter
+    Chapter,
+    // the rest of the book, in reading order
+    Book,
+}
+
+impl MacroScope {
+    pub fn from_config(value: &str) -> Result<Self, Error> {
+        match value {
+            "chapter" => Ok(MacroScope::Chapter),
+            "book" => Ok(MacroScope::Book),
+            other => Err(Error::msg(format!(
+                "unknown value `{}`, expected \"chapter\" or \"book\"",
+                other
+            ))),
+        }
+    }
+}
+
+// collect the macros defined in the equations of a book, and make them
+// available to the equations after them; those of the preamble chapter, if
+// any, are available to every chapter
+pub fn collect_macros(renderer: &ChapterRenderer, book: &mut Book) {
+    let preamble = renderer.preamble_chapter.as_deref();
+    let mut book_macros = MacroMap::new(DuplicatePolicy::LaterWins);
+    if let Some(preamble) = preamble {
+        let chapters = pipeline::reading_order(&book.sections);
+        match chapters
+            .iter()
+            .find(|chapter| is_preamble(chapter, preamble))
+        {
+            Some(chapter) => {
+                rewrite(renderer, &mut book_macros, chapter, true);
+            }
+            None => diagnostics::warn(&format!(
+                "`preamble-chapter = \"{}\"` in `[preprocessor.katex]` isn't a chapter of the book",
+                preamble.display()
+            )),
+        }
+    }
+    let mut rendered = pipeline::rendered_chapters(renderer, book).into_iter();
+    pipeline::visit_chapters(&mut book.sections, &mut |chapter| {
+        // chapters that opted out of rendering are left as they are
+        if !rendered.next().unwrap_or(true) {
+            return;
+        }
+        let in_preamble = preamble.is_some_and(|preamble| is_preamble(chapter, preamble));
+        let rewritten = match renderer.chapter_macros {
+            // the definitions of the preamble are already collected
+            Some(MacroScope::Book) if !in_preamble => {
+                rewrite(renderer, &mut book_macros, chapter, true)
+            }
+            scope => {
+                let collect = scope.is_some() || in_preamble;
+                rewrite(renderer, &mut book_macros.clone(), chapter, collect)
+            }
+        };
+        if let Some(rewritten) = rewritten {
+            chapter.content = rewritten;
+        }
+    });
+}
+
+fn is_preamble(chapter: &Chapter, preamble: &Path) -> bool {
+    chapter.path.as_deref() == Some(preamble)
+}
+
+// the content of a chapter with its definitions removed from its equations,
+// and the macros they use defined at their start, if anything changed;
+// definitions are collected into `macros` unless `collect` is false
+fn rewrite(
+    renderer: &ChapterRenderer,
+    macros: &mut MacroMap,
+    chapter: &Chapter,
+    collect: bool,
+) -> Option<String> {
+    let content = &chapter.content;
+    let mut rewritten = String::with_capacity(content.len());
+    let mut copied = 0;
+    for span in renderer.scanner.scan(content, &mut |_| {}) {
+        let (source, tex) = match span {
+            Span::Math { source, tex, .. } => (source, tex),
+            _ => continue,
+        };
+        let (start, tex_start) = match (offset(content, source), offset(source, tex)) {
+            (Some(start), Some(tex_start)) => (start, tex_start),
+            _ => continue,
+        };
+        let location = Location {
+            path: chapter
+                .path
+                .clone()
+                .unwrap_or_else(|| chapter.name.clone().into()),
+            line: content[..start].matches('\n').count() + 1,
+        };
+        let rest = if collect {
+            match macros.define_leading(tex, location) {
+                Ok(rest) => rest,
+                Err(error) => {
+                    diagnostics::warn(&format!("{:#}", error));
+                    continue;
+                }
+            }
+        } else {
+            tex
+        };
+        let prelude = prelude(macros, rest);
+        if rest.len() == tex.len() && prelude.is_empty() {
+            continue;
+        }
+        rewritten.push_str(&content[copied..start]);
+        copied = start + source.len();
+        // equations holding only definitions are left out, as in latex
+        if rest.is_empty() {
+            continue;
+        }
+        let mut rewritten_tex = format!("{}{}", prelude, rest);
+        // an environment written without delimiters keeps its `\begin{...}`
+        // first, for the scanner to find it again
+        if source.len() == tex.len() {
+            if let Some(begin) = environment_start(rest) {
+                rewritten_tex = format!("{}{}{}", &rest[..begin], prelude, &rest[begin..]);
+            }
+        }
+        rewritten.push_str(&source[..tex_start]);
+        rewritten.push_str(&rewritten_tex);
+        rewritten.push_str(&source[tex_start + tex.len()..]);
+    }
+    if copied == 0 {
+        return None;
+    }
+    rewritten.push_str(&content[copied..]);
+    Some(rewritten)
+}
+
+// the definitions of the macros some tex uses, directly or through other
+// macros, with `\gdef` so that they hold in every cell of an environment
+fn prelude(macros: &MacroMap, tex: &str) -> String {
+    let mut prelude = String::new();
+    for (name, expansion) in macros.used_by(tex) {
+        // environment names don't start with a backslash, unlike macros
+        if !name.starts_with('\\') {
+            continue;
+        }
+        let arguments = (1..=9)
+            .rev()
+            .find(|argument| expansion.contains(&format!("#{}", argument)))
+            .unwrap_or(0);
+        let parameters: String = (1..=arguments)
+            .map(|argument| format!("#{}", argument))
+            .collect();
+        prelude.push_str(&format!("\\gdef{}{}{{{}}}", name, parameters, expansion));
+    }
+    prelude
+}
+
+// where the content of an environment starts, after `\begin{name}` and the
+// column count of `alignat`
+fn environment_start(tex: &str) -> Option<usize> {
+    let inner = tex.strip_prefix("\\begin{")?;
+    let name_end = inner.find('}')?;
+    let mut start = "\\begin{".len() + name_end + 1;
+    if inner[..name_end].starts_with("alignat") && tex[start..].starts_with('{') {
+        start += tex[start..].find('}')? + 1;
+    }
+    Some(start)
+}
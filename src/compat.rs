@@ -0,0 +1,126 @@
+use std::path::PathBuf;
+
+use anyhow::Error;
+
+use crate::macros::{control_sequences, Location, MacroMap};
+
+// a command unsupported by katex, and the supported approximation replacing it
+pub struct Shim {
+    pub name: &'static str,
+    pub expansion: &'static str,
+}
+
+const fn shim(name: &'static str, expansion: &'static str) -> Shim {
+    Shim { name, expansion }
+}
+
+// common commands from LaTeX packages, mapped to what katex can render
+pub const SHIMS: &[Shim] = &[
+    // relsize
+    shim(r"\mathlarger", r"{\large #1}"),
+    shim(r"\mathsmaller", r"{\small #1}"),
+    // vertical spacing, approximated by invisible rules
+    shim(r"\vspace", r"\rule{0pt}{#1}"),
+    shim(r"\smallskip", r"\rule{0pt}{3pt}"),
+    shim(r"\medskip", r"\rule{0pt}{6pt}"),
+    shim(r"\bigskip", r"\rule{0pt}{12pt}"),
+    shim(r"\mathstrut", r"\vphantom{(}"),
+    // horizontal layout
+    shim(r"\noindent", r"{}"),
+    shim(r"\hfill", r"\quad"),
+    shim(r"\lefteqn", r"\mathrlap{#1}"),
+    shim(r"\vcenter", r"{#1}"),
+    shim(r"\displaylimits", r"\limits"),
+    // boxes and fonts
+    shim(r"\mbox", r"\text{#1}"),
+    shim(r"\hbox", r"\text{#1}"),
+    shim(r"\textsc", r"\text{#1}"),
+    shim(r"\mathbbm", r"\mathbb{#1}"),
+    shim(r"\nicefrac", r"{}^{#1}\!/_{#2}"),
+];
+
+// commands katex doesn't support, and what to use instead
+const SUGGESTIONS: &[(&str, &str)] = &[
+    (r"\boldmath", r"use `\boldsymbol` instead"),
+    (r"\mathbbm", r"use `\mathbb` instead"),
+    (r"\mathds", r"use `\mathbb` instead"),
+    (r"\nicefrac", r"use `\tfrac` instead"),
+    (
+        r"\si",
+        r"write units with `\mathrm`, as siunitx isn't supported",
+    ),
+    (
+        r"\SI",
+        r"write units with `\mathrm`, as siunitx isn't supported",
+    ),
+    (
+        r"\abs",
+        r"define it in the macros file, as in `\abs:\left|#1\right|`",
+    ),
+    (
+        r"\norm",
+        r"define it in the macros file, as in `\norm:\left\|#1\right\|`",
+    ),
+];
+
+const UNDEFINED_PREFIX: &str = "Undefined control sequence: ";
+
+// name of the undefined command a katex error is about, if any
+pub fn undefined_command(error: &str) -> Option<String> {
+    let start = error.find(UNDEFINED_PREFIX)? + UNDEFINED_PREFIX.len();
+    // backslashes are escaped in the error message
+    let name: String = error[start..]
+        .chars()
+        .skip_while(|&c| c == '\\')
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect();
+    if name.is_empty() {
+        None
+    } else {
+        Some(format!("\\{}", name))
+    }
+}
+
+// what to do about a katex error, for common ones; commands with a shim are
+// pointed to `compat-shims`, unless shims are already added
+pub fn suggestion(error: &str, shims: bool) -> Option<String> {
+    let name = undefined_command(error)?;
+    if !shims && SHIMS.iter().any(|shim| shim.name == name) {
+        return Some(format!(
+            "set `compat-shims = true` for an approximation of `{}`",
+            name
+        ));
+    }
+    SUGGESTIONS
+        .iter()
+        .find(|(command, _)| *command == name)
+        .map(|(_, suggestion)| String::from(*suggestion))
+}
+
+// add the shims to the macros, unless a macro with the same name is already
+// defined, and return the shims added
+pub fn add_shims(macros: &mut MacroMap) -> Result<Vec<&'static Shim>, Error> {
+    let mut added = Vec::new();
+    for (index, shim) in SHIMS.iter().enumerate() {
+        if macros.get(shim.name).is_some() {
+            continue;
+        }
+        let location = Location {
+            path: PathBuf::from("<compat shims>"),
+            line: index + 1,
+        };
+        macros.define(shim.name, shim.expansion, location)?;
+        added.push(shim);
+    }
+    Ok(added)
+}
+
+// shims among the given ones that an equation relies on
+pub fn substitutions<'a>(tex: &str, shims: &[&'a Shim]) -> Vec<&'a Shim> {
+    let names = control_sequences(tex);
+    shims
+        .iter()
+        .filter(|shim| names.iter().any(|name| name == shim.name))
+        .copied()
+        .collect()
+}
@@ -0,0 +1,158 @@
+use std::collections::BTreeMap;
+
+use anyhow::Error;
+use serde::{Deserialize, Deserializer};
+
+// the `[preprocessor.katex]` table of `book.toml`
+//
+// Options are deserialized with their types checked, and a default for those
+// left out; values with more to check than their type, like the mode of a
+// delimiter, are checked when a renderer is set up. Keys mdbook itself sets
+// for preprocessors, like `command` or `after`, are ignored.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, rename_all = "kebab-case")]
+pub struct KatexConfig {
+    // paths of the macros files, relative to the book root, as a string for
+    // a single one
+    #[serde(deserialize_with = "one_or_more")]
+    pub macros: Vec<String>,
+    // TOML file whose `math` table holds options shared with other tools,
+    // relative to the book root
+    pub metadata: Option<String>,
+    // file whose tex is put before every equation, relative to the book root
+    pub preamble: Option<String>,
+    // "later-wins" or "error", for macros defined twice
+    pub duplicate_macros: Option<String>,
+    // read macro files that aren't UTF-8, replacing invalid bytes
+    pub lossy_utf8: bool,
+    // substitutes for commands katex doesn't support
+    pub compat_shims: bool,
+    pub trace_macros: bool,
+    // markers mapped to "display" or "inline", instead of `$$` and `$`
+    pub delimiters: Option<BTreeMap<String, String>>,
+    // opening and closing markers replacing `$$` or `$`, none if empty
+    pub block_delimiter: Option<Vec<String>>,
+    pub inline_delimiter: Option<Vec<String>>,
+    pub mathjax_delimiters: bool,
+    // false to only render display equations, whatever the delimiters above
+    pub inline: Option<bool>,
+    // false to leave `\begin{align}` and other amsmath environments written
+    // without delimiters as text
+    pub math_environments: Option<bool>,
+    // false to leave ` ```math ` blocks and ``$`x`$`` as code
+    pub math_code: Option<bool>,
+    // `\( \)` and `\[ \]`, on top of the other delimiters
+    pub latex_delimiters: bool,
+    // handling of `%` comments in equations
+    pub comments: Option<String>,
+    // "on" or "off", for chapters that don't say
+    pub default: Option<String>,
+    // patterns of the chapter paths to render, from `src`, and of those not to
+    #[serde(deserialize_with = "one_or_more")]
+    pub include: Vec<String>,
+    #[serde(deserialize_with = "one_or_more")]
+    pub exclude: Vec<String>,
+    // in bytes, 0 for no limit
+    pub max_inline_length: Option<i64>,
+    // languages of fenced blocks holding data with math in their strings
+    pub data_blocks: Vec<String>,
+    pub normalize_scripts: bool,
+    // replace symbols like `°` inside equations, with the default table and
+    // the `symbols` one
+    pub unicode_symbols: bool,
+    pub symbols: Option<BTreeMap<String, String>>,
+    // "warn", "error" or "ignore", for equations that fail to render
+    pub error_mode: Option<String>,
+    // fail the build on equations that fail to render, as with `error-mode = "error"`
+    pub fail_on_error: bool,
+    // "chapter" or "book", to number display equations
+    pub numbering: Option<String>,
+    // "chapter" or "book", for macros defined inside equations to hold until
+    // the end of the chapter or of the book
+    pub chapter_macros: Option<String>,
+    // chapter, from `src`, whose macro definitions hold for every chapter
+    pub preamble_chapter: Option<String>,
+    // render equations that fail again, with katex's default options
+    pub retry_with_defaults: bool,
+    // equations carry their tex, which katex's copy-tex extension copies
+    pub copy_tex: bool,
+    // equations carry the line of the chapter they're written at, for tools
+    // linking them to their source
+    pub source_lines: bool,
+    // url display equations link to for editing, with `{path}` and `{line}`
+    pub equation_edit_url: Option<String>,
+    // render equations when building the book, rather than in the browser
+    pub pre_render: Option<bool>,
+    // "html", "mathml" or "htmlAndMathml"
+    pub output: Option<String>,
+    pub mathml_attributes: Vec<String>,
+    // a JSON object of other katex options, with katex's names
+    pub raw_opts: Option<String>,
+    pub leqno: bool,
+    pub fleqn: bool,
+    // katex options, katex defaults if unset
+    pub throw_on_error: Option<bool>,
+    pub error_color: Option<String>,
+    pub min_rule_thickness: Option<f64>,
+    pub max_size: Option<f64>,
+    pub max_expand: Option<i32>,
+    pub trust: Option<bool>,
+    pub feed_safe: bool,
+    pub print_scale_down: bool,
+    pub nowrap_inline: bool,
+    // css length inline equations are shifted by, like "-0.1em"
+    pub inline_vertical_align: Option<String>,
+    // equations laid out left to right in books in right-to-left languages
+    pub bidi_isolation: Option<bool>,
+    pub warnings_as_comments: bool,
+    pub meta_description: bool,
+    pub meta_description_length: Option<i64>,
+    // 0 for no limit
+    pub max_display_width: Option<i64>,
+    // local copy of the katex stylesheet, relative to the root of the book
+    pub stylesheet: Option<String>,
+    // copy the stylesheet and fonts from `katex-dist` to `stylesheet`
+    pub static_css: bool,
+    pub katex_dist: Option<String>,
+    // on when `MDBOOK_KATEX_CACHE_DIR` is set, unless set to false
+    pub cache: Option<bool>,
+    // "fs" or "memory"
+    pub cache_backend: Option<String>,
+    // relative to the root of the book
+    pub cache_dir: Option<String>,
+    // JSON manifest of what was rendered, relative to the root of the book
+    pub manifest: Option<String>,
+    // words, equations and reading time of each chapter in the manifest
+    pub reading_time: bool,
+    pub words_per_minute: Option<u32>,
+    pub inline_equation_words: Option<u32>,
+    pub display_equation_words: Option<u32>,
+    // threads rendering chapters, all available cores for 0
+    pub jobs: Option<usize>,
+}
+
+impl KatexConfig {
+    // the options in a preprocessor table, or all defaults without one
+    pub fn from_table(table: Option<&toml::value::Table>) -> Result<Self, Error> {
+        match table {
+            None => Ok(KatexConfig::default()),
+            Some(table) => toml::Value::Table(table.clone())
+                .try_into()
+                .map_err(|error| Error::new(error).context("invalid `[preprocessor.katex]` table")),
+        }
+    }
+}
+
+// a list of strings, or a single one
+fn one_or_more<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMore {
+        One(String),
+        More(Vec<String>),
+    }
+    Ok(match OneOrMore::deserialize(deserializer)? {
+        OneOrMore::One(one) => vec![one],
+        OneOrMore::More(more) => more,
+    })
+}
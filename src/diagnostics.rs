@@ -0,0 +1,171 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::env;
+use std::ffi::OsString;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+// how bad a diagnostic is
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Debug,
+}
+
+impl Severity {
+    fn prefix(self) -> &'static str {
+        match self {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+            Severity::Debug => "Debug",
+        }
+    }
+
+    fn color(self) -> Color {
+        match self {
+            Severity::Error => Color::Red,
+            Severity::Warning => Color::Yellow,
+            Severity::Debug => Color::Cyan,
+        }
+    }
+}
+
+// what to do with equations that fail to render, which are kept as written
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ErrorMode {
+    // print a warning for each of them
+    #[default]
+    Warn,
+    // fail the build with an error listing all of them
+    Error,
+    Ignore,
+}
+
+impl ErrorMode {
+    pub fn from_config(value: &str) -> Result<Self, anyhow::Error> {
+        match value {
+            "warn" => Ok(ErrorMode::Warn),
+            "error" => Ok(ErrorMode::Error),
+            "ignore" => Ok(ErrorMode::Ignore),
+            other => Err(anyhow::Error::msg(format!(
+                "unknown value `{}`, expected \"warn\", \"error\" or \"ignore\"",
+                other
+            ))),
+        }
+    }
+}
+
+// a diagnostic held back by `capture`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    // printed only the first time
+    pub once: bool,
+}
+
+thread_local! {
+    // diagnostics of this thread, while `capture` runs
+    static CAPTURED: RefCell<Option<Vec<Diagnostic>>> = const { RefCell::new(None) };
+}
+
+// messages of `warn_once` diagnostics already printed
+static PRINTED_ONCE: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+// run `work`, holding back the diagnostics it emits on this thread instead
+// of printing them, so that they can be replayed in a deterministic order
+pub fn capture<R>(work: impl FnOnce() -> R) -> (R, Vec<Diagnostic>) {
+    let outer = CAPTURED.with(|captured| captured.replace(Some(Vec::new())));
+    let result = work();
+    let diagnostics = CAPTURED.with(|captured| captured.replace(outer));
+    (result, diagnostics.unwrap_or_default())
+}
+
+// emit diagnostics held back by `capture`
+pub fn replay(diagnostics: Vec<Diagnostic>) {
+    for diagnostic in diagnostics {
+        emit_diagnostic(diagnostic);
+    }
+}
+
+fn emit_diagnostic(diagnostic: Diagnostic) {
+    let diagnostic = CAPTURED.with(|captured| match &mut *captured.borrow_mut() {
+        Some(captured) => {
+            captured.push(diagnostic);
+            None
+        }
+        None => Some(diagnostic),
+    });
+    if let Some(diagnostic) = diagnostic {
+        if diagnostic.once {
+            let mut printed = PRINTED_ONCE.lock().unwrap();
+            if !printed
+                .get_or_insert_with(HashSet::new)
+                .insert(diagnostic.message.clone())
+            {
+                return;
+            }
+        }
+        print(diagnostic.severity, &diagnostic.message);
+    }
+}
+
+// print a diagnostic, unless it's being captured
+pub fn emit(severity: Severity, message: &str) {
+    emit_diagnostic(Diagnostic {
+        severity,
+        message: String::from(message),
+        once: false,
+    })
+}
+
+// print a diagnostic to stderr, with a severity prefix colored when stderr is
+// a terminal and `NO_COLOR` isn't set
+fn print(severity: Severity, message: &str) {
+    let color = use_color(env::var_os("NO_COLOR"), io::stderr().is_terminal());
+    let choice = if color {
+        ColorChoice::Auto
+    } else {
+        ColorChoice::Never
+    };
+    let mut stderr = StandardStream::stderr(choice);
+    // diagnostics are best effort, there is nowhere else to report failures
+    let _ = stderr.set_color(
+        ColorSpec::new()
+            .set_fg(Some(severity.color()))
+            .set_bold(true),
+    );
+    let _ = write!(stderr, "{}:", severity.prefix());
+    let _ = stderr.reset();
+    let _ = writeln!(stderr, " {}", message);
+}
+
+pub fn error(message: &str) {
+    emit(Severity::Error, message)
+}
+
+pub fn warn(message: &str) {
+    emit(Severity::Warning, message)
+}
+
+// a warning printed only the first time, in the order diagnostics are printed
+pub fn warn_once(message: &str) {
+    emit_diagnostic(Diagnostic {
+        severity: Severity::Warning,
+        message: String::from(message),
+        once: true,
+    })
+}
+
+pub fn debug(message: &str) {
+    emit(Severity::Debug, message)
+}
+
+// see https://no-color.org: any non-empty value disables colors
+pub fn use_color(no_color: Option<OsString>, is_terminal: bool) -> bool {
+    let no_color = no_color.is_some_and(|value| !value.is_empty());
+    is_terminal && !no_color
+}
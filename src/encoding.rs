@@ -0,0 +1,68 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use mdbook::errors::Error;
+
+use crate::diagnostics;
+
+// where a file stops being valid UTF-8, with a hint to re-encode it
+fn invalid_utf8(bytes: &[u8], error: &std::str::Utf8Error, path: &Path) -> Error {
+    let offset = error.valid_up_to();
+    let before = &bytes[..offset];
+    let line = before.iter().filter(|&&b| b == b'\n').count() + 1;
+    let column = before.iter().rev().take_while(|&&b| b != b'\n').count() + 1;
+    Error::msg(format!(
+        "{} isn't valid UTF-8: invalid byte 0x{:02x} at offset {} (line {}, column {}); \
+         re-encode it as UTF-8, e.g. with `iconv -f latin1 -t utf-8`",
+        path.display(),
+        bytes[offset],
+        offset,
+        line,
+        column
+    ))
+}
+
+// content of the file at `path`; invalid UTF-8 is replaced with U+FFFD when
+// `lossy` is set, instead of failing
+pub fn decode(bytes: Vec<u8>, path: &Path, lossy: bool) -> Result<String, Error> {
+    match String::from_utf8(bytes) {
+        Ok(content) => Ok(content),
+        Err(error) => {
+            let utf8_error = error.utf8_error();
+            let bytes = error.into_bytes();
+            let invalid = invalid_utf8(&bytes, &utf8_error, path);
+            if !lossy {
+                return Err(invalid);
+            }
+            diagnostics::warn(&format!("{}, converting it lossily", invalid));
+            Ok(String::from_utf8_lossy(&bytes).into_owned())
+        }
+    }
+}
+
+pub fn read_to_string(path: &Path, lossy: bool) -> Result<String, Error> {
+    let bytes = fs::read(path).with_context(|| format!("unable to read {}", path.display()))?;
+    decode(bytes, path, lossy)
+}
+
+// markdown files under a directory that aren't valid UTF-8, with the error
+// for each of them
+pub fn find_invalid(dir: &Path) -> Result<Vec<(PathBuf, Error)>, Error> {
+    let mut invalid = Vec::new();
+    let entries = fs::read_dir(dir).with_context(|| format!("unable to read {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.is_dir() {
+            invalid.extend(find_invalid(&path)?);
+        } else if path.extension().is_some_and(|extension| extension == "md") {
+            let bytes = fs::read(&path)?;
+            if let Err(error) = std::str::from_utf8(&bytes) {
+                let error = invalid_utf8(&bytes, &error, &path);
+                invalid.push((path, error));
+            }
+        }
+    }
+    invalid.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(invalid)
+}
@@ -0,0 +1,65 @@
+// the katex engine, embedded javascript run with quickjs, which can be left
+// out of builds with `--no-default-features`; equations are then kept as they
+// are, and only the plain text backend approximates them
+#[cfg(feature = "katex")]
+use std::collections::HashMap;
+
+use crate::RenderOpts;
+
+// katex options built once for a batch of equations; katex renders with the
+// javascript context of the thread, set up on its first equation
+//
+// katex copies the macros it's given into javascript for every equation, so
+// equations get options with the macros they use, and those using none the
+// options built here.
+#[cfg(feature = "katex")]
+pub struct Engine<'a> {
+    inline_opts: &'a RenderOpts,
+    display_opts: &'a RenderOpts,
+    inline_katex: katex::Opts,
+    display_katex: katex::Opts,
+}
+
+#[cfg(feature = "katex")]
+impl<'a> Engine<'a> {
+    pub fn new(inline_opts: &'a RenderOpts, display_opts: &'a RenderOpts) -> Self {
+        Engine {
+            inline_opts,
+            display_opts,
+            inline_katex: inline_opts.to_katex_with(HashMap::new()),
+            display_katex: display_opts.to_katex_with(HashMap::new()),
+        }
+    }
+
+    // an equation rendered to html, or the katex error message
+    pub fn render(&self, tex: &str, display: bool) -> Result<String, String> {
+        let (opts, katex_opts) = if display {
+            (self.display_opts, &self.display_katex)
+        } else {
+            (self.inline_opts, &self.inline_katex)
+        };
+        let macros = opts.macros_used_by(tex);
+        let rendered = if macros.is_empty() {
+            katex::render_with_opts(tex, katex_opts)
+        } else {
+            katex::render_with_opts(tex, opts.to_katex_with(macros))
+        };
+        rendered.map_err(|error| error.to_string())
+    }
+}
+
+#[cfg(not(feature = "katex"))]
+pub struct Engine;
+
+#[cfg(not(feature = "katex"))]
+impl Engine {
+    pub const MISSING: &'static str = "mdbook-katex was built without the `katex` feature";
+
+    pub fn new(_inline_opts: &RenderOpts, _display_opts: &RenderOpts) -> Self {
+        Engine
+    }
+
+    pub fn render(&self, _tex: &str, _display: bool) -> Result<String, String> {
+        Err(String::from(Self::MISSING))
+    }
+}
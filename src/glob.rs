@@ -0,0 +1,61 @@
+// chapters picked by path, with the `include` and `exclude` patterns
+//
+// Patterns match paths from the source directory of the book, with `/`
+// between directories: `*` matches anything but `/`, `?` a single character
+// but `/`, and `**` any number of directories, as in `appendix/**`.
+use std::path::Path;
+
+// the chapters whose paths match `include`, or every chapter if it's empty,
+// and none of `exclude`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChapterFilter {
+    include: Vec<String>,
+    exclude: Vec<String>,
+}
+
+impl ChapterFilter {
+    pub fn new(include: Vec<String>, exclude: Vec<String>) -> Self {
+        ChapterFilter { include, exclude }
+    }
+
+    // whether a chapter at a path from the source directory is picked;
+    // chapters without a path, like drafts, only are without `include`
+    pub fn selects(&self, path: Option<&Path>) -> bool {
+        let path = match path {
+            Some(path) => path.to_string_lossy().replace('\\', "/"),
+            None => return self.include.is_empty(),
+        };
+        let included =
+            self.include.is_empty() || self.include.iter().any(|pattern| matches(pattern, &path));
+        included && !self.exclude.iter().any(|pattern| matches(pattern, &path))
+    }
+}
+
+// whether a pattern matches the whole of a path
+pub fn matches(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.trim_start_matches("./").chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches_chars(&pattern, &path)
+}
+
+fn matches_chars(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        // `**/` matches nothing, or any directories up to a `/`
+        ['*', '*', '/', rest @ ..] => {
+            matches_chars(rest, path)
+                || (0..path.len())
+                    .filter(|&end| path[end] == '/')
+                    .any(|end| matches_chars(rest, &path[end + 1..]))
+        }
+        ['*', '*', rest @ ..] => (0..=path.len()).any(|start| matches_chars(rest, &path[start..])),
+        ['*', rest @ ..] => {
+            let segment = path.iter().position(|&c| c == '/').unwrap_or(path.len());
+            (0..=segment).any(|start| matches_chars(rest, &path[start..]))
+        }
+        ['?', rest @ ..] => {
+            matches!(path.first(), Some(&c) if c != '/') && matches_chars(rest, &path[1..])
+        }
+        [c, rest @ ..] => path.first() == Some(c) && matches_chars(rest, &path[1..]),
+    }
+}
@@ -0,0 +1,186 @@
+use std::fs;
+
+use anyhow::Context;
+use mdbook::book::{Book, BookItem};
+use mdbook::errors::Error;
+use mdbook::renderer::RenderContext;
+
+use crate::chapter_macros;
+use crate::html::escape_attribute;
+use crate::scan::Span;
+use crate::tex;
+use crate::{diagnostics, BookContext, ChapterRenderer, KatexProcessor, MathSpan};
+
+// configuration table of the renderer
+pub const TABLE: &str = "output.katex-handout";
+
+// a labeled equation of the book, with the chapter it's in
+#[derive(Debug, PartialEq)]
+pub struct Equation {
+    pub label: String,
+    pub chapter: String,
+    pub tex: String,
+}
+
+// label of an equation, from `\tag{...}`, or `\label{...}` for books written
+// for mathjax
+fn label(tex: &str) -> Option<&str> {
+    for command in &["\\tag*{", "\\tag{", "\\label{"] {
+        if let Some(start) = tex.find(command) {
+            let start = start + command.len();
+            let end = start + tex[start..].find('}')?;
+            return Some(tex[start..end].trim());
+        }
+    }
+    None
+}
+
+// the labeled equations of a book in reading order, from chapters rendered
+// by the preprocessor, and among `labels` if given
+pub fn labeled_equations(
+    renderer: &ChapterRenderer,
+    book: &Book,
+    labels: Option<&[String]>,
+) -> Vec<Equation> {
+    // macros defined in chapters apply, as when the chapters are rendered
+    let mut book = book.clone();
+    if renderer.chapter_macros.is_some() || renderer.preamble_chapter.is_some() {
+        chapter_macros::collect_macros(renderer, &mut book);
+    }
+    // chapters that opted out of rendering are left out
+    let mut inherited = renderer.inherited_settings(&book).into_iter();
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            let inherited = inherited.next().unwrap_or(renderer.enabled);
+            let (setting, _) = ChapterRenderer::front_matter_setting(&chapter.content);
+            if !setting.unwrap_or(inherited) {
+                chapter.content.clear();
+            }
+        }
+    });
+    let mut equations = Vec::new();
+    for item in book.iter() {
+        let chapter = match item {
+            BookItem::Chapter(chapter) => chapter,
+            _ => continue,
+        };
+        for span in renderer.scanner.scan(&chapter.content, &mut |_| {}) {
+            let tex = match span {
+                Span::Math { tex, .. } => tex,
+                _ => continue,
+            };
+            let label = match label(tex) {
+                Some(label) => label,
+                None => continue,
+            };
+            if labels.is_some_and(|labels| !labels.iter().any(|wanted| wanted == label)) {
+                continue;
+            }
+            equations.push(Equation {
+                label: String::from(label),
+                chapter: chapter.name.clone(),
+                tex: tex::without_labels(tex),
+            });
+        }
+    }
+    equations
+}
+
+// equations are rendered as for the html output, with the same options and macros
+fn handout_context(ctx: &RenderContext) -> BookContext<'_> {
+    BookContext {
+        root: &ctx.root,
+        config: &ctx.config,
+        renderer: "html",
+    }
+}
+
+fn string_option<'a>(ctx: &'a RenderContext, name: &str) -> Result<Option<&'a str>, Error> {
+    match ctx.config.get(&format!("{}.{}", TABLE, name)) {
+        None => Ok(None),
+        Some(toml::Value::String(value)) => Ok(Some(value)),
+        Some(_) => Err(Error::msg(format!(
+            "invalid option `{}` in `[{}]`, expected a string",
+            name, TABLE
+        ))),
+    }
+}
+
+fn load_labels(ctx: &RenderContext) -> Result<Option<Vec<String>>, Error> {
+    let invalid = || {
+        Error::msg(format!(
+            "invalid option `labels` in `[{}]`, expected an array of strings",
+            TABLE
+        ))
+    };
+    match ctx.config.get(&format!("{}.labels", TABLE)) {
+        None => Ok(None),
+        Some(toml::Value::Array(labels)) => labels
+            .iter()
+            .map(|label| label.as_str().map(String::from).ok_or_else(invalid))
+            .collect::<Result<_, _>>()
+            .map(Some),
+        Some(_) => Err(invalid()),
+    }
+}
+
+// a single page with the labeled equations of the book, grouped by chapter
+pub fn page(ctx: &RenderContext) -> Result<String, Error> {
+    let labels = load_labels(ctx)?;
+    let renderer = KatexProcessor::default().renderer_for(&handout_context(ctx))?;
+    let equations = labeled_equations(&renderer, &ctx.book, labels.as_deref());
+    let spans: Vec<MathSpan> = equations
+        .iter()
+        .map(|equation| MathSpan {
+            tex: &equation.tex,
+            display: true,
+        })
+        .collect();
+    let book_title = ctx.config.book.title.as_deref().unwrap_or("Equations");
+    let title = string_option(ctx, "title")?.unwrap_or(book_title);
+    let mut page = format!(
+        "<!DOCTYPE html>\n<html lang=\"{}\">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n{}</head>\n<body>\n<h1>{}</h1>\n",
+        escape_attribute(ctx.config.book.language.as_deref().unwrap_or("en")),
+        escape_attribute(title),
+        crate::katex_header(),
+        escape_attribute(title)
+    );
+    let mut chapter = None;
+    for (equation, rendered) in equations.iter().zip(renderer.render_many(&spans)) {
+        let rendered = match rendered {
+            Ok(rendered) => rendered,
+            Err(error) => {
+                diagnostics::warn(&format!(
+                    "equation ({}) of `{}` left out of the handout: {}",
+                    equation.label, equation.chapter, error
+                ));
+                continue;
+            }
+        };
+        if chapter != Some(&equation.chapter) {
+            chapter = Some(&equation.chapter);
+            page.push_str(&format!(
+                "<h2>{}</h2>\n",
+                escape_attribute(&equation.chapter)
+            ));
+        }
+        page.push_str(&format!(
+            "<figure id=\"{}\">\n{}\n<figcaption>({})</figcaption>\n</figure>\n",
+            escape_attribute(&equation.label),
+            rendered,
+            escape_attribute(&equation.label)
+        ));
+    }
+    page.push_str("</body>\n</html>\n");
+    Ok(page)
+}
+
+// write the handout to the destination of the renderer
+pub fn render(ctx: &RenderContext) -> Result<(), Error> {
+    let file = string_option(ctx, "file")?.unwrap_or("handout.html");
+    let path = ctx.destination.join(file);
+    let page = page(ctx)?;
+    fs::create_dir_all(&ctx.destination)
+        .and_then(|_| fs::write(&path, page))
+        .with_context(|| format!("unable to write {}", path.display()))
+}
@@ -0,0 +1,433 @@
+use std::borrow::Cow;
+
+// characters with a meaning in markdown, which could be taken for emphasis
+// markers, code spans, links or escapes once rendered equations are passed to
+// the markdown renderer
+const MARKDOWN_CHARACTERS: &[(char, &str)] = &[
+    ('*', "&#42;"),
+    ('_', "&#95;"),
+    ('`', "&#96;"),
+    ('~', "&#126;"),
+    ('[', "&#91;"),
+    (']', "&#93;"),
+    ('\\', "&#92;"),
+];
+
+// put between an inline equation ending with a superscript and a footnote
+// marker right after it, so that `$x^2$[^1]` doesn't read as x to the 21; a
+// narrow no-break space keeps them on the same line
+pub const FOOTNOTE_GAP: &str = "&#8239;";
+
+// comments starting and ending regions where equations are escaped for
+// javascript strings, e.g. in a script filling tooltips
+const ESCAPE_JS_START: &str = "<!-- katex-escape: js -->";
+const ESCAPE_JS_END: &str = "<!-- /katex-escape -->";
+
+// whether equations after some text are escaped for javascript strings, if
+// the text starts or ends a region
+pub fn escape_region(text: &str) -> Option<bool> {
+    match (text.rfind(ESCAPE_JS_START), text.rfind(ESCAPE_JS_END)) {
+        (Some(start), Some(end)) => Some(start > end),
+        (Some(_), None) => Some(true),
+        (None, Some(_)) => Some(false),
+        (None, None) => None,
+    }
+}
+
+// some HTML as the content of a javascript string or JSON string, in single or
+// double quotes, which can't end a surrounding script element either; only
+// `\u` escapes are used, which markdown leaves alone
+pub fn escape_js_string(html: &str) -> String {
+    let mut result = String::with_capacity(html.len());
+    for c in html.chars() {
+        match c {
+            '\\' => result.push_str("\\u005c"),
+            '"' => result.push_str("\\u0022"),
+            '\'' => result.push_str("\\u0027"),
+            '<' => result.push_str("\\u003c"),
+            '>' => result.push_str("\\u003e"),
+            '&' => result.push_str("\\u0026"),
+            '\u{2028}' => result.push_str("\\u2028"),
+            '\u{2029}' => result.push_str("\\u2029"),
+            c if c.is_control() => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+// replace markdown characters in the text of some HTML with entities, leaving
+// tags untouched
+pub fn escape_markdown(html: &str) -> Cow<'_, str> {
+    if !html
+        .chars()
+        .any(|c| MARKDOWN_CHARACTERS.iter().any(|(special, _)| *special == c))
+    {
+        return Cow::Borrowed(html);
+    }
+    let mut result = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => in_tag = false,
+            _ => {}
+        }
+        match MARKDOWN_CHARACTERS
+            .iter()
+            .find(|(special, _)| *special == c)
+        {
+            Some((_, entity)) if !in_tag => result.push_str(entity),
+            _ => result.push(c),
+        }
+    }
+    Cow::Owned(result)
+}
+
+// text escaped for an attribute value in double quotes
+pub fn escape_attribute(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+// a chapter description, and a script replacing the description of the page
+// with it, which is taken from the book otherwise; on the print page, the
+// first chapter's description is kept
+pub fn meta_description(description: &str) -> String {
+    format!(
+        r#"<meta name="description" content="{}" class="katex-description">
+<script>
+(function () {{
+    var chapter = document.currentScript.previousElementSibling;
+    var page = document.head.querySelector('meta[name="description"]');
+    if (!window.katexDescription) {{
+        window.katexDescription = true;
+        if (page) {{ page.content = chapter.content; }} else {{ document.head.appendChild(chapter); }}
+    }}
+}})();
+</script>
+
+"#,
+        escape_attribute(description)
+    )
+}
+
+// length of the chapter description some content starts with, if any
+pub fn description_length(content: &str) -> Option<usize> {
+    let tag = content.strip_prefix("<meta name=\"description\" content=\"")?;
+    let tag_end = tag.find('>')?;
+    if !tag[..tag_end].ends_with("class=\"katex-description\"") {
+        return None;
+    }
+    let end = "</script>\n\n";
+    let length = content.find(end)? + end.len();
+    Some(length)
+}
+
+// the comment marking the key equation of a chapter, with the path of its
+// preview image in the built book
+pub const HERO: &str = "<!-- katex-hero:";
+
+// open graph and twitter card tags previewing a chapter with an image of its
+// key equation, made by the author or another tool from the tex in `data-tex`,
+// and a script moving them to the head of the page; on the print page, those
+// of the first chapter are kept
+pub fn hero_meta(image: &str, description: &str, tex: &str) -> String {
+    format!(
+        r#"<meta property="og:image" content="{image}" data-tex="{}" class="katex-hero">
+<meta property="og:description" content="{}" class="katex-hero">
+<meta name="twitter:card" content="summary_large_image" class="katex-hero">
+<meta name="twitter:image" content="{image}" class="katex-hero">
+<script>
+(function () {{
+    if (!window.katexHero) {{
+        window.katexHero = true;
+        var tags = document.querySelectorAll('meta.katex-hero');
+        for (var i = 0; i < tags.length; i++) {{ document.head.appendChild(tags[i]); }}
+    }}
+}})();
+</script>
+
+"#,
+        escape_attribute(tex.trim()),
+        escape_attribute(description),
+        image = escape_attribute(image)
+    )
+}
+
+// length of the preview tags some content starts with, if any
+pub fn hero_length(content: &str) -> Option<usize> {
+    if !content.starts_with("<meta property=\"og:image\"") {
+        return None;
+    }
+    let end = "</script>\n\n";
+    Some(content.find(end)? + end.len())
+}
+
+// link to a local copy of the katex stylesheet
+pub fn stylesheet_link(url: &str) -> String {
+    format!(
+        "<link rel=\"stylesheet\" href=\"{}\" data-katex-version=\"{}\">\n\n",
+        escape_attribute(url),
+        crate::KATEX_VERSION
+    )
+}
+
+// katex and its auto-render extension, rendering the equations left by the
+// preprocessor in the browser with `options`, which also hold the delimiters to
+// look for; the rest of the page, like code, is left alone
+pub fn auto_render_scripts(options: &serde_json::Value) -> String {
+    let cdn = format!(
+        "https://cdn.jsdelivr.net/npm/katex@{}/dist",
+        crate::KATEX_VERSION
+    );
+    format!(
+        "<script defer src=\"{}/katex.min.js\" integrity=\"sha384-g7c+Jr9ZivxKLnZTDUhnkOnsh30B4H0rpLUpJ4jAIKs4fnJI+sEnkvrMWph2EDg4\" crossorigin=\"anonymous\"></script>\n\
+         <script defer src=\"{}/contrib/auto-render.min.js\" integrity=\"sha384-mll67QQFJfxn0IYznZYonOWZ644AWYC+Pt2cHqMaRhXVrursRwvLnLaebdGIlYNa\" crossorigin=\"anonymous\" onload=\"document.querySelectorAll('.katex-source').forEach(function (el) {{ renderMathInElement(el, {}) }})\"></script>\n\n",
+        cdn,
+        cdn,
+        escape_attribute(&options.to_string())
+    )
+}
+
+// an equation left as text for the browser to render, on one line and with
+// html and markdown characters as entities, so that it reaches katex as is;
+// its span keeps it from being processed again, and readers without javascript
+// are told what they're looking at
+pub fn client_side(open: &str, tex: &str, close: &str) -> String {
+    let text = format!("{}{}{}", open, tex.replace('\n', " "), close);
+    format!(
+        "<span class=\"katex-source\">{}</span>{}",
+        escape_markdown(&escape_attribute(&text)),
+        NOSCRIPT_NOTE
+    )
+}
+
+// the note following equations left for the browser
+pub const NOSCRIPT_NOTE: &str =
+    "<noscript><small class=\"katex-noscript\"> (TeX, rendered with JavaScript)</small></noscript>";
+
+// katex's copy-tex extension, copying the tex of equations, as `$...$`, along
+// with text selected around them
+pub fn copy_tex_assets() -> String {
+    let cdn = format!(
+        "https://cdn.jsdelivr.net/npm/katex@{}/dist/contrib",
+        crate::KATEX_VERSION
+    );
+    format!(
+        "<link rel=\"stylesheet\" href=\"{}/copy-tex.css\">\n<script defer src=\"{}/copy-tex.min.js\"></script>\n\n",
+        cdn, cdn
+    )
+}
+
+// a rendered equation carrying the tex it was written with, for readers and
+// scripts to copy
+pub fn with_tex(rendered: &str, tex: &str) -> String {
+    let attribute = format!(" data-tex=\"{}\"", escape_attribute(tex.trim()));
+    match rendered.strip_prefix("<span") {
+        Some(rest) => format!("<span{}{}", attribute, rest),
+        None => format!("<span{}>{}</span>", attribute, rendered),
+    }
+}
+
+// a rendered equation carrying the line of the chapter it's written at, on
+// its first element, which may follow strict mode warnings
+pub fn with_source_line(rendered: &str, line: usize) -> String {
+    let attribute = format!(" data-source-line=\"{}\"", line);
+    match rendered.find("<span") {
+        Some(start) => {
+            let end = start + "<span".len();
+            format!("{}{}{}", &rendered[..end], attribute, &rendered[end..])
+        }
+        None => format!("<span{}>{}</span>", attribute, rendered),
+    }
+}
+
+// links to edit display equations, in the corner of the equation and faint
+// until it's hovered
+pub const EDIT_LINKS: &str = r#"<style>
+.katex-editable { position: relative; display: block; }
+.katex-edit-link { position: absolute; top: 0; right: 0; opacity: 0.2; text-decoration: none; }
+.katex-editable:hover .katex-edit-link, .katex-edit-link:focus { opacity: 1; }
+@media print { .katex-edit-link { display: none; } }
+</style>
+
+"#;
+
+// a display equation with a link to edit it at `url`
+pub fn with_edit_link(rendered: &str, url: &str) -> String {
+    format!(
+        "<span class=\"katex-editable\">{}<a class=\"katex-edit-link\" href=\"{}\" \
+         title=\"Edit this equation\" aria-label=\"Edit this equation\">✎</a></span>",
+        rendered,
+        escape_attribute(url)
+    )
+}
+
+// inline equations kept on one line, scrolling when wider than the text
+pub const NOWRAP_INLINE: &str = r#"<style>
+.katex-nowrap { display: inline-block; max-width: 100%; overflow-x: auto; overflow-y: hidden; white-space: nowrap; vertical-align: bottom; }
+</style>
+
+"#;
+
+// an inline equation kept on one line; feeds get no stylesheet, so the style
+// is inline there, without the overflow fallback
+pub fn nowrap(rendered: &str, feed_safe: bool) -> String {
+    if feed_safe {
+        format!("<span style=\"white-space: nowrap\">{}</span>", rendered)
+    } else {
+        format!("<span class=\"katex-nowrap\">{}</span>", rendered)
+    }
+}
+
+// units of the lengths inline equations can be shifted by
+const LENGTH_UNITS: &[&str] = &["em", "ex", "rem", "px", "pt"];
+
+// whether a value is a css length, like `-0.1em` or `0`
+pub fn is_length(value: &str) -> bool {
+    let number = LENGTH_UNITS
+        .iter()
+        .find_map(|unit| value.strip_suffix(unit))
+        .unwrap_or(if value == "0" { value } else { "" });
+    let digits = number.strip_prefix(['-', '+']).unwrap_or(number);
+    digits.chars().any(|c| c.is_ascii_digit())
+        && digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+        && digits.matches('.').count() <= 1
+}
+
+// inline equations shifted up or down, for themes whose line height leaves
+// them off the baseline of the text
+pub fn vertical_align_style(length: &str) -> String {
+    format!(
+        "<style>\n.katex-valign {{ vertical-align: {}; }}\n</style>\n\n",
+        length
+    )
+}
+
+// an inline equation shifted by `vertical_align_style`, or inline in feeds
+pub fn vertical_align(rendered: &str, length: &str, feed_safe: bool) -> String {
+    if feed_safe {
+        format!(
+            "<span style=\"vertical-align: {}\">{}</span>",
+            length, rendered
+        )
+    } else {
+        format!("<span class=\"katex-valign\">{}</span>", rendered)
+    }
+}
+
+// attributes added to the `<math>` element of mathml output, so that assistive
+// technology and search engines interpret equations correctly
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MathAttribute {
+    // `display="inline"` on inline equations, display ones have `"block"`
+    Display,
+    // the tex source, read out when the mathml isn't supported
+    Alttext,
+    // the language of the book
+    Lang,
+    Role,
+}
+
+impl MathAttribute {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "display" => Some(MathAttribute::Display),
+            "alttext" => Some(MathAttribute::Alttext),
+            "lang" => Some(MathAttribute::Lang),
+            "role" => Some(MathAttribute::Role),
+            _ => None,
+        }
+    }
+}
+
+// add attributes to the `<math>` element of a rendered equation
+pub fn math_attributes(
+    rendered: &str,
+    attributes: &[MathAttribute],
+    tex: &str,
+    display: bool,
+    lang: Option<&str>,
+) -> String {
+    let start = match rendered.find("<math") {
+        Some(start) => start + "<math".len(),
+        None => return String::from(rendered),
+    };
+    let mut added = String::new();
+    for attribute in attributes {
+        match attribute {
+            MathAttribute::Display if !display => added.push_str(" display=\"inline\""),
+            MathAttribute::Display => {}
+            MathAttribute::Alttext => {
+                added.push_str(&format!(" alttext=\"{}\"", escape_attribute(tex.trim())))
+            }
+            MathAttribute::Lang => {
+                if let Some(lang) = lang {
+                    added.push_str(&format!(" lang=\"{}\"", escape_attribute(lang)))
+                }
+            }
+            MathAttribute::Role => added.push_str(" role=\"math\""),
+        }
+    }
+    let mut result = String::with_capacity(rendered.len() + added.len());
+    result.push_str(&rendered[..start]);
+    result.push_str(&added);
+    result.push_str(&rendered[start..]);
+    result
+}
+
+// languages written right to left, by their primary subtag
+const RTL_LANGUAGES: &[&str] = &[
+    "ar", "arc", "ckb", "dv", "fa", "he", "iw", "ps", "sd", "syr", "ug", "ur", "yi",
+];
+
+// scripts written right to left, for tags like `pa-Arab`
+const RTL_SCRIPTS: &[&str] = &["arab", "hebr", "syrc", "thaa", "nkoo", "adlm"];
+
+// whether a book language, like `ar` or `he-IL`, is written right to left
+pub fn right_to_left(lang: &str) -> bool {
+    let mut subtags = lang.split(['-', '_']);
+    let language = subtags.next().unwrap_or("").to_ascii_lowercase();
+    match subtags.find(|subtag| subtag.len() == 4) {
+        Some(script) => RTL_SCRIPTS.contains(&script.to_ascii_lowercase().as_str()),
+        None => RTL_LANGUAGES.contains(&language.as_str()),
+    }
+}
+
+// a rendered equation laid out left to right whatever the direction of the
+// text around it; the `dir` attribute also isolates it, so that the text
+// after an equation isn't reordered with it
+pub fn left_to_right(rendered: &str) -> String {
+    match rendered.strip_prefix("<span") {
+        Some(rest) => format!("<span dir=\"ltr\"{}", rest),
+        None => format!("<span dir=\"ltr\">{}</span>", rendered),
+    }
+}
+
+// shrink display equations wider than the page when printing, e.g. to PDF,
+// instead of cutting them off; the guard keeps the print page, where every
+// chapter includes it, from scaling equations more than once
+pub const PRINT_SCALE_DOWN: &str = r#"<style>
+@media print { .katex-display { overflow: visible !important; } }
+</style>
+<script>
+if (!window.katexPrintScaleDown) {
+    window.katexPrintScaleDown = true;
+    window.addEventListener("beforeprint", function () {
+        document.querySelectorAll(".katex-display > .katex").forEach(function (e) {
+            var scale = e.parentNode.clientWidth / e.scrollWidth;
+            if (scale < 1) { e.style.fontSize = (1.21 * scale) + "em"; }
+        });
+    });
+    window.addEventListener("afterprint", function () {
+        document.querySelectorAll(".katex-display > .katex").forEach(function (e) {
+            e.style.fontSize = "";
+        });
+    });
+}
+</script>
+
+"#;
@@ -1,168 +1,2490 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
+// the scanning, tex and html modules, along with the options, make up a core
+// with no dependency on mdbook, which builds for wasm32 with
+// `--no-default-features`, e.g. for editor previews; the preprocessor itself
+// and the katex engine are the `preprocessor` and `katex` features
+#[cfg(feature = "preprocessor")]
+use std::borrow::Cow;
+#[cfg(feature = "preprocessor")]
+use std::collections::{BTreeMap, HashMap};
+#[cfg(feature = "preprocessor")]
+use std::path::{Path, PathBuf};
 
+#[cfg(feature = "preprocessor")]
+use anyhow::Context;
+use anyhow::Error;
+#[cfg(feature = "preprocessor")]
 use mdbook::book::{Book, BookItem};
-use mdbook::errors::Error;
+#[cfg(feature = "preprocessor")]
 use mdbook::preprocess::{Preprocessor, PreprocessorContext};
+#[cfg(feature = "preprocessor")]
+use mdbook::Config;
 
-pub struct KatexProcessor;
+#[cfg(feature = "preprocessor")]
+pub mod analysis;
+#[cfg(feature = "preprocessor")]
+pub mod assets;
+#[cfg(feature = "preprocessor")]
+pub mod cache;
+#[cfg(feature = "preprocessor")]
+pub mod chapter_macros;
+pub mod compat;
+pub mod config;
+#[cfg(feature = "preprocessor")]
+pub mod diagnostics;
+#[cfg(feature = "preprocessor")]
+pub mod encoding;
+pub mod engine;
+pub mod glob;
+#[cfg(feature = "preprocessor")]
+pub mod handout;
+pub mod html;
+pub mod macros;
+#[cfg(feature = "preprocessor")]
+pub mod manifest;
+pub mod markdown;
+#[cfg(feature = "preprocessor")]
+pub mod metadata;
+#[cfg(feature = "preprocessor")]
+pub mod migrate;
+#[cfg(feature = "preprocessor")]
+pub mod numbering;
+pub mod opts;
+#[cfg(feature = "preprocessor")]
+pub mod parallel;
+#[cfg(feature = "preprocessor")]
+pub mod pipeline;
+pub mod reading;
+pub mod scan;
+pub mod snippets;
+pub mod strict;
+pub mod tex;
+pub mod text;
 
+#[cfg(feature = "preprocessor")]
+use cache::{Cache, FsCache, MemoryCache};
+#[cfg(feature = "preprocessor")]
+use chapter_macros::MacroScope;
+#[cfg(feature = "preprocessor")]
+use compat::Shim;
+pub use config::KatexConfig;
+#[cfg(feature = "preprocessor")]
+use diagnostics::ErrorMode;
+#[cfg(feature = "preprocessor")]
+use engine::Engine;
+#[cfg(feature = "preprocessor")]
+use glob::ChapterFilter;
+#[cfg(feature = "preprocessor")]
+use html::MathAttribute;
+#[cfg(feature = "preprocessor")]
+use macros::{DuplicatePolicy, MacroMap};
+#[cfg(feature = "preprocessor")]
+use manifest::Manifest;
+#[cfg(feature = "preprocessor")]
+use numbering::Numbering;
+pub use opts::{OutputType, RenderOpts};
+#[cfg(feature = "preprocessor")]
+use reading::{Reading, ReadingSpeed};
+#[cfg(feature = "preprocessor")]
+use scan::Span;
+pub use scan::{default_delimiters, Delimiter, Scanner};
+#[cfg(feature = "preprocessor")]
+use snippets::Snippets;
+#[cfg(feature = "preprocessor")]
+use tex::CommentHandling;
+
+// the preprocessor, configured by `[preprocessor.katex]`, and by what tools
+// embedding it set with a `KatexProcessorBuilder`
+#[cfg(feature = "preprocessor")]
+#[derive(Clone, Debug, Default)]
+pub struct KatexProcessor {
+    // options on top of `[preprocessor.katex]`, as if they were set there
+    options: toml::value::Table,
+    // macros defined after those of the macros file
+    macros: BTreeMap<String, String>,
+    // delimiters replacing the configured ones
+    delimiters: Option<Vec<Delimiter>>,
+}
+
+// a `KatexProcessor` for other tools, as in
+// `KatexProcessorBuilder::new().macros(macros).delimiters(delimiters).build()`
+#[cfg(feature = "preprocessor")]
+#[derive(Clone, Debug, Default)]
+pub struct KatexProcessorBuilder {
+    processor: KatexProcessor,
+}
+
+#[cfg(feature = "preprocessor")]
+impl KatexProcessorBuilder {
+    pub fn new() -> Self {
+        KatexProcessorBuilder::default()
+    }
+
+    // an option of `[preprocessor.katex]`, by its name there, as in
+    // `option("leqno", true)`; it wins over the one in book.toml
+    pub fn option(mut self, name: &str, value: impl Into<toml::Value>) -> Self {
+        self.processor
+            .options
+            .insert(String::from(name), value.into());
+        self
+    }
+
+    // macros, as in the macros file, defined after those there; `duplicate-macros`
+    // applies to those defined in both
+    pub fn macros(mut self, macros: HashMap<String, String>) -> Self {
+        self.processor.macros.extend(macros);
+        self
+    }
+
+    pub fn delimiters(mut self, delimiters: Vec<Delimiter>) -> Self {
+        self.processor.delimiters = Some(delimiters);
+        self
+    }
+
+    pub fn build(self) -> KatexProcessor {
+        self.processor
+    }
+}
+
+// renderers that can't show HTML, which get plain text approximations of equations
+pub const TEXT_RENDERERS: &[&str] = &["markdown", "man", "plaintext", "text"];
+
+// version of the katex engine bundled with the katex crate
+pub const KATEX_VERSION: &str = "0.12.0";
+
+// fail unless equations are rendered with an approved version of katex, given
+// in full or as a prefix like `0.12`
+pub fn check_katex_version(expected: &str) -> Result<(), Error> {
+    let mut bundled = KATEX_VERSION.split('.');
+    let matches = expected
+        .trim_start_matches('v')
+        .split('.')
+        .all(|part| bundled.next() == Some(part));
+    if matches {
+        Ok(())
+    } else {
+        Err(Error::msg(format!(
+            "equations are rendered with KaTeX {}, but KaTeX {} is expected",
+            KATEX_VERSION, expected
+        )))
+    }
+}
+
+// default length of chapter descriptions, in characters
+pub const DEFAULT_DESCRIPTION_LENGTH: usize = 160;
+
+// chapters with at least this many equations have them rendered in parallel
+#[cfg(feature = "preprocessor")]
+const PARALLEL_EQUATIONS: usize = 256;
+
+// chapters with at least this many equations are cached paragraph by paragraph,
+// so that editing a paragraph only renders its equations again
+#[cfg(feature = "preprocessor")]
+const SEGMENTED_EQUATIONS: usize = 100;
+
+// split spans into segments at paragraphs starting a line without indentation,
+// which displayed equations of later paragraphs don't look past to reindent
+#[cfg(feature = "preprocessor")]
+fn segments(spans: Vec<Span<'_>>) -> Vec<Vec<Span<'_>>> {
+    let mut segments = vec![Vec::new()];
+    for span in spans {
+        let text = match span {
+            Span::Text(text) => text,
+            other => {
+                segments.last_mut().unwrap().push(other);
+                continue;
+            }
+        };
+        let mut rest = text.as_str();
+        while let Some(end) = paragraph_start(rest) {
+            let segment = segments.last_mut().unwrap();
+            segment.push(Span::Text(String::from(&rest[..end])));
+            segments.push(Vec::new());
+            rest = &rest[end..];
+        }
+        if !rest.is_empty() {
+            segments
+                .last_mut()
+                .unwrap()
+                .push(Span::Text(String::from(rest)));
+        }
+    }
+    segments.retain(|segment| !segment.is_empty());
+    segments
+}
+
+// move a position past the lines of some spans
+#[cfg(feature = "preprocessor")]
+fn advance(position: &mut Option<Position>, spans: &[Span]) {
+    if let Some(position) = position {
+        position.line += spans.iter().map(Span::lines).sum::<usize>();
+    }
+}
+
+// position of the first paragraph of some text starting without indentation,
+// after a blank line; text ending with a blank line is followed by an equation
+// or a data block, starting a paragraph without indentation as well
+#[cfg(feature = "preprocessor")]
+fn paragraph_start(text: &str) -> Option<usize> {
+    text.match_indices('\n')
+        .map(|(index, _)| index + 1)
+        .find(|&start| {
+            text[..start].ends_with("\n\n")
+                && (start == text.len() || text[start..].starts_with(|c: char| !c.is_whitespace()))
+        })
+}
+
+// whether equations after a segment are in a `<!-- katex-escape: js -->` region
+#[cfg(feature = "preprocessor")]
+fn segment_escape(segment: &[Span], escape_js: bool) -> bool {
+    segment
+        .iter()
+        .fold(escape_js, |escape_js, span| match span {
+            Span::Text(text) => html::escape_region(text).unwrap_or(escape_js),
+            _ => escape_js,
+        })
+}
+
+// how equations are output
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq)]
+pub enum Backend {
+    // html rendered by katex
+    #[default]
+    Html,
+    // plain text approximations, without the katex stylesheet
+    Text,
+}
+
+impl Backend {
+    pub fn for_renderer(renderer: &str) -> Self {
+        if renderer == "html" {
+            Backend::Html
+        } else {
+            Backend::Text
+        }
+    }
+}
+
+// what the preprocessor is configured from: the book root, its configuration,
+// and the renderer the book is processed for
+#[cfg(feature = "preprocessor")]
+#[derive(Clone, Copy)]
+pub struct BookContext<'a> {
+    pub root: &'a Path,
+    pub config: &'a Config,
+    pub renderer: &'a str,
+}
+
+#[cfg(feature = "preprocessor")]
+impl<'a> From<&'a PreprocessorContext> for BookContext<'a> {
+    fn from(ctx: &'a PreprocessorContext) -> Self {
+        BookContext {
+            root: &ctx.root,
+            config: &ctx.config,
+            renderer: &ctx.renderer,
+        }
+    }
+}
+
+#[cfg(feature = "preprocessor")]
 impl Preprocessor for KatexProcessor {
     fn name(&self) -> &str {
         "katex"
     }
 
     fn run(&self, ctx: &PreprocessorContext, mut book: Book) -> Result<Book, Error> {
-        let (inline_opts, display_opts) = self.build_opts(ctx);
+        let renderer = self.renderer(ctx)?;
+        let mut manifest = renderer.manifest();
+        let chapters = pipeline::take_chapters(&renderer, &mut book)?;
+        let mut rendered = Vec::with_capacity(chapters.len());
+        let rendering =
+            pipeline::render_chapters(&renderer, &chapters, |index, (content, stats)| {
+                if let Some(manifest) = &mut manifest {
+                    manifest.add(
+                        &chapters[index].name,
+                        chapters[index].path.as_deref(),
+                        stats,
+                    );
+                }
+                rendered.push(content);
+                true
+            });
+        let mut rendered = rendered.into_iter();
         book.for_each_mut(|item| {
             if let BookItem::Chapter(chapter) = item {
-                chapter.content =
-                    self.process_chapter(&chapter.content, &inline_opts, &display_opts)
+                chapter.content = rendered.next().unwrap_or_default();
             }
         });
+        if let Some(manifest) = &manifest {
+            renderer.write_manifest(manifest)?;
+        }
+        rendering?;
         Ok(book)
     }
 
     fn supports_renderer(&self, renderer: &str) -> bool {
-        renderer == "html"
+        renderer == "html" || TEXT_RENDERERS.contains(&renderer)
     }
 }
 
-impl KatexProcessor {
-    fn build_opts(&self, ctx: &PreprocessorContext) -> (katex::Opts, katex::Opts) {
-        // load macros as a HashMap
-        let macros = Self::load_macros(ctx);
-        // inline rendering options
-        let inline_opts = katex::Opts::builder()
-            .display_mode(false)
-            .output_type(katex::OutputType::Html)
-            .macros(macros.clone())
-            .build()
-            .unwrap();
-        // display rendering options
-        let display_opts = katex::Opts::builder()
-            .display_mode(true)
-            .output_type(katex::OutputType::Html)
-            .macros(macros)
-            .build()
-            .unwrap();
-        (inline_opts, display_opts)
+// what a renderer was configured from, for chapters overriding options
+#[cfg(feature = "preprocessor")]
+struct OwnedContext {
+    root: PathBuf,
+    config: Config,
+    renderer: String,
+    processor: KatexProcessor,
+}
+
+// where content being rendered starts in its chapter: the line, and the edit
+// url of the chapter with `{line}` left to fill
+#[cfg(feature = "preprocessor")]
+#[derive(Clone, Copy, Debug, Hash)]
+struct Position<'a> {
+    line: usize,
+    edit_url: Option<&'a str>,
+}
+
+// an equation to render, without its delimiters
+#[derive(Clone, Copy, Debug)]
+pub struct MathSpan<'a> {
+    pub tex: &'a str,
+    pub display: bool,
+}
+
+// an equation katex failed to render, with the tex it was given
+#[derive(Clone, Debug, PartialEq, serde::Serialize)]
+pub struct RenderError {
+    pub tex: String,
+    pub error: String,
+    // line of the chapter the equation starts on, ignoring its front matter
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    // what to do about common errors, like using `\bm`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub suggestion: Option<String>,
+}
+
+impl std::fmt::Display for RenderError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "couldn't render `{}`: {}", self.tex, self.error)?;
+        match &self.suggestion {
+            Some(suggestion) => write!(f, "; {}", suggestion),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for RenderError {}
+
+// an equation rendered, with the error it first failed with if it was then
+// rendered with the fallback options
+#[cfg(feature = "preprocessor")]
+struct Rendered {
+    html: String,
+    fallback: Option<RenderError>,
+}
+
+// what rendering a chapter did, for the build manifest
+#[cfg(feature = "preprocessor")]
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChapterStats {
+    // false for chapters left out with `katex: off`
+    pub rendered: bool,
+    pub equations: usize,
+    pub failures: Vec<RenderError>,
+    // equations rendered with the fallback options, with the error they first
+    // failed with
+    pub fallbacks: Vec<RenderError>,
+    // equations already rendered, e.g. by an earlier build, left as they are
+    pub prerendered: usize,
+    // the chapter came from the cache, with the equations counted when it
+    // was rendered
+    pub cached: bool,
+    // with `reading-time`, measured whether the chapter came from the cache or not
+    pub reading: Option<Reading>,
+}
+
+// everything needed to render the chapters of a book
+#[cfg(feature = "preprocessor")]
+pub struct ChapterRenderer {
+    // chapters are rendered unless they, or a parent chapter, opt out with
+    // `katex: off` in their front matter, or the opposite with `default = "off"`
+    enabled: bool,
+    // only chapters matching the `include` and `exclude` patterns are rendered
+    chapter_filter: ChapterFilter,
+    backend: Backend,
+    // equations are rendered in the browser, with `pre-render = false`
+    client_side: bool,
+    // equations carry their tex, copied by katex's copy-tex extension
+    copy_tex: bool,
+    // equations carry the line of the chapter they're written at
+    source_lines: bool,
+    // display equations link to this url to be edited, with `{path}` and
+    // `{line}` filled in; `{path}` is from the root of the book, under
+    // `source_dir`, as in mdbook's edit links
+    edit_url: Option<String>,
+    source_dir: String,
+    macros: MacroMap,
+    trace_macros: bool,
+    // tex put before every equation, from the `preamble` file
+    preamble: Option<String>,
+    inline_opts: RenderOpts,
+    display_opts: RenderOpts,
+    // options equations that fail to render are tried again with
+    fallback_opts: Option<(RenderOpts, RenderOpts)>,
+    scanner: Scanner,
+    comments: CommentHandling,
+    // equations are written for mathjax, with markdown escapes
+    markdown_escapes: bool,
+    // unicode superscripts and subscripts are replaced with `^` and `_`
+    normalize_scripts: bool,
+    // unicode symbols replaced with commands inside equations
+    symbols: HashMap<char, String>,
+    // self-contained equations, without the katex stylesheet, e.g. for feeds
+    feed_safe: bool,
+    print_scale_down: bool,
+    // inline equations can't be broken across lines
+    nowrap_inline: bool,
+    // css length inline equations are shifted by
+    inline_vertical_align: Option<String>,
+    // equations are isolated from the right-to-left text around them
+    bidi_isolation: bool,
+    // local copy of the katex stylesheet, relative to the root of the book,
+    // instead of the one on the cdn
+    stylesheet: Option<String>,
+    site_url: Option<String>,
+    // the stylesheet is in `output.html.additional-css`, so every page links it
+    stylesheet_registered: bool,
+    // strict mode warnings are added as html comments before equations
+    warnings_as_comments: bool,
+    // length of the chapter descriptions added for search engines and previews
+    meta_description: Option<usize>,
+    // display equations estimated wider than this are broken into lines
+    max_display_width: Option<usize>,
+    // attributes added to mathml `<math>` elements, with the book language
+    math_attributes: Vec<MathAttribute>,
+    lang: Option<String>,
+    shims: Vec<&'static Shim>,
+    // files equations include with `\input{path}`, from the book root
+    snippets: Option<Snippets>,
+    // how equations that fail to render are reported
+    error_mode: ErrorMode,
+    // display equations are numbered, and their labels referred to
+    numbering: Option<Numbering>,
+    // macros defined inside equations hold for the equations after them
+    chapter_macros: Option<MacroScope>,
+    // chapter whose macro definitions hold for the whole book
+    preamble_chapter: Option<PathBuf>,
+    // threads rendering chapters, and the equations of large chapters
+    jobs: usize,
+    cache: Option<Box<dyn Cache>>,
+    fingerprint: u64,
+    // where the build manifest is written, if anywhere
+    manifest: Option<PathBuf>,
+    // with `reading-time`, how fast chapters are read, for the manifest
+    reading_speed: Option<ReadingSpeed>,
+    // chapters overriding options get a renderer of their own, from this context
+    context: Option<OwnedContext>,
+}
+
+#[cfg(feature = "preprocessor")]
+impl ChapterRenderer {
+    // renderer with the given options, and defaults for everything else
+    pub fn new(inline_opts: RenderOpts, display_opts: RenderOpts) -> Self {
+        ChapterRenderer {
+            chapter_filter: ChapterFilter::default(),
+            backend: Backend::default(),
+            client_side: false,
+            copy_tex: false,
+            source_lines: false,
+            edit_url: None,
+            source_dir: String::from("src"),
+            macros: MacroMap::default(),
+            trace_macros: false,
+            preamble: None,
+            inline_opts,
+            display_opts,
+            fallback_opts: None,
+            enabled: true,
+            scanner: Scanner::default(),
+            comments: CommentHandling::default(),
+            markdown_escapes: false,
+            normalize_scripts: false,
+            symbols: HashMap::new(),
+            feed_safe: false,
+            print_scale_down: false,
+            nowrap_inline: false,
+            inline_vertical_align: None,
+            bidi_isolation: false,
+            stylesheet: None,
+            site_url: None,
+            stylesheet_registered: false,
+            warnings_as_comments: false,
+            meta_description: None,
+            max_display_width: None,
+            math_attributes: Vec::new(),
+            lang: None,
+            shims: Vec::new(),
+            snippets: None,
+            error_mode: ErrorMode::default(),
+            numbering: None,
+            chapter_macros: None,
+            preamble_chapter: None,
+            jobs: 1,
+            cache: None,
+            fingerprint: 0,
+            manifest: None,
+            reading_speed: None,
+            context: None,
+        }
+    }
+
+    pub fn with_enabled(mut self, enabled: bool) -> Self {
+        self.enabled = enabled;
+        self
+    }
+
+    pub fn with_chapter_filter(mut self, chapter_filter: ChapterFilter) -> Self {
+        self.chapter_filter = chapter_filter;
+        self
+    }
+
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        self.backend = backend;
+        self
+    }
+
+    pub fn with_macros(mut self, macros: MacroMap) -> Self {
+        self.macros = macros;
+        self
+    }
+
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    pub fn error_mode(&self) -> ErrorMode {
+        self.error_mode
+    }
+
+    pub fn with_error_mode(mut self, error_mode: ErrorMode) -> Self {
+        self.error_mode = error_mode;
+        self
+    }
+
+    // render on this many threads, all available cores for 0
+    pub fn with_jobs(mut self, jobs: usize) -> Self {
+        self.jobs = parallel::jobs(jobs);
+        self
+    }
+
+    pub fn with_cache(mut self, cache: Box<dyn Cache>) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    pub fn with_scanner(mut self, scanner: Scanner) -> Self {
+        self.scanner = scanner;
+        self
+    }
+
+    pub fn with_comments(mut self, comments: CommentHandling) -> Self {
+        self.comments = comments;
+        self
+    }
+
+    pub fn with_max_display_width(mut self, max_display_width: Option<usize>) -> Self {
+        self.max_display_width = max_display_width;
+        self
+    }
+
+    pub fn with_normalize_scripts(mut self, normalize_scripts: bool) -> Self {
+        self.normalize_scripts = normalize_scripts;
+        self
+    }
+
+    pub fn with_symbols(mut self, symbols: HashMap<char, String>) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn with_meta_description(mut self, meta_description: Option<usize>) -> Self {
+        self.meta_description = meta_description;
+        self
+    }
+
+    pub fn with_nowrap_inline(mut self, nowrap_inline: bool) -> Self {
+        self.nowrap_inline = nowrap_inline;
+        self
+    }
+
+    pub fn with_inline_vertical_align(mut self, length: Option<&str>) -> Self {
+        self.inline_vertical_align = length.map(String::from);
+        self
     }
 
-    fn load_macros(ctx: &PreprocessorContext) -> HashMap<String, String> {
-        // get macros path from context
-        let mut macros_path = None;
-        if let Some(config) = ctx.config.get_preprocessor("katex") {
-            if let Some(toml::value::Value::String(macros_value)) = config.get("macros") {
-                macros_path = Some(Path::new(macros_value));
+    pub fn with_copy_tex(mut self, copy_tex: bool) -> Self {
+        self.copy_tex = copy_tex;
+        self
+    }
+
+    pub fn with_source_lines(mut self, source_lines: bool) -> Self {
+        self.source_lines = source_lines;
+        self
+    }
+
+    // links to edit display equations, with the source directory of the book
+    pub fn with_edit_url(mut self, template: &str, source_dir: &str) -> Self {
+        self.edit_url = Some(String::from(template));
+        self.source_dir = String::from(source_dir);
+        self
+    }
+
+    pub fn with_client_side(mut self, client_side: bool) -> Self {
+        self.client_side = client_side;
+        self
+    }
+
+    pub fn with_preamble(mut self, preamble: &str) -> Self {
+        self.preamble = Some(String::from(preamble));
+        self
+    }
+
+    pub fn with_bidi_isolation(mut self, bidi_isolation: bool) -> Self {
+        self.bidi_isolation = bidi_isolation;
+        self
+    }
+
+    pub fn with_stylesheet(mut self, stylesheet: &str, site_url: Option<&str>) -> Self {
+        self.stylesheet = Some(String::from(stylesheet));
+        self.site_url = site_url.map(String::from);
+        self
+    }
+
+    pub fn with_math_attributes(
+        mut self,
+        math_attributes: Vec<MathAttribute>,
+        lang: Option<&str>,
+    ) -> Self {
+        self.math_attributes = math_attributes;
+        self.lang = lang.map(String::from);
+        self
+    }
+
+    pub fn with_feed_safe(mut self, feed_safe: bool) -> Self {
+        self.feed_safe = feed_safe;
+        self
+    }
+
+    pub fn with_warnings_as_comments(mut self, warnings_as_comments: bool) -> Self {
+        self.warnings_as_comments = warnings_as_comments;
+        self
+    }
+
+    pub fn with_markdown_escapes(mut self, markdown_escapes: bool) -> Self {
+        self.markdown_escapes = markdown_escapes;
+        self
+    }
+
+    // the tex katex renders for an equation
+    fn prepare<'a>(&self, tex: &'a str) -> Cow<'a, str> {
+        let mut tex = if self.markdown_escapes {
+            Cow::Owned(tex::unescape_markdown(tex))
+        } else {
+            Cow::Borrowed(tex)
+        };
+        if let Some(snippets) = &self.snippets {
+            match snippets.expand(&tex) {
+                Ok(Cow::Owned(expanded)) => tex = Cow::Owned(expanded),
+                Ok(Cow::Borrowed(_)) => {}
+                Err(error) => diagnostics::warn_once(&format!("{:#}", error)),
+            }
+        }
+        if let Cow::Owned(handled) = tex::handle_comments(&tex, self.comments) {
+            tex = Cow::Owned(handled);
+        }
+        if let Cow::Owned(expanded) = self.macros.expand_environments(&tex) {
+            tex = Cow::Owned(expanded);
+        }
+        if let Cow::Owned(translated) = tex::translate_environments(&tex) {
+            tex = Cow::Owned(translated);
+        }
+        if self.normalize_scripts {
+            if let Cow::Owned(normalized) = tex::normalize_scripts(&tex) {
+                tex = Cow::Owned(normalized);
             }
         }
-        // load macros as a HashMap
-        let mut map = HashMap::new();
-        if let Some(path) = macros_path {
-            let macro_str = load_as_string(&path);
-            for couple in macro_str.split("\n") {
-                // only consider lines starting with a backslash
-                if let Some('\\') = couple.chars().next() {
-                    let couple: Vec<&str> = couple.splitn(2, ":").collect();
-                    map.insert(String::from(couple[0]), String::from(couple[1]));
+        if let Cow::Owned(replaced) = tex::replace_symbols(&tex, &self.symbols) {
+            tex = Cow::Owned(replaced);
+        }
+        tex
+    }
+
+    // the tex katex is given for an equation, after the preamble; on a line
+    // of its own, so that a comment ending the preamble doesn't hide it
+    fn after_preamble<'a>(&self, tex: &'a str) -> Cow<'a, str> {
+        match &self.preamble {
+            Some(preamble) => Cow::Owned(format!("{}\n{}", preamble.trim_end(), tex)),
+            None => Cow::Borrowed(tex),
+        }
+    }
+
+    pub fn render(&self, raw_content: &str) -> String {
+        self.render_chapter(raw_content, self.enabled, "")
+    }
+
+    // whether each chapter of a book is rendered when it doesn't say so in its
+    // front matter, as its parent chapter is, in the order of `for_each_mut`
+    pub fn inherited_settings(&self, book: &Book) -> Vec<bool> {
+        let mut settings = Vec::new();
+        self.collect_settings(&book.sections, self.enabled, &mut settings);
+        settings
+    }
+
+    fn collect_settings(&self, items: &[BookItem], inherited: bool, settings: &mut Vec<bool>) {
+        for item in items {
+            if let BookItem::Chapter(chapter) = item {
+                // invalid settings are reported when rendering
+                let (value, _) = markdown::take_front_matter_option(&chapter.content, "katex");
+                let setting = value.as_deref().and_then(Self::parse_setting);
+                // `for_each_mut` visits sub-chapters before their parent
+                self.collect_settings(&chapter.sub_items, setting.unwrap_or(inherited), settings);
+                // chapters left out by the patterns are, unlike their sub-chapters
+                let selected = self.chapter_filter.selects(chapter.path.as_deref());
+                settings.push(inherited && selected);
+            }
+        }
+    }
+
+    // a renderer for a chapter starting with `<!-- katex: leqno=true, ... -->`,
+    // with those options merged into `[preprocessor.katex]`
+    fn overriding(&self, directive: &str) -> Result<ChapterRenderer, Error> {
+        let context = self
+            .context
+            .as_ref()
+            .ok_or_else(|| Error::msg("options can't be overridden in this renderer"))?;
+        let mut config = context.config.clone();
+        let mut options = config
+            .get_preprocessor("katex")
+            .cloned()
+            .unwrap_or_default();
+        for (name, value) in parse_directive(directive)? {
+            options.insert(name, value);
+        }
+        config.set("preprocessor.katex", options)?;
+        context
+            .processor
+            .renderer_for(&BookContext {
+                root: &context.root,
+                config: &config,
+                renderer: &context.renderer,
+            })
+            .context("invalid `<!-- katex: ... -->` directive")
+    }
+
+    // `katex: on` or `katex: off` in the front matter, and the chapter without it
+    fn front_matter_setting(raw_content: &str) -> (Option<bool>, Cow<'_, str>) {
+        let (value, content) = markdown::take_front_matter_option(raw_content, "katex");
+        let setting = value.as_deref().and_then(|value| {
+            let setting = Self::parse_setting(value);
+            if setting.is_none() {
+                diagnostics::warn(&format!(
+                    "invalid `katex: {}` in front matter, expected `on` or `off`",
+                    value
+                ));
+            }
+            setting
+        });
+        (setting, content)
+    }
+
+    fn parse_setting(value: &str) -> Option<bool> {
+        match value {
+            "on" | "true" => Some(true),
+            "off" | "false" => Some(false),
+            _ => None,
+        }
+    }
+
+    // url of the local stylesheet, if any, from a chapter: absolute under
+    // `output.html.site-url` when set, so that books served from a subpath find
+    // it, or relative to the chapter
+    fn stylesheet_url(&self, path_to_root: &str) -> Option<String> {
+        let stylesheet = self.stylesheet.as_deref()?;
+        Some(self.book_url(stylesheet, path_to_root))
+    }
+
+    // url of a file of the built book, from `output.html.site-url` if set
+    fn book_url(&self, file: &str, path_to_root: &str) -> String {
+        if file.contains("://") || file.starts_with('/') {
+            return String::from(file);
+        }
+        match &self.site_url {
+            Some(site_url) => format!("{}/{}", site_url.trim_end_matches('/'), file),
+            None => format!("{}{}", path_to_root, file),
+        }
+    }
+
+    // the preview image and tex of the key equation of a chapter, the first
+    // after a `<!-- katex-hero: path/to/image.png -->` comment
+    fn hero(&self, raw_content: &str) -> Option<(String, String)> {
+        let mut image = None;
+        for span in self.scanner.scan(raw_content, &mut |_| {}) {
+            match span {
+                Span::Text(text) => {
+                    if let Some(start) = text.rfind(html::HERO) {
+                        let rest = &text[start + html::HERO.len()..];
+                        if let Some(end) = rest.find("-->") {
+                            image = Some(String::from(rest[..end].trim()));
+                        }
+                    }
+                }
+                Span::Math { tex, .. } => {
+                    if let Some(image) = image.take() {
+                        return Some((image, String::from(tex)));
+                    }
                 }
+                Span::Data { .. } => {}
             }
         }
-        map
+        None
     }
 
-    // render Katex equations in HTML, and add the Katex CSS
-    fn process_chapter(
+    // render a chapter, unless its front matter or, when it has none, `inherited`
+    // says otherwise; `path_to_root` leads from the chapter to the root of the
+    // book, as in `../` for `part/chapter.md`
+    pub fn render_chapter(&self, raw_content: &str, inherited: bool, path_to_root: &str) -> String {
+        self.render_chapter_with_stats(raw_content, inherited, path_to_root)
+            .0
+    }
+
+    // render a chapter as `render_chapter` does, along with what was rendered
+    pub fn render_chapter_with_stats(
         &self,
         raw_content: &str,
-        inline_opts: &katex::Opts,
-        display_opts: &katex::Opts,
-    ) -> String {
-        // add katex css
-        let mut rendered_content = katex_header();
-        // render display equations
-        let content = Self::render_between_delimiters(&raw_content, "$$", display_opts, false);
-        // render inline equations
-        let content = Self::render_between_delimiters(&content, "$", inline_opts, true);
-        rendered_content.push_str(&content);
-        rendered_content
+        inherited: bool,
+        path_to_root: &str,
+    ) -> (String, ChapterStats) {
+        self.render_chapter_from(raw_content, inherited, path_to_root, None, 1)
+    }
+
+    // render a chapter as `render_chapter_with_stats` does, knowing its path
+    // in the source directory, which the edit links of its equations lead to
+    pub fn render_chapter_file(
+        &self,
+        raw_content: &str,
+        inherited: bool,
+        path: &Path,
+    ) -> (String, ChapterStats) {
+        let path_to_root = mdbook::utils::fs::path_to_root(path);
+        self.render_chapter_from(raw_content, inherited, &path_to_root, Some(path), 1)
+    }
+
+    // render a chapter whose content starts at `first_line` of its file, after
+    // the front matter or options taken out of it
+    fn render_chapter_from(
+        &self,
+        raw_content: &str,
+        inherited: bool,
+        path_to_root: &str,
+        path: Option<&Path>,
+        first_line: usize,
+    ) -> (String, ChapterStats) {
+        let mut stats = ChapterStats::default();
+        let (setting, content) = Self::front_matter_setting(raw_content);
+        if !setting.unwrap_or(inherited) {
+            return (content.into_owned(), stats);
+        }
+        // the front matter is taken from the start of the chapter
+        let first_line =
+            first_line + raw_content.matches('\n').count() - content.matches('\n').count();
+        let raw_content = content;
+        let directive = markdown::take_directive(&raw_content, "katex")
+            .filter(|(directive, _)| !scan::is_region_directive(directive));
+        if let Some((directive, rest)) = directive {
+            match self.overriding(directive) {
+                Ok(renderer) => {
+                    let taken = &raw_content[..raw_content.len() - rest.len()];
+                    let first_line = first_line + taken.matches('\n').count();
+                    return renderer.render_chapter_from(
+                        rest,
+                        true,
+                        path_to_root,
+                        path,
+                        first_line,
+                    );
+                }
+                Err(e) => diagnostics::warn(&format!(
+                    "{:#}, rendering the chapter with the options in book.toml",
+                    e
+                )),
+            }
+        }
+        let raw_content = &*raw_content;
+        if let Some(speed) = &self.reading_speed {
+            let spans = self.scanner.scan(raw_content, &mut |_| {});
+            stats.reading = Some(Reading::measure(&spans, speed));
+        }
+        // rendered chapters are cached by content, for the current configuration
+        // and the macros the chapter uses, so that editing a macro only
+        // invalidates the chapters using it
+        let included = self.included(raw_content);
+        let key = cache::hash(&(
+            self.fingerprint,
+            raw_content,
+            self.macros.used_by(raw_content),
+            (&included, self.macros.used_by(&included)),
+            self.stylesheet_url(path_to_root),
+            // for the url of the preview image of the chapter
+            (path_to_root, &self.site_url),
+            self.source_lines.then_some(first_line),
+            self.edit_url.as_ref().map(|_| (first_line, path)),
+        ));
+        stats.rendered = true;
+        // cached chapters start with their number of equations, for the manifest
+        let cached = self.cache.as_ref().and_then(|c| c.get("chapters", key));
+        if let Some((equations, rendered)) = cached.as_deref().and_then(|c| c.split_once('\n')) {
+            if let Ok(equations) = equations.parse() {
+                stats.cached = true;
+                stats.equations = equations;
+                return (String::from(rendered), stats);
+            }
+        }
+        let position = (path, first_line);
+        let rendered = self.process_chapter_at(raw_content, path_to_root, position, &mut stats);
+        for failure in &stats.failures {
+            self.report_error(&failure.tex);
+        }
+        for fallback in &stats.fallbacks {
+            diagnostics::warn(&format!(
+                "rendered `{}` with the default KaTeX options, after failing with: {}",
+                fallback.tex, fallback.error
+            ));
+        }
+        // chapters with errors are rendered again, so that errors are reported each time
+        let clean = stats.failures.is_empty() && stats.fallbacks.is_empty();
+        if let (Some(cache), true) = (&self.cache, clean) {
+            cache.put(
+                "chapters",
+                key,
+                &format!("{}\n{}", stats.equations, rendered),
+            );
+        }
+        (rendered, stats)
+    }
+
+    // a manifest to fill while rendering the book, if one is to be written
+    pub fn manifest(&self) -> Option<Manifest> {
+        let renderer = self
+            .context
+            .as_ref()
+            .map_or("html", |context| &context.renderer);
+        self.manifest
+            .as_ref()
+            .map(|_| Manifest::new(renderer, self.cache.is_some()))
+    }
+
+    pub fn write_manifest(&self, manifest: &Manifest) -> Result<(), Error> {
+        match &self.manifest {
+            Some(path) => manifest.write(path),
+            None => Ok(()),
+        }
+    }
+
+    // a chapter at the root of the book
+    #[cfg(all(test, feature = "katex"))]
+    fn process_chapter(&self, raw_content: &str, on_error: &mut dyn FnMut(&str)) -> String {
+        let mut stats = ChapterStats::default();
+        let rendered = self.process_chapter_at(raw_content, "", (None, 1), &mut stats);
+        for failure in &stats.failures {
+            on_error(&failure.tex);
+        }
+        rendered
     }
 
-    // render equations between given delimiters, with specified options
-    fn render_between_delimiters(
+    // render Katex equations in HTML, and add the Katex CSS, counting equations
+    // and those that fail to render in `stats`
+    // `position` is the path of the chapter in the source directory, if known,
+    // and the line its content starts at
+    fn process_chapter_at(
+        &self,
         raw_content: &str,
-        delimiters: &str,
-        opts: &katex::Opts,
-        escape_backslash: bool,
+        path_to_root: &str,
+        (path, first_line): (Option<&Path>, usize),
+        stats: &mut ChapterStats,
     ) -> String {
-        let mut rendered_content = String::new();
-        let mut inside_delimiters = false;
-        for item in Self::split(&raw_content, &delimiters, escape_backslash) {
-            if inside_delimiters {
-                // try to render equation
-                if let Ok(rendered) = katex::render_with_opts(&item, opts) {
-                    rendered_content.push_str(&rendered)
-                // if rendering fails, keep the unrendered equation
-                } else {
-                    rendered_content.push_str(&item)
-                }
-            // outside delimiters
+        if self.backend == Backend::Text {
+            return self.process_chapter_as_text(raw_content);
+        }
+        let edit_url = self.edit_url(path);
+        let position = |line| Position {
+            line,
+            edit_url: edit_url.as_deref(),
+        };
+        let mut rendered_content = self.chapter_header(path_to_root);
+        // a chapter processed before, e.g. by the preprocessor listed twice,
+        // starts with the same header, and is left as it is but for equations
+        // that failed to render
+        if let Some(body) = raw_content.strip_prefix(&rendered_content) {
+            let description = if self.meta_description.is_some() {
+                html::description_length(body)
             } else {
-                rendered_content.push_str(&item)
+                Some(0)
+            };
+            let description =
+                description.map(|length| length + html::hero_length(&body[length..]).unwrap_or(0));
+            match description {
+                Some(length) if !rendered_content.is_empty() || length > 0 => {
+                    rendered_content.push_str(&body[..length]);
+                    let first_line = first_line
+                        + raw_content[..raw_content.len() - body.len() + length]
+                            .matches('\n')
+                            .count();
+                    self.render_spans(
+                        &body[length..],
+                        Some(position(first_line)),
+                        stats,
+                        &mut rendered_content,
+                    );
+                    return rendered_content;
+                }
+                _ => {}
             }
-            inside_delimiters = !inside_delimiters;
         }
+        if let Some(length) = self.meta_description {
+            rendered_content.push_str(&html::meta_description(
+                &self.description(raw_content, length),
+            ));
+        }
+        if let Some((image, tex)) = self.hero(raw_content) {
+            rendered_content.push_str(&html::hero_meta(
+                &self.book_url(&image, path_to_root),
+                &self.to_text(&tex),
+                &tex,
+            ));
+        }
+        stats.prerendered = scan::rendered_equations(raw_content);
+        let position = Some(position(first_line));
+        self.render_spans(raw_content, position, stats, &mut rendered_content);
         rendered_content
     }
 
-    fn split(string: &str, separator: &str, escape_backslash: bool) -> Vec<String> {
-        let mut result = Vec::new();
-        let mut splits = string.split(separator);
-        let mut current_split = splits.next();
-        // iterate over splits
-        while let Some(substring) = current_split {
-            let mut result_split = String::from(substring);
-            if escape_backslash {
-                // while the current split ends with a backslash
-                while let Some('\\') = current_split.unwrap().chars().last() {
-                    // removes the backslash, add the separator back, and add the next split
-                    result_split.pop();
-                    result_split.push_str(separator);
-                    current_split = splits.next();
-                    if let Some(split) = current_split {
-                        result_split.push_str(split);
+    // the stylesheet, styles and scripts a chapter starts with
+    fn chapter_header(&self, path_to_root: &str) -> String {
+        // add katex css
+        let mut header = match (self.feed_safe, self.stylesheet_url(path_to_root)) {
+            (true, _) => String::new(),
+            (false, Some(_)) if self.stylesheet_registered => String::new(),
+            (false, Some(url)) => html::stylesheet_link(&url),
+            (false, None) => katex_header(),
+        };
+        if self.print_scale_down && !self.feed_safe {
+            header.push_str(html::PRINT_SCALE_DOWN);
+        }
+        if self.edit_url.is_some() && !self.feed_safe {
+            header.push_str(html::EDIT_LINKS);
+        }
+        if self.client_side {
+            header.push_str(&html::auto_render_scripts(&self.auto_render_options()));
+        }
+        if self.copy_tex && !self.feed_safe {
+            header.push_str(&html::copy_tex_assets());
+        }
+        if self.nowrap_inline && !self.feed_safe {
+            header.push_str(html::NOWRAP_INLINE);
+        }
+        if let (Some(length), false) = (&self.inline_vertical_align, self.feed_safe) {
+            header.push_str(&html::vertical_align_style(length));
+        }
+        header
+    }
+
+    // render equations, on several threads for large batches like generated
+    // chapters, and one after the other otherwise
+    pub fn render_many(&self, spans: &[MathSpan]) -> Vec<Result<String, RenderError>> {
+        self.render_all(spans)
+            .into_iter()
+            .map(|rendered| rendered.map(|rendered| rendered.html))
+            .collect()
+    }
+
+    fn render_all(&self, spans: &[MathSpan]) -> Vec<Result<Rendered, RenderError>> {
+        if self.jobs <= 1 || spans.len() < PARALLEL_EQUATIONS {
+            return self.render_batch(spans);
+        }
+        let batches: Vec<&[MathSpan]> = spans.chunks(spans.len().div_ceil(self.jobs)).collect();
+        let mut rendered = Vec::with_capacity(spans.len());
+        parallel::for_each_ordered(
+            &batches,
+            self.jobs,
+            |batch| self.render_batch(batch),
+            |_, batch| {
+                rendered.extend(batch);
+                true
+            },
+        );
+        rendered
+    }
+
+    // render equations one after the other, sharing the setup of all of them
+    fn render_batch(&self, spans: &[MathSpan]) -> Vec<Result<Rendered, RenderError>> {
+        let engine = Engine::new(&self.inline_opts, &self.display_opts);
+        spans
+            .iter()
+            .map(|span| {
+                // equations are cached too, so that editing a chapter only
+                // renders the equations that changed
+                let included = self.included(span.tex);
+                let key = cache::hash(&(
+                    self.fingerprint,
+                    span.tex,
+                    span.display,
+                    self.macros.used_by(span.tex),
+                    (&included, self.macros.used_by(&included)),
+                ));
+                if let Some(html) = self.cache.as_ref().and_then(|c| c.get("equations", key)) {
+                    return Ok(Rendered {
+                        html,
+                        fallback: None,
+                    });
+                }
+                let rendered = self.render_math(span, &engine);
+                // equations rendered with the fallback options are reported each time
+                if let (
+                    Some(cache),
+                    Ok(Rendered {
+                        html,
+                        fallback: None,
+                    }),
+                ) = (&self.cache, &rendered)
+                {
+                    cache.put("equations", key, html);
+                }
+                rendered
+            })
+            .collect()
+    }
+
+    // an equation as it goes in a chapter
+    fn render_math(&self, span: &MathSpan, engine: &Engine) -> Result<Rendered, RenderError> {
+        let display = span.display;
+        self.warn_substitutions(span.tex);
+        if self.client_side {
+            return Ok(Rendered {
+                html: self.client_side_math(span),
+                fallback: None,
+            });
+        }
+        let mut tex = self.prepare(span.tex);
+        // long formulas, e.g. generated ones, on several lines for narrow screens
+        if let (true, Some(max)) = (display, self.max_display_width) {
+            if let Some(broken) = tex::break_lines(&tex, max) {
+                tex = Cow::Owned(broken);
+            }
+        }
+        // large operators inline, without moving the equation to its own line
+        let tex = match tex::forced_display(&tex) {
+            Some(body) if !display => Cow::Owned(format!("\\displaystyle {}", body)),
+            _ => tex,
+        };
+        // unbalanced braces and environments are reported where they're opened,
+        // unless katex is to render its errors
+        let opts = if display {
+            &self.display_opts
+        } else {
+            &self.inline_opts
+        };
+        let rendered = match tex::check_balance(&tex) {
+            Err(unbalanced) if opts.throws_on_error() => Err(unbalanced),
+            _ => engine.render(&self.after_preamble(&tex), display),
+        };
+        let (tex, rendered, fallback) = match rendered {
+            Ok(rendered) => (tex, rendered, None),
+            Err(error) => {
+                let error = RenderError {
+                    tex: tex.clone().into_owned(),
+                    suggestion: compat::suggestion(&error, !self.shims.is_empty()),
+                    error,
+                    line: None,
+                };
+                match self.retry(span) {
+                    Some((tex, rendered)) => (Cow::Owned(tex), rendered, Some(error)),
+                    None => return Err(error),
+                }
+            }
+        };
+        let mut output = String::new();
+        // found by grepping the built book
+        if self.warnings_as_comments {
+            let warnings = strict::warnings(&tex, display);
+            output.push_str(&strict::as_comments(&warnings));
+        }
+        let rendered = if self.math_attributes.is_empty() {
+            rendered
+        } else {
+            html::math_attributes(
+                &rendered,
+                &self.math_attributes,
+                &tex,
+                display,
+                self.lang.as_deref(),
+            )
+        };
+        let rendered = if self.copy_tex {
+            html::with_tex(&rendered, span.tex)
+        } else {
+            rendered
+        };
+        let rendered = if self.bidi_isolation {
+            html::left_to_right(&rendered)
+        } else {
+            rendered
+        };
+        // so that emphasis around equations isn't thrown off by their text
+        let rendered = html::escape_markdown(&rendered);
+        let rendered = if self.nowrap_inline && !display {
+            Cow::Owned(html::nowrap(&rendered, self.feed_safe))
+        } else {
+            rendered
+        };
+        match &self.inline_vertical_align {
+            Some(length) if !display => {
+                output.push_str(&html::vertical_align(&rendered, length, self.feed_safe))
+            }
+            _ => output.push_str(&rendered),
+        }
+        Ok(Rendered {
+            html: output,
+            fallback,
+        })
+    }
+
+    // an equation left for the browser to render, as katex would have been
+    // given it, between the first delimiters of its kind
+    fn client_side_math(&self, span: &MathSpan) -> String {
+        let tex = self.prepare(span.tex);
+        let (open, close) = self
+            .scanner
+            .delimiters()
+            .iter()
+            .find(|delimiter| delimiter.display == span.display)
+            .map_or(("", ""), |delimiter| {
+                (delimiter.marker.as_str(), delimiter.closing.as_str())
+            });
+        html::client_side(open, &self.after_preamble(&tex), close)
+    }
+
+    // the options of the auto-render extension, with the delimiters equations
+    // are found with and the katex options of display equations, which only
+    // differ from inline ones by the display mode the delimiters set
+    fn auto_render_options(&self) -> serde_json::Value {
+        let delimiters = self
+            .scanner
+            .delimiters()
+            .iter()
+            .map(|delimiter| {
+                serde_json::json!({
+                    "left": delimiter.marker,
+                    "right": delimiter.closing,
+                    "display": delimiter.display,
+                })
+            })
+            .collect();
+        let mut options = self.display_opts.to_json();
+        options.insert(
+            String::from("delimiters"),
+            serde_json::Value::Array(delimiters),
+        );
+        serde_json::Value::Object(options)
+    }
+
+    // with `retry-with-defaults`, an equation that failed to render, rendered
+    // again as written, without the changes made before rendering, and with
+    // katex's default options but for the output and macros
+    fn retry(&self, span: &MathSpan) -> Option<(String, String)> {
+        let (inline_opts, display_opts) = self.fallback_opts.as_ref()?;
+        let tex = if self.markdown_escapes {
+            tex::unescape_markdown(span.tex)
+        } else {
+            String::from(span.tex)
+        };
+        let tex = match &self.snippets {
+            Some(snippets) => snippets.expand(&tex).ok()?.into_owned(),
+            None => tex,
+        };
+        // environments are defined along with the macros
+        let tex = self.macros.expand_environments(&tex).into_owned();
+        let tex = tex::translate_environments(&tex).into_owned();
+        let rendered = Engine::new(inline_opts, display_opts)
+            .render(&self.after_preamble(&tex), span.display)
+            .ok()?;
+        Some((tex, rendered))
+    }
+
+    // the edit url of a chapter, with `{line}` left for each equation to fill
+    fn edit_url(&self, path: Option<&Path>) -> Option<String> {
+        let (template, path) = match (&self.edit_url, path) {
+            (Some(template), Some(path)) if !self.feed_safe => (template, path),
+            _ => return None,
+        };
+        // as in the edit url of mdbook pages, from the root of the book
+        let path = Path::new(&self.source_dir).join(path);
+        Some(template.replace("{path}", &path.to_string_lossy().replace('\\', "/")))
+    }
+
+    // a rendered equation with the line it's written at, and for display
+    // equations a link to edit it there
+    fn with_source(&self, output: String, display: bool, position: Position) -> String {
+        let output = if self.source_lines {
+            html::with_source_line(&output, position.line)
+        } else {
+            output
+        };
+        match position.edit_url {
+            Some(url) if display => {
+                html::with_edit_link(&output, &url.replace("{line}", &position.line.to_string()))
+            }
+            _ => output,
+        }
+    }
+
+    // render the equations in some content, appending it to `rendered_content`;
+    // `position` is where it starts in its chapter, if it's part of one rather
+    // than of a data string
+    fn render_spans(
+        &self,
+        content: &str,
+        position: Option<Position>,
+        stats: &mut ChapterStats,
+        rendered_content: &mut String,
+    ) {
+        let spans = self.scanner.scan(content, &mut |tex| {
+            diagnostics::warn(&format!(
+                "inline equation of {} bytes starting with `{}` is longer than \
+                 `max-inline-length`, keeping it as text",
+                tex.len(),
+                tex.chars().take(40).collect::<String>()
+            ))
+        });
+        let equations = spans
+            .iter()
+            .filter(|span| matches!(span, Span::Math { .. }))
+            .count();
+        stats.equations += equations;
+        let segments = match &self.cache {
+            Some(_) if equations >= SEGMENTED_EQUATIONS => segments(spans),
+            _ => vec![spans],
+        };
+        // in `<!-- katex-escape: js -->` regions, equations go in javascript strings
+        let mut escape_js = false;
+        // segments are looked up first, so that the equations of the others are
+        // rendered together, in parallel when there are many
+        let mut lookups = Vec::with_capacity(segments.len());
+        let mut start = position;
+        for segment in &segments {
+            let key = (segments.len() > 1)
+                .then(|| self.segment_key(segment, escape_js, start))
+                .flatten();
+            advance(&mut start, segment);
+            let cached = key
+                .zip(self.cache.as_ref())
+                .and_then(|(key, cache)| cache.get("segments", key));
+            lookups.push((key, cached));
+            escape_js = segment_escape(segment, escape_js);
+        }
+        let math: Vec<MathSpan> = segments
+            .iter()
+            .zip(&lookups)
+            .filter(|(_, (_, cached))| cached.is_none())
+            .flat_map(|(segment, _)| segment)
+            .filter_map(|span| match span {
+                Span::Math { tex, display, .. } => Some(MathSpan {
+                    tex,
+                    display: *display,
+                }),
+                _ => None,
+            })
+            .collect();
+        let mut rendered = self.render_all(&math).into_iter();
+        let mut escape_js = false;
+        // lines are counted for failures to be reported with theirs
+        let mut position = position;
+        for (segment, (key, cached)) in segments.into_iter().zip(lookups) {
+            if let Some(cached) = cached {
+                escape_js = segment_escape(&segment, escape_js);
+                advance(&mut position, &segment);
+                rendered_content.push_str(&cached);
+                continue;
+            }
+            let start = rendered_content.len();
+            let (failures, fallbacks) = (stats.failures.len(), stats.fallbacks.len());
+            self.write_spans(
+                segment,
+                &mut rendered,
+                &mut escape_js,
+                &mut position,
+                stats,
+                rendered_content,
+            );
+            // segments with errors are rendered again, so that errors are reported each time
+            let clean = stats.failures.len() == failures && stats.fallbacks.len() == fallbacks;
+            if let (Some(key), Some(cache), true) = (key, &self.cache, clean) {
+                cache.put("segments", key, &rendered_content[start..]);
+            }
+        }
+    }
+
+    // key of a segment in the cache, `None` for segments with nothing to render
+    // or with data blocks, which count equations of their own
+    fn segment_key(
+        &self,
+        segment: &[Span],
+        escape_js: bool,
+        position: Option<Position>,
+    ) -> Option<u64> {
+        let mut parts = Vec::with_capacity(segment.len());
+        let mut source = String::new();
+        for span in segment {
+            match span {
+                Span::Text(text) => parts.push((false, text.as_str())),
+                Span::Math { source: math, .. } => {
+                    parts.push((true, *math));
+                    source.push_str(math);
+                }
+                Span::Data { .. } => return None,
+            }
+        }
+        if source.is_empty() {
+            return None;
+        }
+        let included = self.included(&source);
+        Some(cache::hash(&(
+            self.fingerprint,
+            parts,
+            self.macros.used_by(&source),
+            (&included, self.macros.used_by(&included)),
+            escape_js,
+            position.filter(|_| self.source_lines || self.edit_url.is_some()),
+        )))
+    }
+
+    // the snippets included with `\input`, which cache keys depend on as well
+    fn included(&self, tex: &str) -> String {
+        self.snippets
+            .as_ref()
+            .map_or_else(String::new, |snippets| snippets.included(tex))
+    }
+
+    // append spans to `rendered_content`, with their equations from `rendered`
+    fn write_spans(
+        &self,
+        spans: Vec<Span>,
+        rendered: &mut impl Iterator<Item = Result<Rendered, RenderError>>,
+        escape_js: &mut bool,
+        position: &mut Option<Position>,
+        stats: &mut ChapterStats,
+        rendered_content: &mut String,
+    ) {
+        let mut spans = spans.into_iter().peekable();
+        while let Some(span) = spans.next() {
+            let start = *position;
+            if let Some(position) = position {
+                position.line += span.lines();
+            }
+            match span {
+                Span::Text(text) => {
+                    if let Some(escape) = html::escape_region(&text) {
+                        *escape_js = escape;
                     }
+                    rendered_content.push_str(&text)
+                }
+                Span::Math {
+                    source,
+                    tex,
+                    display,
+                } => match rendered.next() {
+                    Some(Ok(Rendered {
+                        html: output,
+                        fallback,
+                    })) => {
+                        stats.fallbacks.extend(fallback);
+                        // so that indented equations, e.g. under list items, aren't code blocks
+                        if display {
+                            markdown::reindent_display(rendered_content);
+                        }
+                        let output = match start {
+                            Some(start) => self.with_source(output, display, start),
+                            None => output,
+                        };
+                        if *escape_js {
+                            rendered_content.push_str(&html::escape_js_string(&output))
+                        } else {
+                            rendered_content.push_str(&output)
+                        }
+                        let before_footnote = matches!(
+                            spans.peek(),
+                            Some(Span::Text(next)) if next.starts_with("[^")
+                        );
+                        if before_footnote && !display && tex::ends_with_superscript(tex) {
+                            rendered_content.push_str(html::FOOTNOTE_GAP);
+                        }
+                    }
+                    // if rendering fails, keep the unrendered equation
+                    failed => {
+                        if let Some(Err(error)) = failed {
+                            stats.failures.push(RenderError {
+                                line: start.map(|start| start.line),
+                                ..error
+                            });
+                        }
+                        if *escape_js {
+                            rendered_content.push_str(&html::escape_js_string(source))
+                        } else {
+                            rendered_content.push_str(source)
+                        }
+                    }
+                },
+                Span::Data {
+                    open,
+                    content,
+                    close,
+                } => {
+                    rendered_content.push_str(open);
+                    let failures = stats.failures.len();
+                    rendered_content.push_str(&self.render_data(content, stats));
+                    // equations in data are reported at the start of their block
+                    for failure in &mut stats.failures[failures..] {
+                        failure.line = start.map(|start| start.line);
+                    }
+                    rendered_content.push_str(close);
                 }
             }
-            result.push(result_split);
-            current_split = splits.next()
         }
-        result
+    }
+
+    // math in the strings of a data block, e.g. the questions of an mdbook-quiz
+    // quiz, leaving the rest of the data alone; blocks that aren't valid JSON or
+    // TOML are kept as they are
+    fn render_data(&self, content: &str, stats: &mut ChapterStats) -> String {
+        if content.trim_start().starts_with(['{', '[']) {
+            if let Ok(mut value) = serde_json::from_str::<serde_json::Value>(content) {
+                self.render_json(&mut value, stats);
+                return format!("{}\n", serde_json::to_string_pretty(&value).unwrap());
+            }
+        }
+        match toml::from_str::<toml::Value>(content) {
+            Ok(mut value) => {
+                self.render_toml(&mut value, stats);
+                toml::to_string(&value).unwrap_or_else(|_| String::from(content))
+            }
+            Err(e) => {
+                diagnostics::warn(&format!(
+                    "data block isn't valid JSON or TOML, keeping it as it is: {}",
+                    e
+                ));
+                String::from(content)
+            }
+        }
+    }
+
+    fn render_string(&self, value: &str, stats: &mut ChapterStats) -> String {
+        let mut rendered = String::new();
+        self.render_spans(value, None, stats, &mut rendered);
+        rendered
+    }
+
+    fn render_json(&self, value: &mut serde_json::Value, stats: &mut ChapterStats) {
+        match value {
+            serde_json::Value::String(string) => *string = self.render_string(string, stats),
+            serde_json::Value::Array(values) => {
+                for value in values {
+                    self.render_json(value, stats);
+                }
+            }
+            serde_json::Value::Object(values) => {
+                for value in values.values_mut() {
+                    self.render_json(value, stats);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn render_toml(&self, value: &mut toml::Value, stats: &mut ChapterStats) {
+        match value {
+            toml::Value::String(string) => *string = self.render_string(string, stats),
+            toml::Value::Array(values) => {
+                for value in values {
+                    self.render_toml(value, stats);
+                }
+            }
+            toml::Value::Table(values) => {
+                for (_, value) in values.iter_mut() {
+                    self.render_toml(value, stats);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    // plain text excerpt of a chapter, with approximations of its equations
+    pub fn description(&self, raw_content: &str, max_length: usize) -> String {
+        let mut text = markdown::PlainText::default();
+        for span in self.scanner.scan(raw_content, &mut |_| {}) {
+            match span {
+                Span::Text(markdown) => text.push_markdown(&markdown),
+                Span::Math { tex, .. } => text.push_plain(&self.to_text(tex)),
+                Span::Data {
+                    open,
+                    content,
+                    close,
+                } => text.push_markdown(&format!("{}{}{}", open, content, close)),
+            }
+        }
+        text.excerpt(max_length)
+    }
+
+    fn to_text(&self, tex: &str) -> String {
+        let tex = self.prepare(tex);
+        let tex = tex::forced_display(&tex).unwrap_or(&tex);
+        text::to_text(&self.macros.expand_simple(tex))
+    }
+
+    // replace equations with plain text approximations
+    fn process_chapter_as_text(&self, raw_content: &str) -> String {
+        let mut content = String::with_capacity(raw_content.len());
+        for span in self.scanner.scan(raw_content, &mut |_| {}) {
+            match span {
+                Span::Text(text) => content.push_str(&text),
+                Span::Math { tex, .. } => content.push_str(&self.to_text(tex)),
+                Span::Data {
+                    open,
+                    content: data,
+                    close,
+                } => {
+                    content.push_str(open);
+                    content.push_str(data);
+                    content.push_str(close);
+                }
+            }
+        }
+        content
+    }
+
+    // warn, once per command, about unsupported commands replaced by shims
+    fn warn_substitutions(&self, tex: &str) {
+        for shim in compat::substitutions(tex, &self.shims) {
+            diagnostics::warn_once(&format!(
+                "`{}` isn't supported by KaTeX, substituted with `{}`",
+                shim.name, shim.expansion
+            ));
+        }
+    }
+
+    // print the chain of macro expansions behind a failing equation
+    fn report_error(&self, equation: &str) {
+        if self.trace_macros {
+            let mut message = format!("macro expansions for `{}`:", equation);
+            for line in self.macros.trace(equation) {
+                message.push('\n');
+                message.push_str(&line);
+            }
+            diagnostics::debug(&message);
+        }
     }
 }
 
-pub fn load_as_string(path: &Path) -> String {
-    let display = path.display();
+#[cfg(feature = "preprocessor")]
+impl KatexProcessor {
+    pub fn renderer(&self, ctx: &PreprocessorContext) -> Result<ChapterRenderer, Error> {
+        self.renderer_for(&BookContext::from(ctx))
+    }
+
+    pub fn builder() -> KatexProcessorBuilder {
+        KatexProcessorBuilder::new()
+    }
+
+    // renderer for a book outside of a preprocessor run, e.g. loaded with `MDBook::load`
+    pub fn renderer_for(&self, ctx: &BookContext) -> Result<ChapterRenderer, Error> {
+        let metadata = metadata::load(ctx.root, ctx.config.get_preprocessor("katex"))?;
+        if self.options.is_empty() && metadata.is_empty() {
+            return self.build_renderer(ctx, &[]);
+        }
+        // options of the builder and of the metadata become part of the book
+        // configuration, so that chapters overriding options start from them
+        let mut config = ctx.config.clone();
+        let mut options = metadata.options;
+        options.extend(
+            config
+                .get_preprocessor("katex")
+                .cloned()
+                .unwrap_or_default(),
+        );
+        options.extend(self.options.clone());
+        config.set("preprocessor.katex", options)?;
+        self.build_renderer(
+            &BookContext {
+                root: ctx.root,
+                config: &config,
+                renderer: ctx.renderer,
+            },
+            &metadata.macros,
+        )
+    }
+
+    // `metadata_macros` are the macros defined in the metadata of the book
+    fn build_renderer(
+        &self,
+        ctx: &BookContext,
+        metadata_macros: &[(String, String, macros::Location)],
+    ) -> Result<ChapterRenderer, Error> {
+        let config = KatexConfig::from_table(ctx.config.get_preprocessor("katex"))?;
+        let mut macros = self.load_macros(&config, ctx.root, metadata_macros)?;
+        let preamble = Self::load_preamble(&config, ctx.root)?;
+        // shims don't override macros with the same name
+        let shims = if config.compat_shims {
+            compat::add_shims(&mut macros)?
+        } else {
+            Vec::new()
+        };
+        let feed_safe = config.feed_safe;
+        // mathml is rendered by browsers and feed readers without the katex stylesheet
+        let output_type = if feed_safe {
+            OutputType::Mathml
+        } else {
+            Self::load_output_type(&config)?
+        };
+        let (inline_opts, display_opts) = Self::build_opts(&config, &macros, output_type)?;
+        let backend = Backend::for_renderer(ctx.renderer);
+        let client_side = config.pre_render == Some(false);
+        #[cfg(not(feature = "katex"))]
+        if backend == Backend::Html && !client_side {
+            diagnostics::warn(&format!(
+                "{}, equations are kept as they are",
+                Engine::MISSING
+            ));
+        }
+        let stylesheet = Self::load_stylesheet(ctx, &config)?;
+        let source_dir = ctx.config.book.src.to_string_lossy().replace('\\', "/");
+        let stylesheet_registered = stylesheet
+            .as_deref()
+            .is_some_and(|stylesheet| Self::check_stylesheet(ctx, stylesheet));
+        Ok(ChapterRenderer {
+            enabled: Self::load_default(&config)?,
+            chapter_filter: ChapterFilter::new(config.include.clone(), config.exclude.clone()),
+            backend,
+            client_side,
+            copy_tex: config.copy_tex,
+            source_lines: config.source_lines,
+            edit_url: config.equation_edit_url.clone(),
+            source_dir: source_dir.clone(),
+            trace_macros: config.trace_macros,
+            inline_opts,
+            display_opts,
+            fallback_opts: if config.retry_with_defaults {
+                Some(Self::build_fallback_opts(&config, &macros, output_type))
+            } else {
+                None
+            },
+            scanner: Scanner::new(match &self.delimiters {
+                Some(delimiters) => delimiters.clone(),
+                None => Self::load_delimiters(&config)?,
+            })
+            .with_max_inline_length(Self::load_max_inline_length(&config)?)
+            .with_data_blocks(Self::load_data_blocks(&config))
+            .with_math_environments(config.math_environments != Some(false))
+            .with_math_code(config.math_code != Some(false)),
+            comments: Self::load_comments(&config)?,
+            markdown_escapes: config.mathjax_delimiters,
+            normalize_scripts: config.normalize_scripts,
+            symbols: Self::load_symbols(&config)?,
+            feed_safe,
+            print_scale_down: config.print_scale_down,
+            nowrap_inline: config.nowrap_inline,
+            inline_vertical_align: Self::load_inline_vertical_align(&config)?,
+            bidi_isolation: config.bidi_isolation != Some(false)
+                && ctx
+                    .config
+                    .book
+                    .language
+                    .as_deref()
+                    .is_some_and(html::right_to_left),
+            stylesheet_registered,
+            stylesheet,
+            site_url: match ctx.config.get("output.html.site-url") {
+                Some(toml::Value::String(site_url)) => Some(site_url.clone()),
+                _ => None,
+            },
+            warnings_as_comments: config.warnings_as_comments,
+            meta_description: Self::load_meta_description(&config)?,
+            max_display_width: Self::load_max_display_width(&config)?,
+            math_attributes: Self::load_math_attributes(&config)?,
+            lang: ctx.config.book.language.clone(),
+            shims,
+            snippets: Some(Snippets::new(ctx.root)),
+            error_mode: Self::load_error_mode(&config)?,
+            numbering: match &config.numbering {
+                Some(value) => Some(
+                    Numbering::from_config(value)
+                        .context("invalid option `numbering` in `[preprocessor.katex]`")?,
+                ),
+                None => None,
+            },
+            chapter_macros: match &config.chapter_macros {
+                Some(value) => Some(
+                    MacroScope::from_config(value)
+                        .context("invalid option `chapter-macros` in `[preprocessor.katex]`")?,
+                ),
+                None => None,
+            },
+            preamble_chapter: config.preamble_chapter.as_ref().map(PathBuf::from),
+            jobs: parallel::jobs(config.jobs.unwrap_or(0)),
+            cache: Self::load_cache(ctx, &config)?,
+            manifest: config.manifest.as_ref().map(|path| ctx.root.join(path)),
+            reading_speed: config
+                .reading_time
+                .then(|| Self::load_reading_speed(&config)),
+            fingerprint: cache::hash(&(
+                Self::fingerprint(ctx),
+                format!("{:?}", (&self.macros, &self.delimiters)),
+                backend,
+                &ctx.config.book.language,
+                &preamble,
+                // the header of chapters depends on `output.html.additional-css`
+                stylesheet_registered,
+                // and edit links on `book.src`
+                &source_dir,
+            )),
+            macros,
+            preamble,
+            context: Some(OwnedContext {
+                root: ctx.root.to_path_buf(),
+                config: ctx.config.clone(),
+                renderer: String::from(ctx.renderer),
+                // the options are already in the configuration
+                processor: KatexProcessor {
+                    options: toml::value::Table::new(),
+                    ..self.clone()
+                },
+            }),
+        })
+    }
+
+    fn build_opts(
+        config: &KatexConfig,
+        macros: &MacroMap,
+        output_type: OutputType,
+    ) -> Result<(RenderOpts, RenderOpts), Error> {
+        // macros as a HashMap
+        let macros = macros.to_katex_macros();
+        let raw_opts = Self::load_raw_opts(config)?;
+        let invalid = "invalid option `raw-opts` in `[preprocessor.katex]`";
+        // inline rendering options
+        let inline_opts = RenderOpts::new(false)
+            .with_output_type(output_type)
+            .with_macros(macros.clone())
+            .with_raw_opts(&raw_opts)
+            .context(invalid)?;
+        // display rendering options
+        let display_opts = RenderOpts::new(true)
+            .with_output_type(output_type)
+            .with_leqno(config.leqno)
+            .with_fleqn(config.fleqn)
+            .with_macros(macros)
+            .with_raw_opts(&raw_opts)
+            .context(invalid)?;
+        Ok((
+            Self::apply_katex_keys(config, inline_opts),
+            Self::apply_katex_keys(config, display_opts),
+        ))
+    }
 
-    let mut file = match File::open(&path) {
-        Err(why) => panic!("couldn't open {}: {}", display, why),
-        Ok(file) => file,
-    };
+    // katex options set with keys like `max-expand`
+    fn apply_katex_keys(config: &KatexConfig, mut opts: RenderOpts) -> RenderOpts {
+        if let Some(throw_on_error) = config.throw_on_error {
+            opts = opts.with_throw_on_error(throw_on_error);
+        }
+        if let Some(error_color) = &config.error_color {
+            opts = opts.with_error_color(error_color);
+        }
+        if let Some(min_rule_thickness) = config.min_rule_thickness {
+            opts = opts.with_min_rule_thickness(min_rule_thickness);
+        }
+        if let Some(max_size) = config.max_size {
+            opts = opts.with_max_size(max_size);
+        }
+        if let Some(max_expand) = config.max_expand {
+            opts = opts.with_max_expand(max_expand);
+        }
+        if let Some(trust) = config.trust {
+            opts = opts.with_trust(trust);
+        }
+        opts
+    }
+
+    // options equations are rendered again with after failing: katex's
+    // defaults, but for the options of the book's layout and its macros
+    fn build_fallback_opts(
+        config: &KatexConfig,
+        macros: &MacroMap,
+        output_type: OutputType,
+    ) -> (RenderOpts, RenderOpts) {
+        let macros = macros.to_katex_macros();
+        let inline_opts = RenderOpts::new(false)
+            .with_output_type(output_type)
+            .with_macros(macros.clone());
+        let display_opts = RenderOpts::new(true)
+            .with_output_type(output_type)
+            .with_leqno(config.leqno)
+            .with_fleqn(config.fleqn)
+            .with_macros(macros);
+        (inline_opts, display_opts)
+    }
+
+    // reading speeds of `reading-time`, with defaults for those unset
+    fn load_reading_speed(config: &KatexConfig) -> ReadingSpeed {
+        let default = ReadingSpeed::default();
+        ReadingSpeed {
+            words_per_minute: config.words_per_minute.unwrap_or(default.words_per_minute),
+            inline_equation_words: config
+                .inline_equation_words
+                .unwrap_or(default.inline_equation_words),
+            display_equation_words: config
+                .display_equation_words
+                .unwrap_or(default.display_equation_words),
+        }
+    }
+
+    // katex options without a key of their own, as a JSON object in `raw-opts`
+    fn load_raw_opts(
+        config: &KatexConfig,
+    ) -> Result<serde_json::Map<String, serde_json::Value>, Error> {
+        let raw_opts = match &config.raw_opts {
+            Some(raw_opts) => raw_opts,
+            None => return Ok(serde_json::Map::new()),
+        };
+        let opts: serde_json::Map<String, serde_json::Value> =
+            match serde_json::from_str(raw_opts) {
+                Ok(serde_json::Value::Object(opts)) => opts,
+                _ => return Err(Error::msg(
+                    "invalid option `raw-opts` in `[preprocessor.katex]`, expected a JSON object",
+                )),
+            };
+        // an option is set in one place only
+        let keys = [
+            (
+                "throwOnError",
+                "throw-on-error",
+                config.throw_on_error.is_some(),
+            ),
+            ("errorColor", "error-color", config.error_color.is_some()),
+            (
+                "minRuleThickness",
+                "min-rule-thickness",
+                config.min_rule_thickness.is_some(),
+            ),
+            ("maxSize", "max-size", config.max_size.is_some()),
+            ("maxExpand", "max-expand", config.max_expand.is_some()),
+            ("trust", "trust", config.trust.is_some()),
+        ];
+        for (name, key, set) in keys {
+            if set && opts.contains_key(name) {
+                return Err(Error::msg(format!(
+                    "`{}` is set with both `{}` and `raw-opts` in `[preprocessor.katex]`",
+                    name, key
+                )));
+            }
+        }
+        Ok(opts)
+    }
+
+    // delimiters from the `delimiters` table, mapping markers to "display" or
+    // "inline", and mathjax delimiters with `mathjax-delimiters`
+    fn load_delimiters(config: &KatexConfig) -> Result<Vec<Delimiter>, Error> {
+        let mut delimiters = match Self::load_delimiter_pairs(config)? {
+            Some(delimiters) => Some(delimiters),
+            None => Self::load_delimiter_table(config)?,
+        };
+        if config.mathjax_delimiters {
+            // `$` isn't a delimiter for mathjax, unless configured explicitly
+            let mathjax = scan::mathjax_delimiters();
+            match delimiters {
+                Some(ref mut delimiters) => {
+                    for delimiter in mathjax {
+                        if !delimiters.contains(&delimiter) {
+                            delimiters.push(delimiter);
+                        }
+                    }
+                }
+                None => delimiters = Some(mathjax),
+            }
+        }
+        let mut delimiters = delimiters.unwrap_or_else(default_delimiters);
+        // LaTeX delimiters come on top of the others, `$` included
+        if config.latex_delimiters {
+            for delimiter in scan::latex_delimiters() {
+                if !delimiters.contains(&delimiter) {
+                    delimiters.push(delimiter);
+                }
+            }
+        }
+        // display equations only, for books where `$` is mostly a dollar sign
+        if config.inline == Some(false) {
+            delimiters.retain(|delimiter| delimiter.display);
+        }
+        Ok(delimiters)
+    }
+
+    // `$$` and `$`, or the pairs replacing them with `block-delimiter` and
+    // `inline-delimiter`
+    fn load_delimiter_pairs(config: &KatexConfig) -> Result<Option<Vec<Delimiter>>, Error> {
+        if config.block_delimiter.is_none() && config.inline_delimiter.is_none() {
+            return Ok(None);
+        }
+        if config.delimiters.is_some() {
+            return Err(Error::msg(
+                "`block-delimiter` and `inline-delimiter` can't be used along with \
+                 `[preprocessor.katex.delimiters]`",
+            ));
+        }
+        let pair = |key: &str, markers: &Option<Vec<String>>, default: &str, display: bool| {
+            let markers = match markers {
+                Some(markers) => markers,
+                None => return Ok(Some(Delimiter::new(default, display))),
+            };
+            match &markers[..] {
+                [] => Ok(None),
+                [marker, closing] if !marker.is_empty() && !closing.is_empty() => {
+                    Ok(Some(Delimiter::pair(marker, closing, display)))
+                }
+                _ => Err(Error::msg(format!(
+                    "invalid option `{}` in `[preprocessor.katex]`, expected an opening \
+                     and a closing marker, or `[]` for none",
+                    key
+                ))),
+            }
+        };
+        let block = pair("block-delimiter", &config.block_delimiter, "$$", true)?;
+        let inline = pair("inline-delimiter", &config.inline_delimiter, "$", false)?;
+        Ok(Some(block.into_iter().chain(inline).collect()))
+    }
+
+    fn load_delimiter_table(config: &KatexConfig) -> Result<Option<Vec<Delimiter>>, Error> {
+        let table = match &config.delimiters {
+            Some(table) => table,
+            None => return Ok(None),
+        };
+        let mut delimiters = Vec::new();
+        for (marker, mode) in table {
+            let display = match mode.as_str() {
+                "display" => true,
+                "inline" => false,
+                _ => {
+                    return Err(Error::msg(format!(
+                        "invalid mode for delimiter `{}` in `[preprocessor.katex.delimiters]`, \
+                         expected \"display\" or \"inline\"",
+                        marker
+                    )))
+                }
+            };
+            // a pair of markers, opening and closing, are separated by a space
+            let delimiter = match marker.split_whitespace().collect::<Vec<_>>()[..] {
+                [marker] => Delimiter::new(marker, display),
+                [marker, closing] => Delimiter::pair(marker, closing, display),
+                [] => {
+                    return Err(Error::msg(
+                        "empty delimiter in `[preprocessor.katex.delimiters]`",
+                    ))
+                }
+                _ => {
+                    return Err(Error::msg(format!(
+                        "invalid delimiter `{}` in `[preprocessor.katex.delimiters]`, \
+                         expected a marker, or an opening and a closing marker \
+                         separated by a space",
+                        marker
+                    )))
+                }
+            };
+            delimiters.push(delimiter);
+        }
+        Ok(Some(delimiters))
+    }
+
+    // handling of `%` comments inside equations
+    fn load_comments(config: &KatexConfig) -> Result<CommentHandling, Error> {
+        match &config.comments {
+            Some(value) => CommentHandling::from_config(value)
+                .context("invalid option `comments` in `[preprocessor.katex]`"),
+            None => Ok(CommentHandling::default()),
+        }
+    }
+
+    fn load_error_mode(config: &KatexConfig) -> Result<ErrorMode, Error> {
+        let mode = match &config.error_mode {
+            Some(value) => ErrorMode::from_config(value)
+                .context("invalid option `error-mode` in `[preprocessor.katex]`")?,
+            None if config.fail_on_error => ErrorMode::Error,
+            None => ErrorMode::default(),
+        };
+        if config.fail_on_error && mode != ErrorMode::Error {
+            return Err(Error::msg(format!(
+                "`fail-on-error = true` can't be used with `error-mode = \"{}\"` in `[preprocessor.katex]`",
+                config.error_mode.as_deref().unwrap_or_default()
+            )));
+        }
+        Ok(mode)
+    }
+
+    // path of a local copy of the katex stylesheet, with `stylesheet = "..."`
+    // or a copy made with `static-css = true`
+    fn load_stylesheet(ctx: &BookContext, config: &KatexConfig) -> Result<Option<String>, Error> {
+        if !config.static_css {
+            return Ok(config.stylesheet.clone());
+        }
+        let stylesheet = config
+            .stylesheet
+            .as_deref()
+            .unwrap_or(assets::DEFAULT_STYLESHEET);
+        // other renderers don't link the stylesheet
+        if ctx.renderer == "html" {
+            let dist = ctx
+                .root
+                .join(config.katex_dist.as_deref().unwrap_or(assets::DEFAULT_DIST));
+            let src = ctx.root.join(&ctx.config.book.src);
+            assets::install(&dist, &src.join(stylesheet.trim_start_matches('/')))
+                .context("unable to copy the KaTeX stylesheet for `static-css`")?;
+        }
+        Ok(Some(String::from(stylesheet)))
+    }
+
+    // whether a local stylesheet is in `output.html.additional-css`, warning
+    // when it, or its fonts, won't be in the built book
+    fn check_stylesheet(ctx: &BookContext, stylesheet: &str) -> bool {
+        if stylesheet.contains("://") || ctx.renderer != "html" {
+            return false;
+        }
+        let stylesheet = stylesheet.trim_start_matches('/');
+        let registered = match ctx.config.get("output.html.additional-css") {
+            Some(toml::Value::Array(paths)) => paths.iter().any(|path| {
+                path.as_str().is_some_and(|path| {
+                    Path::new(path.trim_start_matches("./")) == Path::new(stylesheet)
+                })
+            }),
+            _ => false,
+        };
+        let src = ctx.root.join(&ctx.config.book.src);
+        // additional css is copied from the book root, other files from the source directory
+        let path = if registered {
+            ctx.root.join(stylesheet)
+        } else {
+            src.join(stylesheet)
+        };
+        let fonts = Path::new(stylesheet)
+            .parent()
+            .map_or_else(|| PathBuf::from("fonts"), |dir| dir.join("fonts"));
+        if !path.exists() {
+            diagnostics::warn(&format!(
+                "the KaTeX stylesheet `{}` isn't in {}; copy `katex.min.css` from a KaTeX {} \
+                 release there, or equations will be unstyled",
+                stylesheet,
+                path.parent().unwrap_or(&src).display(),
+                KATEX_VERSION
+            ));
+        } else if !src.join(&fonts).is_dir() {
+            diagnostics::warn(&format!(
+                "the KaTeX fonts aren't in {}; copy the `fonts` directory of a KaTeX {} release \
+                 there, next to the stylesheet in the built book",
+                src.join(&fonts).display(),
+                KATEX_VERSION
+            ));
+        }
+        registered
+    }
+
+    // languages of fenced blocks holding data with math in their strings, from
+    // `data-blocks = ["quiz"]`
+    fn load_data_blocks(config: &KatexConfig) -> Vec<String> {
+        config.data_blocks.clone()
+    }
+
+    // symbols replaced inside equations: the default ones with `unicode-symbols`,
+    // and those of the `symbols` table, e.g. `"°" = "^\\circ"`
+    fn load_symbols(config: &KatexConfig) -> Result<HashMap<char, String>, Error> {
+        let mut symbols = HashMap::new();
+        if config.unicode_symbols {
+            for (symbol, command) in tex::DEFAULT_SYMBOLS {
+                symbols.insert(*symbol, String::from(*command));
+            }
+        }
+        for (symbol, command) in config.symbols.iter().flatten() {
+            let mut chars = symbol.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => symbols.insert(c, command.clone()),
+                _ => {
+                    return Err(Error::msg(format!(
+                        "invalid symbol `{}` in `[preprocessor.katex.symbols]`, expected a \
+                         single character",
+                        symbol
+                    )))
+                }
+            };
+        }
+        Ok(symbols)
+    }
+
+    // what equations are rendered to, `output = "html"` by default
+    fn load_output_type(config: &KatexConfig) -> Result<OutputType, Error> {
+        let output_type = match &config.output {
+            // the copy-tex extension copies the tex of the mathml annotation
+            None if config.copy_tex => OutputType::HtmlAndMathml,
+            None => OutputType::Html,
+            Some(value) => OutputType::from_config(value)
+                .context("invalid option `output` in `[preprocessor.katex]`")?,
+        };
+        if config.copy_tex && output_type == OutputType::Html {
+            diagnostics::warn(
+                "`copy-tex = true` with `output = \"html\"`: katex's copy-tex extension \
+                 copies the tex of mathml output, equations only keep it in `data-tex`",
+            );
+        }
+        Ok(output_type)
+    }
+
+    // attributes added to mathml output, from `mathml-attributes = ["alttext"]`
+    fn load_math_attributes(config: &KatexConfig) -> Result<Vec<MathAttribute>, Error> {
+        config
+            .mathml_attributes
+            .iter()
+            .map(|name| {
+                MathAttribute::from_name(name).ok_or_else(|| {
+                    Error::msg(
+                        "invalid option `mathml-attributes` in `[preprocessor.katex]`, expected \
+                         an array of \"display\", \"alttext\", \"lang\" or \"role\"",
+                    )
+                })
+            })
+            .collect()
+    }
+
+    // whether chapters are rendered by default, `default = "on"` or `"off"`
+    fn load_default(config: &KatexConfig) -> Result<bool, Error> {
+        match config.default.as_deref() {
+            None | Some("on") => Ok(true),
+            Some("off") => Ok(false),
+            Some(_) => Err(Error::msg(
+                "invalid option `default` in `[preprocessor.katex]`, expected \"on\" or \"off\"",
+            )),
+        }
+    }
+
+    // maximum length of inline equations, `max-inline-length = 0` for no limit
+    fn load_max_inline_length(config: &KatexConfig) -> Result<Option<usize>, Error> {
+        match config.max_inline_length {
+            None => Ok(Some(scan::DEFAULT_MAX_INLINE_LENGTH)),
+            Some(0) => Ok(None),
+            Some(max) if max > 0 => Ok(Some(max as usize)),
+            Some(_) => Err(Error::msg(
+                "invalid option `max-inline-length` in `[preprocessor.katex]`, \
+                 expected a positive integer",
+            )),
+        }
+    }
+
+    // length of chapter descriptions, if enabled with `meta-description`
+    fn load_meta_description(config: &KatexConfig) -> Result<Option<usize>, Error> {
+        if !config.meta_description {
+            return Ok(None);
+        }
+        match config.meta_description_length {
+            None => Ok(Some(DEFAULT_DESCRIPTION_LENGTH)),
+            Some(length) if length > 0 => Ok(Some(length as usize)),
+            Some(_) => Err(Error::msg(
+                "invalid option `meta-description-length` in `[preprocessor.katex]`, \
+                 expected a positive integer",
+            )),
+        }
+    }
+
+    // css length inline equations are shifted by, with `inline-vertical-align`
+    fn load_inline_vertical_align(config: &KatexConfig) -> Result<Option<String>, Error> {
+        match config.inline_vertical_align.as_deref().map(str::trim) {
+            None => Ok(None),
+            Some(length) if html::is_length(length) => Ok(Some(String::from(length))),
+            Some(_) => Err(Error::msg(
+                "invalid option `inline-vertical-align` in `[preprocessor.katex]`, \
+                 expected a length like \"-0.1em\"",
+            )),
+        }
+    }
+
+    // width estimate above which display equations are broken, if enabled with
+    // `max-display-width`
+    fn load_max_display_width(config: &KatexConfig) -> Result<Option<usize>, Error> {
+        match config.max_display_width {
+            None | Some(0) => Ok(None),
+            Some(max) if max > 0 => Ok(Some(max as usize)),
+            Some(_) => Err(Error::msg(
+                "invalid option `max-display-width` in `[preprocessor.katex]`, \
+                 expected a positive integer",
+            )),
+        }
+    }
+
+    // directory of the on-disk cache, from `cache-dir` relative to the book root
+    pub fn cache_dir(ctx: &BookContext) -> PathBuf {
+        // an invalid table is reported when setting up a renderer
+        let config = KatexConfig::from_table(ctx.config.get_preprocessor("katex"));
+        match config.ok().and_then(|config| config.cache_dir) {
+            Some(dir) => ctx.root.join(dir),
+            None => FsCache::default_dir(ctx.root),
+        }
+    }
+
+    // cache, if enabled with `cache = true`, or by setting `MDBOOK_KATEX_CACHE_DIR`
+    // or running under `mdbook serve` unless `cache = false`; on disk unless
+    // `cache-backend = "memory"`, or turned on by `mdbook serve` alone
+    fn load_cache(
+        ctx: &BookContext,
+        config: &KatexConfig,
+    ) -> Result<Option<Box<dyn Cache>>, Error> {
+        let from_env = std::env::var_os(cache::CACHE_DIR_VAR).is_some_and(|dir| !dir.is_empty());
+        let enabled = config
+            .cache
+            .unwrap_or_else(|| from_env || Self::serving(ctx));
+        if !enabled {
+            return Ok(None);
+        }
+        // the cache `mdbook serve` turns on leaves nothing behind
+        let backend = match (
+            &config.cache_backend,
+            config.cache,
+            &config.cache_dir,
+            from_env,
+        ) {
+            (Some(backend), ..) => backend.as_str(),
+            (None, None, None, false) => "memory",
+            _ => "fs",
+        };
+        match backend {
+            "fs" => Ok(Some(Box::new(FsCache::new(&Self::cache_dir(ctx))))),
+            "memory" => Ok(Some(Box::new(MemoryCache::new()))),
+            _ => Err(Error::msg(
+                "invalid option `cache-backend` in `[preprocessor.katex]`, \
+                 expected \"fs\" or \"memory\"",
+            )),
+        }
+    }
+
+    // whether the book is built by `mdbook serve`, which sets where pages find
+    // the live reload server, and runs the preprocessor again on every change
+    fn serving(ctx: &BookContext) -> bool {
+        [
+            "output.html.live-reload-endpoint",
+            "output.html.livereload-url",
+        ]
+        .iter()
+        .any(|key| ctx.config.get(key).is_some())
+    }
+
+    // hash of everything, besides the chapter itself and its macros, that
+    // affects rendering
+    fn fingerprint(ctx: &BookContext) -> u64 {
+        let config = format!("{:?}", ctx.config.get_preprocessor("katex"));
+        cache::hash(&config)
+    }
+
+    fn load_preamble(config: &KatexConfig, root: &Path) -> Result<Option<String>, Error> {
+        let path = match &config.preamble {
+            Some(path) => root.join(path),
+            None => return Ok(None),
+        };
+        let preamble = encoding::read_to_string(&path, config.lossy_utf8)
+            .with_context(|| format!("failed to load the preamble from {}", path.display()))?;
+        Ok(Some(preamble))
+    }
+
+    fn load_macros(
+        &self,
+        config: &KatexConfig,
+        root: &Path,
+        metadata_macros: &[(String, String, macros::Location)],
+    ) -> Result<MacroMap, Error> {
+        let policy = match &config.duplicate_macros {
+            Some(value) => DuplicatePolicy::from_config(value)
+                .context("invalid option `duplicate-macros` in `[preprocessor.katex]`")?,
+            None => DuplicatePolicy::default(),
+        };
+        // parse macros, keeping track of where they are defined
+        let mut map = MacroMap::new(policy);
+        // those of the metadata come first, for the book's own files to win
+        for (name, expansion, location) in metadata_macros {
+            map.define(name, expansion, location.clone())?;
+        }
+        // relative to the book root, not to where mdbook was started
+        for path in config.macros.iter().map(|path| root.join(path)) {
+            let macro_str = encoding::read_to_string(&path, config.lossy_utf8)
+                .with_context(|| format!("failed to load macros from {}", path.display()))?;
+            map.parse(&macro_str, &path)
+                .with_context(|| format!("failed to load macros from {}", path.display()))?;
+        }
+        for (line, (name, expansion)) in self.macros.iter().enumerate() {
+            let location = macros::Location {
+                path: PathBuf::from("KatexProcessorBuilder"),
+                line: line + 1,
+            };
+            map.define(name, expansion, location)?;
+        }
+        map.check_recursion().context("invalid macros")?;
+        for duplicate in map.duplicates() {
+            diagnostics::warn(&format!("{}; the later definition wins", duplicate));
+        }
+        Ok(map)
+    }
+}
+
+// options in a `<!-- katex: ... -->` directive, as comma-separated `name=value`
+// pairs; values are TOML values, or strings when they aren't
+#[cfg(feature = "preprocessor")]
+fn parse_directive(directive: &str) -> Result<Vec<(String, toml::Value)>, Error> {
+    // the pairs of a TOML inline table, which lists and tables can be written in
+    let inline = format!("options = {{{}}}", directive.trim().trim_end_matches(','));
+    if let Ok(mut table) = toml::from_str::<toml::value::Table>(&inline) {
+        if let Some(toml::Value::Table(options)) = table.remove("options") {
+            return Ok(options.into_iter().collect());
+        }
+    }
+    // or with strings left unquoted, as long as they have no commas
+    let mut options = Vec::new();
+    for option in directive
+        .split(',')
+        .filter(|option| !option.trim().is_empty())
+    {
+        let (name, value) = option.split_once('=').ok_or_else(|| {
+            Error::msg(format!(
+                "invalid option `{}` in `<!-- katex: ... -->`, expected `name=value`",
+                option.trim()
+            ))
+        })?;
+        let value = value.trim();
+        let value = match toml::from_str::<toml::value::Table>(&format!("value = {}", value)) {
+            Ok(mut table) => table.remove("value").unwrap(),
+            Err(_) => toml::Value::String(String::from(value)),
+        };
+        options.push((String::from(name.trim()), value));
+    }
+    Ok(options)
+}
 
-    let mut string = String::new();
-    match file.read_to_string(&mut string) {
-        Err(why) => panic!("couldn't read {}: {}", display, why),
-        Ok(_) => (),
-    };
-    string
+#[cfg(feature = "preprocessor")]
+pub fn load_as_string(path: &Path) -> Result<String, Error> {
+    encoding::read_to_string(path, false)
 }
 
+// the stylesheet link records the version of katex equations were rendered with
+#[cfg(feature = "preprocessor")]
 fn katex_header() -> String {
-    String::from("<link rel=\"stylesheet\" href=\"https://cdn.jsdelivr.net/npm/katex@0.12.0/dist/katex.min.css\" integrity=\"sha384-AfEj0r4/OFrOo5t7NnNe46zW/tFgW6x/bCJG8FqQCEo3+Aro6EYUG4+cU+KJWu/X\" crossorigin=\"anonymous\">\n\n")
+    format!("<link rel=\"stylesheet\" href=\"https://cdn.jsdelivr.net/npm/katex@{}/dist/katex.min.css\" integrity=\"sha384-AfEj0r4/OFrOo5t7NnNe46zW/tFgW6x/bCJG8FqQCEo3+Aro6EYUG4+cU+KJWu/X\" crossorigin=\"anonymous\" data-katex-version=\"{}\">\n\n", KATEX_VERSION, KATEX_VERSION)
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "katex", feature = "preprocessor"))]
 mod tests;
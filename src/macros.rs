@@ -0,0 +1,841 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+
+// where a macro was defined
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub path: PathBuf,
+    pub line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.line)
+    }
+}
+
+// what to do when the same macro is defined twice
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum DuplicatePolicy {
+    // keep the last definition, and warn
+    #[default]
+    LaterWins,
+    // abort with an error
+    Error,
+}
+
+impl DuplicatePolicy {
+    pub fn from_config(value: &str) -> Result<Self, Error> {
+        match value {
+            "later-wins" => Ok(DuplicatePolicy::LaterWins),
+            "error" => Ok(DuplicatePolicy::Error),
+            other => Err(Error::msg(format!(
+                "unknown value `{}`, expected \"later-wins\" or \"error\"",
+                other
+            ))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MacroDefinition {
+    pub expansion: String,
+    pub location: Location,
+}
+
+// an environment defined with `\newenvironment{name}[parameters]{begin}{end}`,
+// which katex doesn't support: `\begin{name}` and `\end{name}` are replaced
+// with `begin` and `end` in a group
+#[derive(Clone, Debug, PartialEq)]
+pub struct Environment {
+    pub parameters: usize,
+    pub begin: String,
+    pub end: String,
+    pub location: Location,
+}
+
+// environments can expand to other environments, this bounds the work
+const MAX_ENVIRONMENT_EXPANSIONS: usize = 256;
+
+// a macro redefined at a later location
+#[derive(Clone, Debug, PartialEq)]
+pub struct Duplicate {
+    pub name: String,
+    pub first: Location,
+    pub second: Location,
+}
+
+impl fmt::Display for Duplicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "macro `{}` defined at {} is redefined at {}",
+            self.name, self.first, self.second
+        )
+    }
+}
+
+// macro definitions, collected from one or several sources
+#[derive(Clone, Debug, Default)]
+pub struct MacroMap {
+    definitions: HashMap<String, MacroDefinition>,
+    environments: HashMap<String, Environment>,
+    duplicates: Vec<Duplicate>,
+    policy: DuplicatePolicy,
+}
+
+impl MacroMap {
+    pub fn new(policy: DuplicatePolicy) -> Self {
+        MacroMap {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    // add a definition, following the duplicate policy if it already exists
+    pub fn define(&mut self, name: &str, expansion: &str, location: Location) -> Result<(), Error> {
+        let definition = MacroDefinition {
+            expansion: String::from(expansion),
+            location,
+        };
+        if let Some(previous) = self.definitions.get(name) {
+            let duplicate = Duplicate {
+                name: String::from(name),
+                first: previous.location.clone(),
+                second: definition.location.clone(),
+            };
+            if self.policy == DuplicatePolicy::Error {
+                return Err(Error::msg(duplicate.to_string()));
+            }
+            self.duplicates.push(duplicate);
+        }
+        self.definitions.insert(String::from(name), definition);
+        Ok(())
+    }
+
+    // add an environment, following the duplicate policy if it already exists
+    pub fn define_environment(
+        &mut self,
+        name: &str,
+        environment: Environment,
+    ) -> Result<(), Error> {
+        if let Some(previous) = self.environments.get(name) {
+            let duplicate = Duplicate {
+                name: format!("\\begin{{{}}}", name),
+                first: previous.location.clone(),
+                second: environment.location.clone(),
+            };
+            if self.policy == DuplicatePolicy::Error {
+                return Err(Error::msg(duplicate.to_string()));
+            }
+            self.duplicates.push(duplicate);
+        }
+        self.environments.insert(String::from(name), environment);
+        Ok(())
+    }
+
+    // parse the content of a macros file, one `\name:expansion` pair per line,
+    // or latex definitions like `\newcommand{\name}[1]{expansion}`, as in a
+    // preamble, spanning several lines until their braces are balanced
+    pub fn parse(&mut self, content: &str, path: &Path) -> Result<(), Error> {
+        let mut lines = content.lines().enumerate();
+        while let Some((index, line)) = lines.next() {
+            // only consider lines starting with a backslash
+            if !line.starts_with('\\') {
+                continue;
+            }
+            let location = Location {
+                path: path.to_path_buf(),
+                line: index + 1,
+            };
+            if definition_command(line).is_none() {
+                if let Some((name, expansion)) = line.split_once(':') {
+                    self.define(name, expansion, location)?;
+                }
+                continue;
+            }
+            let (code, mut commented) = without_comment(line);
+            let mut definition = String::from(code);
+            while depth(&definition) > 0 {
+                let next = match lines.next() {
+                    Some((_, next)) => next,
+                    None => break,
+                };
+                // as in tex, a line ending with a comment isn't followed by a
+                // space, and spaces starting a line are skipped
+                if !commented {
+                    definition.push('\n');
+                }
+                let (code, next_commented) = without_comment(next);
+                definition.push_str(code.trim_start());
+                commented = next_commented;
+            }
+            let rest = self.define_leading(&definition, location.clone())?;
+            if !rest.is_empty() {
+                return Err(Error::msg(format!(
+                    "invalid macro definition at {}, unexpected `{}` after it",
+                    location, rest
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // define the macros an equation starts with, as in
+    // `\newcommand{\R}{\mathbb{R}} \R^n`, and return the rest of it
+    pub fn define_leading<'a>(
+        &mut self,
+        tex: &'a str,
+        location: Location,
+    ) -> Result<&'a str, Error> {
+        let mut rest = tex.trim_start();
+        while definition_command(rest).is_some() {
+            rest = self.parse_definition(rest, location.clone())?.trim_start();
+        }
+        Ok(rest.trim_end())
+    }
+
+    // a definition with one of `DEFINITIONS`, and what follows it
+    fn parse_definition<'a>(
+        &mut self,
+        definition: &'a str,
+        location: Location,
+    ) -> Result<&'a str, Error> {
+        let command = definition_command(definition).unwrap_or_default();
+        let rest = &definition[command.len()..];
+        match command {
+            "\\newenvironment" | "\\renewenvironment" => {
+                let (name, parameters, begin, end, rest) =
+                    parse_environment(rest).ok_or_else(|| {
+                        Error::msg(format!(
+                            "invalid environment definition at {}, expected \
+                         `\\newenvironment{{name}}[parameters]{{begin}}{{end}}`",
+                            location
+                        ))
+                    })?;
+                let environment = Environment {
+                    parameters,
+                    begin,
+                    end,
+                    location,
+                };
+                self.define_environment(&name, environment)?;
+                Ok(rest)
+            }
+            "\\DeclareMathOperator" => {
+                let (star, rest) = match rest.strip_prefix('*') {
+                    Some(rest) => ("*", rest),
+                    None => ("", rest),
+                };
+                let (name, rest) = parse_name(rest).ok_or_else(|| {
+                    invalid_definition(&location, "\\DeclareMathOperator{\\name}{text}")
+                })?;
+                let rest = rest.trim_start();
+                let text = brace_group(rest).ok_or_else(|| {
+                    invalid_definition(&location, "\\DeclareMathOperator{\\name}{text}")
+                })?;
+                let expansion = format!("\\operatorname{}{{{}}}", star, text);
+                self.define(name, &expansion, location)?;
+                Ok(&rest[text.len() + 2..])
+            }
+            "\\def" => {
+                let (name, expansion, arguments, rest) = parse_def(rest)
+                    .ok_or_else(|| invalid_definition(&location, "\\def\\name#1#2{expansion}"))?;
+                let expansion = with_arguments(name, expansion, arguments, &location)?;
+                self.define(name, &expansion, location)?;
+                Ok(rest)
+            }
+            _ => {
+                let (name, expansion, arguments, rest) =
+                    parse_newcommand(rest.strip_prefix('*').unwrap_or(rest), &location)?;
+                // `\providecommand` leaves existing macros alone
+                if command == "\\providecommand" && self.definitions.contains_key(name) {
+                    return Ok(rest);
+                }
+                let expansion = with_arguments(name, expansion, arguments, &location)?;
+                self.define(name, &expansion, location)?;
+                Ok(rest)
+            }
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&MacroDefinition> {
+        self.definitions.get(name)
+    }
+
+    pub fn environment(&self, name: &str) -> Option<&Environment> {
+        self.environments.get(name)
+    }
+
+    // macros redefined so far, in definition order
+    pub fn duplicates(&self) -> &[Duplicate] {
+        &self.duplicates
+    }
+
+    // macros in the format expected by katex
+    pub fn to_katex_macros(&self) -> HashMap<String, String> {
+        self.definitions
+            .iter()
+            .map(|(name, definition)| (name.clone(), definition.expansion.clone()))
+            .collect()
+    }
+}
+
+impl MacroMap {
+    // chain of macro expansions reachable from an equation, one line per
+    // expansion, indented by nesting depth
+    pub fn trace(&self, equation: &str) -> Vec<String> {
+        let mut lines = Vec::new();
+        let mut stack = Vec::new();
+        self.trace_into(equation, &mut stack, &mut lines);
+        lines
+    }
+
+    fn trace_into(&self, tex: &str, stack: &mut Vec<String>, lines: &mut Vec<String>) {
+        for name in control_sequences(tex) {
+            if let Some(definition) = self.definitions.get(&name) {
+                let indent = "  ".repeat(stack.len() + 1);
+                // stop on recursive definitions instead of looping forever
+                if stack.contains(&name) {
+                    lines.push(format!("{}{} -> (recursive)", indent, name));
+                    continue;
+                }
+                lines.push(format!(
+                    "{}{} -> {} ({})",
+                    indent, name, definition.expansion, definition.location
+                ));
+                stack.push(name);
+                self.trace_into(&definition.expansion, stack, lines);
+                stack.pop();
+            }
+        }
+    }
+}
+
+impl MacroMap {
+    // definitions some TeX can expand to, directly or through other macros,
+    // sorted by name: everything about the macros that can affect its rendering
+    pub fn used_by(&self, tex: &str) -> Vec<(&str, &str)> {
+        let mut used: Vec<(&str, &str)> = Vec::new();
+        let mut pending = control_sequences(tex);
+        let mut pending_environments = environment_names(tex);
+        loop {
+            if let Some(name) = pending.pop() {
+                if let Some((name, definition)) = self.definitions.get_key_value(&name) {
+                    if used.iter().any(|(used, _)| used == name) {
+                        continue;
+                    }
+                    used.push((name, &definition.expansion));
+                    pending.extend(control_sequences(&definition.expansion));
+                    pending_environments.extend(environment_names(&definition.expansion));
+                }
+            } else if let Some(name) = pending_environments.pop() {
+                // environment names don't start with a backslash, unlike macros
+                if let Some((name, environment)) = self.environments.get_key_value(&name) {
+                    if used.iter().any(|(used, _)| used == name) {
+                        continue;
+                    }
+                    used.push((name, &environment.begin));
+                    used.push((name, &environment.end));
+                    for part in [&environment.begin, &environment.end] {
+                        pending.extend(control_sequences(part));
+                        pending_environments.extend(environment_names(part));
+                    }
+                }
+            } else {
+                break;
+            }
+        }
+        used.sort();
+        used
+    }
+}
+
+impl MacroMap {
+    // replace the environments defined with `\newenvironment` in an equation
+    // with their definitions, in a group as in LaTeX
+    pub fn expand_environments<'a>(&self, tex: &'a str) -> Cow<'a, str> {
+        if self.environments.is_empty() || !tex.contains("\\begin{") {
+            return Cow::Borrowed(tex);
+        }
+        let mut tex = String::from(tex);
+        let mut position = 0;
+        let mut expansions = 0;
+        while expansions < MAX_ENVIRONMENT_EXPANSIONS {
+            let next = match next_environment(&tex[position..]) {
+                Some((offset, begin, name, length)) => (position + offset, begin, name, length),
+                None => break,
+            };
+            let (start, begin, name, length) = next;
+            let environment = match self.environments.get(name) {
+                Some(environment) => environment,
+                None => {
+                    position = start + length;
+                    continue;
+                }
+            };
+            let (mut replacement, replaced) = if begin {
+                let mut end = start + length;
+                let mut arguments = Vec::new();
+                for _ in 0..environment.parameters {
+                    let rest = &tex[end..];
+                    let skipped = rest.len() - rest.trim_start().len();
+                    let argument = match brace_group(&rest[skipped..]) {
+                        Some(argument) => argument,
+                        None => break,
+                    };
+                    arguments.push(argument);
+                    end += skipped + argument.len() + 2;
+                }
+                let mut expansion = String::from("\\begingroup ");
+                substitute(&mut expansion, &environment.begin, &arguments);
+                (expansion, end - start)
+            } else {
+                (format!("{}\\endgroup", environment.end), length)
+            };
+            separate(&mut replacement, &tex[start + replaced..]);
+            tex.replace_range(start..start + replaced, &replacement);
+            // the replacement is scanned too, for environments it uses
+            position = start;
+            expansions += 1;
+        }
+        Cow::Owned(tex)
+    }
+
+    // expand the macros without parameters in an equation, for backends that
+    // don't go through katex; macros with parameters are left as they are
+    pub fn expand_simple(&self, tex: &str) -> String {
+        let mut tex = self.expand_environments(tex).into_owned();
+        // recursive macros are rejected when loading, this only bounds the work
+        for _ in 0..16 {
+            let mut expanded = false;
+            for name in control_sequences(&tex) {
+                if let Some(definition) = self.definitions.get(&name) {
+                    if !definition.expansion.contains('#') {
+                        tex =
+                            replace_command(&tex, &name, &format!("{{{}}}", definition.expansion));
+                        expanded = true;
+                    }
+                }
+            }
+            if !expanded {
+                break;
+            }
+        }
+        tex
+    }
+}
+
+// the state of a macro in the search for recursive ones
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    // on the path being searched
+    Open,
+    // searched, with no cycle through the macros it uses
+    Done,
+}
+
+impl MacroMap {
+    // fail if a macro expands, directly or through other macros, to itself;
+    // a single depth-first search visits each macro once
+    pub fn check_recursion(&self) -> Result<(), Error> {
+        let mut names: Vec<&String> = self.definitions.keys().collect();
+        names.sort();
+        let mut visits: HashMap<&str, Visit> = HashMap::new();
+        for name in names {
+            if visits.contains_key(name.as_str()) {
+                continue;
+            }
+            // the open macros, with the macros they use left to search
+            let mut stack = vec![self.open(name, &mut visits)];
+            while let Some((_, used)) = stack.last_mut() {
+                let next = match used.pop() {
+                    Some(next) => next,
+                    None => {
+                        let (name, _) = stack.pop().unwrap();
+                        visits.insert(name, Visit::Done);
+                        continue;
+                    }
+                };
+                match visits.get(next) {
+                    Some(Visit::Done) => {}
+                    Some(Visit::Open) => return Err(self.recursion_error(&stack, next)),
+                    None => stack.push(self.open(next, &mut visits)),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // mark a macro open, with the defined macros its expansion uses
+    fn open<'a>(
+        &'a self,
+        name: &'a str,
+        visits: &mut HashMap<&'a str, Visit>,
+    ) -> (&'a str, Vec<&'a str>) {
+        visits.insert(name, Visit::Open);
+        let mut used: Vec<&str> = control_sequences(&self.definitions[name].expansion)
+            .iter()
+            .filter_map(|used| self.definitions.get_key_value(used))
+            .map(|(used, _)| used.as_str())
+            .collect();
+        // they're popped from the end, so searched in order
+        used.reverse();
+        (name, used)
+    }
+
+    // the error for the cycle closed by `name`, from the open macros
+    fn recursion_error(&self, stack: &[(&str, Vec<&str>)], name: &str) -> Error {
+        let start = stack.iter().position(|(open, _)| *open == name).unwrap();
+        let chain: Vec<String> = stack[start..]
+            .iter()
+            .map(|(open, _)| *open)
+            .chain(std::iter::once(name))
+            .map(|name| format!("{} ({})", name, self.definitions[name].location))
+            .collect();
+        Error::msg(format!(
+            "macro `{}` is defined recursively: {}",
+            name,
+            chain.join(" -> ")
+        ))
+    }
+}
+
+// append some TeX, replacing `#1` to `#9` with arguments, without gluing
+// control words to the letters after them
+fn substitute(output: &mut String, tex: &str, arguments: &[&str]) {
+    let mut rest = tex;
+    while let Some(offset) = rest.find('#') {
+        let argument = rest[offset + 1..]
+            .chars()
+            .next()
+            .and_then(|digit| digit.to_digit(10))
+            .and_then(|digit| arguments.get((digit as usize).checked_sub(1)?));
+        match argument {
+            Some(argument) => {
+                join(output, &rest[..offset], argument);
+                rest = &rest[offset + 2..];
+            }
+            None => {
+                output.push_str(&rest[..offset + 1]);
+                rest = &rest[offset + 1..];
+            }
+        }
+    }
+    join(output, rest, "");
+}
+
+// append `tex` then `next`, separated by a space where a control word would
+// otherwise run into a letter
+fn join(output: &mut String, tex: &str, next: &str) {
+    output.push_str(tex);
+    separate(output, next);
+    output.push_str(next);
+}
+
+// a space after the control word ending `output`, if `next` starts with a letter
+fn separate(output: &mut String, next: &str) {
+    let letters = output.len()
+        - output
+            .trim_end_matches(|c: char| c.is_ascii_alphabetic())
+            .len();
+    let before = &output[..output.len() - letters];
+    let backslashes = before.len() - before.trim_end_matches('\\').len();
+    if letters > 0 && backslashes % 2 == 1 && next.starts_with(|c: char| c.is_ascii_alphabetic()) {
+        output.push(' ');
+    }
+}
+
+// name, number of parameters, begin and end of `{name}[parameters]{begin}{end}`
+// latex commands defining macros or environments in a macros file
+const DEFINITIONS: &[&str] = &[
+    "\\newcommand",
+    "\\renewcommand",
+    "\\providecommand",
+    "\\DeclareMathOperator",
+    "\\def",
+    "\\newenvironment",
+    "\\renewenvironment",
+];
+
+// the command of `DEFINITIONS` some line starts with
+fn definition_command(line: &str) -> Option<&'static str> {
+    DEFINITIONS.iter().copied().find(|command| {
+        line.strip_prefix(command)
+            .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_alphabetic()))
+    })
+}
+
+// a line without its `%` comment, and whether it had one; `\%` is a percent sign
+fn without_comment(line: &str) -> (&str, bool) {
+    let mut escaped = false;
+    for (index, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '%' => return (&line[..index], true),
+            _ => {}
+        }
+    }
+    (line, false)
+}
+
+// how many braces are left open in some tex
+fn depth(tex: &str) -> i32 {
+    let mut depth = 0;
+    let mut escaped = false;
+    for c in tex.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    depth
+}
+
+fn invalid_definition(location: &Location, expected: &str) -> Error {
+    Error::msg(format!(
+        "invalid macro definition at {}, expected `{}`",
+        location, expected
+    ))
+}
+
+// the name of a macro being defined, `\name` or `{\name}`, and what follows it
+fn parse_name(definition: &str) -> Option<(&str, &str)> {
+    let definition = definition.trim_start();
+    if let Some(group) = brace_group(definition) {
+        let name = group.trim();
+        let rest = &definition[group.len() + 2..];
+        return control_sequence(name)
+            .filter(|length| *length == name.len())
+            .map(|_| (name, rest));
+    }
+    let length = control_sequence(definition)?;
+    Some((&definition[..length], &definition[length..]))
+}
+
+// length of the control sequence at the start of some tex, like `\name` or `\,`
+fn control_sequence(tex: &str) -> Option<usize> {
+    let rest = tex.strip_prefix('\\')?;
+    let letters = rest.len()
+        - rest
+            .trim_start_matches(|c: char| c.is_ascii_alphabetic())
+            .len();
+    match letters {
+        0 => rest.chars().next().map(|c| 1 + c.len_utf8()),
+        letters => Some(1 + letters),
+    }
+}
+
+// `{\name}[arguments]{expansion}` after `\newcommand`: its name, expansion,
+// number of arguments, and what follows it
+fn parse_newcommand<'a>(
+    definition: &'a str,
+    location: &Location,
+) -> Result<(&'a str, &'a str, usize, &'a str), Error> {
+    let expected = "\\newcommand{\\name}[arguments]{expansion}";
+    let (name, rest) =
+        parse_name(definition).ok_or_else(|| invalid_definition(location, expected))?;
+    let mut rest = rest.trim_start();
+    let mut arguments = 0;
+    if let Some(after) = rest.strip_prefix('[') {
+        let (count, after) = after
+            .split_once(']')
+            .ok_or_else(|| invalid_definition(location, expected))?;
+        arguments = count
+            .trim()
+            .parse()
+            .ok()
+            .filter(|count| *count <= 9)
+            .ok_or_else(|| invalid_definition(location, expected))?;
+        rest = after.trim_start();
+    }
+    if rest.starts_with('[') {
+        return Err(Error::msg(format!(
+            "`{}` defined at {} takes an optional argument, which KaTeX doesn't support",
+            name, location
+        )));
+    }
+    let expansion = brace_group(rest).ok_or_else(|| invalid_definition(location, expected))?;
+    Ok((name, expansion, arguments, &rest[expansion.len() + 2..]))
+}
+
+// `\name#1#2{expansion}` after `\def`: its name, expansion, number of
+// arguments, and what follows it; arguments delimited by other tokens aren't
+// supported
+fn parse_def(definition: &str) -> Option<(&str, &str, usize, &str)> {
+    let definition = definition.trim_start();
+    let length = control_sequence(definition)?;
+    let name = &definition[..length];
+    let mut rest = definition[length..].trim_start();
+    let mut arguments = 0;
+    while let Some(after) = rest.strip_prefix('#') {
+        let digit = after.chars().next()?.to_digit(10)?;
+        if digit as usize != arguments + 1 {
+            return None;
+        }
+        arguments += 1;
+        rest = after[1..].trim_start();
+    }
+    let expansion = brace_group(rest)?;
+    Some((name, expansion, arguments, &rest[expansion.len() + 2..]))
+}
+
+// the expansion katex gets for a macro taking some arguments: katex counts
+// the arguments a macro takes from the ones its expansion uses, so unused
+// ones are consumed by `\@firstoftwo`
+fn with_arguments(
+    name: &str,
+    expansion: &str,
+    arguments: usize,
+    location: &Location,
+) -> Result<String, Error> {
+    let used = (1..=9)
+        .rev()
+        .find(|argument| expansion.contains(&format!("#{}", argument)))
+        .unwrap_or(0);
+    if used > arguments {
+        return Err(Error::msg(format!(
+            "`{}` defined at {} uses `#{}` but takes {} arguments",
+            name, location, used, arguments
+        )));
+    }
+    if used == arguments {
+        return Ok(String::from(expansion));
+    }
+    Ok(format!("{}\\@firstoftwo{{}}{{#{}}}", expansion, arguments))
+}
+
+fn parse_environment(definition: &str) -> Option<(String, usize, String, String, &str)> {
+    let definition = definition.trim_start();
+    let name = brace_group(definition)?;
+    let mut rest = definition[name.len() + 2..].trim_start();
+    let mut parameters = 0;
+    if let Some(after) = rest.strip_prefix('[') {
+        let (count, after) = after.split_once(']')?;
+        parameters = count.trim().parse().ok().filter(|count| *count <= 9)?;
+        rest = after.trim_start();
+    }
+    let begin = brace_group(rest)?;
+    rest = rest[begin.len() + 2..].trim_start();
+    let end = brace_group(rest)?;
+    if name.is_empty() {
+        return None;
+    }
+    Some((
+        String::from(name),
+        parameters,
+        String::from(begin),
+        String::from(end),
+        &rest[end.len() + 2..],
+    ))
+}
+
+// content of the brace group at the start of some TeX, without its braces
+fn brace_group(tex: &str) -> Option<&str> {
+    if !tex.starts_with('{') {
+        return None;
+    }
+    let mut depth = 0;
+    let mut escaped = false;
+    for (index, c) in tex.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(&tex[1..index]);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+// the first `\begin{name}` or `\end{name}` in some TeX: its offset, whether
+// it begins the environment, its name, and its length
+fn next_environment(tex: &str) -> Option<(usize, bool, &str, usize)> {
+    let mut position = 0;
+    while let Some(offset) = tex[position..].find('\\') {
+        let start = position + offset;
+        let rest = &tex[start..];
+        for (command, begin) in [("\\begin", true), ("\\end", false)] {
+            if let Some(name) = rest.strip_prefix(command).and_then(brace_group) {
+                return Some((start, begin, name, command.len() + name.len() + 2));
+            }
+        }
+        // skip escaped characters, like `\\`
+        position = start + rest[1..].chars().next().map_or(1, |c| 1 + c.len_utf8());
+    }
+    None
+}
+
+// names of the environments begun in some TeX
+fn environment_names(tex: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut rest = tex;
+    while let Some((offset, begin, name, length)) = next_environment(rest) {
+        if begin && !names.iter().any(|known| known == name) {
+            names.push(String::from(name));
+        }
+        rest = &rest[offset + length..];
+    }
+    names
+}
+
+// control sequences used in some TeX, in order of appearance, without repetition
+pub fn control_sequences(tex: &str) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            continue;
+        }
+        let mut name = String::from("\\");
+        while let Some(&next) = chars.peek() {
+            if !next.is_ascii_alphabetic() {
+                break;
+            }
+            name.push(next);
+            chars.next();
+        }
+        // single-character control sequence, like `\\` or `\{`
+        if name.len() == 1 {
+            if let Some(next) = chars.next() {
+                name.push(next);
+            }
+        }
+        if !names.contains(&name) {
+            names.push(name);
+        }
+    }
+    names
+}
+
+// replace a command, without touching longer commands sharing its prefix
+pub fn replace_command(tex: &str, name: &str, replacement: &str) -> String {
+    let mut result = String::with_capacity(tex.len());
+    let mut rest = tex;
+    while let Some(offset) = rest.find(name) {
+        let after = &rest[offset + name.len()..];
+        result.push_str(&rest[..offset]);
+        if after.starts_with(|c: char| c.is_ascii_alphabetic()) {
+            result.push_str(name);
+        } else {
+            result.push_str(replacement);
+        }
+        rest = after;
+    }
+    result.push_str(rest);
+    result
+}
@@ -1,55 +1,399 @@
-extern crate katex;
 extern crate toml;
 
+use anyhow::Context;
 use clap::{App, Arg, ArgMatches, SubCommand};
 use mdbook::errors::Error;
 use mdbook::preprocess::{CmdPreprocessor, Preprocessor};
-use mdbook_katex::KatexProcessor;
-use std::io;
+use mdbook::renderer::RenderContext;
+use mdbook::{Config, MDBook};
+use mdbook_katex::{
+    analysis, cache, diagnostics, encoding, handout, migrate, pipeline, BookContext,
+    ChapterRenderer, KatexProcessor,
+};
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Component, Path, PathBuf};
 use std::process;
 
 pub fn make_app() -> App<'static, 'static> {
     App::new("mdbook-katex")
         .about("A preprocessor that renders KaTex equations to HTML.")
+        .arg(
+            Arg::with_name("expect-katex-version")
+                .long("expect-katex-version")
+                .takes_value(true)
+                .value_name("version")
+                .help("Fail unless equations are rendered with this version of KaTeX, e.g. 0.12"),
+        )
         .subcommand(
             SubCommand::with_name("supports")
                 .arg(Arg::with_name("renderer").required(true))
                 .about("Check whether a renderer is supported by this preprocessor"),
         )
+        .subcommand(
+            SubCommand::with_name("unsupported")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Report the commands used in a book that KaTeX doesn't support"),
+        )
+        .subcommand(
+            SubCommand::with_name("dollars")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about(
+                    "List the dollar signs of a book, as delimiters of equations, likely \
+                     prices, or in code",
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("migrate")
+                .arg(
+                    Arg::with_name("dir")
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .long("dry-run")
+                        .help("Report the changes without writing them"),
+                )
+                .arg(
+                    Arg::with_name("lossy-utf8")
+                        .long("lossy-utf8")
+                        .help("Convert chapters that aren't UTF-8, replacing invalid bytes"),
+                )
+                .about("Convert a book using MathJax to mdbook-katex"),
+        )
+        .subcommand(
+            SubCommand::with_name("render-chapter")
+                .arg(
+                    Arg::with_name("chapter")
+                        .required(true)
+                        .help("Path of the chapter, e.g. src/ch05.md"),
+                )
+                .arg(
+                    Arg::with_name("book-root")
+                        .long("book-root")
+                        .takes_value(true)
+                        .default_value(".")
+                        .help("Root directory of the book"),
+                )
+                .about("Print a chapter of a book as the preprocessor renders it"),
+        )
+        .subcommand(SubCommand::with_name("handout").about(
+            "Render the labeled equations of a book to a single page, as the renderer of \
+             `[output.katex-handout]`",
+        ))
+        .subcommand(
+            SubCommand::with_name("cache")
+                .about("Save, restore or clear the render cache, e.g. between CI runs")
+                .subcommand(cache_subcommand(
+                    "export",
+                    "Write the render cache to a tar archive",
+                ))
+                .subcommand(cache_subcommand(
+                    "import",
+                    "Restore the render cache from a tar archive",
+                ))
+                .subcommand(
+                    SubCommand::with_name("clear")
+                        .arg(
+                            Arg::with_name("dir")
+                                .default_value(".")
+                                .help("Root directory of the book"),
+                        )
+                        .about("Remove every entry of the render cache"),
+                ),
+        )
+}
+
+fn cache_subcommand(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .arg(
+            Arg::with_name("archive")
+                .required(true)
+                .help("Path of the archive, or - for the standard input or output"),
+        )
+        .arg(
+            Arg::with_name("dir")
+                .default_value(".")
+                .help("Root directory of the book"),
+        )
+        .about(about)
 }
 
 fn main() {
     let matches = make_app().get_matches();
-    let preprocessor = KatexProcessor;
+    let preprocessor = KatexProcessor::default();
+    if let Some(expected) = matches.value_of("expect-katex-version") {
+        if let Err(e) = mdbook_katex::check_katex_version(expected) {
+            diagnostics::error(&e.to_string());
+            process::exit(1);
+        }
+    }
     if let Some(sub_args) = matches.subcommand_matches("supports") {
         handle_supports(&preprocessor, sub_args);
     }
-    if let Err(e) = handle_preprocessing(&preprocessor) {
-        eprintln!("{}", e);
+    let result = match matches.subcommand() {
+        ("unsupported", Some(sub_args)) => handle_unsupported(&preprocessor, sub_args),
+        ("dollars", Some(sub_args)) => handle_dollars(&preprocessor, sub_args),
+        ("migrate", Some(sub_args)) => handle_migrate(&preprocessor, sub_args),
+        ("render-chapter", Some(sub_args)) => handle_render_chapter(&preprocessor, sub_args),
+        ("cache", Some(sub_args)) => handle_cache(sub_args),
+        ("handout", Some(_)) => handle_handout(),
+        _ => handle_preprocessing(&preprocessor),
+    };
+    // report the whole error chain, and fail so that mdbook aborts the build
+    if let Err(e) = result {
+        let mut message = e.to_string();
+        for cause in e.chain().skip(1) {
+            message.push_str(&format!("\n\tCaused by: {}", cause));
+        }
+        diagnostics::error(&message);
+        process::exit(1);
     }
 }
 
-fn handle_preprocessing(pre: &dyn Preprocessor) -> Result<(), Error> {
-    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())?;
+fn handle_preprocessing(pre: &KatexProcessor) -> Result<(), Error> {
+    let (ctx, book) = CmdPreprocessor::parse_input(io::stdin())
+        .context("unable to parse the input from mdbook")?;
 
     if ctx.mdbook_version != mdbook::MDBOOK_VERSION {
-        eprintln!(
-            "Warning: The {} plugin was built against version {} of mdbook, \
+        diagnostics::warn(&format!(
+            "The {} plugin was built against version {} of mdbook, \
              but we're being called from version {}",
             pre.name(),
             mdbook::MDBOOK_VERSION,
             ctx.mdbook_version
-        );
+        ));
+    }
+
+    // render and write chapters one at a time, instead of the whole processed book
+    let renderer = pre
+        .renderer(&ctx)
+        .context("unable to set up the katex preprocessor")?;
+    let mut writer = BufWriter::new(io::stdout());
+    let written = pipeline::write_book(&renderer, book, &mut writer)
+        .and_then(|_| writer.flush().map_err(Error::from));
+    match written {
+        // mdbook stopped reading, e.g. when `mdbook serve` is interrupted
+        Err(e) if is_broken_pipe(&e) => Ok(()),
+        written => written,
+    }
+}
+
+// load a book, reporting chapters that aren't UTF-8 first, since mdbook's own
+// error doesn't say where the invalid bytes are
+fn load_book(dir: &str) -> Result<MDBook, Error> {
+    let invalid = invalid_chapters(dir)?;
+    if !invalid.is_empty() {
+        let messages: Vec<String> = invalid.iter().map(|(_, e)| e.to_string()).collect();
+        return Err(Error::msg(messages.join("\n")));
+    }
+    MDBook::load(dir).with_context(|| format!("unable to load the book in {}", dir))
+}
+
+// configuration of a book, without loading its chapters
+fn load_config(root: &Path) -> Result<Config, Error> {
+    let config_path = root.join("book.toml");
+    if config_path.exists() {
+        Config::from_disk(&config_path)
+    } else {
+        Ok(Config::default())
     }
+}
+
+fn invalid_chapters(dir: &str) -> Result<Vec<(PathBuf, Error)>, Error> {
+    let root = Path::new(dir);
+    let config = load_config(root)?;
+    encoding::find_invalid(&root.join(&config.book.src))
+}
+
+// the renderer the html output of a book would use
+fn html_renderer(pre: &KatexProcessor, book: &MDBook) -> Result<ChapterRenderer, Error> {
+    pre.renderer_for(&BookContext {
+        root: &book.root,
+        config: &book.config,
+        renderer: "html",
+    })
+    .context("unable to set up the katex preprocessor")
+}
 
-    let processed_book = pre.run(&ctx, book)?;
-    serde_json::to_writer(io::stdout(), &processed_book)?;
+// why commands unsupported by katex can't be found, in builds without it:
+// every equation would fail without naming a command
+#[cfg(feature = "katex")]
+fn katex_missing() -> Option<&'static str> {
+    None
+}
+
+#[cfg(not(feature = "katex"))]
+fn katex_missing() -> Option<&'static str> {
+    Some(mdbook_katex::engine::Engine::MISSING)
+}
+
+fn handle_unsupported(pre: &KatexProcessor, sub_args: &ArgMatches) -> Result<(), Error> {
+    if let Some(missing) = katex_missing() {
+        return Err(Error::msg(format!(
+            "{}, unable to look for unsupported commands",
+            missing
+        )));
+    }
+    let dir = sub_args.value_of("dir").expect("Default argument");
+    let book = load_book(dir)?;
+    let renderer = html_renderer(pre, &book)?;
+    print!("{}", analysis::analyze_book(&renderer, &book.book));
+    Ok(())
+}
+
+fn handle_dollars(pre: &KatexProcessor, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Default argument");
+    let book = load_book(dir)?;
+    let renderer = html_renderer(pre, &book)?;
+    print!("{}", analysis::catalog_dollars(&renderer, &book.book));
+    Ok(())
+}
+
+fn handle_render_chapter(pre: &KatexProcessor, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("book-root").expect("Default argument");
+    let chapter = Path::new(sub_args.value_of("chapter").expect("Required argument"));
+    let book = load_book(dir)?;
+    let renderer = html_renderer(pre, &book)?;
+    let path = chapter_path(&book, chapter);
+    let content = pipeline::render_chapter(&renderer, book.book, &path)?;
+    print!("{}", content);
     Ok(())
 }
 
+// the path of a chapter in the source directory of a book, from a path
+// relative to the source directory, the root of the book, or the current one
+fn chapter_path(book: &MDBook, chapter: &Path) -> PathBuf {
+    let chapter = without_current_dir(chapter);
+    let prefixes = [
+        without_current_dir(&book.source_dir()),
+        without_current_dir(&book.config.book.src),
+    ];
+    prefixes
+        .iter()
+        .find_map(|prefix| chapter.strip_prefix(prefix).ok())
+        .map_or_else(|| chapter.clone(), Path::to_path_buf)
+}
+
+// a path without its `./` components, as in `./src/ch05.md`
+fn without_current_dir(path: &Path) -> PathBuf {
+    path.components()
+        .filter(|component| *component != Component::CurDir)
+        .collect()
+}
+
+// mdbook runs renderers with the render context on the standard input
+fn handle_handout() -> Result<(), Error> {
+    let ctx = RenderContext::from_json(io::stdin()).context("unable to read the book")?;
+    handout::render(&ctx)
+}
+
+fn handle_migrate(pre: &KatexProcessor, sub_args: &ArgMatches) -> Result<(), Error> {
+    let dir = sub_args.value_of("dir").expect("Default argument");
+    let dry_run = sub_args.is_present("dry-run");
+    if sub_args.is_present("lossy-utf8") {
+        let invalid = invalid_chapters(dir)?;
+        for (path, _) in &invalid {
+            println!("converted {} to UTF-8", path.display());
+            if !dry_run {
+                let content = encoding::read_to_string(path, true)?;
+                fs::write(path, content)
+                    .with_context(|| format!("unable to write {}", path.display()))?;
+            }
+        }
+        // the book can't be loaded until chapters are converted
+        if dry_run && !invalid.is_empty() {
+            return Ok(());
+        }
+    }
+    let book = load_book(dir)?;
+    let migration = migrate::migrate_book(&book)?;
+    for change in &migration.changes {
+        println!("{}", change);
+    }
+    for warning in &migration.warnings {
+        diagnostics::warn(warning);
+    }
+    match katex_missing() {
+        Some(missing) => diagnostics::warn(&format!(
+            "{}, unable to look for unsupported commands",
+            missing
+        )),
+        None => {
+            let renderer = html_renderer(pre, &book)?;
+            print!("{}", migrate::unsupported(&book, &migration, &renderer));
+        }
+    }
+    if dry_run {
+        return Ok(());
+    }
+    migrate::apply(&migration)
+}
+
+fn handle_cache(sub_args: &ArgMatches) -> Result<(), Error> {
+    let (command, args) = match sub_args.subcommand() {
+        (command, Some(args)) => (command, args),
+        _ => {
+            return Err(Error::msg(
+                "expected `cache export`, `cache import` or `cache clear`",
+            ))
+        }
+    };
+    let root = Path::new(args.value_of("dir").expect("Default argument"));
+    let config = load_config(root)?;
+    let dir = KatexProcessor::cache_dir(&BookContext {
+        root,
+        config: &config,
+        renderer: "html",
+    });
+    if command == "clear" {
+        let count = cache::clear(&dir)?;
+        eprintln!("removed {} cache entries from {}", count, dir.display());
+        return Ok(());
+    }
+    let archive = args.value_of("archive").expect("Required argument");
+    if command == "export" {
+        let count = if archive == "-" {
+            cache::export(&dir, &mut BufWriter::new(io::stdout()))?
+        } else {
+            let file = fs::File::create(archive)
+                .with_context(|| format!("unable to create {}", archive))?;
+            cache::export(&dir, &mut BufWriter::new(file))?
+        };
+        eprintln!("exported {} cache entries from {}", count, dir.display());
+    } else {
+        let count = if archive == "-" {
+            cache::import(&dir, &mut io::stdin().lock())?
+        } else {
+            let file =
+                fs::File::open(archive).with_context(|| format!("unable to open {}", archive))?;
+            cache::import(&dir, &mut io::BufReader::new(file))?
+        };
+        eprintln!("imported {} cache entries into {}", count, dir.display());
+    }
+    Ok(())
+}
+
+fn is_broken_pipe(error: &Error) -> bool {
+    error.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|e| e.kind() == io::ErrorKind::BrokenPipe)
+    })
+}
+
 fn handle_supports(pre: &dyn Preprocessor, sub_args: &ArgMatches) -> ! {
     let renderer = sub_args.value_of("renderer").expect("Required argument");
-    let supported = pre.supports_renderer(&renderer);
+    let supported = pre.supports_renderer(renderer);
     if supported {
         process::exit(0);
     } else {
@@ -0,0 +1,121 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use mdbook::errors::Error;
+use serde::Serialize;
+
+use crate::reading::Reading;
+use crate::{ChapterStats, RenderError};
+
+// a chapter of the manifest
+#[derive(Debug, Serialize)]
+pub struct ChapterEntry {
+    pub name: String,
+    // source file of the chapter, relative to the source directory
+    pub path: Option<PathBuf>,
+    pub rendered: bool,
+    pub cached: bool,
+    pub equations: usize,
+    pub failures: Vec<RenderError>,
+    // equations rendered with the default options after failing
+    pub fallbacks: Vec<RenderError>,
+    // with `reading-time`
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub reading: Option<Reading>,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct CacheStats {
+    pub hits: usize,
+    pub misses: usize,
+}
+
+// what a build rendered, written as JSON with `manifest = "..."`, so that
+// documentation pipelines can track equations and failures over time
+#[derive(Debug, Serialize)]
+pub struct Manifest {
+    pub renderer: String,
+    pub chapters: Vec<ChapterEntry>,
+    // `None` when chapters aren't cached
+    pub cache: Option<CacheStats>,
+}
+
+impl Manifest {
+    pub fn new(renderer: &str, caching: bool) -> Self {
+        Manifest {
+            renderer: String::from(renderer),
+            chapters: Vec::new(),
+            cache: if caching {
+                Some(CacheStats::default())
+            } else {
+                None
+            },
+        }
+    }
+
+    pub fn add(&mut self, name: &str, path: Option<&Path>, stats: ChapterStats) {
+        if let (Some(cache), true) = (&mut self.cache, stats.rendered) {
+            if stats.cached {
+                cache.hits += 1;
+            } else {
+                cache.misses += 1;
+            }
+        }
+        self.chapters.push(ChapterEntry {
+            name: String::from(name),
+            path: path.map(Path::to_path_buf),
+            rendered: stats.rendered,
+            cached: stats.cached,
+            equations: stats.equations,
+            failures: stats.failures,
+            fallbacks: stats.fallbacks,
+            reading: stats.reading,
+        });
+    }
+
+    // equations of the book, including those of chapters from the cache
+    pub fn equations(&self) -> usize {
+        self.chapters.iter().map(|chapter| chapter.equations).sum()
+    }
+
+    pub fn failures(&self) -> usize {
+        self.chapters
+            .iter()
+            .map(|chapter| chapter.failures.len())
+            .sum()
+    }
+
+    // reading time of the book, with `reading-time`
+    pub fn reading_minutes(&self) -> Option<f64> {
+        let minutes: Vec<f64> = self
+            .chapters
+            .iter()
+            .filter_map(|chapter| chapter.reading.as_ref())
+            .map(|reading| reading.reading_minutes)
+            .collect();
+        (!minutes.is_empty()).then(|| (minutes.iter().sum::<f64>() * 100.0).round() / 100.0)
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::json!({
+            "equations": self.equations(),
+            "failures": self.failures(),
+        });
+        if let Some(minutes) = self.reading_minutes() {
+            json["reading_minutes"] = serde_json::json!(minutes);
+        }
+        if let serde_json::Value::Object(fields) = serde_json::json!(self) {
+            json.as_object_mut().unwrap().extend(fields);
+        }
+        json
+    }
+
+    pub fn write(&self, path: &Path) -> Result<(), Error> {
+        let json = serde_json::to_string_pretty(&self.to_json())?;
+        path.parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::write(path, json + "\n"))
+            .with_context(|| format!("unable to write the manifest to {}", path.display()))
+    }
+}
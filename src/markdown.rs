@@ -0,0 +1,269 @@
+use std::borrow::Cow;
+
+// width of the indentation of a line, with tabs stopping at multiples of four
+fn indentation(line: &str) -> usize {
+    let mut width = 0;
+    for c in line.chars() {
+        match c {
+            ' ' => width += 1,
+            '\t' => width += 4 - width % 4,
+            _ => break,
+        }
+    }
+    width
+}
+
+// where the content of a list item starts, as in `- item` or `1. item`
+fn list_item_content(line: &str) -> Option<usize> {
+    let rest = line.trim_start_matches([' ', '\t']);
+    let marker = if rest.starts_with(['-', '*', '+']) {
+        1
+    } else {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 || digits > 9 || !rest[digits..].starts_with(['.', ')']) {
+            return None;
+        }
+        digits + 1
+    };
+    let after = &rest[marker..];
+    let spaces = after.len() - after.trim_start_matches(' ').len();
+    if spaces == 0 && !after.trim().is_empty() {
+        return None;
+    }
+    // with more than four spaces, the content is an indented code block, and
+    // starts one space after the marker
+    let spaces = if spaces == 0 || spaces > 4 { 1 } else { spaces };
+    Some(indentation(line) + marker + spaces)
+}
+
+// a display equation starting a line indented by four spaces or more past
+// the content of its list item, or with no list item, would end up in a code
+// block once rendered: reindent it to the content of its list item
+pub fn reindent_display(output: &mut String) {
+    let line_start = output.rfind('\n').map_or(0, |index| index + 1);
+    let prefix = &output[line_start..];
+    if !prefix.chars().all(|c| c == ' ' || c == '\t') {
+        return;
+    }
+    let indent = indentation(prefix);
+    if indent < 4 {
+        return;
+    }
+    // the innermost list item the equation can be part of
+    let mut content = 0;
+    for line in output[..line_start].lines().rev() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let line_indent = indentation(line);
+        if let (Some(item), true) = (list_item_content(line), line_indent < indent) {
+            content = item;
+            break;
+        }
+        // a top-level paragraph, outside of any list
+        if line_indent == 0 {
+            break;
+        }
+    }
+    if indent >= content + 4 {
+        output.truncate(line_start);
+        output.push_str(&" ".repeat(content));
+    }
+}
+
+// value of `key` in the front matter of a chapter, between `---` lines as in
+// `katex: off`, or `+++` lines as in `katex = false`, along with the chapter
+// without that line; the front matter goes away if nothing else is left in it
+pub fn take_front_matter_option<'a>(content: &'a str, key: &str) -> (Option<String>, Cow<'a, str>) {
+    let fence = match content.split_inclusive('\n').next() {
+        Some(line) if matches!(line.trim_end(), "---" | "+++") => line.trim_end(),
+        _ => return (None, Cow::Borrowed(content)),
+    };
+    let mut offset = 0;
+    let mut option: Option<(String, std::ops::Range<usize>)> = None;
+    let mut others = false;
+    for (index, line) in content.split_inclusive('\n').enumerate() {
+        let range = offset..offset + line.len();
+        offset = range.end;
+        if index == 0 {
+            continue;
+        }
+        if line.trim_end() == fence {
+            let Some((value, line)) = option else {
+                return (None, Cow::Borrowed(content));
+            };
+            if others {
+                let without = format!("{}{}", &content[..line.start], &content[line.end..]);
+                return (Some(value), Cow::Owned(without));
+            }
+            return (Some(value), Cow::Borrowed(&content[range.end..]));
+        }
+        let value = line
+            .strip_prefix(key)
+            .map(str::trim_start)
+            .and_then(|rest| rest.strip_prefix([':', '=']));
+        match value {
+            Some(value) if option.is_none() => {
+                let value = value.trim().trim_matches(['"', '\'']);
+                option = Some((String::from(value), range));
+            }
+            _ if !line.trim().is_empty() => others = true,
+            _ => {}
+        }
+    }
+    // no closing fence: not front matter
+    (None, Cow::Borrowed(content))
+}
+
+// content of a `<!-- name: ... -->` comment at the top of a chapter, before
+// anything but blank lines, along with the chapter without it
+pub fn take_directive<'a>(content: &'a str, name: &str) -> Option<(&'a str, &'a str)> {
+    let start = content.len() - content.trim_start().len();
+    let rest = content[start..].strip_prefix("<!--")?.trim_start();
+    let rest = rest.strip_prefix(name)?.strip_prefix(':')?;
+    let end = rest.find("-->")?;
+    let after = &rest[end + "-->".len()..];
+    // the line break after the comment goes with it
+    let after = after
+        .strip_prefix("\r\n")
+        .or_else(|| after.strip_prefix('\n'))
+        .unwrap_or(after);
+    Some((rest[..end].trim(), after))
+}
+
+// plain text of a chapter, for descriptions: headings, code blocks, HTML and
+// images are left out, and links keep their text
+pub struct PlainText {
+    text: String,
+    in_fence: bool,
+    // the rest of the current line is left out
+    skip_line: bool,
+    at_line_start: bool,
+}
+
+impl Default for PlainText {
+    fn default() -> Self {
+        PlainText {
+            text: String::new(),
+            in_fence: false,
+            skip_line: false,
+            at_line_start: true,
+        }
+    }
+}
+
+impl PlainText {
+    pub fn push_markdown(&mut self, markdown: &str) {
+        for full_line in markdown.split_inclusive('\n') {
+            let mut line = full_line;
+            if self.at_line_start {
+                let trimmed = line.trim_start();
+                if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+                    self.in_fence = !self.in_fence;
+                    self.skip_line = true;
+                } else {
+                    self.skip_line = self.in_fence
+                        || trimmed.starts_with(['#', '<', '|'])
+                        || trimmed.starts_with("{{#");
+                }
+                line = strip_block_markers(trimmed);
+            }
+            if !self.skip_line {
+                push_inline(&mut self.text, line);
+            }
+            self.at_line_start = full_line.ends_with('\n');
+            if self.at_line_start {
+                self.text.push(' ');
+            }
+        }
+    }
+
+    // text that isn't markdown, like approximations of equations
+    pub fn push_plain(&mut self, text: &str) {
+        if !self.skip_line {
+            self.text.push_str(text);
+        }
+        self.at_line_start = false;
+    }
+
+    // the start of the text, cut at a word boundary
+    pub fn excerpt(&self, max_length: usize) -> String {
+        let text = self.text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.chars().count() <= max_length {
+            return text;
+        }
+        let cut: String = text.chars().take(max_length).collect();
+        let cut = match cut.rfind(' ') {
+            Some(space) => &cut[..space],
+            None => &cut[..],
+        };
+        format!("{}…", cut.trim_end_matches([',', ';', ':', '.']))
+    }
+}
+
+// without quote and list markers
+fn strip_block_markers(line: &str) -> &str {
+    let mut line = line;
+    loop {
+        let stripped = line.trim_start();
+        let stripped = if let Some(rest) = stripped.strip_prefix('>') {
+            rest
+        } else if let Some(rest) = stripped
+            .strip_prefix(['-', '*', '+'])
+            .filter(|rest| rest.starts_with(' '))
+        {
+            rest
+        } else {
+            let digits = stripped.chars().take_while(|c| c.is_ascii_digit()).count();
+            match stripped[digits..].strip_prefix(['.', ')']) {
+                Some(rest) if digits > 0 && rest.starts_with(' ') => rest,
+                _ => return stripped,
+            }
+        };
+        line = stripped;
+    }
+}
+
+// text of a line without emphasis, code, link and HTML markup
+fn push_inline(text: &mut String, line: &str) {
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '`' | '~' => {}
+            // images are left out, along with their description
+            '!' if chars.peek() == Some(&'[') => {
+                for c in chars.by_ref() {
+                    if c == ')' {
+                        break;
+                    }
+                }
+            }
+            '[' => {}
+            // the destination of a link
+            ']' if chars.peek() == Some(&'(') => {
+                for c in chars.by_ref() {
+                    if c == ')' {
+                        break;
+                    }
+                }
+            }
+            ']' => {}
+            '<' if chars
+                .peek()
+                .is_some_and(|c| c.is_ascii_alphabetic() || *c == '/' || *c == '!') =>
+            {
+                for c in chars.by_ref() {
+                    if c == '>' {
+                        break;
+                    }
+                }
+            }
+            '\\' => {
+                if let Some(next) = chars.next() {
+                    text.push(next);
+                }
+            }
+            c => text.push(c),
+        }
+    }
+}
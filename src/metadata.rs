@@ -0,0 +1,115 @@
+// math settings shared with other tools, like a site generator rendering the
+// same equations, outside of `[preprocessor.katex]`
+//
+// They're in the `math` table of `[book.metadata]` in book.toml, or of the
+// TOML file `metadata = "..."` names, with the names of the options of
+// `[preprocessor.katex]`; `macros` is either macro files or a table of macro
+// definitions. Options set in both places take the value of the more specific
+// one: `[preprocessor.katex]` over the metadata file, over `[book.metadata]`.
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Error};
+use toml::value::{Table, Value};
+
+use crate::macros::Location;
+use crate::KatexConfig;
+
+// what the metadata of a book sets
+#[derive(Debug, Default)]
+pub struct Metadata {
+    // options, as if set in `[preprocessor.katex]`
+    pub options: Table,
+    // macros defined in a `macros` table, in the order of their sources
+    pub macros: Vec<(String, String, Location)>,
+}
+
+impl Metadata {
+    pub fn is_empty(&self) -> bool {
+        self.options.is_empty() && self.macros.is_empty()
+    }
+
+    // add the options of a `math` table, over those already there
+    fn add(&mut self, mut math: Table, source: &Path) -> Result<(), Error> {
+        // macros defined in place, rather than in files
+        if let Some(Value::Table(macros)) = math.get("macros") {
+            for (line, (name, expansion)) in macros.iter().enumerate() {
+                let expansion = expansion.as_str().ok_or_else(|| {
+                    Error::msg(format!(
+                        "invalid macro `{}` in the `math` metadata of {}, expected a string",
+                        name,
+                        source.display()
+                    ))
+                })?;
+                let location = Location {
+                    path: source.to_path_buf(),
+                    line: line + 1,
+                };
+                self.macros
+                    .push((name.clone(), String::from(expansion), location));
+            }
+            math.remove("macros");
+        }
+        // a metadata file doesn't name another one
+        math.remove("metadata");
+        KatexConfig::from_table(Some(&math))
+            .with_context(|| format!("invalid `math` metadata in {}", source.display()))?;
+        self.options.extend(math);
+        Ok(())
+    }
+}
+
+// the metadata of the book at `root`, with `[preprocessor.katex]` for the path
+// of the metadata file
+pub fn load(root: &Path, options: Option<&Table>) -> Result<Metadata, Error> {
+    let mut metadata = Metadata::default();
+    // mdbook drops `[book.metadata]`, so it's read from book.toml again
+    let book_toml = root.join("book.toml");
+    if let Some(math) = math_table(&book_toml, &["book", "metadata", "math"])? {
+        metadata.add(math, &book_toml)?;
+    }
+    let file = options
+        .and_then(|options| options.get("metadata"))
+        .and_then(Value::as_str);
+    if let Some(file) = file {
+        let path = root.join(file);
+        if !path.exists() {
+            return Err(Error::msg(format!(
+                "the metadata file {} doesn't exist",
+                path.display()
+            )));
+        }
+        if let Some(math) = math_table(&path, &["math"])? {
+            metadata.add(math, &path)?;
+        }
+    }
+    Ok(metadata)
+}
+
+// the table at `keys` in a TOML file, if the file and the table exist
+fn math_table(path: &Path, keys: &[&str]) -> Result<Option<Table>, Error> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let content =
+        fs::read_to_string(path).with_context(|| format!("unable to read {}", path.display()))?;
+    let mut value: Value =
+        toml::from_str(&content).with_context(|| format!("unable to parse {}", path.display()))?;
+    for key in keys {
+        value = match value {
+            Value::Table(mut table) => match table.remove(*key) {
+                Some(value) => value,
+                None => return Ok(None),
+            },
+            _ => return Ok(None),
+        };
+    }
+    match value {
+        Value::Table(table) => Ok(Some(table)),
+        _ => Err(Error::msg(format!(
+            "invalid `{}` in {}, expected a table",
+            keys.join("."),
+            path.display()
+        ))),
+    }
+}
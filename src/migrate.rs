@@ -0,0 +1,354 @@
+use std::fs;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use mdbook::book::{BookItem, Chapter};
+use mdbook::errors::Error;
+use mdbook::MDBook;
+
+use crate::analysis::{self, Report};
+use crate::{tex, ChapterRenderer};
+
+// delimiters of mdbook's mathjax support, written with escaped backslashes in
+// markdown, and whether they are for display equations
+const MATHJAX_DELIMITERS: &[(&str, &str, bool)] = &[
+    (r"\\[", r"\\]", true),
+    (r"\\(", r"\\)", false),
+    ("$$", "$$", true),
+];
+
+// theme files where mathjax is usually loaded or configured
+const THEME_FILES: &[&str] = &["head.hbs", "index.hbs"];
+
+// files to rewrite for a book to use mdbook-katex, and what's left to do by hand
+#[derive(Debug, Default)]
+pub struct Migration {
+    // rewritten files and their new content
+    pub files: Vec<(PathBuf, String)>,
+    // changes made to the book
+    pub changes: Vec<String>,
+    // constructs that couldn't be migrated
+    pub warnings: Vec<String>,
+}
+
+// plan the migration of a book using mathjax, without writing anything
+pub fn migrate_book(book: &MDBook) -> Result<Migration, Error> {
+    let mut migration = Migration::default();
+
+    let config_path = book.root.join("book.toml");
+    let config = fs::read_to_string(&config_path)
+        .with_context(|| format!("unable to read {}", config_path.display()))?;
+    let (new_config, changes) = migrate_config(&config);
+    if new_config != config {
+        migration.files.push((config_path, new_config));
+    }
+    migration.changes.extend(changes);
+
+    for additional in book
+        .config
+        .html_config()
+        .into_iter()
+        .flat_map(|html| html.additional_js)
+    {
+        if additional
+            .to_string_lossy()
+            .to_lowercase()
+            .contains("mathjax")
+        {
+            migration.warnings.push(format!(
+                "`{}` in `additional-js` looks like MathJax configuration, \
+                 remove it and move its macros to a macros file",
+                additional.display()
+            ));
+        }
+    }
+
+    let theme_dir = book.root.join("theme");
+    for name in THEME_FILES {
+        let path = theme_dir.join(name);
+        if let Ok(content) = fs::read_to_string(&path) {
+            if content.contains("MathJax") {
+                migration.warnings.push(format!(
+                    "{} loads or configures MathJax, remove it and move its \
+                     macros to a macros file",
+                    path.display()
+                ));
+            }
+        }
+    }
+
+    let src_dir = book.root.join(&book.config.book.src);
+    for item in book.iter() {
+        let path = match item {
+            BookItem::Chapter(chapter) => match &chapter.path {
+                Some(path) => src_dir.join(path),
+                None => continue,
+            },
+            _ => continue,
+        };
+        let content = fs::read_to_string(&path)
+            .with_context(|| format!("unable to read {}", path.display()))?;
+        let (new_content, equations) = convert_delimiters(&content);
+        if new_content != content {
+            migration.changes.push(format!(
+                "converted {} equations in {}",
+                equations,
+                path.display()
+            ));
+            migration.files.push((path, new_content));
+        }
+    }
+    Ok(migration)
+}
+
+// disable mathjax support, and enable this preprocessor
+pub fn migrate_config(config: &str) -> (String, Vec<String>) {
+    let mut changes = Vec::new();
+    let mut result = String::with_capacity(config.len());
+    for line in config.split_inclusive('\n') {
+        let key = line.split('=').next().unwrap_or_default().trim();
+        if key == "mathjax-support" {
+            changes.push(String::from("removed `mathjax-support` from book.toml"));
+            continue;
+        }
+        result.push_str(line);
+    }
+    if !config
+        .lines()
+        .any(|line| line.trim() == "[preprocessor.katex]")
+    {
+        if !result.is_empty() && !result.ends_with('\n') {
+            result.push('\n');
+        }
+        result.push_str("\n[preprocessor.katex]\n");
+        changes.push(String::from("added `[preprocessor.katex]` to book.toml"));
+    }
+    (result, changes)
+}
+
+// convert mathjax equations to `$` and `$$` delimiters, and escape dollar
+// signs in text, outside of code and html blocks; returns the content and the
+// number of equations converted
+pub fn convert_delimiters(content: &str) -> (String, usize) {
+    let mut result = String::with_capacity(content.len());
+    let mut equations = 0;
+    let mut text = String::new();
+    // the block copied as is, if any, and whether the previous line was blank
+    let mut block: Option<Block> = None;
+    let mut after_blank = true;
+    for line in content.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        let blank = trimmed.is_empty();
+        if let Some(current) = &block {
+            let ends = match current {
+                Block::Fence(marker) => trimmed.starts_with(marker.as_str()),
+                Block::Html(Some(close)) => line.contains(close.as_str()),
+                Block::Html(None) => blank,
+                Block::Indented => !blank && !is_indented(line),
+            };
+            match (current, ends) {
+                // an indented block ends before the first line of text after it
+                (Block::Indented, true) => block = None,
+                (_, true) => {
+                    block = None;
+                    result.push_str(line);
+                    after_blank = blank;
+                    continue;
+                }
+                (_, false) => {
+                    result.push_str(line);
+                    after_blank = blank;
+                    continue;
+                }
+            }
+        }
+        let opened = if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            Some(Block::Fence(String::from(&trimmed[..3])))
+        } else if after_blank && is_indented(line) && !blank {
+            Some(Block::Indented)
+        } else if after_blank {
+            html_block(trimmed).map(Block::Html)
+        } else {
+            None
+        };
+        match opened {
+            Some(opened) => {
+                equations += convert_text(&text, &mut result);
+                text.clear();
+                // an html block closed on its first line doesn't go on
+                let closed = match &opened {
+                    Block::Html(Some(close)) => trimmed[1..].contains(close.as_str()),
+                    _ => false,
+                };
+                if !closed {
+                    block = Some(opened);
+                }
+                result.push_str(line);
+            }
+            None => text.push_str(line),
+        }
+        after_blank = blank;
+    }
+    equations += convert_text(&text, &mut result);
+    (result, equations)
+}
+
+// a block of a chapter whose lines are copied as they are
+enum Block {
+    // a fenced code block, with its fence
+    Fence(String),
+    // an indented code block
+    Indented,
+    // an html block, with the text ending it, or ending at a blank line
+    Html(Option<String>),
+}
+
+fn is_indented(line: &str) -> bool {
+    line.starts_with("    ") || line.starts_with('\t')
+}
+
+// the start of an html block, as markdown has it, with the text it ends with
+// if it doesn't end at a blank line
+fn html_block(trimmed: &str) -> Option<Option<String>> {
+    let tag = trimmed.strip_prefix('<')?;
+    if tag.starts_with("!--") {
+        return Some(Some(String::from("-->")));
+    }
+    for name in ["pre", "script", "style", "textarea"] {
+        if tag.starts_with(name) {
+            return Some(Some(format!("</{}>", name)));
+        }
+    }
+    let name: String = tag
+        .trim_start_matches('/')
+        .chars()
+        .take_while(char::is_ascii_alphanumeric)
+        .collect::<String>()
+        .to_ascii_lowercase();
+    if name.is_empty() {
+        return None;
+    }
+    // other tags only start a block on a line of their own
+    let alone = !tag.contains('<') && trimmed.trim_end().ends_with('>');
+    (HTML_BLOCK_TAGS.contains(&name.as_str()) || alone).then_some(None)
+}
+
+// tags starting an html block even with text after them
+const HTML_BLOCK_TAGS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "details",
+    "dialog",
+    "div",
+    "dl",
+    "fieldset",
+    "figcaption",
+    "figure",
+    "footer",
+    "form",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "li",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "section",
+    "summary",
+    "table",
+    "tbody",
+    "td",
+    "th",
+    "thead",
+    "tr",
+    "ul",
+];
+
+// convert the equations in some text without code blocks
+fn convert_text(text: &str, result: &mut String) -> usize {
+    let mut equations = 0;
+    let mut position = 0;
+    'outer: while let Some(c) = text[position..].chars().next() {
+        let rest = &text[position..];
+        // code spans are kept as they are
+        if c == '`' {
+            let ticks = rest.len() - rest.trim_start_matches('`').len();
+            let marker = &rest[..ticks];
+            if let Some(end) = rest[ticks..].find(marker) {
+                let end = ticks + end + ticks;
+                result.push_str(&rest[..end]);
+                position += end;
+                continue;
+            }
+        }
+        for (open, close, display) in MATHJAX_DELIMITERS {
+            if !rest.starts_with(open) {
+                continue;
+            }
+            if let Some(end) = rest[open.len()..].find(close) {
+                let body = &rest[open.len()..open.len() + end];
+                let delimiter = if *display { "$$" } else { "$" };
+                result.push_str(delimiter);
+                result.push_str(&tex::unescape_markdown(body));
+                result.push_str(delimiter);
+                equations += 1;
+                position += open.len() + end + close.len();
+                continue 'outer;
+            }
+        }
+        // escapes, like dollar signs already escaped, are kept as they are
+        if c == '\\' {
+            if let Some(escaped) = rest[1..].chars().next() {
+                result.push_str(&rest[..1 + escaped.len_utf8()]);
+                position += 1 + escaped.len_utf8();
+                continue;
+            }
+        }
+        // a dollar sign that mathjax wouldn't have taken for a delimiter
+        if c == '$' {
+            result.push('\\');
+        }
+        result.push(c);
+        position += c.len_utf8();
+    }
+    equations
+}
+
+// write the rewritten files of a migration
+pub fn apply(migration: &Migration) -> Result<(), Error> {
+    for (path, content) in &migration.files {
+        fs::write(path, content).with_context(|| format!("unable to write {}", path.display()))?;
+    }
+    Ok(())
+}
+
+// commands katex doesn't support in the chapters, as rewritten by a migration
+pub fn unsupported(book: &MDBook, migration: &Migration, renderer: &ChapterRenderer) -> Report {
+    let src_dir = book.root.join(&book.config.book.src);
+    let mut report = Report::default();
+    for item in book.iter() {
+        if let BookItem::Chapter(Chapter {
+            path: Some(path),
+            content,
+            ..
+        }) = item
+        {
+            let full_path = src_dir.join(path);
+            let content = migration
+                .files
+                .iter()
+                .find(|(file, _)| *file == full_path)
+                .map_or(content, |(_, migrated)| migrated);
+            analysis::analyze_chapter(renderer, content, &path.display().to_string(), &mut report);
+        }
+    }
+    report
+}
@@ -0,0 +1,296 @@
+// numbers of display equations, and the `\eqref{...}` and `\ref{...}`
+// referring to them by their `\label{...}`, across the chapters of a book
+//
+// Chapters are rewritten before they're rendered: numbered equations get a
+// `\tag{...}` and an anchor, and references become links to them.
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Error;
+use mdbook::book::{Book, Chapter};
+use mdbook::utils::fs::path_to_root;
+
+use crate::html::escape_attribute;
+use crate::scan::{offset, Span};
+use crate::tex::{self, without_labels, UNNUMBERED};
+use crate::{diagnostics, pipeline, ChapterRenderer};
+
+// how equations are numbered, with `numbering = "..."`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Numbering {
+    // from 1 in each chapter, after the chapter number, as in `(2.3.1)`
+    Chapter,
+    // from 1 to the end of the book
+    Book,
+}
+
+impl Numbering {
+    pub fn from_config(value: &str) -> Result<Self, Error> {
+        match value {
+            "chapter" => Ok(Numbering::Chapter),
+            "book" => Ok(Numbering::Book),
+            other => Err(Error::msg(format!(
+                "unknown value `{}`, expected \"chapter\" or \"book\"",
+                other
+            ))),
+        }
+    }
+}
+
+// commands referring to a labeled equation, by number
+const REFERENCES: &[&str] = &["\\eqref{", "\\ref{"];
+
+// an equation a label refers to
+struct Target {
+    number: String,
+    chapter: String,
+    path: Option<PathBuf>,
+}
+
+// the argument of the first `command{...}` in some tex
+fn argument<'a>(tex: &'a str, command: &str) -> Option<&'a str> {
+    let start = tex.find(command)? + command.len();
+    let end = start + tex[start..].find('}')?;
+    Some(tex[start..end].trim())
+}
+
+// the tag an equation gives itself with `\tag{...}`
+fn own_tag(tex: &str) -> Option<&str> {
+    argument(tex, "\\tag*{").or_else(|| argument(tex, "\\tag{"))
+}
+
+// the numbers given to the display equations of a book, and what its labels
+// refer to
+struct Plan {
+    // for each chapter in reading order, the number added to each of its
+    // display equations, if any; `None` for chapters that aren't rendered
+    chapters: Vec<Option<Vec<Option<String>>>>,
+    labels: HashMap<String, Target>,
+}
+
+// labels are unique, since they give the anchors of their equations: links to
+// them from elsewhere keep working when equations or chapters are reordered
+fn plan(renderer: &ChapterRenderer, numbering: Numbering, book: &Book) -> Result<Plan, Error> {
+    let mut plan = Plan {
+        chapters: Vec::new(),
+        labels: HashMap::new(),
+    };
+    let mut count = 0;
+    let rendered = pipeline::rendered_chapters(renderer, book);
+    for (chapter, rendered) in pipeline::reading_order(&book.sections)
+        .into_iter()
+        .zip(rendered)
+    {
+        // chapters that opted out of rendering are left as they are
+        if !rendered {
+            plan.chapters.push(None);
+            continue;
+        }
+        if numbering == Numbering::Chapter {
+            count = 0;
+        }
+        let mut numbers = Vec::new();
+        for span in renderer.scanner.scan(&chapter.content, &mut |_| {}) {
+            let tex = match span {
+                Span::Math {
+                    tex, display: true, ..
+                } => tex,
+                _ => continue,
+            };
+            let unnumbered = UNNUMBERED.iter().any(|command| tex.contains(command))
+                || tex::starred_environment(tex);
+            let number = match own_tag(tex) {
+                Some(tag) => Some(String::from(tag)),
+                None if unnumbered => None,
+                None => {
+                    count += 1;
+                    Some(match (&chapter.number, numbering) {
+                        (Some(section), Numbering::Chapter) => format!("{}{}", section, count),
+                        _ => count.to_string(),
+                    })
+                }
+            };
+            if let (Some(label), Some(number)) = (argument(tex, "\\label{"), &number) {
+                if let Some(target) = plan.labels.get(label) {
+                    return Err(Error::msg(format!(
+                        "label `{}` of `{}` is already used in `{}`, labels must be unique",
+                        label, chapter.name, target.chapter
+                    )));
+                }
+                let target = Target {
+                    number: number.clone(),
+                    chapter: chapter.name.clone(),
+                    path: chapter.path.clone(),
+                };
+                plan.labels.insert(String::from(label), target);
+            }
+            numbers.push(number.filter(|_| own_tag(tex).is_none()));
+        }
+        plan.chapters.push(Some(numbers));
+    }
+    Ok(plan)
+}
+
+// number the display equations of a book, and resolve references to them
+pub fn number_equations(
+    renderer: &ChapterRenderer,
+    numbering: Numbering,
+    book: &mut Book,
+) -> Result<(), Error> {
+    let plan = plan(renderer, numbering, book)?;
+    let mut chapters = plan.chapters.iter();
+    pipeline::visit_chapters(&mut book.sections, &mut |chapter| {
+        let numbers = match chapters.next() {
+            Some(Some(numbers)) => numbers,
+            _ => return,
+        };
+        let rewritten = rewrite(renderer, &plan.labels, chapter, numbers);
+        if let Some(rewritten) = rewritten {
+            chapter.content = rewritten;
+        }
+    });
+    Ok(())
+}
+
+// the content of a chapter with its equations numbered and its references
+// resolved, if anything changed
+fn rewrite(
+    renderer: &ChapterRenderer,
+    labels: &HashMap<String, Target>,
+    chapter: &Chapter,
+    numbers: &[Option<String>],
+) -> Option<String> {
+    let content = &chapter.content;
+    let references = References { labels, chapter };
+    let mut rewritten = String::with_capacity(content.len());
+    let mut copied = 0;
+    let mut numbers = numbers.iter();
+    for span in renderer.scanner.scan(content, &mut |_| {}) {
+        let (source, tex, display) = match span {
+            Span::Math {
+                source,
+                tex,
+                display,
+            } => (source, tex, display),
+            _ => continue,
+        };
+        let number = if display { numbers.next() } else { None };
+        let (start, tex_start) = match (offset(content, source), offset(source, tex)) {
+            (Some(start), Some(tex_start)) => (start, tex_start),
+            _ => continue,
+        };
+        let tex_end = tex_start + tex.len();
+        rewritten.push_str(&content[copied..start]);
+        copied = start + source.len();
+        // an inline equation that's only a reference becomes a link
+        if let (false, Some(link)) = (display, references.link(tex)) {
+            rewritten.push_str(&link);
+            continue;
+        }
+        let label = argument(tex, "\\label{").filter(|label| labels.contains_key(*label));
+        if let (true, Some(label)) = (display, label) {
+            rewritten.push_str(&format!("<span id=\"{}\"></span>", escape_attribute(label)));
+        }
+        let mut rewritten_tex = references.resolve(&without_labels(tex));
+        for command in UNNUMBERED {
+            rewritten_tex = rewritten_tex.replace(command, "");
+        }
+        if let Some(Some(number)) = number {
+            let tag = format!(" \\tag{{{}}}", number);
+            match rewritten_tex.rfind("\\end{") {
+                // ending with the environment, written without delimiters
+                Some(end) if tex_start == 0 && source.len() == tex.len() => {
+                    rewritten_tex.insert_str(end, &tag)
+                }
+                _ => rewritten_tex = format!("{}{}", rewritten_tex.trim_end(), tag),
+            }
+        }
+        rewritten.push_str(&source[..tex_start]);
+        rewritten.push_str(&rewritten_tex);
+        rewritten.push_str(&source[tex_end..]);
+    }
+    if copied == 0 {
+        return None;
+    }
+    rewritten.push_str(&content[copied..]);
+    Some(rewritten)
+}
+
+// references of a chapter to labeled equations
+struct References<'a> {
+    labels: &'a HashMap<String, Target>,
+    chapter: &'a Chapter,
+}
+
+impl References<'_> {
+    // the number a reference shows, `??` as in LaTeX for unknown labels
+    fn number(&self, label: &str, command: &str) -> (String, Option<&Target>) {
+        let target = self.labels.get(label);
+        if target.is_none() {
+            diagnostics::warn(&format!(
+                "`{}{}}}` of `{}` doesn't refer to a labeled equation",
+                command, label, self.chapter.name
+            ));
+        }
+        let number = target.map_or("??", |target| target.number.as_str());
+        match command {
+            "\\eqref{" => (format!("({})", number), target),
+            _ => (String::from(number), target),
+        }
+    }
+
+    // link for an equation that's only `\eqref{...}` or `\ref{...}`
+    fn link(&self, tex: &str) -> Option<String> {
+        let tex = tex.trim();
+        let command = REFERENCES
+            .iter()
+            .copied()
+            .find(|command| tex.starts_with(command))?;
+        let label = tex.strip_prefix(command)?.strip_suffix('}')?;
+        if label.contains('}') {
+            return None;
+        }
+        let (number, target) = self.number(label.trim(), command);
+        Some(match target {
+            Some(target) => format!(
+                "<a href=\"{}\">{}</a>",
+                escape_attribute(&self.href(label.trim(), target)),
+                escape_attribute(&number)
+            ),
+            None => escape_attribute(&number),
+        })
+    }
+
+    fn href(&self, label: &str, target: &Target) -> String {
+        match (&target.path, &self.chapter.path) {
+            (Some(path), Some(current)) if path != current => {
+                format!("{}{}#{}", path_to_root(current), html_path(path), label)
+            }
+            _ => format!("#{}", label),
+        }
+    }
+
+    // references inside equations, replaced with the numbers they refer to
+    fn resolve(&self, tex: &str) -> String {
+        let mut result = String::from(tex);
+        for command in REFERENCES {
+            while let Some(start) = result.find(command) {
+                let end = match result[start..].find('}') {
+                    Some(end) => start + end,
+                    None => break,
+                };
+                let label = String::from(result[start + command.len()..end].trim());
+                let (number, _) = self.number(&label, command);
+                result.replace_range(start..=end, &format!("\\text{{{}}}", number));
+            }
+        }
+        result
+    }
+}
+
+// the page mdbook renders a chapter to
+fn html_path(path: &Path) -> String {
+    path.with_extension("html")
+        .to_string_lossy()
+        .replace('\\', "/")
+}
@@ -0,0 +1,305 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::Arc;
+
+use anyhow::Error;
+
+// what katex renders equations to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputType {
+    Html,
+    Mathml,
+    HtmlAndMathml,
+}
+
+impl OutputType {
+    pub fn from_config(value: &str) -> Result<Self, Error> {
+        match value {
+            "html" => Ok(OutputType::Html),
+            "mathml" => Ok(OutputType::Mathml),
+            "htmlAndMathml" => Ok(OutputType::HtmlAndMathml),
+            other => Err(Error::msg(format!(
+                "unknown value `{}`, expected \"html\", \"mathml\" or \"htmlAndMathml\"",
+                other
+            ))),
+        }
+    }
+}
+
+#[cfg(feature = "katex")]
+impl From<OutputType> for katex::OutputType {
+    fn from(output_type: OutputType) -> Self {
+        match output_type {
+            OutputType::Html => katex::OutputType::Html,
+            OutputType::Mathml => katex::OutputType::Mathml,
+            OutputType::HtmlAndMathml => katex::OutputType::HtmlAndMathml,
+        }
+    }
+}
+
+// katex options for inline or display equations
+//
+// `katex::Opts` can hold a callback that isn't thread safe, so renderers keep
+// their options in here instead, and katex ones are built when rendering
+// equations; a renderer can then be shared between threads.
+#[cfg_attr(not(feature = "katex"), allow(dead_code))]
+#[derive(Clone, Debug)]
+pub struct RenderOpts {
+    display_mode: bool,
+    output_type: OutputType,
+    leqno: bool,
+    fleqn: bool,
+    // shared by the inline and display options, and those of chapters
+    // overriding options
+    macros: Arc<HashMap<String, String>>,
+    // katex defaults if unset
+    throw_on_error: Option<bool>,
+    error_color: Option<String>,
+    min_rule_thickness: Option<f64>,
+    max_size: Option<f64>,
+    max_expand: Option<i32>,
+    trust: Option<bool>,
+}
+
+// katex options with a key of their own in `[preprocessor.katex]`
+const DEDICATED_OPTS: &[(&str, &str)] = &[
+    ("displayMode", "delimiters"),
+    ("output", "output"),
+    ("leqno", "leqno"),
+    ("fleqn", "fleqn"),
+    ("macros", "macros"),
+];
+
+// katex options `raw-opts` can set: those the katex crate passes on to katex
+const RAW_OPTS: &str = "\"throwOnError\", \"errorColor\", \"minRuleThickness\", \"maxSize\", \
+                        \"maxExpand\" or \"trust\"";
+
+impl RenderOpts {
+    pub fn new(display_mode: bool) -> Self {
+        RenderOpts {
+            display_mode,
+            output_type: OutputType::Html,
+            leqno: false,
+            fleqn: false,
+            macros: Arc::default(),
+            throw_on_error: None,
+            error_color: None,
+            min_rule_thickness: None,
+            max_size: None,
+            max_expand: None,
+            trust: None,
+        }
+    }
+
+    pub fn with_output_type(mut self, output_type: OutputType) -> Self {
+        self.output_type = output_type;
+        self
+    }
+
+    pub fn with_leqno(mut self, leqno: bool) -> Self {
+        self.leqno = leqno;
+        self
+    }
+
+    pub fn with_fleqn(mut self, fleqn: bool) -> Self {
+        self.fleqn = fleqn;
+        self
+    }
+
+    pub fn with_macros(mut self, macros: HashMap<String, String>) -> Self {
+        self.macros = Arc::new(macros);
+        self
+    }
+
+    pub fn with_throw_on_error(mut self, throw_on_error: bool) -> Self {
+        self.throw_on_error = Some(throw_on_error);
+        self
+    }
+
+    pub fn with_error_color(mut self, error_color: &str) -> Self {
+        self.error_color = Some(String::from(error_color));
+        self
+    }
+
+    pub fn with_min_rule_thickness(mut self, min_rule_thickness: f64) -> Self {
+        self.min_rule_thickness = Some(min_rule_thickness);
+        self
+    }
+
+    pub fn with_max_size(mut self, max_size: f64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    pub fn with_max_expand(mut self, max_expand: i32) -> Self {
+        self.max_expand = Some(max_expand);
+        self
+    }
+
+    pub fn with_trust(mut self, trust: bool) -> Self {
+        self.trust = Some(trust);
+        self
+    }
+
+    // whether katex fails on invalid equations, rather than rendering the error
+    pub fn throws_on_error(&self) -> bool {
+        self.throw_on_error.unwrap_or(true)
+    }
+
+    // katex options given as a JSON object, with katex's own names, as in
+    // `{"maxExpand": 500, "trust": true}`
+    pub fn with_raw_opts(
+        mut self,
+        raw: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<Self, Error> {
+        for (name, value) in raw {
+            if let Some((_, key)) = DEDICATED_OPTS.iter().find(|(opt, _)| opt == name) {
+                return Err(Error::msg(format!(
+                    "`{}` is set with the `{}` option rather than in `raw-opts`",
+                    name, key
+                )));
+            }
+            let invalid = |expected: &str| {
+                Error::msg(format!(
+                    "invalid value for `{}`, expected {}",
+                    name, expected
+                ))
+            };
+            match name.as_str() {
+                "throwOnError" => {
+                    self.throw_on_error = Some(value.as_bool().ok_or_else(|| invalid("a boolean"))?)
+                }
+                "errorColor" => {
+                    self.error_color = Some(
+                        value
+                            .as_str()
+                            .ok_or_else(|| invalid("a string"))?
+                            .to_string(),
+                    )
+                }
+                "minRuleThickness" => {
+                    self.min_rule_thickness =
+                        Some(value.as_f64().ok_or_else(|| invalid("a number"))?)
+                }
+                "maxSize" => {
+                    self.max_size = Some(value.as_f64().ok_or_else(|| invalid("a number"))?)
+                }
+                "maxExpand" => {
+                    let max = value.as_i64().and_then(|max| i32::try_from(max).ok());
+                    self.max_expand = Some(max.ok_or_else(|| invalid("an integer"))?)
+                }
+                "trust" => self.trust = Some(value.as_bool().ok_or_else(|| invalid("a boolean"))?),
+                other => {
+                    return Err(Error::msg(format!(
+                        "unknown katex option `{}`, expected {}",
+                        other, RAW_OPTS
+                    )))
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    // the options as a JSON object for katex in the browser, with katex's own
+    // names; those left unset keep katex's defaults there too
+    pub fn to_json(&self) -> serde_json::Map<String, serde_json::Value> {
+        let mut json = serde_json::Map::new();
+        let output = match self.output_type {
+            OutputType::Html => "html",
+            OutputType::Mathml => "mathml",
+            OutputType::HtmlAndMathml => "htmlAndMathml",
+        };
+        json.insert(String::from("output"), output.into());
+        json.insert(String::from("leqno"), self.leqno.into());
+        json.insert(String::from("fleqn"), self.fleqn.into());
+        let macros = self
+            .macros
+            .iter()
+            .map(|(name, expansion)| (name.clone(), expansion.as_str().into()))
+            .collect();
+        json.insert(String::from("macros"), serde_json::Value::Object(macros));
+        if let Some(throw_on_error) = self.throw_on_error {
+            json.insert(String::from("throwOnError"), throw_on_error.into());
+        }
+        if let Some(color) = &self.error_color {
+            json.insert(String::from("errorColor"), color.as_str().into());
+        }
+        if let Some(thickness) = self.min_rule_thickness {
+            json.insert(String::from("minRuleThickness"), thickness.into());
+        }
+        if let Some(max_size) = self.max_size {
+            json.insert(String::from("maxSize"), max_size.into());
+        }
+        if let Some(max_expand) = self.max_expand {
+            json.insert(String::from("maxExpand"), max_expand.into());
+        }
+        if let Some(trust) = self.trust {
+            json.insert(String::from("trust"), trust.into());
+        }
+        json
+    }
+
+    // the macros some tex expands to, directly or through other macros; katex
+    // copies the macros it's given for every equation, so it only gets those
+    #[cfg(feature = "katex")]
+    pub fn macros_used_by(&self, tex: &str) -> HashMap<String, String> {
+        let mut used = HashMap::new();
+        // macros that aren't control sequences, like `~`, are always given
+        for (name, expansion) in self.macros.iter() {
+            if !name.starts_with('\\') {
+                used.insert(name.clone(), expansion.clone());
+            }
+        }
+        if self.macros.len() == used.len() {
+            return used;
+        }
+        let mut pending = crate::macros::control_sequences(tex);
+        while let Some(name) = pending.pop() {
+            if used.contains_key(&name) {
+                continue;
+            }
+            if let Some(expansion) = self.macros.get(&name) {
+                pending.extend(crate::macros::control_sequences(expansion));
+                used.insert(name, expansion.clone());
+            }
+        }
+        used
+    }
+
+    #[cfg(feature = "katex")]
+    pub fn to_katex(&self) -> katex::Opts {
+        self.to_katex_with(HashMap::clone(&self.macros))
+    }
+
+    // katex options with only the given macros
+    #[cfg(feature = "katex")]
+    pub fn to_katex_with(&self, macros: HashMap<String, String>) -> katex::Opts {
+        let mut opts = katex::Opts::builder()
+            .display_mode(self.display_mode)
+            .output_type(katex::OutputType::from(self.output_type))
+            .leqno(self.leqno)
+            .fleqn(self.fleqn)
+            .macros(macros)
+            .build()
+            .unwrap();
+        if let Some(throw_on_error) = self.throw_on_error {
+            opts.set_throw_on_error(throw_on_error);
+        }
+        if let Some(color) = &self.error_color {
+            opts.set_error_color(color.clone());
+        }
+        if let Some(thickness) = self.min_rule_thickness {
+            opts.set_min_rule_thickness(thickness);
+        }
+        if let Some(max_size) = self.max_size {
+            opts.set_max_size(Some(max_size));
+        }
+        if let Some(max_expand) = self.max_expand {
+            opts.set_max_expand(Some(max_expand));
+        }
+        if let Some(trust) = self.trust {
+            opts.set_trust(trust);
+        }
+        opts
+    }
+}
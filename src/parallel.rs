@@ -0,0 +1,85 @@
+// rendering chapters, or the equations of a large chapter, on several threads
+//
+// katex keeps a javascript context per thread, so workers render many items
+// each rather than one thread per item; work started from a worker stays on
+// it, so that chapters rendered in parallel don't split their equations again.
+// Diagnostics of workers are printed along with their results, in the order of
+// the items, so that logs are the same from one run to the next.
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::diagnostics;
+
+thread_local! {
+    static WORKER: Cell<bool> = const { Cell::new(false) };
+}
+
+// number of threads for `jobs = n`, all available cores for 0
+pub fn jobs(configured: usize) -> usize {
+    match configured {
+        0 => thread::available_parallelism().map_or(1, |jobs| jobs.get()),
+        jobs => jobs,
+    }
+}
+
+// apply `work` to items on up to `jobs` threads, handing each result to
+// `on_result` on the calling thread, in the order of the items; stops early
+// when `on_result` returns false
+pub fn for_each_ordered<T, R, W, F>(items: &[T], jobs: usize, work: W, mut on_result: F)
+where
+    T: Sync,
+    R: Send,
+    W: Fn(&T) -> R + Sync,
+    F: FnMut(usize, R) -> bool,
+{
+    let nested = WORKER.with(Cell::get);
+    if jobs <= 1 || items.len() <= 1 || nested {
+        for (index, item) in items.iter().enumerate() {
+            if !on_result(index, work(item)) {
+                return;
+            }
+        }
+        return;
+    }
+    let next = AtomicUsize::new(0);
+    let stop = AtomicBool::new(false);
+    let (sender, receiver) = mpsc::channel();
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(items.len()) {
+            let sender = sender.clone();
+            let (next, stop, work) = (&next, &stop, &work);
+            scope.spawn(move || {
+                WORKER.with(|worker| worker.set(true));
+                while !stop.load(Ordering::Relaxed) {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let item = match items.get(index) {
+                        Some(item) => item,
+                        None => break,
+                    };
+                    let result = diagnostics::capture(|| work(item));
+                    if sender.send((index, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+        // results arrive in any order, and are handed over in the items' order
+        let mut pending = BTreeMap::new();
+        let mut expected = 0;
+        for (index, result) in receiver {
+            pending.insert(index, result);
+            while let Some((result, diagnostics)) = pending.remove(&expected) {
+                diagnostics::replay(diagnostics);
+                if !on_result(expected, result) {
+                    stop.store(true, Ordering::Relaxed);
+                    return;
+                }
+                expected += 1;
+            }
+        }
+    })
+}
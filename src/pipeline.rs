@@ -0,0 +1,309 @@
+use std::io::{self, Write};
+use std::mem;
+use std::ops::Range;
+use std::sync::mpsc;
+use std::thread;
+
+use std::path::{Path, PathBuf};
+
+use mdbook::book::{Book, BookItem, Chapter};
+use mdbook::errors::Error;
+use mdbook::utils::fs::path_to_root;
+
+use crate::diagnostics::{self, ErrorMode};
+use crate::{chapter_macros, numbering, parallel, ChapterRenderer, ChapterStats};
+
+// number of rendered chapters waiting to be written, at most
+const PIPELINE_DEPTH: usize = 4;
+
+// JSON-serialized placeholder standing for the content of a chapter
+const PLACEHOLDER_START: &str = "\"\\u0000mdbook-katex-chapter-";
+const PLACEHOLDER_END: &str = "\\u0000\"";
+
+// a chapter taken out of a book, with what rendering it needs
+pub struct PendingChapter {
+    pub name: String,
+    pub path: Option<PathBuf>,
+    pub content: String,
+    pub inherited: bool,
+    pub path_to_root: String,
+}
+
+// placeholder for the content of chapters that opted out of rendering
+const DISABLED: &str = "\0mdbook-katex-disabled\0";
+
+// visit chapters in reading order, parents before their sub-chapters, unlike
+// `Book::for_each_mut`
+pub fn visit_chapters(items: &mut [BookItem], visit: &mut impl FnMut(&mut Chapter)) {
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            visit(chapter);
+            visit_chapters(&mut chapter.sub_items, visit);
+        }
+    }
+}
+
+// the chapters of a book in reading order
+pub fn reading_order(items: &[BookItem]) -> Vec<&Chapter> {
+    let mut chapters = Vec::new();
+    for item in items {
+        if let BookItem::Chapter(chapter) = item {
+            chapters.push(chapter);
+            chapters.extend(reading_order(&chapter.sub_items));
+        }
+    }
+    chapters
+}
+
+// whether each chapter of a book is rendered, in reading order, as set in its
+// front matter or inherited from its parent
+pub fn rendered_chapters(renderer: &ChapterRenderer, book: &Book) -> Vec<bool> {
+    let mut book = book.clone();
+    let mut inherited = renderer.inherited_settings(&book).into_iter();
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            let inherited = inherited.next().unwrap_or(renderer.enabled);
+            let (setting, _) = ChapterRenderer::front_matter_setting(&chapter.content);
+            chapter.content = if setting.unwrap_or(inherited) {
+                String::new()
+            } else {
+                String::from(DISABLED)
+            };
+        }
+    });
+    let mut rendered = Vec::new();
+    visit_chapters(&mut book.sections, &mut |chapter| {
+        rendered.push(chapter.content != DISABLED)
+    });
+    rendered
+}
+
+// take the content of every chapter out of a book, in the order of `for_each_mut`,
+// replacing it with a placeholder made by `placeholder` from the chapter index
+fn take_chapters_with(
+    renderer: &ChapterRenderer,
+    book: &mut Book,
+    placeholder: impl Fn(usize) -> String,
+) -> Result<Vec<PendingChapter>, Error> {
+    if renderer.chapter_macros.is_some() || renderer.preamble_chapter.is_some() {
+        chapter_macros::collect_macros(renderer, book);
+    }
+    if let Some(numbering) = renderer.numbering {
+        numbering::number_equations(renderer, numbering, book)?;
+    }
+    let mut inherited = renderer.inherited_settings(book).into_iter();
+    let mut chapters = Vec::new();
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            let content = mem::replace(&mut chapter.content, placeholder(chapters.len()));
+            chapters.push(PendingChapter {
+                name: chapter.name.clone(),
+                path: chapter.path.clone(),
+                content,
+                inherited: inherited.next().unwrap_or(renderer.enabled),
+                path_to_root: chapter.path.as_ref().map(path_to_root).unwrap_or_default(),
+            });
+        }
+    });
+    Ok(chapters)
+}
+
+// take the content of every chapter out of a book, in the order of `for_each_mut`
+pub fn take_chapters(
+    renderer: &ChapterRenderer,
+    book: &mut Book,
+) -> Result<Vec<PendingChapter>, Error> {
+    take_chapters_with(renderer, book, |_| String::new())
+}
+
+// render a single chapter of a book, at its path in the source directory,
+// with the macros and equation numbers the rest of the book gives it
+pub fn render_chapter(
+    renderer: &ChapterRenderer,
+    mut book: Book,
+    path: &Path,
+) -> Result<String, Error> {
+    let chapters = take_chapters(renderer, &mut book)?;
+    let index = chapters
+        .iter()
+        .position(|chapter| chapter.path.as_deref() == Some(path))
+        .ok_or_else(|| Error::msg(format!("{} isn't a chapter of the book", path.display())))?;
+    let mut content = String::new();
+    render_chapters(renderer, &chapters[index..=index], |_, (rendered, _)| {
+        content = rendered;
+        true
+    })?;
+    Ok(content)
+}
+
+// render chapters on the threads of the renderer, handing them over to
+// `on_rendered` in order; stops early when `on_rendered` returns false
+//
+// Equations that failed to render are reported along the way, following
+// `error-mode`; with `error-mode = "error"` or `fail-on-error = true`, they
+// are kept for an error listing all of them once every chapter is done.
+pub fn render_chapters(
+    renderer: &ChapterRenderer,
+    chapters: &[PendingChapter],
+    mut on_rendered: impl FnMut(usize, (String, ChapterStats)) -> bool,
+) -> Result<(), Error> {
+    let mut failures = Vec::new();
+    parallel::for_each_ordered(
+        chapters,
+        renderer.jobs(),
+        |chapter| match &chapter.path {
+            Some(path) => renderer.render_chapter_file(&chapter.content, chapter.inherited, path),
+            None => renderer.render_chapter_with_stats(
+                &chapter.content,
+                chapter.inherited,
+                &chapter.path_to_root,
+            ),
+        },
+        |index, rendered| {
+            report_prerendered(&chapters[index], &rendered.1);
+            let reported = report_failures(renderer.error_mode(), &chapters[index], &rendered.1);
+            failures.extend(reported);
+            on_rendered(index, rendered)
+        },
+    );
+    let summary = match failures.len() {
+        0 => return Ok(()),
+        1 => String::from("an equation failed to render:"),
+        count => format!("{} equations failed to render:", count),
+    };
+    Err(Error::msg(
+        std::iter::once(summary)
+            .chain(failures.iter().map(|failure| format!("  {}", failure)))
+            .collect::<Vec<_>>()
+            .join("\n"),
+    ))
+}
+
+// where a chapter is, in diagnostics
+fn location(chapter: &PendingChapter) -> String {
+    match &chapter.path {
+        Some(path) => path.display().to_string(),
+        None => chapter.name.clone(),
+    }
+}
+
+// equations of a chapter already rendered, which the chapter probably got
+// from a build of the book
+fn report_prerendered(chapter: &PendingChapter, stats: &ChapterStats) {
+    if stats.prerendered > 0 {
+        diagnostics::warn(&format!(
+            "{} already holds {} rendered equations, which are left as they are",
+            location(chapter),
+            stats.prerendered
+        ));
+    }
+}
+
+// print the equations of a chapter that failed to render, with where they
+// are, returning those failing the build instead
+fn report_failures(mode: ErrorMode, chapter: &PendingChapter, stats: &ChapterStats) -> Vec<String> {
+    let location = location(chapter);
+    let mut failing = Vec::new();
+    for failure in &stats.failures {
+        let message = match failure.line {
+            Some(line) => format!("{}:{}: {}", location, line, failure),
+            None => format!("{}: {}", location, failure),
+        };
+        match mode {
+            ErrorMode::Warn => diagnostics::warn(&message),
+            ErrorMode::Error => failing.push(message),
+            ErrorMode::Ignore => {}
+        }
+    }
+    failing
+}
+
+// render the chapters of a book and write the processed book as JSON, without
+// keeping all the rendered chapters in memory
+//
+// The book is serialized with a placeholder in place of each chapter content.
+// Chapters are rendered, in parallel unless `jobs = 1`, while a separate
+// thread writes each rendered chapter in place of its placeholder, in order,
+// then drops it.
+pub fn write_book<W: Write + Send>(
+    renderer: &ChapterRenderer,
+    mut book: Book,
+    writer: &mut W,
+) -> Result<(), Error> {
+    // replace chapter contents with numbered placeholders
+    let pending = take_chapters_with(renderer, &mut book, |index| {
+        format!("\0mdbook-katex-chapter-{}\0", index)
+    })?;
+    let skeleton = serde_json::to_string(&book)?;
+    let placeholders = find_placeholders(&skeleton)?;
+    // chapters are rendered in the order they appear in the serialized book
+    let mut pending: Vec<Option<PendingChapter>> = pending.into_iter().map(Some).collect();
+    let chapters: Vec<PendingChapter> = placeholders
+        .iter()
+        .filter_map(|(_, index)| pending.get_mut(*index).and_then(Option::take))
+        .collect();
+    let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+    let mut manifest = renderer.manifest();
+    let mut rendering = Ok(());
+    thread::scope(|scope| {
+        let writing =
+            scope.spawn(move || write_skeleton(&skeleton, &placeholders, &receiver, writer));
+        rendering = render_chapters(renderer, &chapters, |index, (rendered, stats)| {
+            if let Some(manifest) = &mut manifest {
+                let chapter = &chapters[index];
+                manifest.add(&chapter.name, chapter.path.as_deref(), stats);
+            }
+            // the writer stopped, no need to render the remaining chapters
+            sender.send(rendered).is_ok()
+        });
+        drop(sender);
+        writing
+            .join()
+            .unwrap_or_else(|_| Err(Error::msg("writing the processed book panicked")))
+    })?;
+    if let Some(manifest) = &manifest {
+        renderer.write_manifest(manifest)?;
+    }
+    rendering
+}
+
+// byte range and chapter index of each placeholder in the serialized book
+fn find_placeholders(skeleton: &str) -> Result<Vec<(Range<usize>, usize)>, Error> {
+    let mut placeholders = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = skeleton[offset..].find(PLACEHOLDER_START) {
+        let start = offset + start;
+        let index_start = start + PLACEHOLDER_START.len();
+        let index_end = skeleton[index_start..]
+            .find(PLACEHOLDER_END)
+            .map(|end| index_start + end)
+            .ok_or_else(|| Error::msg("unterminated chapter placeholder"))?;
+        let index = skeleton[index_start..index_end]
+            .parse()
+            .map_err(|_| Error::msg("invalid chapter placeholder"))?;
+        offset = index_end + PLACEHOLDER_END.len();
+        placeholders.push((start..offset, index));
+    }
+    Ok(placeholders)
+}
+
+// write the serialized book, replacing placeholders with rendered chapters in order
+fn write_skeleton<W: Write>(
+    skeleton: &str,
+    placeholders: &[(Range<usize>, usize)],
+    receiver: &mpsc::Receiver<String>,
+    writer: &mut W,
+) -> Result<(), Error> {
+    let mut written = 0;
+    for (range, _) in placeholders {
+        writer.write_all(&skeleton.as_bytes()[written..range.start])?;
+        let rendered = receiver
+            .recv()
+            .map_err(|_| Error::msg("chapter rendering stopped unexpectedly"))?;
+        // keep io errors as such, so that callers can tell a closed pipe apart
+        serde_json::to_writer(&mut *writer, &rendered).map_err(io::Error::from)?;
+        written = range.end;
+    }
+    writer.write_all(&skeleton.as_bytes()[written..])?;
+    Ok(())
+}
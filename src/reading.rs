@@ -0,0 +1,79 @@
+// words, equations and reading time of a chapter, for course authors balancing
+// the length of their chapters
+use serde::Serialize;
+
+use crate::scan::Span;
+
+// default reading speed of prose, in words per minute
+pub const DEFAULT_WORDS_PER_MINUTE: u32 = 200;
+
+// default number of words an inline and a display equation take as long to
+// read as
+pub const DEFAULT_INLINE_EQUATION_WORDS: u32 = 5;
+pub const DEFAULT_DISPLAY_EQUATION_WORDS: u32 = 20;
+
+// how long equations and prose take to read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ReadingSpeed {
+    pub words_per_minute: u32,
+    pub inline_equation_words: u32,
+    pub display_equation_words: u32,
+}
+
+impl Default for ReadingSpeed {
+    fn default() -> Self {
+        ReadingSpeed {
+            words_per_minute: DEFAULT_WORDS_PER_MINUTE,
+            inline_equation_words: DEFAULT_INLINE_EQUATION_WORDS,
+            display_equation_words: DEFAULT_DISPLAY_EQUATION_WORDS,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Reading {
+    // words of the text around equations
+    pub words: usize,
+    pub inline_equations: usize,
+    pub display_equations: usize,
+    // equations per thousand words
+    pub equation_density: f64,
+    // with each equation read as long as a few words
+    pub reading_minutes: f64,
+}
+
+impl Reading {
+    pub fn measure(spans: &[Span], speed: &ReadingSpeed) -> Self {
+        let mut reading = Reading::default();
+        for span in spans {
+            match span {
+                Span::Text(text) => reading.words += count_words(text),
+                Span::Math { display: true, .. } => reading.display_equations += 1,
+                Span::Math { display: false, .. } => reading.inline_equations += 1,
+                Span::Data { .. } => {}
+            }
+        }
+        let equations = reading.inline_equations + reading.display_equations;
+        if reading.words > 0 {
+            reading.equation_density = round(equations as f64 * 1000.0 / reading.words as f64);
+        }
+        let weighted = reading.words
+            + reading.inline_equations * speed.inline_equation_words as usize
+            + reading.display_equations * speed.display_equation_words as usize;
+        reading.reading_minutes = round(weighted as f64 / f64::from(speed.words_per_minute.max(1)));
+        reading
+    }
+}
+
+// words are runs of non-whitespace with a letter or a digit, so that list
+// markers and punctuation on their own aren't counted
+fn count_words(text: &str) -> usize {
+    text.split_whitespace()
+        .filter(|word| word.chars().any(char::is_alphanumeric))
+        .count()
+}
+
+// to two decimals, so that manifests don't change with floating point noise
+fn round(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
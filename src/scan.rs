@@ -0,0 +1,682 @@
+// a delimiter of equations, and whether equations between it are displayed
+#[derive(Clone, Debug, PartialEq)]
+pub struct Delimiter {
+    pub marker: String,
+    // the marker ending equations, the same as the one starting them unless
+    // they come in pairs like `\\(` and `\\)`
+    pub closing: String,
+    pub display: bool,
+}
+
+impl Delimiter {
+    pub fn new(marker: &str, display: bool) -> Self {
+        Delimiter::pair(marker, marker, display)
+    }
+
+    pub fn pair(marker: &str, closing: &str, display: bool) -> Self {
+        Delimiter {
+            marker: String::from(marker),
+            closing: String::from(closing),
+            display,
+        }
+    }
+
+    // single-character delimiters can be escaped with a backslash
+    fn escapable(&self) -> bool {
+        self.marker.chars().count() == 1 && self.marker == self.closing
+    }
+}
+
+// `$$` for display equations, and `$` for inline equations
+pub fn default_delimiters() -> Vec<Delimiter> {
+    vec![Delimiter::new("$$", true), Delimiter::new("$", false)]
+}
+
+// delimiters of mdbook's mathjax support: `$$` and `\\[ \\]` for display
+// equations, and `\\( \\)` for inline equations, with backslashes escaped
+// for markdown
+pub fn mathjax_delimiters() -> Vec<Delimiter> {
+    vec![
+        Delimiter::new("$$", true),
+        Delimiter::pair(r"\\[", r"\\]", true),
+        Delimiter::pair(r"\\(", r"\\)", false),
+    ]
+}
+
+// delimiters of LaTeX sources: `\[ \]` for display equations, and `\( \)`
+// for inline equations
+pub fn latex_delimiters() -> Vec<Delimiter> {
+    vec![
+        Delimiter::pair(r"\[", r"\]", true),
+        Delimiter::pair(r"\(", r"\)", false),
+    ]
+}
+
+// a piece of chapter content
+#[derive(Clone, Debug, PartialEq)]
+pub enum Span<'a> {
+    // regular text, with escaped delimiters replaced by the delimiters themselves
+    Text(String),
+    // an equation, along with its source including delimiters
+    Math {
+        source: &'a str,
+        tex: &'a str,
+        display: bool,
+    },
+    // a fenced block of structured data, as configured with `data-blocks`,
+    // split into its opening line, content, and closing line
+    Data {
+        open: &'a str,
+        content: &'a str,
+        close: &'a str,
+    },
+}
+
+impl Span<'_> {
+    // number of line breaks in the span, as written in the chapter
+    pub fn lines(&self) -> usize {
+        match self {
+            Span::Text(text) => text.matches('\n').count(),
+            Span::Math { source, .. } => source.matches('\n').count(),
+            Span::Data {
+                open,
+                content,
+                close,
+            } => [open, content, close]
+                .iter()
+                .map(|part| part.matches('\n').count())
+                .sum(),
+        }
+    }
+}
+
+// regions copied as they are: html comments, and mermaid diagrams after
+// mdbook-mermaid, which gives a meaning to dollar signs in them; code blocks,
+// mermaid ones included, and code spans are copied as well
+const VERBATIM: &[(&str, &str)] = &[("<!--", "-->"), ("<pre class=\"mermaid\">", "</pre>")];
+
+// languages of fenced blocks holding markdown rather than code, which are
+// scanned for equations: admonitions, before mdbook-admonish
+const MARKDOWN_FENCES: &[&str] = &["admonish"];
+
+// a fenced code block: its opening fence line, language, content, and closing
+// fence line, empty when the block runs to the end of the content
+struct Fence<'a> {
+    open: &'a str,
+    language: &'a str,
+    content: &'a str,
+    close: &'a str,
+}
+
+// a fenced code block starting at `position`, at the start of a line
+fn fenced_block(content: &str, position: usize) -> Option<Fence<'_>> {
+    if !(position == 0 || content[..position].ends_with('\n')) {
+        return None;
+    }
+    let rest = &content[position..];
+    let line_end = rest.find('\n').map_or(rest.len(), |end| end + 1);
+    let line = rest[..line_end].trim_start_matches(' ');
+    let marker = line.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let fence_length = line.chars().take_while(|c| *c == marker).count();
+    if fence_length < 3 {
+        return None;
+    }
+    let language = line[fence_length..].split_whitespace().next().unwrap_or("");
+    // the block ends with a line of at least as many fence characters
+    let mut end = line_end;
+    for closing in rest[line_end..].split_inclusive('\n') {
+        let trimmed = closing.trim();
+        if trimmed.len() >= fence_length && trimmed.chars().all(|c| c == marker) {
+            return Some(Fence {
+                open: &rest[..line_end],
+                language,
+                content: &rest[line_end..end],
+                close: &rest[end..end + closing.len()],
+            });
+        }
+        end += closing.len();
+    }
+    Some(Fence {
+        open: &rest[..line_end],
+        language,
+        content: &rest[line_end..],
+        close: "",
+    })
+}
+
+// language of the fenced blocks of display equations, on github and gitlab
+const MATH_FENCE: &str = "math";
+
+// a ` ```math ` block at `position`, as a display equation up to the end of
+// its closing fence, without the line break after it
+fn math_fence(content: &str, position: usize) -> Option<Span<'_>> {
+    let fence = fenced_block(content, position)?;
+    if fence.language != MATH_FENCE || fence.close.is_empty() {
+        return None;
+    }
+    let length = fence.open.len() + fence.content.len() + fence.close.trim_end().len();
+    Some(Span::Math {
+        source: &content[position..position + length],
+        tex: fence.content,
+        display: true,
+    })
+}
+
+// an inline equation written as in gitlab, ``$`x^2`$``, at the start of
+// `rest`
+fn dollar_code_span(rest: &str) -> Option<Span<'_>> {
+    let code = rest.strip_prefix('$')?;
+    let length = code_span(code)?;
+    if !code[length..].starts_with('$') {
+        return None;
+    }
+    let ticks = code.len() - code.trim_start_matches('`').len();
+    Some(Span::Math {
+        source: &rest[..length + 2],
+        tex: &code[ticks..length - ticks],
+        display: false,
+    })
+}
+
+// length of a code span at the start of `rest`, closed by as many backticks
+// as it opens with, within its paragraph
+fn code_span(rest: &str) -> Option<usize> {
+    let ticks = rest.len() - rest.trim_start_matches('`').len();
+    let mut position = ticks;
+    while let Some(offset) = rest[position..].find('`') {
+        let start = position + offset;
+        if rest[position..start].contains("\n\n") {
+            return None;
+        }
+        let run = rest[start..].len() - rest[start..].trim_start_matches('`').len();
+        if run == ticks {
+            return Some(start + run);
+        }
+        position = start + run;
+    }
+    None
+}
+
+// the start of an equation katex already rendered, e.g. in the output of an
+// earlier build pasted into a chapter
+const RENDERED_CLASS: &str = "class=\"katex";
+
+// length of the equation already rendered at the start of some text, up to
+// the end of its outermost span
+fn rendered_length(rest: &str) -> Option<usize> {
+    if !rest.starts_with("<span ") || !rest[..rest.find('>')?].contains(RENDERED_CLASS) {
+        return None;
+    }
+    let mut depth = 0;
+    let mut position = 0;
+    while let Some(offset) = rest[position..].find("span") {
+        let at = position + offset;
+        position = at + "span".len();
+        if rest[..at].ends_with("</") {
+            depth -= 1;
+            if depth == 0 {
+                return Some(position + rest[position..].find('>')? + 1);
+            }
+        } else if rest[..at].ends_with('<') {
+            depth += 1;
+        }
+    }
+    None
+}
+
+// the equations already rendered in some content, outside of code, which are
+// copied as they are rather than scanned
+pub fn rendered_equations(content: &str) -> usize {
+    if !content.contains(RENDERED_CLASS) {
+        return 0;
+    }
+    let mut count = 0;
+    let mut position = 0;
+    while let Some(c) = content[position..].chars().next() {
+        match copied_length(content, position) {
+            Some(length) => {
+                if rendered_length(&content[position..]).is_some() {
+                    count += 1;
+                }
+                position += length.max(1);
+            }
+            None => position += c.len_utf8(),
+        }
+    }
+    count
+}
+
+// comments turning equations off until the end of the content or the comment
+// turning them back on, for regions full of dollar signs like shell sessions
+const DISABLE: &str = "disable";
+const ENABLE: &str = "enable";
+
+// the text of the `<!-- katex:... -->` comment at the start of `rest`, as in
+// `disable`, and its length
+fn region_comment(rest: &str) -> Option<(&str, usize)> {
+    let inside = rest.strip_prefix("<!--")?;
+    let end = inside.find("-->")?;
+    let directive = inside[..end].trim().strip_prefix("katex:")?.trim();
+    Some((directive, "<!--".len() + end + "-->".len()))
+}
+
+// whether the text of a `<!-- katex:... -->` comment starts or ends a region
+// without equations, rather than setting options
+pub fn is_region_directive(directive: &str) -> bool {
+    directive == DISABLE || directive == ENABLE
+}
+
+// length of the region without equations at the start of `rest`, up to the
+// end of the comment turning them back on
+fn disabled_length(rest: &str) -> Option<usize> {
+    match region_comment(rest)? {
+        (DISABLE, _) => {}
+        _ => return None,
+    }
+    let mut position = 0;
+    while let Some(offset) = rest[position + 1..].find("<!--") {
+        position += 1 + offset;
+        if let Some((ENABLE, length)) = region_comment(&rest[position..]) {
+            return Some(position + length);
+        }
+    }
+    Some(rest.len())
+}
+
+// length of the code block, code span, verbatim region, region without
+// equations or rendered equation at `position`, which the scanner copies as it
+// is; blocks holding markdown aren't copied
+pub fn copied_length(content: &str, position: usize) -> Option<usize> {
+    if let Some(fence) = fenced_block(content, position) {
+        if MARKDOWN_FENCES.contains(&fence.language) {
+            return None;
+        }
+        return Some(fence.open.len() + fence.content.len() + fence.close.len());
+    }
+    let rest = &content[position..];
+    if rest.starts_with('`') {
+        return code_span(rest);
+    }
+    if let Some(length) = disabled_length(rest) {
+        return Some(length);
+    }
+    VERBATIM
+        .iter()
+        .find_map(|(start, end)| {
+            let inside = rest.strip_prefix(start)?;
+            Some(
+                inside
+                    .find(end)
+                    .map_or(rest.len(), |offset| start.len() + offset + end.len()),
+            )
+        })
+        .or_else(|| rendered_length(rest))
+}
+
+// where a slice of `content`, like the source of an equation, starts in it
+pub fn offset(content: &str, part: &str) -> Option<usize> {
+    let offset = (part.as_ptr() as usize).checked_sub(content.as_ptr() as usize)?;
+    (offset + part.len() <= content.len()).then_some(offset)
+}
+
+// amsmath environments rendered as display equations when written without
+// delimiters, as latex documents do; `cases` needs math mode in latex, but is
+// often written so
+pub const MATH_ENVIRONMENTS: &[&str] = &[
+    "equation",
+    "equation*",
+    "align",
+    "align*",
+    "alignat",
+    "alignat*",
+    "flalign",
+    "flalign*",
+    "gather",
+    "gather*",
+    "multline",
+    "multline*",
+    "eqnarray",
+    "eqnarray*",
+    "cases",
+];
+
+// length of a math environment at the start of `rest`, up to its matching
+// `\end{...}`, if one of `MATH_ENVIRONMENTS` starts there and is closed
+fn math_environment(rest: &str) -> Option<usize> {
+    let name = MATH_ENVIRONMENTS.iter().find(|name| {
+        rest.strip_prefix("\\begin{")
+            .and_then(|inner| inner.strip_prefix(**name))
+            .is_some_and(|after| after.starts_with('}'))
+    })?;
+    let begin = format!("\\begin{{{}}}", name);
+    let end = format!("\\end{{{}}}", name);
+    let mut depth = 0;
+    let mut position = 0;
+    loop {
+        let next_begin = rest[position..]
+            .find(&begin)
+            .map(|offset| position + offset);
+        let next_end = position + rest[position..].find(&end)?;
+        match next_begin {
+            Some(start) if start < next_end => {
+                depth += 1;
+                position = start + begin.len();
+            }
+            _ => {
+                depth -= 1;
+                position = next_end + end.len();
+                if depth == 0 {
+                    return Some(position);
+                }
+            }
+        }
+    }
+}
+
+// default maximum length of inline equations, in bytes
+pub const DEFAULT_MAX_INLINE_LENGTH: usize = 2000;
+
+// splits content into text and equations
+#[derive(Clone, Debug)]
+pub struct Scanner {
+    // sorted by decreasing length, so that `$$` isn't mistaken for two `$`
+    delimiters: Vec<Delimiter>,
+    max_inline_length: Option<usize>,
+    // languages of fenced blocks holding TOML or JSON data, like `quiz`
+    data_blocks: Vec<String>,
+    // whether `MATH_ENVIRONMENTS` without delimiters are display equations
+    math_environments: bool,
+    // whether ` ```math ` blocks and ``$`x`$`` are equations
+    math_code: bool,
+}
+
+impl Default for Scanner {
+    fn default() -> Self {
+        Scanner::new(default_delimiters())
+    }
+}
+
+impl Scanner {
+    pub fn new(mut delimiters: Vec<Delimiter>) -> Self {
+        delimiters.sort_by_key(|delimiter| std::cmp::Reverse(delimiter.marker.len()));
+        Scanner {
+            delimiters,
+            max_inline_length: Some(DEFAULT_MAX_INLINE_LENGTH),
+            data_blocks: Vec::new(),
+            math_environments: false,
+            math_code: false,
+        }
+    }
+
+    // fenced blocks with these languages are data blocks
+    pub fn with_data_blocks(mut self, data_blocks: Vec<String>) -> Self {
+        self.data_blocks = data_blocks;
+        self
+    }
+
+    // amsmath environments without delimiters are display equations
+    pub fn with_math_environments(mut self, math_environments: bool) -> Self {
+        self.math_environments = math_environments;
+        self
+    }
+
+    // the delimiters equations are found with, longest first
+    pub fn delimiters(&self) -> &[Delimiter] {
+        &self.delimiters
+    }
+
+    // ` ```math ` blocks are display equations, and ``$`x`$`` inline ones
+    pub fn with_math_code(mut self, math_code: bool) -> Self {
+        self.math_code = math_code;
+        self
+    }
+
+    // a data block starting at `position`, at the start of a line
+    fn data_block<'a>(&self, content: &'a str, position: usize) -> Option<Span<'a>> {
+        if self.data_blocks.is_empty() {
+            return None;
+        }
+        let fence = fenced_block(content, position)?;
+        if fence.close.is_empty() || !self.data_blocks.iter().any(|block| block == fence.language) {
+            return None;
+        }
+        Some(Span::Data {
+            open: fence.open,
+            content: fence.content,
+            close: fence.close,
+        })
+    }
+
+    // inline equations longer than this are treated as text, `None` for no limit
+    pub fn with_max_inline_length(mut self, max_inline_length: Option<usize>) -> Self {
+        self.max_inline_length = max_inline_length;
+        self
+    }
+
+    // split content into text and equations, in a single traversal
+    //
+    // At each position, data blocks and math code are split out, and code
+    // blocks, code spans, verbatim regions and regions turned off with
+    // `<!-- katex:disable -->` are copied, then math
+    // environments are split out, and longer delimiters are tried first.
+    // Delimiters left unclosed, or enclosing nothing, are kept as text. Inline
+    // equations longer than the maximum length are passed to `on_reject` and
+    // kept as text.
+    pub fn scan<'a>(&self, content: &'a str, on_reject: &mut dyn FnMut(&str)) -> Vec<Span<'a>> {
+        let mut spans = Vec::new();
+        let mut text = String::new();
+        let mut position = 0;
+        let mut markdown_close = None;
+        'outer: while let Some(c) = content[position..].chars().next() {
+            let rest = &content[position..];
+            if let Some(
+                block @ Span::Data {
+                    open,
+                    content,
+                    close,
+                },
+            ) = self.data_block(content, position)
+            {
+                if !text.is_empty() {
+                    spans.push(Span::Text(std::mem::take(&mut text)));
+                }
+                position += open.len() + content.len() + close.len();
+                spans.push(block);
+                continue;
+            }
+            // the closing fence of a block holding markdown
+            if let Some((start, close)) = markdown_close.filter(|(start, _)| position >= *start) {
+                markdown_close = None;
+                if position == start {
+                    text.push_str(close);
+                    position += close.len();
+                    continue;
+                }
+            }
+            let math_code = if self.math_code {
+                math_fence(content, position).or_else(|| dollar_code_span(rest))
+            } else {
+                None
+            };
+            if let Some(span @ Span::Math { source, .. }) = math_code {
+                if !text.is_empty() {
+                    spans.push(Span::Text(std::mem::take(&mut text)));
+                }
+                position += source.len();
+                spans.push(span);
+                continue;
+            }
+            if let Some(fence) = fenced_block(content, position) {
+                // only the fences of blocks holding markdown
+                let length = if MARKDOWN_FENCES.contains(&fence.language) {
+                    let start = position + fence.open.len() + fence.content.len();
+                    markdown_close = Some((start, fence.close));
+                    fence.open.len()
+                } else {
+                    fence.open.len() + fence.content.len() + fence.close.len()
+                };
+                text.push_str(&rest[..length]);
+                position += length;
+                continue;
+            }
+            if c == '`' {
+                // backticks left unclosed are text
+                let length = code_span(rest)
+                    .unwrap_or_else(|| rest.len() - rest.trim_start_matches('`').len());
+                text.push_str(&rest[..length]);
+                position += length;
+                continue;
+            }
+            if let Some(length) = disabled_length(rest) {
+                text.push_str(&rest[..length]);
+                position += length;
+                continue;
+            }
+            for (start, end) in VERBATIM {
+                if let Some(inside) = rest.strip_prefix(start) {
+                    let length = inside
+                        .find(end)
+                        .map_or(rest.len(), |offset| start.len() + offset + end.len());
+                    text.push_str(&rest[..length]);
+                    position += length;
+                    continue 'outer;
+                }
+            }
+            // equations already rendered aren't rendered again
+            if let Some(length) = rendered_length(rest) {
+                text.push_str(&rest[..length]);
+                position += length;
+                continue;
+            }
+            let environment = if self.math_environments {
+                math_environment(rest)
+            } else {
+                None
+            };
+            if let Some(length) = environment {
+                if !text.is_empty() {
+                    spans.push(Span::Text(std::mem::take(&mut text)));
+                }
+                spans.push(Span::Math {
+                    source: &rest[..length],
+                    tex: &rest[..length],
+                    display: true,
+                });
+                position += length;
+                continue;
+            }
+            // escaped delimiter, kept as text without the backslash
+            if c == '\\' {
+                // an escaped backtick doesn't start a code span
+                if rest[1..].starts_with('`') {
+                    text.push_str("\\`");
+                    position += 2;
+                    continue;
+                }
+                // an escaped backslash doesn't escape what follows, as in `\\$x$`,
+                // unless it starts a delimiter like mathjax's `\\(`
+                let starts_delimiter = self
+                    .delimiters
+                    .iter()
+                    .any(|delimiter| rest.starts_with(&delimiter.marker));
+                if rest[1..].starts_with('\\') && !starts_delimiter {
+                    text.push_str("\\\\");
+                    position += 2;
+                    continue;
+                }
+                for delimiter in &self.delimiters {
+                    if delimiter.escapable() && rest[1..].starts_with(&delimiter.marker) {
+                        text.push_str(&delimiter.marker);
+                        position += 1 + delimiter.marker.len();
+                        continue 'outer;
+                    }
+                }
+            }
+            for delimiter in &self.delimiters {
+                if !rest.starts_with(&delimiter.marker) {
+                    continue;
+                }
+                let start = position + delimiter.marker.len();
+                let end = match find_closing(content, start, delimiter) {
+                    Some(end) if end > start => end,
+                    _ => continue,
+                };
+                let tex = &content[start..end];
+                if let (false, Some(max)) = (delimiter.display, self.max_inline_length) {
+                    if tex.len() > max {
+                        on_reject(tex);
+                        continue;
+                    }
+                }
+                if !text.is_empty() {
+                    spans.push(Span::Text(std::mem::take(&mut text)));
+                }
+                let after = end + delimiter.closing.len();
+                spans.push(Span::Math {
+                    source: &content[position..after],
+                    tex,
+                    display: delimiter.display,
+                });
+                position = after;
+                continue 'outer;
+            }
+            text.push(c);
+            position += c.len_utf8();
+        }
+        if !text.is_empty() {
+            spans.push(Span::Text(text));
+        }
+        spans
+    }
+}
+
+// position of the delimiter closing an equation starting at `start`
+fn find_closing(content: &str, start: usize, delimiter: &Delimiter) -> Option<usize> {
+    let mut position = start;
+    while let Some(offset) = content[position..].find(&delimiter.closing) {
+        let end = position + offset;
+        // code, comments and disabled regions before the delimiter are stepped
+        // over, and inline equations don't run past a blank line
+        let (text_end, skipped) = match copied_before(content, position, end) {
+            Some((skipped, length)) => (skipped, Some(skipped + length)),
+            None => (end, None),
+        };
+        if !delimiter.display && has_blank_line(&content[position..text_end]) {
+            return None;
+        }
+        if let Some(after) = skipped {
+            position = after;
+            continue;
+        }
+        // `\\` is a line break, so the delimiter after it is escaped only
+        // after an odd number of backslashes
+        let backslashes = content[..end].len() - content[..end].trim_end_matches('\\').len();
+        if delimiter.escapable() && backslashes % 2 == 1 {
+            position = end + delimiter.closing.len();
+            continue;
+        }
+        return Some(end);
+    }
+    None
+}
+
+// the first region copied as is between `from` and `to`, with its length
+fn copied_before(content: &str, from: usize, to: usize) -> Option<(usize, usize)> {
+    content[from..to].char_indices().find_map(|(offset, c)| {
+        let position = from + offset;
+        let line_start = position == 0 || content.as_bytes()[position - 1] == b'\n';
+        if !(line_start || c == '`' || c == '<') {
+            return None;
+        }
+        copied_length(content, position).map(|length| (position, length))
+    })
+}
+
+// whether some text has a line with only whitespace between two others
+fn has_blank_line(text: &str) -> bool {
+    let mut lines = text.split('\n');
+    lines.next();
+    let mut lines: Vec<&str> = lines.collect();
+    lines.pop();
+    lines.iter().any(|line| line.trim().is_empty())
+}
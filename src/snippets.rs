@@ -0,0 +1,112 @@
+// `\input{path}` inside equations, replaced with the file at `path`, relative
+// to the book root, so that long sub-expressions shared between equations can
+// live in one file
+use std::borrow::Cow;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{Context, Error};
+
+// snippets including snippets beyond this are most likely including each other
+pub const MAX_INPUT_DEPTH: usize = 16;
+
+const INPUT: &str = "\\input{";
+
+#[derive(Clone, Debug)]
+pub struct Snippets {
+    root: PathBuf,
+}
+
+impl Snippets {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Snippets { root: root.into() }
+    }
+
+    // the tex with each `\input{path}` replaced by the content of the file,
+    // including the snippets it includes itself
+    pub fn expand<'a>(&self, tex: &'a str) -> Result<Cow<'a, str>, Error> {
+        if !tex.contains(INPUT) {
+            return Ok(Cow::Borrowed(tex));
+        }
+        self.expand_at(tex, 0).map(Cow::Owned)
+    }
+
+    fn expand_at(&self, tex: &str, depth: usize) -> Result<String, Error> {
+        let mut expanded = String::with_capacity(tex.len());
+        let mut rest = tex;
+        while let Some((before, path, after)) = next_input(rest) {
+            if depth >= MAX_INPUT_DEPTH {
+                return Err(Error::msg(format!(
+                    "`\\input{{{}}}` is nested more than {} levels deep, \
+                     snippets might be including each other",
+                    path, MAX_INPUT_DEPTH
+                )));
+            }
+            expanded.push_str(before);
+            expanded.push_str(&self.expand_at(&self.load(path)?, depth + 1)?);
+            rest = after;
+        }
+        expanded.push_str(rest);
+        Ok(expanded)
+    }
+
+    // the content of the snippets some tex includes, directly or not, for cache
+    // keys; snippets that can't be read are left out, as they fail to render
+    pub fn included(&self, tex: &str) -> String {
+        let mut included = String::new();
+        let mut pending = vec![(String::from(tex), 0)];
+        while let Some((tex, depth)) = pending.pop() {
+            let mut rest = tex.as_str();
+            while let Some((_, path, after)) = next_input(rest) {
+                if let (true, Ok(snippet)) = (depth < MAX_INPUT_DEPTH, self.load(path)) {
+                    included.push_str(&snippet);
+                    pending.push((snippet, depth + 1));
+                }
+                rest = after;
+            }
+        }
+        included
+    }
+
+    // a snippet without the line break ending its file, which would otherwise
+    // be part of the equation
+    fn load(&self, path: &str) -> Result<String, Error> {
+        let name = path.trim();
+        // snippets are published with the book, so they can't be from outside it
+        if Path::new(name).has_root() || Path::new(name).is_absolute() {
+            return Err(Error::msg(format!(
+                "`\\input{{{}}}` names an absolute path, snippets must be in the book",
+                name
+            )));
+        }
+        let path = self.root.join(name);
+        let inside = match (path.canonicalize(), self.root.canonicalize()) {
+            (Ok(path), Ok(root)) => path.starts_with(root),
+            // missing snippets are reported when reading them
+            _ => !Path::new(name)
+                .components()
+                .any(|component| component == Component::ParentDir),
+        };
+        if !inside {
+            return Err(Error::msg(format!(
+                "`\\input{{{}}}` is outside the book, snippets must be in it",
+                name
+            )));
+        }
+        let snippet = fs::read_to_string(&path)
+            .with_context(|| format!("unable to read snippet {}", path.display()))?;
+        Ok(String::from(snippet.trim_end()))
+    }
+}
+
+// the tex before the next `\input{path}`, its path, and the tex after it
+fn next_input(tex: &str) -> Option<(&str, &str, &str)> {
+    let start = tex.find(INPUT)?;
+    let path_start = start + INPUT.len();
+    let path_len = tex[path_start..].find('}')?;
+    Some((
+        &tex[..start],
+        &tex[path_start..path_start + path_len],
+        &tex[path_start + path_len + 1..],
+    ))
+}
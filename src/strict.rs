@@ -0,0 +1,128 @@
+// warnings katex gives in its default strict mode, for LaTeX-incompatible
+// input it renders anyway; katex only prints them to a javascript console,
+// so the common ones are found here
+use crate::macros::control_sequences;
+
+// commands whose argument is text, where unicode characters are expected
+const TEXT_COMMANDS: &[&str] = &[
+    r"\text",
+    r"\textrm",
+    r"\textit",
+    r"\textbf",
+    r"\texttt",
+    r"\textsf",
+    r"\textup",
+    r"\textnormal",
+    r"\mbox",
+    r"\hbox",
+    r"\label",
+    r"\tag",
+];
+
+// a character that latex can't typeset in math mode, as katex decides it:
+// letters outside of the ascii and greek ranges it has math symbols for
+fn unicode_text_character(c: char) -> bool {
+    c.is_alphabetic() && !c.is_ascii() && !('\u{0370}'..='\u{03ff}').contains(&c)
+}
+
+// unicode text characters used in math mode, outside of text commands
+fn unicode_in_math_mode(tex: &str, warnings: &mut Vec<String>) {
+    let mut position = 0;
+    // brace depth outside of the text argument being read, if any
+    let mut text_depth: Option<usize> = None;
+    let mut depth = 0;
+    let mut reported: Vec<char> = Vec::new();
+    while let Some(c) = tex[position..].chars().next() {
+        let rest = &tex[position..];
+        match c {
+            '\\' => {
+                let length = match rest[1..].find(|c: char| !c.is_ascii_alphabetic()) {
+                    // a control symbol, like `\{`
+                    Some(0) => 1 + rest[1..].chars().next().map_or(0, char::len_utf8),
+                    Some(length) => 1 + length,
+                    None => rest.len(),
+                };
+                let name = &rest[..length];
+                if text_depth.is_none() && TEXT_COMMANDS.contains(&name) {
+                    let after = rest[length..].trim_start();
+                    if after.starts_with('{') {
+                        text_depth = Some(depth);
+                    }
+                }
+                position += name.len();
+                continue;
+            }
+            '{' => depth += 1,
+            '}' => {
+                depth = depth.saturating_sub(1);
+                if text_depth == Some(depth) {
+                    text_depth = None;
+                }
+            }
+            c if text_depth.is_none() && unicode_text_character(c) && !reported.contains(&c) => {
+                reported.push(c);
+                warnings.push(format!(
+                    "LaTeX-incompatible input and strict mode is set to 'warn': \
+                     Unicode text character \"{}\" used in math mode [unicodeTextInMathMode]",
+                    c
+                ));
+            }
+            _ => {}
+        }
+        position += c.len_utf8();
+    }
+}
+
+// a `%` comment running to the end of the equation, which would comment out
+// the closing delimiter in latex
+fn comment_at_end(tex: &str, warnings: &mut Vec<String>) {
+    let last_line = tex.rsplit('\n').next().unwrap_or(tex);
+    let mut backslashes = 0;
+    for c in last_line.chars() {
+        if c == '%' && backslashes % 2 == 0 {
+            warnings.push(String::from(
+                "LaTeX-incompatible input and strict mode is set to 'warn': \
+                 % comment has no terminating newline; LaTeX would fail because of \
+                 commenting the end of math mode (e.g. $) [commentAtEnd]",
+            ));
+            return;
+        }
+        backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+    }
+}
+
+// line breaks in display equations, outside of environments
+fn newline_in_display_mode(tex: &str, warnings: &mut Vec<String>) {
+    if tex.contains(r"\begin") {
+        return;
+    }
+    let names = control_sequences(tex);
+    if names
+        .iter()
+        .any(|name| name == r"\\" || name == r"\newline")
+    {
+        warnings.push(String::from(
+            "LaTeX-incompatible input and strict mode is set to 'warn': \
+             In LaTeX, \\\\ or \\newline does nothing in display mode [newLineInDisplayMode]",
+        ));
+    }
+}
+
+// strict mode warnings for an equation that renders
+pub fn warnings(tex: &str, display: bool) -> Vec<String> {
+    let mut warnings = Vec::new();
+    unicode_in_math_mode(tex, &mut warnings);
+    comment_at_end(tex, &mut warnings);
+    if display {
+        newline_in_display_mode(tex, &mut warnings);
+    }
+    warnings
+}
+
+// warnings as html comments, which can't contain `--`
+pub fn as_comments(warnings: &[String]) -> String {
+    warnings
+        .iter()
+        .map(|warning| format!("<!-- KaTeX warning: {} -->", warning.replace("--", "- -")))
+        .collect()
+}
@@ -1,108 +1,2922 @@
 use super::*;
+use std::collections::HashMap;
+use std::sync::Arc;
 
 #[test]
 fn test_name() {
-    let preprocessor = KatexProcessor;
+    let preprocessor = KatexProcessor::default();
     assert_eq!(preprocessor.name(), "katex")
 }
 
 #[test]
 fn test_support_html() {
-    let preprocessor = KatexProcessor;
+    let preprocessor = KatexProcessor::default();
     assert!(preprocessor.supports_renderer("html"));
     assert!(!preprocessor.supports_renderer("other_renderer"))
 }
 
-fn mock_build_opts(macros: HashMap<String, String>) -> (katex::Opts, katex::Opts) {
-    let inline_opts = katex::Opts::builder()
-        .display_mode(false)
-        .output_type(katex::OutputType::Html)
-        .macros(macros.clone())
-        .build()
-        .unwrap();
-    let display_opts = katex::Opts::builder()
-        .display_mode(true)
-        .output_type(katex::OutputType::Html)
-        .macros(macros)
-        .build()
-        .unwrap();
+fn mock_build_opts(macros: HashMap<String, String>) -> (RenderOpts, RenderOpts) {
+    let inline_opts = RenderOpts::new(false)
+        .with_output_type(OutputType::Html)
+        .with_macros(macros.clone());
+    let display_opts = RenderOpts::new(true)
+        .with_output_type(OutputType::Html)
+        .with_macros(macros);
     (inline_opts, display_opts)
 }
 
 #[test]
 fn test_rendering_without_math() {
-    let preprocessor = KatexProcessor;
     let macros = HashMap::new();
     let (inline_opts, display_opts) = mock_build_opts(macros);
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
     let raw_content = r"Some text, and more text.";
     let mut expected_output = katex_header();
     expected_output.push_str(raw_content);
-    let rendered_content = preprocessor.process_chapter(&raw_content, &inline_opts, &display_opts);
+    let rendered_content = renderer.process_chapter(raw_content, &mut |_| {});
     debug_assert_eq!(expected_output, rendered_content);
 }
 
 #[test]
 fn test_dollar_escaping() {
-    let preprocessor = KatexProcessor;
     let macros = HashMap::new();
     let (inline_opts, display_opts) = mock_build_opts(macros);
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
     let raw_content = r"Some text, \$\$ and more text.";
     let mut expected_output = katex_header();
     expected_output.push_str(r"Some text, $$ and more text.");
-    let rendered_content = preprocessor.process_chapter(&raw_content, &inline_opts, &display_opts);
+    let rendered_content = renderer.process_chapter(raw_content, &mut |_| {});
     debug_assert_eq!(expected_output, rendered_content);
 }
 
 #[test]
 fn test_inline_rendering() {
-    let preprocessor = KatexProcessor;
     let macros = HashMap::new();
     let (inline_opts, display_opts) = mock_build_opts(macros);
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
     let raw_content = r"Some text, $\nabla f(x) \in \mathbb{R}^n$, and more text.";
     let mut expected_output = katex_header();
     expected_output.push_str("Some text, <span class=\"katex\"><span class=\"katex-html\" aria-hidden=\"true\"><span class=\"base\"><span class=\"strut\" style=\"height:1em;vertical-align:-0.25em;\"></span><span class=\"mord\">∇</span><span class=\"mord mathnormal\" style=\"margin-right:0.10764em;\">f</span><span class=\"mopen\">(</span><span class=\"mord mathnormal\">x</span><span class=\"mclose\">)</span><span class=\"mspace\" style=\"margin-right:0.2777777777777778em;\"></span><span class=\"mrel\">∈</span><span class=\"mspace\" style=\"margin-right:0.2777777777777778em;\"></span></span><span class=\"base\"><span class=\"strut\" style=\"height:0.68889em;vertical-align:0em;\"></span><span class=\"mord\"><span class=\"mord\"><span class=\"mord mathbb\">R</span></span><span class=\"msupsub\"><span class=\"vlist-t\"><span class=\"vlist-r\"><span class=\"vlist\" style=\"height:0.664392em;\"><span style=\"top:-3.063em;margin-right:0.05em;\"><span class=\"pstrut\" style=\"height:2.7em;\"></span><span class=\"sizing reset-size6 size3 mtight\"><span class=\"mord mathnormal mtight\">n</span></span></span></span></span></span></span></span></span></span></span>, and more text.");
-    let rendered_content = preprocessor.process_chapter(&raw_content, &inline_opts, &display_opts);
+    let rendered_content = renderer.process_chapter(raw_content, &mut |_| {});
     debug_assert_eq!(expected_output, rendered_content);
 }
 
 #[test]
 fn test_display_rendering() {
-    let preprocessor = KatexProcessor;
     let macros = HashMap::new();
     let (inline_opts, display_opts) = mock_build_opts(macros);
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
     let raw_content = r"Some text, $\nabla f(x) \in \mathbb{R}^n$, and more text.";
     let mut expected_output = katex_header();
     expected_output.push_str("Some text, <span class=\"katex\"><span class=\"katex-html\" aria-hidden=\"true\"><span class=\"base\"><span class=\"strut\" style=\"height:1em;vertical-align:-0.25em;\"></span><span class=\"mord\">∇</span><span class=\"mord mathnormal\" style=\"margin-right:0.10764em;\">f</span><span class=\"mopen\">(</span><span class=\"mord mathnormal\">x</span><span class=\"mclose\">)</span><span class=\"mspace\" style=\"margin-right:0.2777777777777778em;\"></span><span class=\"mrel\">∈</span><span class=\"mspace\" style=\"margin-right:0.2777777777777778em;\"></span></span><span class=\"base\"><span class=\"strut\" style=\"height:0.68889em;vertical-align:0em;\"></span><span class=\"mord\"><span class=\"mord\"><span class=\"mord mathbb\">R</span></span><span class=\"msupsub\"><span class=\"vlist-t\"><span class=\"vlist-r\"><span class=\"vlist\" style=\"height:0.664392em;\"><span style=\"top:-3.063em;margin-right:0.05em;\"><span class=\"pstrut\" style=\"height:2.7em;\"></span><span class=\"sizing reset-size6 size3 mtight\"><span class=\"mord mathnormal mtight\">n</span></span></span></span></span></span></span></span></span></span></span>, and more text.");
-    let rendered_content = preprocessor.process_chapter(&raw_content, &inline_opts, &display_opts);
+    let rendered_content = renderer.process_chapter(raw_content, &mut |_| {});
     debug_assert_eq!(expected_output, rendered_content);
 }
 
 #[test]
 fn test_macros_without_argument() {
-    let preprocessor = KatexProcessor;
     let mut macros = HashMap::new();
     macros.insert(String::from(r"\grad"), String::from(r"\nabla"));
     let (inline_opts, display_opts) = mock_build_opts(macros);
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
     let raw_content_no_macro = r"Some text, $\nabla f(x) \in \mathbb{R}^n$, and more text.";
     let raw_content_macro = r"Some text, $\grad f(x) \in \mathbb{R}^n$, and more text.";
-    let rendered_content_macro =
-        preprocessor.process_chapter(&raw_content_macro, &inline_opts, &display_opts);
-    let rendered_content_no_macro =
-        preprocessor.process_chapter(&raw_content_no_macro, &inline_opts, &display_opts);
+    let rendered_content_macro = renderer.process_chapter(raw_content_macro, &mut |_| {});
+    let rendered_content_no_macro = renderer.process_chapter(raw_content_no_macro, &mut |_| {});
     debug_assert_eq!(rendered_content_macro, rendered_content_no_macro);
 }
 
 #[test]
 fn test_macros_with_argument() {
-    let preprocessor = KatexProcessor;
     let mut macros = HashMap::new();
     macros.insert(String::from(r"\R"), String::from(r"\mathbb{R}^#1"));
     let (inline_opts, display_opts) = mock_build_opts(macros);
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
     let raw_content_no_macro = r"Some text, $\nabla f(x) \in \mathbb{R}^1$, and more text.";
     let raw_content_macro = r"Some text, $\nabla f(x) \in \R{1}$, and more text.";
-    let rendered_content_macro =
-        preprocessor.process_chapter(&raw_content_macro, &inline_opts, &display_opts);
-    let rendered_content_no_macro =
-        preprocessor.process_chapter(&raw_content_no_macro, &inline_opts, &display_opts);
+    let rendered_content_macro = renderer.process_chapter(raw_content_macro, &mut |_| {});
+    let rendered_content_no_macro = renderer.process_chapter(raw_content_no_macro, &mut |_| {});
     debug_assert_eq!(rendered_content_macro, rendered_content_no_macro);
 }
+
+#[test]
+fn test_duplicate_macros_later_wins() {
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    let content = "\\grad:{\\nabla}\n\\R:{\\mathbb{R}}\n\\grad:{\\partial}";
+    map.parse(content, Path::new("macros.txt")).unwrap();
+    assert_eq!(map.get(r"\grad").unwrap().expansion, r"{\partial}");
+    assert_eq!(map.duplicates().len(), 1);
+    assert_eq!(
+        map.duplicates()[0].to_string(),
+        r"macro `\grad` defined at macros.txt:1 is redefined at macros.txt:3"
+    );
+}
+
+#[test]
+fn test_duplicate_macros_error() {
+    let mut map = MacroMap::new(DuplicatePolicy::Error);
+    let content = "\\grad:{\\nabla}\n\\grad:{\\partial}";
+    assert!(map.parse(content, Path::new("macros.txt")).is_err());
+}
+
+#[test]
+fn test_macro_expansion_trace() {
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    let content = "\\R:{\\mathbb{R}^{\\dims}}\n\\dims:{#1 \\timez #2}";
+    map.parse(content, Path::new("macros.txt")).unwrap();
+    let trace = map.trace(r"\nabla f(x) \in \R{n}{p}");
+    assert_eq!(
+        trace,
+        vec![
+            String::from(r"  \R -> {\mathbb{R}^{\dims}} (macros.txt:1)"),
+            String::from(r"    \dims -> {#1 \timez #2} (macros.txt:2)"),
+        ]
+    );
+}
+
+#[test]
+fn test_recursive_macros() {
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    let content = "\\a:{\\b + 1}\n\\b:{\\c}\n\\c:{\\a^2}\n\\d:{\\b}";
+    map.parse(content, Path::new("macros.txt")).unwrap();
+    let error = map.check_recursion().unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        r"macro `\a` is defined recursively: \a (macros.txt:1) -> \b (macros.txt:2) -> \c (macros.txt:3) -> \a (macros.txt:1)"
+    );
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    map.parse("\\a:{\\b\\b}\n\\b:{x}", Path::new("macros.txt"))
+        .unwrap();
+    assert!(map.check_recursion().is_ok());
+    // each macro is searched once, however many paths lead to it
+    let layer = |i: u8| (b'a' + i) as char;
+    let mut content = String::new();
+    for i in 0..24 {
+        for side in ["l", "r"] {
+            content.push_str(&format!(
+                "\\n{}{}:{{\\n{}l + \\n{}r}}\n",
+                layer(i),
+                side,
+                layer(i + 1),
+                layer(i + 1)
+            ));
+        }
+    }
+    content.push_str(&format!("\\n{}l:{{x}}\n", layer(24)));
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    map.parse(
+        &format!("{}\\n{}r:{{y}}", content, layer(24)),
+        Path::new("macros.txt"),
+    )
+    .unwrap();
+    assert!(map.check_recursion().is_ok());
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    map.parse(
+        &format!("{}\\n{}r:{{\\nar}}", content, layer(24)),
+        Path::new("macros.txt"),
+    )
+    .unwrap();
+    let error = map.check_recursion().unwrap_err().to_string();
+    assert!(error.ends_with(r"\nyr (macros.txt:50) -> \nar (macros.txt:2) -> \nbl (macros.txt:3)"));
+}
+
+#[test]
+fn test_cache_roundtrip() {
+    let dir = std::env::temp_dir().join(format!("mdbook-katex-test-{}", std::process::id()));
+    let caches: Vec<Box<dyn Cache>> =
+        vec![Box::new(FsCache::new(&dir)), Box::new(MemoryCache::new())];
+    for cache in caches {
+        let key = cache::hash("Some text, $x$.");
+        assert_eq!(cache.get("chapters", key), None);
+        cache.put("chapters", key, "rendered");
+        assert_eq!(cache.get("chapters", key), Some(String::from("rendered")));
+        assert_eq!(cache.get("equations", key), None);
+        assert_ne!(key, cache::hash("Some text, $y$."));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cache_dir_per_book() {
+    if std::env::var_os(cache::CACHE_DIR_VAR).is_some() {
+        return;
+    }
+    let first = FsCache::default_dir(Path::new("/books/first"));
+    let second = FsCache::default_dir(Path::new("/books/second"));
+    assert_ne!(first, second);
+    assert_eq!(first.parent(), second.parent());
+    assert_eq!(first, FsCache::default_dir(Path::new("/books/first")));
+}
+
+#[test]
+fn test_stable_hash() {
+    use std::hash::{Hash, Hasher};
+    // FNV-1a test vectors, so that keys don't change between builds
+    let fnv = |bytes: &[u8]| {
+        let mut hasher = cache::StableHasher::default();
+        hasher.write(bytes);
+        hasher.finish()
+    };
+    assert_eq!(fnv(b""), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv(b"a"), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(fnv(b"foobar"), 0x8594_4171_f739_67e8);
+    // nor between platforms
+    let hash = |value: &dyn Fn(&mut cache::StableHasher)| {
+        let mut hasher = cache::StableHasher::default();
+        value(&mut hasher);
+        hasher.finish()
+    };
+    assert_eq!(
+        hash(&|hasher| 7usize.hash(hasher)),
+        hash(&|hasher| 7u64.hash(hasher))
+    );
+    assert_eq!(hash(&|hasher| 1u32.hash(hasher)), fnv(&[1, 0, 0, 0]));
+}
+
+#[test]
+fn test_macros_used_by() {
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    map.parse(
+        "\\R:\\mathbb{R}\n\\vec:\\R^{#1}\n\\N:\\mathbb{N}",
+        Path::new("macros.txt"),
+    )
+    .unwrap();
+    assert_eq!(
+        map.used_by("Let $x \\in \\vec{2}$."),
+        vec![("\\R", "\\mathbb{R}"), ("\\vec", "\\R^{#1}")]
+    );
+    assert!(map.used_by("No macros, $x$.").is_empty());
+}
+
+#[test]
+fn test_newenvironment() {
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    map.parse(
+        "\\R:\\mathbb{R}\n\\newenvironment{vect}[1]{\\left(\\begin{matrix}#1\\\\}{\\end{matrix}\\right)}\n\\newenvironment{bold}{\\bf}{}",
+        Path::new("macros.txt"),
+    )
+    .unwrap();
+    assert_eq!(map.environment("vect").unwrap().parameters, 1);
+    assert_eq!(
+        map.expand_environments("\\begin{vect}{x} y \\end{vect}z"),
+        "\\begingroup \\left(\\begin{matrix}x\\\\ y \\end{matrix}\\right)\\endgroup z"
+    );
+    // nested, and unknown environments are left alone
+    assert_eq!(
+        map.expand_environments("\\begin{bold}\\begin{aligned}\\R\\end{aligned}\\end{bold}"),
+        "\\begingroup \\bf\\begin{aligned}\\R\\end{aligned}\\endgroup"
+    );
+    assert_eq!(
+        map.used_by("\\begin{bold}x\\end{bold}"),
+        vec![("bold", ""), ("bold", "\\bf")]
+    );
+    // rendered like the equivalent TeX
+    let mut macros = HashMap::new();
+    macros.insert(String::from("\\R"), String::from("\\mathbb{R}"));
+    let (inline_opts, display_opts) = mock_build_opts(macros);
+    let renderer = ChapterRenderer::new(inline_opts, display_opts).with_macros(map);
+    let expected = renderer.render("$$\\begingroup \\bf x\\endgroup y$$");
+    assert_eq!(renderer.render("$$\\begin{bold}x\\end{bold}y$$"), expected);
+
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    let error = map
+        .parse("\\newenvironment{broken}{", Path::new("macros.txt"))
+        .err()
+        .unwrap();
+    assert!(error
+        .to_string()
+        .starts_with("invalid environment definition at macros.txt:1"));
+}
+
+#[test]
+fn test_latex_macro_definitions() {
+    let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+    let preamble = "\\usepackage{amsmath}\n\
+                    \\newcommand{\\vect}[1]{\\boldsymbol{#1}}\n\
+                    \\renewcommand*\\R{\\mathbb{R}} % the reals\n\
+                    \\providecommand{\\R}{\\mathbb{Q}}\n\
+                    \\def\\pair#1#2{(#1, %\n  #2)}\n\
+                    \\DeclareMathOperator*{\\argmax}{arg\\,max}\n\
+                    \\newcommand{\\first}[2]{#1}";
+    map.parse(preamble, Path::new("preamble.tex")).unwrap();
+    let expansions: Vec<(&str, &str)> = [r"\vect", r"\R", r"\pair", r"\argmax", r"\first"]
+        .iter()
+        .map(|name| (*name, map.get(name).unwrap().expansion.as_str()))
+        .collect();
+    assert_eq!(
+        expansions,
+        vec![
+            (r"\vect", r"\boldsymbol{#1}"),
+            (r"\R", r"\mathbb{R}"),
+            (r"\pair", "(#1, #2)"),
+            (r"\argmax", r"\operatorname*{arg\,max}"),
+            (r"\first", r"#1\@firstoftwo{}{#2}"),
+        ]
+    );
+    assert_eq!(map.get(r"\pair").unwrap().location.line, 5);
+    assert!(map.duplicates().is_empty());
+    // rendered like the equivalent TeX
+    let (inline_opts, display_opts) = mock_build_opts(map.to_katex_macros());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let expected = renderer.render("$$\\boldsymbol{x} a (1, 2)$$");
+    assert_eq!(
+        renderer.render("$$\\vect{x} \\first{a}{b} \\pair{1}{2}$$"),
+        expected
+    );
+
+    let errors = [
+        (
+            "\\newcommand{\\opt}[2][x]{#1#2}",
+            "`\\opt` defined at m.tex:1 takes an optional argument, which KaTeX doesn't support",
+        ),
+        (
+            "\\newcommand{\\two}[1]{#1#2}",
+            "`\\two` defined at m.tex:1 uses `#2` but takes 1 arguments",
+        ),
+        (
+            "\\def\\dot#1.{#1}",
+            "invalid macro definition at m.tex:1, expected `\\def\\name#1#2{expansion}`",
+        ),
+    ];
+    for (definition, expected) in errors.iter() {
+        let mut map = MacroMap::new(DuplicatePolicy::LaterWins);
+        let error = map.parse(definition, Path::new("m.tex")).err().unwrap();
+        assert_eq!(error.to_string(), *expected);
+    }
+}
+
+#[test]
+fn test_cache_archive() {
+    let dir = std::env::temp_dir().join(format!("mdbook-katex-archive-{}", std::process::id()));
+    let cache = FsCache::new(&dir.join("from"));
+    cache.put("chapters", 1, "first");
+    cache.put("equations", 2, &"x".repeat(600));
+    let mut archive = Vec::new();
+    assert_eq!(cache::export(&dir.join("from"), &mut archive).unwrap(), 2);
+    assert_eq!(archive.len() % 512, 0);
+
+    assert_eq!(
+        cache::import(&dir.join("to"), &mut &archive[..]).unwrap(),
+        2
+    );
+    let restored = FsCache::new(&dir.join("to"));
+    assert_eq!(restored.get("chapters", 1), Some(String::from("first")));
+    assert_eq!(restored.get("equations", 2), Some("x".repeat(600)));
+    // entries are written next to their file, then moved in place
+    let files: Vec<_> = std::fs::read_dir(dir.join("to").join("chapters"))
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name())
+        .collect();
+    assert_eq!(files, ["0000000000000001"]);
+    assert!(cache::import(&dir.join("to"), &mut &archive[..700]).is_err());
+    // in the padding of the first entry
+    assert!(cache::import(&dir.join("to"), &mut &archive[..1000]).is_err());
+    let mut corrupted = archive.clone();
+    corrupted[124..135].copy_from_slice(b"77777777777");
+    let error = cache::import(&dir.join("to"), &mut &corrupted[..]).unwrap_err();
+    assert!(error.to_string().contains("too large"));
+    assert_eq!(cache::clear(&dir.join("to")).unwrap(), 2);
+    assert_eq!(restored.get("chapters", 1), None);
+    assert_eq!(cache::clear(&dir.join("missing")).unwrap(), 0);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_equation_cache() {
+    // a cache remembering the namespace of each entry stored
+    #[derive(Default)]
+    struct Recording(MemoryCache, std::sync::Mutex<Vec<String>>);
+    impl Cache for Recording {
+        fn get(&self, namespace: &str, key: u64) -> Option<String> {
+            self.0.get(namespace, key)
+        }
+        fn put(&self, namespace: &str, key: u64, value: &str) {
+            self.1.lock().unwrap().push(String::from(namespace));
+            self.0.put(namespace, key, value)
+        }
+    }
+    let cache = Arc::new(Recording::default());
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer =
+        ChapterRenderer::new(inline_opts, display_opts).with_cache(Box::new(cache.clone()));
+    let first = renderer.render("$x$ and $$y$$");
+    assert_eq!(
+        *cache.1.lock().unwrap(),
+        ["equations", "equations", "chapters"]
+    );
+    cache.1.lock().unwrap().clear();
+    // only the new equation, and the chapter, are stored
+    let second = renderer.render("$x$ and $$y$$ and $z$");
+    assert!(second.len() > first.len());
+    assert_eq!(*cache.1.lock().unwrap(), ["equations", "chapters"]);
+    cache.1.lock().unwrap().clear();
+    // failures aren't cached
+    renderer.render_many(&[MathSpan {
+        tex: "\\frac{",
+        display: false,
+    }]);
+    assert!(cache.1.lock().unwrap().is_empty());
+
+    // large chapters are cached by paragraph, starting without indentation
+    let paragraph = |name: &str| -> String {
+        (0..60)
+            .map(|i| format!("${}_{{{}}}$ ", name, i))
+            .collect::<String>()
+    };
+    let chapter = format!(
+        "{}\n\n- item\n\n      $$y$$\n\n{}",
+        paragraph("a"),
+        paragraph("b")
+    );
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let uncached = ChapterRenderer::new(inline_opts, display_opts);
+    assert_eq!(renderer.render(&chapter), uncached.render(&chapter));
+    let stored = cache.1.lock().unwrap().clone();
+    assert_eq!(
+        stored.iter().filter(|entry| *entry == "segments").count(),
+        3
+    );
+    cache.1.lock().unwrap().clear();
+    // editing a paragraph only renders it again
+    let edited = chapter.replace("b_{59}$", "b_{59}$ and $c$");
+    assert_eq!(renderer.render(&edited), uncached.render(&edited));
+    assert_eq!(
+        *cache.1.lock().unwrap(),
+        ["equations", "segments", "chapters"]
+    );
+}
+
+#[test]
+fn test_cache_backend() {
+    let mut cfg = toml::value::Table::new();
+    cfg.insert("cache".into(), true.into());
+    cfg.insert("cache-backend".into(), "memory".into());
+    let ctx = mock_context(cfg.clone());
+    let renderer = KatexProcessor::default()
+        .renderer_for(&(&ctx).into())
+        .unwrap();
+    assert_eq!(renderer.render("$x$"), renderer.render("$x$"));
+
+    cfg.insert("cache-backend".into(), "redis".into());
+    let ctx = mock_context(cfg);
+    assert!(KatexProcessor::default()
+        .renderer_for(&(&ctx).into())
+        .is_err());
+}
+
+#[test]
+fn test_cache_under_serve() {
+    let dir = std::env::temp_dir().join(format!("mdbook-katex-serve-{}", std::process::id()));
+    let mut cfg = toml::value::Table::new();
+    cfg.insert(
+        "cache-dir".into(),
+        toml::Value::from(dir.to_string_lossy().into_owned()),
+    );
+    let mut ctx = mock_context(cfg.clone());
+    assert!(KatexProcessor::default()
+        .renderer(&ctx)
+        .unwrap()
+        .cache
+        .is_none());
+    ctx.config
+        .set("output.html.live-reload-endpoint", "__livereload")
+        .unwrap();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    assert!(renderer.cache.is_some());
+    let (rendered, stats) = renderer.render_chapter_with_stats("Some $x$", true, "");
+    assert!(!stats.cached);
+    // as on the next rebuild, by a new preprocessor process
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let (cached, stats) = renderer.render_chapter_with_stats("Some $x$", true, "");
+    assert!(stats.cached);
+    assert_eq!(cached, rendered);
+    // the manifest counts the equations of cached chapters
+    assert_eq!(stats.equations, 1);
+    cfg.insert("cache".into(), false.into());
+    ctx.config.set("preprocessor.katex", cfg).unwrap();
+    assert!(KatexProcessor::default()
+        .renderer(&ctx)
+        .unwrap()
+        .cache
+        .is_none());
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = std::fs::metadata(dir.join("chapters"))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+    // without `cache-dir`, the cache is in memory, leaving nothing behind
+    let root = std::env::temp_dir().join(format!("mdbook-katex-serve-root-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    let mut ctx = mock_context(toml::value::Table::new());
+    ctx.root = root.clone();
+    ctx.config
+        .set("output.html.live-reload-endpoint", "__livereload")
+        .unwrap();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    assert!(renderer.cache.is_some());
+    renderer.render_chapter_with_stats("Some $x$", true, "");
+    let (_, stats) = renderer.render_chapter_with_stats("Some $x$", true, "");
+    assert!(stats.cached);
+    assert_eq!(std::fs::read_dir(&root).unwrap().count(), 0);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+fn mock_context(katex_config: toml::value::Table) -> PreprocessorContext {
+    let mut ctx: PreprocessorContext = serde_json::from_str(
+        r#"{"root": ".", "config": {}, "renderer": "html", "mdbook_version": "0.4.4"}"#,
+    )
+    .unwrap();
+    ctx.config.set("preprocessor.katex", katex_config).unwrap();
+    ctx
+}
+
+#[test]
+fn test_pipeline_matches_book_serialization() {
+    use mdbook::book::Chapter;
+    let mut book = Book::new();
+    let mut chapter = Chapter::new("One", String::from("Some $x$ text."), "one.md", vec![]);
+    chapter.sub_items.push(BookItem::Chapter(Chapter::new(
+        "Two",
+        String::from("$$\\nabla f$$"),
+        "two.md",
+        vec![String::from("One")],
+    )));
+    book.push_item(chapter);
+    book.push_item(BookItem::Separator);
+    book.push_item(Chapter::new(
+        "Three",
+        String::from("\"quoted\""),
+        "three.md",
+        vec![],
+    ));
+    let ctx = mock_context(toml::value::Table::new());
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let mut streamed = Vec::new();
+    pipeline::write_book(&renderer, book.clone(), &mut streamed).unwrap();
+    let expected =
+        serde_json::to_string(&KatexProcessor::default().run(&ctx, book).unwrap()).unwrap();
+    assert_eq!(String::from_utf8(streamed).unwrap(), expected);
+}
+
+#[test]
+fn test_render_single_chapter() {
+    use mdbook::book::Chapter;
+    use std::path::Path;
+    let mut book = Book::new();
+    book.push_item(Chapter::new(
+        "One",
+        String::from("$$a \\label{x}$$"),
+        "one.md",
+        vec![],
+    ));
+    book.push_item(Chapter::new(
+        "Two",
+        String::from("See $\\eqref{x}$."),
+        "two.md",
+        vec![],
+    ));
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("numbering"), toml::Value::from("book"));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let rendered = pipeline::render_chapter(&renderer, book.clone(), Path::new("two.md")).unwrap();
+    assert!(rendered.ends_with("See <a href=\"one.html#x\">(1)</a>."));
+    let error = pipeline::render_chapter(&renderer, book, Path::new("three.md"))
+        .err()
+        .unwrap();
+    assert_eq!(error.to_string(), "three.md isn't a chapter of the book");
+}
+
+#[test]
+fn test_error_mode() {
+    use mdbook::book::Chapter;
+    let content = "Some $x$ text.\n\nThen\n$$\\frac{a$$";
+    let mut book = Book::new();
+    book.push_item(Chapter::new("One", String::from(content), "one.md", vec![]));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    assert_eq!(renderer.error_mode(), ErrorMode::Warn);
+    let (_, stats) = renderer.render_chapter_with_stats(content, true, "");
+    assert_eq!(stats.failures[0].line, Some(4));
+    assert!(KatexProcessor::default()
+        .run(&mock_context(toml::value::Table::new()), book.clone())
+        .is_ok());
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("error-mode"), toml::Value::from("error"));
+    let error = KatexProcessor::default()
+        .run(&mock_context(config.clone()), book.clone())
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.to_string(),
+        "an equation failed to render:\n  one.md:4: couldn't render `\\frac{a`: \
+         unclosed `{` opened at column 6"
+    );
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("fail-on-error"), toml::Value::Boolean(true));
+    book.push_item(Chapter::new("Two", String::from("$b^$"), "two.md", vec![]));
+    let error = KatexProcessor::default()
+        .run(&mock_context(config.clone()), book.clone())
+        .err()
+        .unwrap();
+    assert!(error
+        .to_string()
+        .starts_with("2 equations failed to render:\n  one.md:4: "));
+    assert!(error
+        .to_string()
+        .contains("\n  two.md:1: couldn't render `b^`"));
+    let mut streamed = Vec::new();
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config.clone()))
+        .unwrap();
+    assert!(pipeline::write_book(&renderer, book, &mut streamed).is_err());
+    config.insert(String::from("error-mode"), toml::Value::from("warn"));
+    assert!(KatexProcessor::default()
+        .renderer(&mock_context(config.clone()))
+        .is_err());
+    config.insert(String::from("error-mode"), toml::Value::from("fatal"));
+    assert!(KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .is_err());
+}
+
+#[test]
+fn test_error_suggestions() {
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    let (_, stats) =
+        renderer.render_chapter_with_stats("$\\boldmath x$ and $\\nicefrac{1}{2}$", true, "");
+    assert_eq!(
+        stats.failures[0].suggestion.as_deref(),
+        Some(r"use `\boldsymbol` instead")
+    );
+    assert!(stats.failures[0]
+        .to_string()
+        .ends_with(r"; use `\boldsymbol` instead"));
+    assert_eq!(
+        stats.failures[1].suggestion.as_deref(),
+        Some(r"set `compat-shims = true` for an approximation of `\nicefrac`")
+    );
+    assert_eq!(
+        compat::suggestion("Undefined control sequence: \\foo", false),
+        None
+    );
+    assert_eq!(compat::suggestion("Expected '}'", false), None);
+}
+
+#[test]
+fn test_chapter_macros() {
+    use mdbook::book::Chapter;
+    let mut book = Book::new();
+    for (name, content) in [
+        ("preamble", "Notation. $$\\newcommand{\\R}{\\mathbb{R}}$$"),
+        ("one", "$$\\def\\v#1{\\mathbf{#1}}$$\n\n$\\v{x} \\in \\R$"),
+        ("two", "$\\v{y} \\in \\R$"),
+    ]
+    .iter()
+    {
+        book.push_item(Chapter::new(
+            name,
+            String::from(*content),
+            format!("{}.md", name),
+            vec![],
+        ));
+    }
+    let contents = |scope: &str| {
+        let mut config = toml::value::Table::new();
+        config.insert(String::from("chapter-macros"), toml::Value::from(scope));
+        config.insert(
+            String::from("preamble-chapter"),
+            toml::Value::from("preamble.md"),
+        );
+        let renderer = KatexProcessor::default()
+            .renderer(&mock_context(config))
+            .unwrap();
+        let mut book = book.clone();
+        chapter_macros::collect_macros(&renderer, &mut book);
+        let mut contents = Vec::new();
+        book.for_each_mut(|item| {
+            if let BookItem::Chapter(chapter) = item {
+                contents.push(chapter.content.clone());
+            }
+        });
+        contents
+    };
+    let chapter = contents("chapter");
+    assert_eq!(chapter[0], "Notation. ");
+    assert_eq!(
+        chapter[1],
+        "\n\n$\\gdef\\R{\\mathbb{R}}\\gdef\\v#1{\\mathbf{#1}}\\v{x} \\in \\R$"
+    );
+    assert_eq!(chapter[2], "$\\gdef\\R{\\mathbb{R}}\\v{y} \\in \\R$");
+    let book_scope = contents("book");
+    assert_eq!(
+        book_scope[2],
+        "$\\gdef\\R{\\mathbb{R}}\\gdef\\v#1{\\mathbf{#1}}\\v{y} \\in \\R$"
+    );
+    // rendered as the expanded tex
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    assert_eq!(
+        renderer.render(&book_scope[2]),
+        renderer.render("$\\mathbf{y} \\in \\mathbb{R}$")
+    );
+}
+
+#[test]
+fn test_numbering() {
+    use mdbook::book::{Chapter, SectionNumber};
+    let mut book = Book::new();
+    let mut one = Chapter::new(
+        "One",
+        String::from("$$a \\label{eq:a}$$\n\n$$b \\notag$$\n\n$$c$$"),
+        "one.md",
+        vec![],
+    );
+    one.number = Some(SectionNumber(vec![1]));
+    one.sub_items.push(BookItem::Chapter(Chapter::new(
+        "Two",
+        String::from("See $\\eqref{eq:a}$, $x = \\ref{eq:a}$ and $\\eqref{eq:z}$."),
+        "part/two.md",
+        vec![String::from("One")],
+    )));
+    book.push_item(one);
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("numbering"), toml::Value::from("chapter"));
+    let ctx = mock_context(config);
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let mut numbered = book.clone();
+    numbering::number_equations(&renderer, Numbering::Chapter, &mut numbered).unwrap();
+    let mut contents = Vec::new();
+    numbered.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            contents.push(chapter.content.clone());
+        }
+    });
+    assert_eq!(
+        contents[1],
+        "<span id=\"eq:a\"></span>$$a \\tag{1.1}$$\n\n$$b $$\n\n$$c \\tag{1.2}$$"
+    );
+    assert_eq!(
+        contents[0],
+        "See <a href=\"../one.html#eq:a\">(1.1)</a>, $x = \\text{1.1}$ and (??)."
+    );
+    // numbered across the book, and rendered
+    let mut book_config = toml::value::Table::new();
+    book_config.insert(String::from("numbering"), toml::Value::from("book"));
+    let mut rendered = KatexProcessor::default()
+        .run(&mock_context(book_config), book)
+        .unwrap();
+    let mut contents = Vec::new();
+    rendered.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            contents.push(chapter.content.clone());
+        }
+    });
+    assert!(contents[1].contains("<span id=\"eq:a\"></span><span class=\"katex-display\">"));
+    assert_eq!(contents[1].matches("class=\"tag\"").count(), 2);
+    assert!(contents[0].contains("href=\"../one.html#eq:a\">(1)</a>"));
+    // labels give anchors, and can't be used twice
+    let mut book = Book::new();
+    for (name, content) in [
+        ("One", "$$a \\label{eq:a}$$"),
+        ("Two", "$$b \\label{eq:a}$$"),
+    ]
+    .iter()
+    {
+        book.push_item(Chapter::new(
+            name,
+            String::from(*content),
+            format!("{}.md", name),
+            vec![],
+        ));
+    }
+    let error = numbering::number_equations(&renderer, Numbering::Book, &mut book)
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.to_string(),
+        "label `eq:a` of `Two` is already used in `One`, labels must be unique"
+    );
+}
+
+#[test]
+fn test_disabled_regions() {
+    let raw = "<!-- katex:disable -->\necho $HOME $PATH\n<!-- katex: enable -->\n$x$ and\n\n\
+               <!--katex:disable-->\n`$y$` costs $5 or $6";
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    let spans = renderer.scanner.scan(raw, &mut |_| {});
+    let equations: Vec<_> = spans
+        .iter()
+        .filter_map(|span| match span {
+            Span::Math { tex, .. } => Some(*tex),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(equations, vec!["x"]);
+    let (rendered, stats) = renderer.render_chapter_with_stats(raw, true, "");
+    assert_eq!(stats.equations, 1);
+    assert!(rendered.contains("<!-- katex:disable -->\necho $HOME $PATH\n<!-- katex: enable -->\n"));
+    assert!(rendered.ends_with("<!--katex:disable-->\n`$y$` costs $5 or $6"));
+    // a dollar before a region doesn't close inside it
+    for raw in [
+        "Costs $5.\n\n<!-- katex:disable -->\nUse $HOME and $PATH\n<!-- katex:enable -->",
+        "Costs $5.\n<!-- katex:disable -->\nUse $HOME and $PATH\n<!-- katex:enable -->",
+    ] {
+        let (rendered, stats) = renderer.render_chapter_with_stats(raw, true, "");
+        assert_eq!(stats.equations, 0);
+        assert!(rendered.ends_with(raw), "{:?}", rendered);
+    }
+}
+
+#[test]
+fn test_closing_delimiter_outside_code() {
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    for raw in [
+        "price $5 and `$HOME` here",
+        "It costs $5.\n\n```sh\necho $HOME\n```\n",
+        "It costs $5.\n\n<!-- a $ b -->",
+    ] {
+        let spans = renderer.scanner.scan(raw, &mut |_| {});
+        assert!(
+            spans.iter().all(|span| !matches!(span, Span::Math { .. })),
+            "{:?}",
+            raw
+        );
+        let (rendered, stats) = renderer.render_chapter_with_stats(raw, true, "");
+        assert_eq!(stats.equations, 0);
+        assert!(rendered.ends_with(raw), "{:?}", rendered);
+    }
+    // inline equations don't run past a blank line
+    let spans = renderer
+        .scanner
+        .scan("costs $5\n\nand $6 more", &mut |_| {});
+    assert!(spans.iter().all(|span| !matches!(span, Span::Math { .. })));
+}
+
+#[test]
+fn test_math_code() {
+    let raw = "Inline $`x^2`$ and\n\n```math\na + b\n```\nafter ``$`y`$``, and\n\n```rust\nlet x = 1;\n```";
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    let spans = renderer.scanner.scan(raw, &mut |_| {});
+    let equations: Vec<_> = spans
+        .iter()
+        .filter_map(|span| match span {
+            Span::Math {
+                source,
+                tex,
+                display,
+            } => Some((*source, *tex, *display)),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        equations,
+        vec![
+            ("$`x^2`$", "x^2", false),
+            ("```math\na + b\n```", "a + b\n", true)
+        ]
+    );
+    let rendered = renderer.render(raw);
+    assert!(rendered.contains(&renderer.render("$x^2$")[katex_header().len()..]));
+    assert!(rendered.contains("</span>\nafter ``$`y`$``"));
+    // left as code with `math-code = false`
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("math-code"), toml::Value::from(false));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    assert!(renderer.render(raw).ends_with(&raw[15..]));
+}
+
+#[test]
+fn test_math_environments() {
+    use mdbook::book::Chapter;
+    assert_eq!(
+        tex::translate_environments(
+            r"\begin{align} a \tag{5} \\ b \label{b} \nonumber \end{align}"
+        ),
+        r"\begin{aligned} a \\ b   \end{aligned} \tag{5}"
+    );
+    assert_eq!(
+        tex::translate_environments(r"\begin{eqnarray*} a &=& b \end{eqnarray*}"),
+        r"\begin{array}{rcl} a &=& b \end{array}"
+    );
+    let raw = "Where\n\\begin{align}\na &= b \\\\\nc &= d\n\\end{align}\nand \
+               \\begin{cases} x & y \\end{cases}, not `\\begin{gather}a\\end{gather}`.";
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    let rendered = renderer.render(raw);
+    assert_eq!(
+        rendered.matches("<span class=\"katex-display\">").count(),
+        2
+    );
+    assert!(!rendered.contains("\\begin{align}"));
+    assert!(rendered.contains("`\\begin{gather}a\\end{gather}`"));
+    // left as text with `math-environments = false`
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("math-environments"), toml::Value::from(false));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    assert!(renderer.render(raw).ends_with(raw));
+    // numbered unless starred
+    let mut book = Book::new();
+    book.push_item(Chapter::new(
+        "One",
+        String::from("\\begin{align*}a\\end{align*} \\begin{gather}b \\label{g}\\end{gather}"),
+        "one.md",
+        vec![],
+    ));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    numbering::number_equations(&renderer, Numbering::Book, &mut book).unwrap();
+    let mut contents = Vec::new();
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            contents.push(chapter.content.clone());
+        }
+    });
+    assert_eq!(
+        contents[0],
+        "\\begin{align*}a\\end{align*} <span id=\"g\"></span>\\begin{gather}b  \\tag{1}\\end{gather}"
+    );
+    let rendered = renderer.render(&contents[0]);
+    assert_eq!(rendered.matches("class=\"tag\"").count(), 1);
+}
+
+#[test]
+fn test_manifest() {
+    use mdbook::book::Chapter;
+    let dir = std::env::temp_dir().join(format!("mdbook-katex-manifest-{}", std::process::id()));
+    let path = dir.join("katex-manifest.json");
+    let mut config = toml::value::Table::new();
+    config.insert(
+        String::from("manifest"),
+        toml::Value::String(path.to_string_lossy().into_owned()),
+    );
+    let mut book = Book::new();
+    book.push_item(Chapter::new(
+        "One",
+        String::from("$x$ and $\\frac{$ and $$y$$"),
+        "one.md",
+        vec![],
+    ));
+    book.push_item(Chapter::new(
+        "Two",
+        String::from("---\nkatex: off\n---\n$z$"),
+        "two.md",
+        vec![],
+    ));
+    KatexProcessor::default()
+        .run(&mock_context(config), book)
+        .unwrap();
+    let manifest: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert_eq!(manifest["renderer"], "html");
+    assert_eq!(manifest["equations"], 3);
+    assert_eq!(manifest["failures"], 1);
+    assert_eq!(manifest["cache"], serde_json::Value::Null);
+    let chapters = manifest["chapters"].as_array().unwrap();
+    assert_eq!(chapters[0]["name"], "One");
+    assert_eq!(chapters[0]["failures"][0]["tex"], "\\frac{");
+    assert_eq!(chapters[1]["path"], "two.md");
+    assert_eq!(chapters[1]["rendered"], false);
+}
+
+#[test]
+fn test_reading_time() {
+    let spans = Scanner::default().scan(
+        "Let $x$ be - a real number.\n\n$$x^2 \\geq 0$$\n",
+        &mut |_| {},
+    );
+    let reading = Reading::measure(&spans, &ReadingSpeed::default());
+    assert_eq!(reading.words, 5);
+    assert_eq!(
+        (reading.inline_equations, reading.display_equations),
+        (1, 1)
+    );
+    assert_eq!(reading.equation_density, 400.0);
+    // 5 words, and equations read as long as 25 more, at 200 words per minute
+    assert_eq!(reading.reading_minutes, 0.15);
+
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("reading-time"), toml::Value::from(true));
+    config.insert(String::from("words-per-minute"), toml::Value::from(100));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let (_, stats) = renderer.render_chapter_with_stats("Let $x$ be", true, "");
+    let reading = stats.reading.as_ref().unwrap();
+    assert_eq!(reading.words, 2);
+    assert_eq!(reading.reading_minutes, 0.07);
+    let mut manifest = Manifest::new("html", false);
+    manifest.add("One", None, stats.clone());
+    manifest.add("Two", None, stats);
+    let json = manifest.to_json();
+    assert_eq!(json["reading_minutes"], 0.14);
+    assert_eq!(json["chapters"][0]["words"], 2);
+    assert_eq!(json["chapters"][0]["inline_equations"], 1);
+    // without `reading-time`
+    let (_, stats) = ChapterRenderer::new(RenderOpts::new(false), RenderOpts::new(true))
+        .render_chapter_with_stats("Let $x$ be", true, "");
+    assert_eq!(stats.reading, None);
+}
+
+#[test]
+fn test_raw_opts() {
+    let raw_opts = |value: &str| {
+        let mut config = toml::value::Table::new();
+        config.insert(String::from("raw-opts"), toml::Value::from(value));
+        KatexProcessor::default().renderer(&mock_context(config))
+    };
+    // errors are rendered in the given color, instead of failing
+    let renderer = raw_opts(r##"{"throwOnError": false, "errorColor": "#abcdef"}"##).unwrap();
+    let rendered = renderer.render_many(&[MathSpan {
+        tex: "\\frac{",
+        display: false,
+    }]);
+    assert!(rendered[0].as_ref().unwrap().contains("#abcdef"));
+    let renderer = raw_opts(r#"{"maxExpand": 0}"#).unwrap();
+    // macro expansions are limited
+    assert!(
+        ChapterRenderer::new(RenderOpts::new(false), RenderOpts::new(true)).render_many(&[
+            MathSpan {
+                tex: "\\def\\a{x}\\a",
+                display: false,
+            }
+        ])[0]
+            .is_ok()
+    );
+    assert!(renderer.render_many(&[MathSpan {
+        tex: "\\def\\a{x}\\a",
+        display: false,
+    }])[0]
+        .is_err());
+
+    for (value, cause) in [
+        (
+            "[1]",
+            "invalid option `raw-opts` in `[preprocessor.katex]`, expected a JSON object",
+        ),
+        (
+            r#"{"output": "mathml"}"#,
+            "`output` is set with the `output` option rather than in `raw-opts`",
+        ),
+        (
+            r#"{"trust": 1}"#,
+            "invalid value for `trust`, expected a boolean",
+        ),
+    ] {
+        let error = raw_opts(value).err().unwrap();
+        assert_eq!(error.chain().last().unwrap().to_string(), cause);
+    }
+    let error = raw_opts(r#"{"strict": false}"#).err().unwrap();
+    assert!(error
+        .root_cause()
+        .to_string()
+        .starts_with("unknown katex option `strict`"));
+}
+
+#[test]
+fn test_katex_option_keys() {
+    let renderer = |options: &[(&str, toml::Value)]| {
+        let mut config = toml::value::Table::new();
+        for (key, value) in options {
+            config.insert(String::from(*key), value.clone());
+        }
+        KatexProcessor::default().renderer(&mock_context(config))
+    };
+    let renderer_with_colors = renderer(&[
+        ("throw-on-error", toml::Value::from(false)),
+        ("error-color", toml::Value::from("#abcdef")),
+    ])
+    .unwrap();
+    let rendered = renderer_with_colors.render_many(&[MathSpan {
+        tex: "\\frac{",
+        display: true,
+    }]);
+    assert!(rendered[0].as_ref().unwrap().contains("#abcdef"));
+    let limited = renderer(&[("max-expand", toml::Value::from(0))]).unwrap();
+    assert!(limited.render_many(&[MathSpan {
+        tex: "\\def\\a{x}\\a",
+        display: false,
+    }])[0]
+        .is_err());
+    // `\href` needs trust
+    let href = [MathSpan {
+        tex: "\\href{https://katex.org}{x}",
+        display: false,
+    }];
+    let untrusted = renderer(&[("throw-on-error", toml::Value::from(false))]).unwrap();
+    assert!(!untrusted.render_many(&href)[0]
+        .as_ref()
+        .unwrap()
+        .contains("katex.org"));
+    let trusted = renderer(&[("trust", toml::Value::from(true))]).unwrap();
+    assert!(trusted.render_many(&href)[0]
+        .as_ref()
+        .unwrap()
+        .contains("href=\"https://katex.org\""));
+    // an option is set in one place only
+    let error = renderer(&[
+        ("max-expand", toml::Value::from(10)),
+        ("raw-opts", toml::Value::from(r#"{"maxExpand": 5}"#)),
+    ])
+    .err()
+    .unwrap();
+    assert_eq!(
+        error.root_cause().to_string(),
+        "`maxExpand` is set with both `max-expand` and `raw-opts` in `[preprocessor.katex]`"
+    );
+}
+
+#[test]
+fn test_retry_with_defaults() {
+    let mut config = toml::value::Table::new();
+    config.insert(
+        String::from("raw-opts"),
+        toml::Value::from(r#"{"maxExpand": 0}"#),
+    );
+    config.insert(String::from("retry-with-defaults"), toml::Value::from(true));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    // the equation failing with `maxExpand`, rendered with the default options
+    let (rendered, stats) =
+        renderer.render_chapter_with_stats("$\\def\\a{x}\\a$ and $\\frac{$", true, "");
+    assert!(rendered.contains("<span class=\"katex\">"));
+    assert!(rendered.ends_with(" and $\\frac{$"));
+    assert_eq!(stats.fallbacks.len(), 1);
+    assert_eq!(stats.fallbacks[0].tex, "\\def\\a{x}\\a");
+    assert_eq!(stats.failures.len(), 1);
+    assert_eq!(stats.failures[0].tex, "\\frac{");
+}
+
+#[test]
+fn test_parallel_rendering() {
+    let items: Vec<usize> = (0..100).collect();
+    let mut results = Vec::new();
+    parallel::for_each_ordered(
+        &items,
+        4,
+        |item| item * 2,
+        |index, result| {
+            results.push((index, result));
+            index < 49
+        },
+    );
+    assert_eq!(
+        results,
+        (0..50).map(|index| (index, index * 2)).collect::<Vec<_>>()
+    );
+
+    // large batches of equations come out as when rendered one after the other
+    let tex: Vec<String> = (0..300).map(|i| format!("x^{{{}}}", i)).collect();
+    let mut spans: Vec<MathSpan> = tex
+        .iter()
+        .map(|tex| MathSpan {
+            tex,
+            display: false,
+        })
+        .collect();
+    spans[10].tex = "\\frac{";
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let sequential = renderer.render_many(&spans);
+    assert!(sequential[10].is_err());
+    assert_eq!(renderer.with_jobs(4).render_many(&spans), sequential);
+}
+
+#[test]
+fn test_parallel_diagnostics_order() {
+    // diagnostics of workers come out in the order of the items
+    let items: Vec<usize> = (0..40).collect();
+    let ((), captured) = diagnostics::capture(|| {
+        parallel::for_each_ordered(
+            &items,
+            4,
+            |item| {
+                diagnostics::warn(&format!("item {}", item));
+                diagnostics::warn_once("once");
+            },
+            |_, ()| true,
+        )
+    });
+    let messages: Vec<&str> = captured
+        .iter()
+        .filter(|diagnostic| !diagnostic.once)
+        .map(|diagnostic| diagnostic.message.as_str())
+        .collect();
+    let expected: Vec<String> = items.iter().map(|item| format!("item {}", item)).collect();
+    assert_eq!(messages, expected);
+    // warnings emitted once are deduplicated when printed
+    assert_eq!(
+        captured.iter().filter(|diagnostic| diagnostic.once).count(),
+        40
+    );
+    assert_eq!(captured[1].severity, diagnostics::Severity::Warning);
+}
+
+#[test]
+fn test_static_css() {
+    let root = std::env::temp_dir().join(format!("mdbook-katex-static-{}", std::process::id()));
+    let dist = root.join("node_modules/katex/dist");
+    std::fs::create_dir_all(dist.join("fonts")).unwrap();
+    std::fs::write(dist.join("katex.min.css"), ".katex{}").unwrap();
+    std::fs::write(dist.join("fonts/KaTeX_Main-Regular.woff2"), "font").unwrap();
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("static-css"), toml::Value::Boolean(true));
+    let mut ctx = mock_context(config);
+    ctx.root = root.clone();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let rendered = renderer.render_chapter("$x$", true, "../");
+    assert!(rendered.contains("href=\"../katex/katex.min.css\""));
+    let copied = root.join("src/katex");
+    assert_eq!(
+        std::fs::read_to_string(copied.join("katex.min.css")).unwrap(),
+        ".katex{}"
+    );
+    assert!(copied.join("fonts/KaTeX_Main-Regular.woff2").is_file());
+    // unchanged files aren't written again
+    assert_eq!(
+        assets::install(&dist, &copied.join("katex.min.css")).unwrap(),
+        0
+    );
+    std::fs::remove_dir_all(&dist).unwrap();
+    assert!(KatexProcessor::default().renderer(&ctx).is_err());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_macros_relative_to_root() {
+    let root = std::env::temp_dir().join(format!("mdbook-katex-macros-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("macros.txt"), "\\R:\\mathbb{R}").unwrap();
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("macros"), toml::Value::from("macros.txt"));
+    let mut ctx = mock_context(config.clone());
+    ctx.root = root.clone();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    assert!(renderer.render("$\\R$").contains("mathbb"));
+    // a missing file is an error naming the resolved path
+    std::fs::remove_file(root.join("macros.txt")).unwrap();
+    let error = KatexProcessor::default().renderer(&ctx).err().unwrap();
+    assert!(format!("{:#}", error).contains(&root.join("macros.txt").display().to_string()));
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_macro_files_and_preamble() {
+    let root = std::env::temp_dir().join(format!("mdbook-katex-preamble-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("sets.txt"), "\\R:\\mathbb{R}").unwrap();
+    std::fs::write(
+        root.join("vectors.tex"),
+        "\\newcommand{\\v}[1]{\\mathbf{#1}}",
+    )
+    .unwrap();
+    std::fs::write(
+        root.join("preamble.tex"),
+        "\\gdef\\half{\\frac{1}{2}} % shared by every equation\n",
+    )
+    .unwrap();
+    let table: toml::value::Table =
+        toml::from_str("macros = [\"sets.txt\", \"vectors.tex\"]\npreamble = \"preamble.tex\"")
+            .unwrap();
+    let mut ctx = mock_context(table);
+    ctx.root = root.clone();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let expected = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap()
+        .render("$\\mathbf{x} \\in \\mathbb{R}, \\frac{1}{2}$");
+    assert_eq!(renderer.render("$\\v{x} \\in \\R, \\half$"), expected);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_book_metadata() {
+    let root = std::env::temp_dir().join(format!("mdbook-katex-metadata-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(
+        root.join("book.toml"),
+        "[book.metadata.math]\ninline = false\nfleqn = true\n\
+         [book.metadata.math.macros]\n\"\\\\R\" = \"\\\\mathbb{R}\"\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("math.toml"), "[math]\nfleqn = false\n").unwrap();
+    let table: toml::value::Table =
+        toml::from_str("metadata = \"math.toml\"\ninline = true").unwrap();
+    let mut ctx = mock_context(table);
+    ctx.root = root.clone();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let expected = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap()
+        .render("$\\mathbb{R}$ and $$x$$");
+    assert_eq!(renderer.render("$\\R$ and $$x$$"), expected);
+    std::fs::write(root.join("math.toml"), "[math]\nfleqn = \"yes\"\n").unwrap();
+    let error = KatexProcessor::default().renderer(&ctx).err().unwrap();
+    assert!(format!("{:#}", error).starts_with("invalid `math` metadata in "));
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_input_snippets() {
+    let root = std::env::temp_dir().join(format!("mdbook-katex-snippets-{}", std::process::id()));
+    std::fs::create_dir_all(root.join("snippets")).unwrap();
+    std::fs::write(
+        root.join("snippets/matrix-def.tex"),
+        "\\begin{pmatrix} a & b \\\\ c & \\input{snippets/d.tex} \\end{pmatrix}\n",
+    )
+    .unwrap();
+    std::fs::write(root.join("snippets/d.tex"), "d\n").unwrap();
+    let mut ctx = mock_context(toml::value::Table::new());
+    ctx.root = root.clone();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    assert_eq!(
+        renderer.render("$$A = \\input{snippets/matrix-def.tex}$$"),
+        renderer.render("$$A = \\begin{pmatrix} a & b \\\\ c & d \\end{pmatrix}$$")
+    );
+    // snippets that can't be read leave the equation failing
+    let mut errors = Vec::new();
+    renderer.process_chapter("$\\input{snippets/missing.tex}$", &mut |tex| {
+        errors.push(String::from(tex))
+    });
+    assert_eq!(errors, [r"\input{snippets/missing.tex}"]);
+    // snippets including each other
+    std::fs::write(root.join("snippets/d.tex"), "\\input{snippets/d.tex}").unwrap();
+    let snippets = snippets::Snippets::new(&root);
+    assert!(snippets.expand(r"\input{snippets/d.tex}").is_err());
+    assert_eq!(snippets.included("x"), "");
+    // nor anything outside the book
+    std::fs::write(root.with_extension("tex"), "secret").unwrap();
+    let outside = format!(
+        "../{}",
+        root.with_extension("tex")
+            .file_name()
+            .unwrap()
+            .to_string_lossy()
+    );
+    for input in [
+        format!(r"\input{{{}}}", root.with_extension("tex").display()),
+        format!(r"\input{{{}}}", outside),
+        String::from(r"\input{../missing.tex}"),
+    ] {
+        let error = snippets.expand(&input).unwrap_err().to_string();
+        assert!(error.contains("snippets must be in"), "{}", error);
+        assert_eq!(snippets.included(&input), "");
+    }
+    std::fs::remove_file(root.with_extension("tex")).unwrap();
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_balance_check() {
+    assert_eq!(tex::check_balance(r"\frac{a}{b} + \{x\} \% 50 % {"), Ok(()));
+    assert_eq!(
+        tex::check_balance(r"x + \frac{a}{b + \sqrt{c}"),
+        Err(String::from("unclosed `{` opened at column 13"))
+    );
+    assert_eq!(
+        tex::check_balance(r"a}"),
+        Err(String::from("unmatched `}` at column 2"))
+    );
+    assert_eq!(
+        tex::check_balance("\\begin{pmatrix} a \\\\\n b \\end{bmatrix}"),
+        Err(String::from(
+            "`\\end{bmatrix}` at line 2, column 4 doesn't match `\\begin{pmatrix}` opened at column 1"
+        ))
+    );
+    assert_eq!(
+        tex::check_balance(r"\left( x \right) + \left[ y"),
+        Err(String::from(r"unclosed `\left` opened at column 20"))
+    );
+    assert_eq!(tex::check_balance("{a % }\n}"), Ok(()));
+    // equations are reported with it
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    let (_, stats) = renderer.render_chapter_with_stats("$\\frac{a$", true, "");
+    assert_eq!(stats.failures[0].error, "unclosed `{` opened at column 6");
+    // unless katex renders errors itself
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("throw-on-error"), toml::Value::Boolean(false));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let (_, stats) = renderer.render_chapter_with_stats("$\\frac{a$", true, "");
+    assert!(stats.failures.is_empty());
+}
+
+#[test]
+fn test_invalid_option_error_chain() {
+    let mut config = toml::value::Table::new();
+    config.insert(
+        String::from("duplicate-macros"),
+        toml::Value::String(String::from("bogus")),
+    );
+    let error = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .err()
+        .unwrap();
+    let chain: Vec<String> = error.chain().map(|cause| cause.to_string()).collect();
+    assert_eq!(
+        chain,
+        vec![
+            "invalid option `duplicate-macros` in `[preprocessor.katex]`",
+            "unknown value `bogus`, expected \"later-wins\" or \"error\"",
+        ]
+    );
+}
+
+#[test]
+fn test_katex_config() {
+    assert_eq!(
+        KatexConfig::from_table(None).unwrap(),
+        KatexConfig::default()
+    );
+    let table: toml::value::Table = toml::from_str(
+        r#"
+        command = "mdbook-katex"
+        after = ["links"]
+        macros = "macros.txt"
+        max-inline-length = 0
+        nowrap-inline = true
+        data-blocks = ["quiz"]
+        delimiters = { "\\(" = "inline" }
+        "#,
+    )
+    .unwrap();
+    let config = KatexConfig::from_table(Some(&table)).unwrap();
+    assert_eq!(config.macros, ["macros.txt"]);
+    assert_eq!(config.max_inline_length, Some(0));
+    assert!(config.nowrap_inline);
+    assert!(!config.leqno);
+    assert_eq!(config.data_blocks, vec!["quiz"]);
+    assert_eq!(config.delimiters.unwrap()["\\("], "inline");
+
+    let table: toml::value::Table = toml::from_str("leqno = \"yes\"").unwrap();
+    let error = KatexConfig::from_table(Some(&table)).err().unwrap();
+    assert_eq!(error.to_string(), "invalid `[preprocessor.katex]` table");
+    assert!(error.chain().nth(1).unwrap().to_string().contains("leqno"));
+}
+
+#[test]
+fn test_use_color() {
+    use std::ffi::OsString;
+    assert!(diagnostics::use_color(None, true));
+    assert!(diagnostics::use_color(Some(OsString::new()), true));
+    assert!(!diagnostics::use_color(Some(OsString::from("1")), true));
+    assert!(!diagnostics::use_color(None, false));
+}
+
+#[test]
+fn test_custom_delimiters() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer =
+        ChapterRenderer::new(inline_opts, display_opts).with_scanner(Scanner::new(vec![
+            Delimiter::new("$$", false),
+            Delimiter::new("@@", true),
+        ]));
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let default_renderer = ChapterRenderer::new(inline_opts, display_opts);
+    assert_eq!(
+        renderer.process_chapter(r"Some $$x$$ and @@y@@, for $5.", &mut |_| {}),
+        default_renderer.process_chapter(r"Some $x$ and $$y$$, for \$5.", &mut |_| {})
+    );
+}
+
+#[test]
+fn test_mathjax_delimiters() {
+    let mut config = toml::value::Table::new();
+    config.insert(
+        String::from("mathjax-delimiters"),
+        toml::Value::Boolean(true),
+    );
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let default_renderer = ChapterRenderer::new(inline_opts, display_opts);
+    assert_eq!(
+        renderer.process_chapter(
+            r"For $5, \\(\\{x\\}\\) and \\[a \\\\ b\\] or $$\\,y$$",
+            &mut |_| {}
+        ),
+        default_renderer
+            .process_chapter(r"For \$5, $\{x\}$ and $$a \\ b$$ or $$\,y$$", &mut |_| {})
+    );
+}
+
+#[test]
+fn test_latex_delimiters() {
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("latex-delimiters"), toml::Value::Boolean(true));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let default_renderer = ChapterRenderer::new(inline_opts, display_opts);
+    assert_eq!(
+        renderer.process_chapter(r"For $x$, \(\{y\}\) and \[a \\ b\] but \\(z", &mut |_| {}),
+        default_renderer.process_chapter(r"For $x$, $\{y\}$ and $$a \\ b$$ but \\(z", &mut |_| {})
+    );
+    // pairs of markers in the delimiters table
+    let mut delimiters = toml::value::Table::new();
+    delimiters.insert(String::from("<< >>"), toml::Value::from("inline"));
+    delimiters.insert(String::from("$$"), toml::Value::from("display"));
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("delimiters"), toml::Value::Table(delimiters));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    assert_eq!(
+        renderer.process_chapter("<<x>> and $$y$$", &mut |_| {}),
+        default_renderer.process_chapter("$x$ and $$y$$", &mut |_| {})
+    );
+    let mut delimiters = toml::value::Table::new();
+    delimiters.insert(String::from("< > >"), toml::Value::from("inline"));
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("delimiters"), toml::Value::Table(delimiters));
+    let error = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .err()
+        .unwrap();
+    assert!(error
+        .root_cause()
+        .to_string()
+        .starts_with("invalid delimiter `< > >`"));
+}
+
+#[test]
+fn test_delimiter_pairs() {
+    let renderer = |block: &[&str], inline: Option<&[&str]>| {
+        let mut config = toml::value::Table::new();
+        config.insert(
+            String::from("block-delimiter"),
+            toml::Value::from(block.to_vec()),
+        );
+        if let Some(inline) = inline {
+            config.insert(
+                String::from("inline-delimiter"),
+                toml::Value::from(inline.to_vec()),
+            );
+        }
+        KatexProcessor::default().renderer(&mock_context(config))
+    };
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let default_renderer = ChapterRenderer::new(inline_opts, display_opts);
+    // the inline delimiter keeps its default
+    assert_eq!(
+        renderer(&["<<", ">>"], None)
+            .unwrap()
+            .process_chapter("<<x>> and $y$", &mut |_| {}),
+        default_renderer.process_chapter("$$x$$ and $y$", &mut |_| {})
+    );
+    // no inline equations
+    assert_eq!(
+        renderer(&["$$", "$$"], Some(&[]))
+            .unwrap()
+            .process_chapter("$5 and $6, $$x$$", &mut |_| {}),
+        default_renderer.process_chapter(r"\$5 and \$6, $$x$$", &mut |_| {})
+    );
+    let error = renderer(&["$$"], None).err().unwrap();
+    assert!(error
+        .root_cause()
+        .to_string()
+        .starts_with("invalid option `block-delimiter`"));
+    // or with `inline = false`, along with `\(` from `latex-delimiters`
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("inline"), toml::Value::Boolean(false));
+    config.insert(String::from("latex-delimiters"), toml::Value::Boolean(true));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    assert_eq!(
+        renderer.process_chapter(r"$5 and \(x\), $$x$$", &mut |_| {}),
+        default_renderer.process_chapter(r"\$5 and \(x\), $$x$$", &mut |_| {})
+    );
+}
+
+#[test]
+fn test_engine_macros_per_equation() {
+    let mut macros = HashMap::new();
+    macros.insert(String::from(r"\R"), String::from(r"\mathbb{R}"));
+    macros.insert(String::from(r"\Rn"), String::from(r"\R^{#1}"));
+    macros.insert(String::from(r"\N"), String::from(r"\mathbb{N}"));
+    let opts = RenderOpts::new(false).with_macros(macros);
+    let used = opts.macros_used_by(r"x \in \Rn{3}");
+    let mut names: Vec<&String> = used.keys().collect();
+    names.sort();
+    assert_eq!(names, [r"\R", r"\Rn"]);
+    assert!(opts.macros_used_by("x + y").is_empty());
+    let renderer = ChapterRenderer::new(opts.clone(), opts.clone());
+    assert_eq!(
+        renderer.render(r"$\Rn{3}$"),
+        ChapterRenderer::new(opts.clone(), opts).render(r"$\mathbb{R}^{3}$")
+    );
+}
+
+#[test]
+fn test_inline_delimiters_inside_display_equation() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let equation = r"\text{price is \$5 or \$6}";
+    let mut expected_output = katex_header();
+    expected_output.push_str("Some text, ");
+    expected_output.push_str(&katex::render_with_opts(equation, display_opts.to_katex()).unwrap());
+    expected_output.push_str(", and more text.");
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let raw_content = r"Some text, $$\text{price is \$5 or \$6}$$, and more text.";
+    let rendered_content = renderer.process_chapter(raw_content, &mut |_| {});
+    assert_eq!(expected_output, rendered_content);
+}
+
+#[test]
+fn test_escaped_dollars() {
+    let spans = Scanner::default().scan(r"\$5 or $x$ and \$\$10 \\$y \\$", &mut |_| {});
+    assert_eq!(
+        spans,
+        vec![
+            Span::Text(String::from("$5 or ")),
+            Span::Math {
+                source: "$x$",
+                tex: "x",
+                display: false
+            },
+            Span::Text(String::from(r" and $$10 \\")),
+            Span::Math {
+                source: r"$y \\$",
+                tex: r"y \\",
+                display: false
+            },
+        ]
+    );
+    // escaped dollars round-trip to the same text, whatever is rendered
+    let renderer = ChapterRenderer::new(RenderOpts::new(false), RenderOpts::new(true));
+    let rendered = renderer.process_chapter(r"Costs \$5, $a$ more", &mut |_| {});
+    assert!(rendered.ends_with(" more"));
+    assert!(rendered.contains("Costs $5, <span class=\"katex\">"));
+}
+
+#[test]
+fn test_scan_single_pass() {
+    let spans = Scanner::default().scan(r"a $$x$$ b $y$ \$ c $ d", &mut |_| {});
+    assert_eq!(
+        spans,
+        vec![
+            Span::Text(String::from("a ")),
+            Span::Math {
+                source: "$$x$$",
+                tex: "x",
+                display: true
+            },
+            Span::Text(String::from(" b ")),
+            Span::Math {
+                source: "$y$",
+                tex: "y",
+                display: false
+            },
+            Span::Text(String::from(" $ c $ d")),
+        ]
+    );
+    // escaped delimiters don't close equations
+    let spans = Scanner::default().scan(r"$a \$ b$", &mut |_| {});
+    assert_eq!(
+        spans,
+        vec![Span::Math {
+            source: r"$a \$ b$",
+            tex: r"a \$ b",
+            display: false
+        }]
+    );
+}
+
+#[test]
+fn test_max_inline_length() {
+    let scanner = Scanner::default().with_max_inline_length(Some(4));
+    let mut rejected = Vec::new();
+    let spans = scanner.scan("$toolong$, or $$toolong$$ $x$", &mut |tex| {
+        rejected.push(String::from(tex))
+    });
+    assert_eq!(
+        rejected,
+        vec![String::from("toolong"), String::from(", or ")]
+    );
+    assert_eq!(
+        spans,
+        vec![
+            Span::Text(String::from("$toolong$, or ")),
+            Span::Math {
+                source: "$$toolong$$",
+                tex: "toolong",
+                display: true
+            },
+            Span::Text(String::from(" ")),
+            Span::Math {
+                source: "$x$",
+                tex: "x",
+                display: false
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_comment_handling() {
+    use tex::{handle_comments, CommentHandling::*};
+    let tex = "100\\% \\\\% comment $x$\ny";
+    assert_eq!(handle_comments(tex, Keep), tex);
+    assert_eq!(handle_comments(tex, Strip), "100\\% \\\\\ny");
+    assert_eq!(
+        handle_comments(tex, Escape),
+        "100\\% \\\\\\% comment $x$\ny"
+    );
+    for handling in &[Keep, Strip, Escape] {
+        assert_eq!(handle_comments(r"100\%", *handling), r"100\%");
+    }
+}
+
+#[test]
+fn test_escaped_comments_rendering() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let expected = katex::render_with_opts(r"50\% \text{ off}", inline_opts.to_katex()).unwrap();
+    let renderer =
+        ChapterRenderer::new(inline_opts, display_opts).with_comments(CommentHandling::Escape);
+    let rendered_content = renderer.process_chapter(r"$50% \text{ off}$", &mut |_| {});
+    assert_eq!(rendered_content, katex_header() + &expected);
+}
+
+fn markdown_to_html(markdown: &str) -> String {
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, pulldown_cmark::Parser::new(markdown));
+    html
+}
+
+#[test]
+fn test_math_adjacent_to_emphasis() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let raw_content = r"**$y$** and *important: $\text{a*b}$* and _$x_1$_";
+    let rendered_content = renderer.process_chapter(raw_content, &mut |_| {});
+    let html = markdown_to_html(&rendered_content);
+    assert!(html.contains("<strong><span class=\"katex\">"));
+    assert!(html.contains("</span></strong> and <em>important: <span class=\"katex\">"));
+    assert!(html.contains(">a*b</span>"));
+    assert!(html.contains("</span></em> and <em><span class=\"katex\">"));
+    assert!(html.trim_end().ends_with("</span></em></p>"));
+}
+
+#[test]
+fn test_math_adjacent_to_footnotes() {
+    for (tex, expected) in [
+        ("x^2", true),
+        ("^2", true),
+        ("x^{n+1}", true),
+        ("f'", true),
+        (r"20^\circ", true),
+        ("x_i", false),
+        (r"\text{x^2}", false),
+        ("{x^2}", false),
+    ]
+    .iter()
+    {
+        assert_eq!(tex::ends_with_superscript(tex), *expected, "{}", tex);
+    }
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let raw_content = "Area x$^2$[^a], $a^2$ [^b], $[0, 1]$[^c] and [^d]$x^2$.\n\n\
+                       [^a]: With $y^2$.\n[^b]: B.\n[^c]: C.\n[^d]: D.\n";
+    let rendered_content = renderer.process_chapter(raw_content, &mut |_| {});
+    assert_eq!(rendered_content.matches(html::FOOTNOTE_GAP).count(), 1);
+    let mut options = pulldown_cmark::Options::empty();
+    options.insert(pulldown_cmark::Options::ENABLE_FOOTNOTES);
+    let mut html = String::new();
+    let parser = pulldown_cmark::Parser::new_ext(&rendered_content, options);
+    pulldown_cmark::html::push_html(&mut html, parser);
+    // every marker is a footnote reference, the first one set apart from the
+    // superscript before it
+    assert_eq!(
+        html.matches("<sup class=\"footnote-reference\">").count(),
+        4
+    );
+    assert_eq!(
+        html.matches("<div class=\"footnote-definition\"").count(),
+        4
+    );
+    assert!(html.contains("</span>\u{202f}<sup class=\"footnote-reference\"><a href=\"#a\">"));
+    assert!(html.contains("</span></span><sup class=\"footnote-reference\"><a href=\"#c\">"));
+    assert_eq!(html.matches("<span class=\"katex\">").count(), 5);
+}
+
+#[test]
+fn test_forced_display_style() {
+    assert_eq!(tex::forced_display(r"!{\sum_i x_i}"), Some(r"\sum_i x_i"));
+    assert_eq!(tex::forced_display(r"!{a} + {b}"), None);
+    assert_eq!(tex::forced_display(r"\sum_i x_i"), None);
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let expected =
+        katex::render_with_opts(r"\displaystyle \sum_i x_i", inline_opts.to_katex()).unwrap();
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let rendered_content = renderer.process_chapter(r"$!{\sum_i x_i}$", &mut |_| {});
+    assert_eq!(rendered_content, katex_header() + &expected);
+}
+
+#[test]
+fn test_compat_shims() {
+    let mut map = MacroMap::new(DuplicatePolicy::Error);
+    map.parse("\\hfill:{\\qquad}", Path::new("macros.txt"))
+        .unwrap();
+    let shims = compat::add_shims(&mut map).unwrap();
+    // user macros take precedence over shims
+    assert!(shims.iter().all(|shim| shim.name != r"\hfill"));
+    assert_eq!(map.get(r"\hfill").unwrap().expansion, r"{\qquad}");
+    let (inline_opts, display_opts) = mock_build_opts(map.to_katex_macros());
+    for shim in compat::SHIMS {
+        let tex = format!(r"\sum{}{{1em}}{{2em}} b", shim.name);
+        assert!(katex::render(&tex).is_err(), "{} is supported", shim.name);
+        assert!(
+            katex::render_with_opts(&tex, inline_opts.to_katex()).is_ok(),
+            "{} doesn't render",
+            shim.name
+        );
+        assert!(katex::render_with_opts(&tex, display_opts.to_katex()).is_ok());
+    }
+    let used = compat::substitutions(r"\mathlarger{\sum} \nicefrac{1}{2}", &shims);
+    let names: Vec<&str> = used.iter().map(|shim| shim.name).collect();
+    assert_eq!(names, vec![r"\mathlarger", r"\nicefrac"]);
+}
+
+#[test]
+fn test_unsupported_report() {
+    let mut map = MacroMap::default();
+    map.parse("\\foobaz:x", Path::new("macros.txt")).unwrap();
+    let (inline_opts, display_opts) = mock_build_opts(map.to_katex_macros());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let mut report = analysis::Report::default();
+    // macros are supported, and `\foo` doesn't hide `\foobar`
+    let raw = r"$\foo + \foobar + \foobaz$ and $$\mathlarger{x} \foo$$ $\alpha$";
+    analysis::analyze_chapter(&renderer, raw, "a.md", &mut report);
+    assert_eq!(report.equations, 3);
+    let names: Vec<&str> = report.unsupported.keys().map(|s| s.as_str()).collect();
+    assert_eq!(names, vec![r"\foo", r"\foobar", r"\mathlarger"]);
+    let foo = &report.unsupported[r"\foo"];
+    assert_eq!(foo.count, 2);
+    assert_eq!(foo.first_chapter, "a.md");
+    assert_eq!(foo.first_equation, r"\foo + \foobar + \foobaz");
+    // unsupported commands are found even when katex doesn't fail on them
+    let mut config = toml::value::Table::new();
+    config.insert("throw-on-error".into(), false.into());
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let mut report = analysis::Report::default();
+    analysis::analyze_chapter(&renderer, r"$\foo$", "a.md", &mut report);
+    let names: Vec<&str> = report.unsupported.keys().map(|s| s.as_str()).collect();
+    assert_eq!(names, vec![r"\foo"]);
+}
+
+#[test]
+fn test_dollar_catalog() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let mut report = analysis::DollarReport::default();
+    let raw = "Costs $5 and $10, \\$3 or $x$.\n\n$$a$$ `$y$`\n```\necho $HOME\n```\n";
+    analysis::catalog_chapter(&renderer, raw, "a.md", &mut report);
+    let found: Vec<(String, &str, usize, usize)> = report
+        .dollars
+        .iter()
+        .map(|dollar| {
+            let kind = dollar.kind.to_string();
+            (kind, dollar.marker.as_str(), dollar.line, dollar.column)
+        })
+        .collect();
+    let expected = vec![
+        ("currency-suspect", "$", 1, 7),
+        ("currency-suspect", "$", 1, 14),
+        ("math-open", "$", 1, 26),
+        ("math-close", "$", 1, 28),
+        ("math-open", "$$", 3, 1),
+        ("math-close", "$$", 3, 4),
+        ("inside-code", "$", 3, 8),
+        ("inside-code", "$", 3, 10),
+        ("inside-code", "$", 5, 6),
+    ];
+    let expected: Vec<(String, &str, usize, usize)> = expected
+        .into_iter()
+        .map(|(kind, marker, line, column)| (String::from(kind), marker, line, column))
+        .collect();
+    assert_eq!(found, expected);
+    assert_eq!(report.count(analysis::DollarKind::CurrencySuspect), 2);
+    let listed = report.to_string();
+    assert!(listed.contains("a.md:5:6: inside-code `$`: echo $HOME\n"));
+    assert!(listed.ends_with(
+        "9 dollar signs: 2 math-open, 2 math-close, 2 currency-suspect, 3 inside-code\n"
+    ));
+}
+
+#[test]
+fn test_migrate_mathjax() {
+    let raw = "Costs $5, \\\\( \\\\{x\\\\} \\\\, y_1 \\\\) and\n\\\\[ a \\\\\\\\ b \\\\]\n\
+               ```\n\\\\( code \\\\)\n```\n`$x$` $$\\frac{1}{2}$$\n";
+    let expected = "Costs \\$5, $ \\{x\\} \\, y_1 $ and\n$$ a \\\\ b $$\n\
+                    ```\n\\\\( code \\\\)\n```\n`$x$` $$\\frac{1}{2}$$\n";
+    assert_eq!(
+        migrate::convert_delimiters(raw),
+        (String::from(expected), 3)
+    );
+    // escaped dollars, indented code and html blocks are left as they are
+    let raw = "It costs \\$5 and $6\n\n    echo $HOME\n\n    $PATH\nthen \\\\( x \\\\)\n\n\
+               <div>\n$7 and $8\n</div>\n\n<!-- $9\n\n$10 -->\n<span>$1</span> and $2\n";
+    let expected = "It costs \\$5 and \\$6\n\n    echo $HOME\n\n    $PATH\nthen $ x $\n\n\
+                    <div>\n$7 and $8\n</div>\n\n<!-- $9\n\n$10 -->\n<span>\\$1</span> and \\$2\n";
+    assert_eq!(
+        migrate::convert_delimiters(raw),
+        (String::from(expected), 1)
+    );
+    let config = "[book]\ntitle = \"t\"\n\n[output.html]\nmathjax-support = true\n";
+    let (migrated, changes) = migrate::migrate_config(config);
+    assert_eq!(
+        migrated,
+        "[book]\ntitle = \"t\"\n\n[output.html]\n\n[preprocessor.katex]\n"
+    );
+    assert_eq!(changes.len(), 2);
+    assert_eq!(migrate::migrate_config(&migrated).0, migrated);
+}
+
+#[test]
+fn test_mermaid_diagrams_kept() {
+    let scanner = Scanner::default();
+    for raw in [
+        "```mermaid\nA[$1] --> B[$2]\n```\n",
+        "<pre class=\"mermaid\">A[$1] --> B[$2]</pre>",
+    ] {
+        let with_math = format!("{}$x$", raw);
+        assert_eq!(
+            scanner.scan(&with_math, &mut |_| {}),
+            vec![
+                Span::Text(String::from(raw)),
+                Span::Math {
+                    source: "$x$",
+                    tex: "x",
+                    display: false
+                }
+            ]
+        );
+    }
+}
+
+#[test]
+fn test_invalid_utf8() {
+    let path = Path::new("chapter.md");
+    let latin1 = b"# Title\n\ncaf\xe9 $x$".to_vec();
+    let error = encoding::decode(latin1.clone(), path, false).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "chapter.md isn't valid UTF-8: invalid byte 0xe9 at offset 12 (line 3, column 4); \
+         re-encode it as UTF-8, e.g. with `iconv -f latin1 -t utf-8`"
+    );
+    assert_eq!(
+        encoding::decode(latin1, path, true).unwrap(),
+        "# Title\n\ncaf\u{fffd} $x$"
+    );
+}
+
+#[test]
+fn test_print_scale_down() {
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("print-scale-down"), toml::Value::Boolean(true));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let rendered = renderer.process_chapter("$$x$$", &mut |_| {});
+    let mut expected = katex_header();
+    expected.push_str(html::PRINT_SCALE_DOWN);
+    assert!(rendered.starts_with(&expected));
+    let default_renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    assert!(!default_renderer
+        .process_chapter("$$x$$", &mut |_| {})
+        .contains("@media print"));
+}
+
+#[test]
+fn test_break_long_display_equations() {
+    let tex = r"f(x) = a_1 x + a_2 x^2 + \left(a_3 + b_3\right) x^3 - a_4 x^4 \leq \frac{1}{2}";
+    assert_eq!(tex::break_lines(tex, 100), None);
+    let broken = tex::break_lines(tex, 12).unwrap();
+    assert_eq!(
+        broken,
+        concat!(
+            r"\begin{aligned}f(x)&= a_1 x \\ &\quad + a_2 x^2 \\ ",
+            r"&\quad + \left(a_3 + b_3\right) x^3 \\ &\quad - a_4 x^4 \leq \frac{1}{2}\end{aligned}"
+        )
+    );
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    assert!(katex::render_with_opts(&broken, display_opts.to_katex()).is_ok());
+    // equations already laid out, or that can't be broken, are left alone
+    assert_eq!(tex::break_lines(r"a &= b + c + d \\ &= e", 2), None);
+    assert_eq!(tex::break_lines(r"\frac{a + b + c}{d}", 2), None);
+    let renderer = ChapterRenderer::new(inline_opts, display_opts).with_max_display_width(Some(12));
+    let rendered = renderer.process_chapter(&format!("$${}$$ ${}$", tex, tex), &mut |_| {});
+    assert_eq!(rendered.matches("col-align-r").count(), 1);
+}
+
+#[test]
+fn test_replace_symbols() {
+    let symbols: HashMap<char, String> = tex::DEFAULT_SYMBOLS
+        .iter()
+        .map(|(symbol, command)| (*symbol, String::from(*command)))
+        .collect();
+    assert_eq!(
+        tex::replace_symbols("20°C, 5 µm and 3 Å", &symbols),
+        r"20^\circ C, 5 \mu m and 3 \mathring{A}"
+    );
+    assert_eq!(tex::replace_symbols("µ_0", &symbols), r"\mu_0");
+    assert!(matches!(
+        tex::replace_symbols("x^2", &symbols),
+        Cow::Borrowed(_)
+    ));
+
+    // the table of `book.toml` adds to the default one, or replaces its entries
+    let table: toml::value::Table = toml::from_str(
+        "unicode-symbols = true\nsymbols = { \"°\" = \"^{\\\\circ}\", \"‰\" = \"\\\\text{‰}\" }",
+    )
+    .unwrap();
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(table))
+        .unwrap();
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let plain = ChapterRenderer::new(inline_opts, display_opts);
+    assert_eq!(
+        renderer.render("$20° \\cdot 5 µ$"),
+        plain.render(r"$20^{\circ} \cdot 5 \mu$")
+    );
+    let table: toml::value::Table = toml::from_str("symbols = { \"ab\" = \"x\" }").unwrap();
+    assert!(KatexProcessor::default()
+        .renderer(&mock_context(table))
+        .is_err());
+}
+
+#[test]
+fn test_normalize_scripts() {
+    assert_eq!(tex::normalize_scripts("x² + H₂O"), "x^2 + H_2O");
+    assert_eq!(
+        tex::normalize_scripts("e⁻¹⁰ + aₙ₊₁²"),
+        "e^{-10} + a_{n+1}^2"
+    );
+    assert!(matches!(tex::normalize_scripts("x^2"), Cow::Borrowed(_)));
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts).with_normalize_scripts(true);
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let default_renderer = ChapterRenderer::new(inline_opts, display_opts);
+    assert_eq!(
+        renderer.process_chapter("$x² + H₂O$", &mut |_| {}),
+        default_renderer.process_chapter("$x^2 + H_2O$", &mut |_| {})
+    );
+}
+
+#[test]
+fn test_indented_display_equations() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let equation = katex::render_with_opts("x", renderer.display_opts.to_katex()).unwrap();
+    for (raw, indent) in [
+        ("Text\n\n    $$x$$\n", ""),
+        ("1. Item\n\n        $$x$$\n", "   "),
+        ("- Item\n    - Nested\n\n\t\t  $$x$$\n", "      "),
+        // not indented enough to be a code block
+        ("- Item\n\n    $$x$$\n", "    "),
+    ] {
+        let rendered = renderer.process_chapter(raw, &mut |_| {});
+        assert!(
+            rendered.contains(&format!("\n{}{}", indent, equation)),
+            "{:?}",
+            raw
+        );
+        assert!(!markdown_to_html(&rendered).contains("<pre>"), "{:?}", raw);
+    }
+}
+
+#[test]
+fn test_text_backend() {
+    for (tex, expected) in [
+        (r"\frac{x^2}{y+1}", "x^2/(y+1)"),
+        (
+            r"\sqrt{a^{n+1}} \leq \alpha \cdot \infty",
+            "sqrt(a^(n+1)) ≤ α · ∞",
+        ),
+        (
+            r"\sum_{i=1}^{n} i = \binom{n+1}{2}",
+            "∑_(i=1)^n i = C(n+1, 2)",
+        ),
+        (
+            r"\left( \mathbf{v} \right) \text{ for all } \{x\}",
+            "( v ) for all {x}",
+        ),
+        (
+            r"\begin{aligned} a &= b \\ &= c \end{aligned}",
+            "a = b\n= c",
+        ),
+        (r"\sin x + \sqrt[3]{8}", "sin x + root(3, 8)"),
+    ] {
+        assert_eq!(text::to_text(tex), expected, "{}", tex);
+    }
+    let mut map = MacroMap::default();
+    map.parse("\\R:\\mathbb{R}", Path::new("macros.txt"))
+        .unwrap();
+    let (inline_opts, display_opts) = mock_build_opts(map.to_katex_macros());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts)
+        .with_backend(Backend::Text)
+        .with_macros(map);
+    assert_eq!(
+        renderer.process_chapter(r"Let $x \in \R$, then $$\frac{1}{x}$$", &mut |_| {}),
+        "Let x ∈ R, then 1/x"
+    );
+    assert!(KatexProcessor::default().supports_renderer("markdown"));
+}
+
+#[test]
+fn test_meta_description() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let raw =
+        "# Title\n\nThe *ratio* $\\frac{a}{b+1}$ of [two \"numbers\"](x.md) ![plot](p.png).\n\n\
+               ```\ncode\n```\n\n- With `a < b`, \\$5\n";
+    assert_eq!(
+        renderer.description(raw, 100),
+        "The ratio a/(b+1) of two \"numbers\" . With a < b, $5"
+    );
+    assert_eq!(renderer.description(raw, 18), "The ratio a/(b+1)…");
+    let renderer = renderer.with_meta_description(Some(100));
+    let rendered = renderer.process_chapter(raw, &mut |_| {});
+    assert!(rendered.contains(
+        "<meta name=\"description\" content=\"The ratio a/(b+1) of two &quot;numbers&quot; . \
+         With a &lt; b, $5\" class=\"katex-description\">"
+    ));
+}
+
+#[test]
+fn test_hero_equation() {
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    let raw =
+        "Intro $a$\n\n<!-- katex-hero: images/euler.png -->\n$$e^{i\\pi} + 1 = 0$$\n\n$$x$$\n";
+    let rendered = renderer.render_chapter(raw, true, "../");
+    assert!(rendered.contains(
+        "<meta property=\"og:image\" content=\"../images/euler.png\" \
+         data-tex=\"e^{i\\pi} + 1 = 0\" class=\"katex-hero\">"
+    ));
+    assert!(rendered.contains("<meta property=\"og:description\" content=\"e^iπ + 1 = 0\""));
+    assert!(rendered.contains("<meta name=\"twitter:image\" content=\"../images/euler.png\""));
+    // processing the chapter again doesn't add them twice
+    let again = renderer.render_chapter(&rendered, true, "../");
+    assert_eq!(again.matches("og:image").count(), 1);
+    assert!(!renderer
+        .render_chapter("$$x$$", true, "")
+        .contains("og:image"));
+}
+
+#[test]
+fn test_feed_safe() {
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("feed-safe"), toml::Value::Boolean(true));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let rendered = renderer.process_chapter("Some $x^2$ and $$y$$", &mut |_| {});
+    assert!(rendered.starts_with("Some <span class=\"katex\"><math"));
+    assert!(!rendered.contains("stylesheet"));
+    assert!(!rendered.contains("katex-html"));
+    assert_eq!(rendered.matches("<math").count(), 2);
+}
+
+#[test]
+fn test_warnings_as_comments() {
+    assert_eq!(
+        strict::warnings(r"\text{café} + é + \alpha + α % note", false).len(),
+        2
+    );
+    assert!(strict::warnings(r"a \\ b", false).is_empty());
+    assert_eq!(strict::warnings(r"a \\ b", true).len(), 1);
+    assert!(strict::warnings("\\begin{aligned} a \\\\ b \\end{aligned} % c\n", true).is_empty());
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts).with_warnings_as_comments(true);
+    let rendered = renderer.process_chapter("Some $é$ and $x$", &mut |_| {});
+    let comment = "<!-- KaTeX warning: LaTeX-incompatible input and strict mode is set to \
+                   'warn': Unicode text character \"é\" used in math mode \
+                   [unicodeTextInMathMode] -->";
+    assert_eq!(rendered.matches("<!--").count(), 1);
+    assert!(rendered.contains(&format!("Some {}<span class=\"katex\">", comment)));
+}
+
+#[test]
+fn test_front_matter_option() {
+    let (value, content) =
+        markdown::take_front_matter_option("---\nkatex: off\n---\nSome $x$", "katex");
+    assert_eq!(value.as_deref(), Some("off"));
+    assert_eq!(content, "Some $x$");
+    let chapter = "+++\ntitle = \"One\"\nkatex = \"on\"\n+++\nSome $x$";
+    let (value, content) = markdown::take_front_matter_option(chapter, "katex");
+    assert_eq!(value.as_deref(), Some("on"));
+    assert_eq!(content, "+++\ntitle = \"One\"\n+++\nSome $x$");
+    let (value, content) = markdown::take_front_matter_option("---\n\nSome $x$", "katex");
+    assert_eq!((value, content), (None, Cow::Borrowed("---\n\nSome $x$")));
+}
+
+#[test]
+fn test_chapter_default_inheritance() {
+    use mdbook::book::Chapter;
+    let mut book = Book::new();
+    let mut parent = Chapter::new(
+        "One",
+        String::from("---\nkatex: on\n---\n$x$"),
+        "one.md",
+        vec![],
+    );
+    parent.sub_items.push(BookItem::Chapter(Chapter::new(
+        "Two",
+        String::from("$y$"),
+        "two.md",
+        vec![String::from("One")],
+    )));
+    book.push_item(parent);
+    book.push_item(Chapter::new(
+        "Three",
+        String::from("$z$"),
+        "three.md",
+        vec![],
+    ));
+    let mut cfg = toml::value::Table::new();
+    cfg.insert("default".into(), "off".into());
+    let mut book = KatexProcessor::default()
+        .run(&mock_context(cfg), book)
+        .unwrap();
+    let mut contents = Vec::new();
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            contents.push(chapter.content.clone());
+        }
+    });
+    // sub-chapters come first
+    assert!(contents[0].contains("katex-html"));
+    assert!(contents[1].contains("katex-html") && !contents[1].contains("---"));
+    assert_eq!(contents[2], "$z$");
+}
+
+#[test]
+fn test_chapter_patterns() {
+    use mdbook::book::Chapter;
+    assert!(glob::matches("appendix/**", "appendix/a/b.md"));
+    assert!(glob::matches("**/notes.md", "notes.md"));
+    assert!(glob::matches("ch0?.md", "ch05.md"));
+    assert!(!glob::matches("*.md", "part/one.md"));
+    let mut book = Book::new();
+    book.push_item(Chapter::new("One", String::from("$x$"), "one.md", vec![]));
+    book.push_item(Chapter::new(
+        "Shell",
+        String::from("$HOME and $PATH"),
+        "tools/shell.md",
+        vec![],
+    ));
+    book.push_item(Chapter::new(
+        "Two",
+        String::from("$y$"),
+        "part/two.md",
+        vec![],
+    ));
+    let mut cfg = toml::value::Table::new();
+    cfg.insert("include".into(), toml::Value::from(vec!["*.md", "part/**"]));
+    cfg.insert("exclude".into(), "part/two.md".into());
+    let mut book = KatexProcessor::default()
+        .run(&mock_context(cfg), book)
+        .unwrap();
+    let mut contents = Vec::new();
+    book.for_each_mut(|item| {
+        if let BookItem::Chapter(chapter) = item {
+            contents.push(chapter.content.clone());
+        }
+    });
+    assert!(contents[0].contains("katex-html"));
+    assert_eq!(contents[1], "$HOME and $PATH");
+    assert_eq!(contents[2], "$y$");
+}
+
+#[test]
+fn test_processor_builder() {
+    let mut macros = HashMap::new();
+    macros.insert(String::from(r"\R"), String::from(r"\mathbb{R}"));
+    let processor = KatexProcessor::builder()
+        .option("fleqn", true)
+        .macros(macros)
+        .delimiters(vec![Delimiter::new("@", false), Delimiter::new("@@", true)])
+        .build();
+    let renderer = processor
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("fleqn"), toml::Value::Boolean(true));
+    let default_renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    assert_eq!(
+        renderer.render("@\\R@ and @@x@@ but $y$"),
+        default_renderer.render("$\\mathbb{R}$ and $$x$$ but \\$y\\$")
+    );
+    // chapters overriding options keep the macros and delimiters
+    let rendered = renderer.render("<!-- katex: fleqn=false -->\n@@\\R@@");
+    assert!(rendered.contains("katex-display") && !rendered.contains("fleqn"));
+    assert!(rendered.contains("mathbb"));
+}
+
+#[test]
+fn test_directive() {
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(toml::value::Table::new()))
+        .unwrap();
+    let rendered = renderer.render("<!-- katex: fleqn=true, comments=\"strip\" -->\n$$x % y$$");
+    assert!(!rendered.contains("<!--"));
+    assert!(rendered.contains("katex-display fleqn"));
+    assert!(!rendered.contains(">y<"));
+    assert!(!renderer.render("$$x$$").contains("fleqn"));
+    // invalid options fall back to the global ones
+    let rendered = renderer.render("<!-- katex: comments=bogus -->\n$$x$$");
+    assert!(rendered.contains("katex-display") && !rendered.contains("fleqn"));
+    assert_eq!(
+        parse_directive("leqno=true, macros=extra.tex").unwrap(),
+        vec![
+            (String::from("leqno"), toml::Value::Boolean(true)),
+            (
+                String::from("macros"),
+                toml::Value::String(String::from("extra.tex"))
+            ),
+        ]
+    );
+    assert!(parse_directive("leqno").is_err());
+    // lists and tables, with commas in them
+    assert_eq!(
+        parse_directive(r#"macros=["a.tex", "b.tex"], symbols={ "→" = "\\to" }"#).unwrap(),
+        vec![
+            (
+                String::from("macros"),
+                toml::Value::from(vec!["a.tex", "b.tex"])
+            ),
+            (
+                String::from("symbols"),
+                toml::Value::Table(
+                    vec![(String::from("→"), toml::Value::from("\\to"))]
+                        .into_iter()
+                        .collect()
+                )
+            ),
+        ]
+    );
+}
+
+#[test]
+fn test_data_blocks() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts)
+        .with_scanner(Scanner::default().with_data_blocks(vec![String::from("quiz")]));
+    let raw = "Some $x$.\n\n```quiz\n[[questions]]\ntype = \"ShortAnswer\"\n\
+               prompt.prompt = \"What is $x^2$ for $x = 2$?\"\nanswer.answer = \"4\"\n```\n";
+    let rendered = renderer.process_chapter(raw, &mut |_| {});
+    let start = rendered.find("```quiz\n").unwrap() + "```quiz\n".len();
+    let end = rendered.rfind("```").unwrap();
+    let quiz: toml::Value = toml::from_str(&rendered[start..end]).unwrap();
+    let question = &quiz["questions"][0];
+    assert!(question["prompt"]["prompt"]
+        .as_str()
+        .unwrap()
+        .starts_with("What is <span class=\"katex\">"));
+    assert_eq!(question["answer"]["answer"].as_str(), Some("4"));
+    assert!(rendered.starts_with(&katex_header()) && rendered.contains("Some <span"));
+
+    let json = "```quiz\n{\"prompt\": \"$y$\", \"points\": 2}\n```\n";
+    let rendered = renderer.process_chapter(json, &mut |_| {});
+    assert!(rendered.contains("\"points\": 2") && rendered.contains("\"prompt\": \"<span"));
+}
+
+#[test]
+fn test_escape_js_region() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let raw = "$a$ <!-- katex-escape: js -->\n<script>tips = [\"$b$\", \"$\\frac{$\"];</script>\n\
+               <!-- /katex-escape --> $c$";
+    let rendered = renderer.process_chapter(raw, &mut |_| {});
+    let script = &rendered[rendered.find("<script>").unwrap()..rendered.find("</script>").unwrap()];
+    assert!(script.contains("[\"\\u003cspan class=\\u0022katex\\u0022\\u003e"));
+    assert!(script.contains("\"$\\u005cfrac{$\"]"));
+    assert!(!script[8..].contains(['<', '\n']));
+    assert_eq!(rendered.matches("<span class=\"katex\">").count(), 2);
+    assert_eq!(
+        html::escape_js_string("</script>\u{2028}"),
+        "\\u003c/script\\u003e\\u2028"
+    );
+}
+
+#[test]
+fn test_nowrap_inline() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts).with_nowrap_inline(true);
+    let rendered = renderer.process_chapter("Some $a + b$ and $$c$$", &mut |_| {});
+    assert_eq!(rendered.matches(".katex-nowrap {").count(), 1);
+    assert!(rendered.contains("Some <span class=\"katex-nowrap\"><span class=\"katex\">"));
+    assert_eq!(rendered.matches("<span class=\"katex-nowrap\">").count(), 1);
+    let renderer = renderer.with_feed_safe(true);
+    let rendered = renderer.process_chapter("Some $a + b$", &mut |_| {});
+    assert!(rendered.starts_with("Some <span style=\"white-space: nowrap\"><span class=\"katex\">"));
+}
+
+#[test]
+fn test_client_side_rendering() {
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("pre-render"), toml::Value::from(false));
+    config.insert(String::from("leqno"), toml::Value::from(true));
+    let mut ctx = mock_context(config);
+    ctx.config
+        .set("preprocessor.katex.macros", "macros.txt")
+        .unwrap();
+    let root = std::env::temp_dir().join(format!("mdbook-katex-client-{}", std::process::id()));
+    std::fs::create_dir_all(&root).unwrap();
+    std::fs::write(root.join("macros.txt"), "\\R:\\mathbb{R}").unwrap();
+    ctx.root = root.clone();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let rendered = renderer.process_chapter(
+        "Where $a_1 < b_2$ and\n\n$$\n\\begin{cases} x \\\\ y \\end{cases}\n$$",
+        &mut |_| {},
+    );
+    assert!(rendered.contains("/katex.min.js\""));
+    let options = "{&quot;delimiters&quot;:[{&quot;display&quot;:true,&quot;left&quot;:&quot;$$&quot;,\
+                   &quot;right&quot;:&quot;$$&quot;},{&quot;display&quot;:false,&quot;left&quot;:&quot;$&quot;,\
+                   &quot;right&quot;:&quot;$&quot;}],&quot;fleqn&quot;:false,&quot;leqno&quot;:true,\
+                   &quot;macros&quot;:{&quot;\\\\R&quot;:&quot;\\\\mathbb{R}&quot;},&quot;output&quot;:&quot;html&quot;}";
+    assert!(rendered.contains(&format!(
+        "onload=\"document.querySelectorAll('.katex-source').forEach(function (el) \
+         {{ renderMathInElement(el, {}) }})\"",
+        options
+    )));
+    assert!(rendered.ends_with(&format!(
+        "Where <span class=\"katex-source\">$a&#95;1 &lt; b&#95;2$</span>{} and\n\n\
+         <span class=\"katex-source\">$$ &#92;begin{{cases}} x &#92;&#92; y &#92;end{{cases}} $$</span>{}",
+        html::NOSCRIPT_NOTE,
+        html::NOSCRIPT_NOTE
+    )));
+    // readers without javascript see the tex marked as such
+    assert_eq!(rendered.matches("<noscript>").count(), 2);
+    assert!(!rendered.contains("class=\"katex\""));
+    // only the equations are handed to the browser, not the code around them
+    assert!(!rendered.contains("document.body"));
+    let code = "Run\n\n```sh\necho $HOME and $PATH\n```\n\nor `$HOME`.";
+    let rendered = renderer.process_chapter(code, &mut |_| {});
+    assert!(rendered.ends_with(code));
+    assert!(!rendered.contains("<span class=\"katex-source\">"));
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn test_idempotent_processing() {
+    use mdbook::book::Chapter;
+    let mut book = Book::new();
+    book.push_item(Chapter::new(
+        "One",
+        String::from(
+            "# One\n\nLet $x^2$[^1] and\n\n$$a \\label{eq:a}$$\n\nby $\\eqref{eq:a}$, \
+             not $\\frac{1}{$ nor `$y$`.\n\n[^1]: A note.",
+        ),
+        "one.md",
+        vec![],
+    ));
+    let options = [
+        "",
+        "numbering = \"chapter\"",
+        "nowrap-inline = true\ninline-vertical-align = \"-0.1em\"\nprint-scale-down = true",
+        "meta-description = true",
+        "feed-safe = true\nmeta-description = true",
+        "copy-tex = true\nbidi-isolation = false",
+        "pre-render = false",
+    ];
+    for option in options.iter() {
+        let config: toml::value::Table = toml::from_str(option).unwrap();
+        let ctx = mock_context(config);
+        let once = KatexProcessor::default().run(&ctx, book.clone()).unwrap();
+        let twice = KatexProcessor::default().run(&ctx, once.clone()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&once).unwrap(),
+            serde_json::to_string(&twice).unwrap(),
+            "with `{}`",
+            option
+        );
+    }
+}
+
+#[test]
+fn test_prerendered_equations() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let earlier = renderer.render_many(&[
+        MathSpan {
+            tex: "\\$1",
+            display: false,
+        },
+        MathSpan {
+            tex: "\\$2",
+            display: true,
+        },
+    ]);
+    let (inline, display) = (earlier[0].as_ref().unwrap(), earlier[1].as_ref().unwrap());
+    let content = format!(
+        "Pasted {} and\n\n{}\n\nthen $x$, `<span class=\"katex\">$y$</span>`",
+        inline, display
+    );
+    assert_eq!(scan::rendered_equations(&content), 2);
+    let (rendered, stats) = renderer.render_chapter_with_stats(&content, true, "");
+    assert_eq!(stats.prerendered, 2);
+    assert_eq!(stats.equations, 1);
+    assert!(rendered.contains(&format!("Pasted {} and", inline)));
+    assert!(rendered.contains(display.as_str()));
+}
+
+#[test]
+fn test_copy_tex() {
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("copy-tex"), toml::Value::from(true));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let rendered = renderer.process_chapter("Some $ a < b $ and $$c$$", &mut |_| {});
+    assert_eq!(rendered.matches("/contrib/copy-tex.min.js").count(), 1);
+    assert!(rendered.contains("Some <span data-tex=\"a &lt; b\" class=\"katex\">"));
+    assert!(rendered.contains("<span data-tex=\"c\" class=\"katex-display\">"));
+    assert!(rendered.contains("<annotation encoding=\"application/x-tex\">c</annotation>"));
+    let renderer = renderer.with_copy_tex(false);
+    assert!(!renderer.render("$x$").contains("data-tex"));
+}
+
+#[test]
+fn test_source_lines() {
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("source-lines"), toml::Value::from(true));
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let content = "---\nkatex: on\n---\n<!-- katex: leqno=true -->\nSome $a$\n\n$$b$$\n$c^$";
+    let (rendered, stats) = renderer.render_chapter_with_stats(content, true, "");
+    assert!(rendered.contains("Some <span data-source-line=\"5\" class=\"katex\">"));
+    assert!(rendered.contains("<span data-source-line=\"7\" class=\"katex-display"));
+    assert_eq!(stats.failures[0].line, Some(8));
+    let renderer = renderer.with_source_lines(false);
+    assert!(!renderer.render("$x$").contains("data-source-line"));
+}
+
+#[test]
+fn test_equation_edit_links() {
+    use std::path::Path;
+    let mut config = toml::value::Table::new();
+    config.insert(
+        String::from("equation-edit-url"),
+        toml::Value::from("https://example.com/edit/main/{path}#L{line}"),
+    );
+    let mut ctx = mock_context(config);
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let content = "Some $a$\n\n$$b$$\n";
+    let (rendered, _) = renderer.render_chapter_file(content, true, Path::new("part/ch.md"));
+    assert!(rendered.contains(".katex-edit-link {"));
+    assert_eq!(rendered.matches("class=\"katex-edit-link\"").count(), 1);
+    assert!(rendered.contains(
+        "</span></span><a class=\"katex-edit-link\" \
+         href=\"https://example.com/edit/main/src/part/ch.md#L3\""
+    ));
+    let (rendered, _) = renderer.render_chapter_with_stats(content, true, "");
+    assert!(!rendered.contains("class=\"katex-edit-link\""));
+    // links cached for another source directory aren't reused
+    ctx.config.book.src = std::path::PathBuf::from("book-src");
+    let moved = KatexProcessor::default().renderer(&ctx).unwrap();
+    assert_ne!(moved.fingerprint, renderer.fingerprint);
+}
+
+#[test]
+fn test_inline_vertical_align() {
+    assert!(html::is_length("-0.1em"));
+    assert!(html::is_length("2px"));
+    assert!(html::is_length("0"));
+    assert!(!html::is_length("em"));
+    assert!(!html::is_length("1.2.3em"));
+    assert!(!html::is_length("bottom"));
+    let mut config = toml::value::Table::new();
+    config.insert(
+        String::from("inline-vertical-align"),
+        toml::Value::from("-0.1em"),
+    );
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .unwrap();
+    let rendered = renderer.process_chapter("Some $a$ and $$c$$", &mut |_| {});
+    assert_eq!(
+        rendered
+            .matches(".katex-valign { vertical-align: -0.1em; }")
+            .count(),
+        1
+    );
+    assert!(rendered.contains("Some <span class=\"katex-valign\"><span class=\"katex\">"));
+    assert_eq!(rendered.matches("<span class=\"katex-valign\">").count(), 1);
+    let renderer = renderer.with_feed_safe(true);
+    let rendered = renderer.process_chapter("Some $a$", &mut |_| {});
+    assert!(
+        rendered.starts_with("Some <span style=\"vertical-align: -0.1em\"><span class=\"katex\">")
+    );
+    let mut config = toml::value::Table::new();
+    config.insert(
+        String::from("inline-vertical-align"),
+        toml::Value::from("middle"),
+    );
+    assert!(KatexProcessor::default()
+        .renderer(&mock_context(config))
+        .is_err());
+}
+
+#[test]
+fn test_bidi_isolation() {
+    assert!(html::right_to_left("ar"));
+    assert!(html::right_to_left("he-IL"));
+    assert!(html::right_to_left("pa-Arab"));
+    assert!(!html::right_to_left("fr"));
+    assert!(!html::right_to_left("ku-Latn"));
+    let mut ctx = mock_context(toml::value::Table::new());
+    ctx.config.book.language = Some(String::from("fa"));
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let rendered = renderer.process_chapter("متن $a + b$ و $$c$$", &mut |_| {});
+    assert!(rendered.contains("متن <span dir=\"ltr\" class=\"katex\">"));
+    assert!(rendered.contains("<span dir=\"ltr\" class=\"katex-display\">"));
+    // not with `bidi-isolation = false`
+    let mut config = toml::value::Table::new();
+    config.insert(String::from("bidi-isolation"), toml::Value::from(false));
+    let mut ctx = mock_context(config);
+    ctx.config.book.language = Some(String::from("fa"));
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let rendered = renderer.process_chapter("متن $a + b$", &mut |_| {});
+    assert!(!rendered.contains("dir=\"ltr\""));
+}
+
+#[test]
+fn test_katex_version() {
+    assert!(check_katex_version(KATEX_VERSION).is_ok());
+    assert!(check_katex_version("0.12").is_ok());
+    assert!(check_katex_version("v0.12.0").is_ok());
+    assert!(check_katex_version("0.1").is_err());
+    assert!(check_katex_version("0.16.9").is_err());
+    assert!(katex_header().contains(&format!("data-katex-version=\"{}\"", KATEX_VERSION)));
+}
+
+#[test]
+fn test_local_stylesheet() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts)
+        .with_stylesheet("katex/katex.min.css", None);
+    let rendered = renderer.render_chapter("$x$", true, "../../");
+    assert!(rendered.starts_with("<link rel=\"stylesheet\" href=\"../../katex/katex.min.css\""));
+    let renderer = renderer.with_stylesheet("katex/katex.min.css", Some("/project/"));
+    let rendered = renderer.render_chapter("$x$", true, "../");
+    assert!(rendered.starts_with("<link rel=\"stylesheet\" href=\"/project/katex/katex.min.css\""));
+
+    let mut cfg = toml::value::Table::new();
+    cfg.insert("stylesheet".into(), "katex.css".into());
+    let mut ctx = mock_context(cfg);
+    ctx.config.set("output.html.site-url", "/book").unwrap();
+    let mut book = Book::new();
+    book.push_item(mdbook::book::Chapter::new(
+        "Nested",
+        String::from("$x$"),
+        "part/nested.md",
+        vec![],
+    ));
+    let book = KatexProcessor::default().run(&ctx, book).unwrap();
+    match &book.sections[0] {
+        BookItem::Chapter(chapter) => assert!(chapter.content.contains("href=\"/book/katex.css\"")),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn test_registered_stylesheet() {
+    let mut cfg = toml::value::Table::new();
+    cfg.insert("stylesheet".into(), "katex/katex.min.css".into());
+    let mut ctx = mock_context(cfg);
+    let linked = KatexProcessor::default().renderer(&ctx).unwrap();
+    assert!(linked
+        .render("$x$")
+        .starts_with("<link rel=\"stylesheet\" href=\"katex/katex.min.css\""));
+    // every page links the stylesheet, chapters don't need to
+    ctx.config
+        .set("output.html.additional-css", vec!["./katex/katex.min.css"])
+        .unwrap();
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    assert!(renderer.render("$x$").starts_with("<span class=\"katex\">"));
+    // so chapters cached with the link aren't reused
+    assert_ne!(renderer.fingerprint, linked.fingerprint);
+}
+
+#[test]
+fn test_mathml_attributes() {
+    let mut cfg = toml::value::Table::new();
+    cfg.insert("feed-safe".into(), true.into());
+    cfg.insert(
+        "mathml-attributes".into(),
+        vec!["display", "alttext", "lang", "role"].into(),
+    );
+    let mut ctx = mock_context(cfg.clone());
+    ctx.config.book.language = Some(String::from("fr"));
+    let renderer = KatexProcessor::default().renderer(&ctx).unwrap();
+    let rendered = renderer.process_chapter("Some $a<b$ and $$y$$", &mut |_| {});
+    assert!(rendered
+        .contains("<math display=\"inline\" alttext=\"a&lt;b\" lang=\"fr\" role=\"math\" xmlns="));
+    assert!(rendered.contains("<math alttext=\"y\" lang=\"fr\" role=\"math\" xmlns="));
+    cfg.insert("mathml-attributes".into(), vec!["aria"].into());
+    assert!(KatexProcessor::default()
+        .renderer(&mock_context(cfg))
+        .is_err());
+}
+
+#[test]
+fn test_render_many() {
+    let (inline_opts, display_opts) = mock_build_opts(HashMap::new());
+    let renderer = ChapterRenderer::new(inline_opts, display_opts);
+    let spans = [
+        MathSpan {
+            tex: "x^2",
+            display: false,
+        },
+        MathSpan {
+            tex: r"\frac{1}{",
+            display: true,
+        },
+        MathSpan {
+            tex: "y",
+            display: true,
+        },
+    ];
+    let rendered = renderer.render_many(&spans);
+    assert_eq!(rendered.len(), 3);
+    assert!(rendered[0]
+        .as_ref()
+        .unwrap()
+        .starts_with("<span class=\"katex\">"));
+    assert_eq!(rendered[1].as_ref().unwrap_err().tex, r"\frac{1}{");
+    assert!(rendered[2]
+        .as_ref()
+        .unwrap()
+        .starts_with("<span class=\"katex-display\">"));
+}
+
+#[test]
+fn test_shared_renderer() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ChapterRenderer>();
+    let cfg = toml::value::Table::new();
+    let renderer = Arc::new(
+        KatexProcessor::default()
+            .renderer(&mock_context(cfg))
+            .unwrap(),
+    );
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let renderer = Arc::clone(&renderer);
+            std::thread::spawn(move || renderer.render(&format!("$x^{}$", i)))
+        })
+        .collect();
+    for thread in threads {
+        assert!(thread.join().unwrap().contains("<span class=\"katex\">"));
+    }
+}
+
+#[test]
+fn test_output_type() {
+    let mut cfg = toml::value::Table::new();
+    let rendered = KatexProcessor::default()
+        .renderer(&mock_context(cfg.clone()))
+        .unwrap()
+        .render("$x$");
+    assert!(!rendered.contains("<math"));
+    cfg.insert("output".into(), "htmlAndMathml".into());
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(cfg.clone()))
+        .unwrap();
+    let rendered = renderer.render("$x$");
+    assert!(rendered.contains("<math") && rendered.contains("katex-html"));
+    cfg.insert("output".into(), "mathml".into());
+    let renderer = KatexProcessor::default()
+        .renderer(&mock_context(cfg.clone()))
+        .unwrap();
+    assert!(!renderer.render("$x$").contains("katex-html"));
+    cfg.insert("output".into(), "svg".into());
+    assert!(KatexProcessor::default()
+        .renderer(&mock_context(cfg))
+        .is_err());
+}
+
+#[test]
+fn test_handout() {
+    use mdbook::book::Chapter;
+    use mdbook::renderer::RenderContext;
+    let mut book = Book::new();
+    let mut parent = Chapter::new(
+        "One",
+        String::from("$$a = b \\tag{1}$$ and $$c \\label{eq:c}$$, not $d$"),
+        "one.md",
+        vec![],
+    );
+    parent.sub_items.push(BookItem::Chapter(Chapter::new(
+        "Two",
+        String::from("---\nkatex: off\n---\n$$e \\tag{2}$$"),
+        "two.md",
+        vec![String::from("One")],
+    )));
+    book.push_item(parent);
+    book.push_item(Chapter::new(
+        "Three",
+        String::from("$$f \\tag{3}$$"),
+        "three.md",
+        vec![],
+    ));
+    let mut ctx = RenderContext::new(".", book, Config::default(), "book");
+    let page = handout::page(&ctx).unwrap();
+    assert!(page.starts_with("<!DOCTYPE html>"));
+    assert!(page.contains("<h2>One</h2>\n<figure id=\"1\">"));
+    assert!(page.contains("<figure id=\"eq:c\">"));
+    assert!(page.contains("<figcaption>(3)</figcaption>"));
+    assert!(!page.contains("<figure id=\"2\">"));
+    assert_eq!(page.matches("<figure").count(), 3);
+    ctx.config
+        .set("output.katex-handout.labels", vec!["3"])
+        .unwrap();
+    let page = handout::page(&ctx).unwrap();
+    assert_eq!(page.matches("<figure").count(), 1);
+    assert!(page.contains("<h2>Three</h2>"));
+    // with macros defined in chapters
+    let mut book = Book::new();
+    book.push_item(Chapter::new(
+        "Macros",
+        String::from("$$\\newcommand{\\Sp}{\\mathbb{S}}$$"),
+        "macros.md",
+        vec![],
+    ));
+    book.push_item(Chapter::new(
+        "Four",
+        String::from("$$\\Sp^n \\tag{4}$$"),
+        "four.md",
+        vec![],
+    ));
+    let mut ctx = RenderContext::new(".", book, Config::default(), "book");
+    ctx.config
+        .set("preprocessor.katex.chapter-macros", "book")
+        .unwrap();
+    let page = handout::page(&ctx).unwrap();
+    assert!(page.contains("<figure id=\"4\">"));
+    assert!(page.contains("mathbb"));
+}
+
+#[test]
+fn test_code_is_skipped() {
+    let scanner = Scanner::default();
+    let equations = |content: &str| -> Vec<String> {
+        scanner
+            .scan(content, &mut |_| {})
+            .into_iter()
+            .filter_map(|span| match span {
+                Span::Math { tex, .. } => Some(String::from(tex)),
+                _ => None,
+            })
+            .collect()
+    };
+    assert_eq!(equations("Run `echo $PATH` and $x$"), vec!["x"]);
+    assert_eq!(equations("``a ` $b$ ``, $c$"), vec!["c"]);
+    assert_eq!(equations("\\`$d$\\`"), vec!["d"]);
+    assert_eq!(equations("`unclosed $e$"), vec!["e"]);
+    assert_eq!(
+        equations("```sh\nexport A=$HOME $B\n```\n$f$\n~~~~\n$g$\n~~~~\n"),
+        vec!["f"]
+    );
+    assert_eq!(equations("<!-- $h$ -->$i$"), vec!["i"]);
+    assert_eq!(equations("```admonish\n$j$\n```\n$k$"), vec!["j", "k"]);
+}
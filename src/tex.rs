@@ -0,0 +1,604 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use anyhow::Error;
+
+// what to do with `%` comments inside equations
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CommentHandling {
+    // leave them to katex, which ignores everything up to the end of the line
+    #[default]
+    Keep,
+    // remove them before rendering
+    Strip,
+    // render `%` as a percent sign, as if it were written `\%`
+    Escape,
+}
+
+impl CommentHandling {
+    pub fn from_config(value: &str) -> Result<Self, Error> {
+        match value {
+            "keep" => Ok(CommentHandling::Keep),
+            "strip" => Ok(CommentHandling::Strip),
+            "escape" => Ok(CommentHandling::Escape),
+            other => Err(Error::msg(format!(
+                "unknown value `{}`, expected \"keep\", \"strip\" or \"escape\"",
+                other
+            ))),
+        }
+    }
+}
+
+// apply comment handling to an equation; `\%` is a percent sign in every mode
+pub fn handle_comments(tex: &str, handling: CommentHandling) -> Cow<'_, str> {
+    if handling == CommentHandling::Keep || !tex.contains('%') {
+        return Cow::Borrowed(tex);
+    }
+    let mut result = String::with_capacity(tex.len());
+    let mut backslashes = 0;
+    let mut in_comment = false;
+    for c in tex.chars() {
+        if in_comment {
+            // comments end with the line, the line break itself is kept
+            if c == '\n' {
+                in_comment = false;
+                result.push(c);
+            }
+            continue;
+        }
+        // a `%` preceded by an odd number of backslashes is escaped
+        if c == '%' && backslashes % 2 == 0 {
+            match handling {
+                CommentHandling::Strip => in_comment = true,
+                CommentHandling::Escape => result.push_str(r"\%"),
+                CommentHandling::Keep => result.push(c),
+            }
+        } else {
+            result.push(c);
+        }
+        backslashes = if c == '\\' { backslashes + 1 } else { 0 };
+    }
+    Cow::Owned(result)
+}
+
+// body of an inline equation written `$!{...}$`, to be typeset in display style
+pub fn forced_display(tex: &str) -> Option<&str> {
+    let body = tex.strip_prefix("!{")?.strip_suffix('}')?;
+    // the braces must enclose the whole equation, as in `!{a} + {b}` they don't
+    let mut depth = 0usize;
+    let mut escaped = false;
+    for c in body.chars() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' => escaped = true,
+            '{' => depth += 1,
+            '}' if depth == 0 => return None,
+            '}' => depth -= 1,
+            _ => {}
+        }
+    }
+    Some(body)
+}
+
+// tex as markdown leaves it, without backslashes escaping punctuation, which
+// is what mathjax sees in the rendered page
+pub fn unescape_markdown(tex: &str) -> String {
+    let mut result = String::with_capacity(tex.len());
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            if let Some(&next) = chars.peek() {
+                if next.is_ascii_punctuation() {
+                    result.push(next);
+                    chars.next();
+                    continue;
+                }
+            }
+        }
+        result.push(c);
+    }
+    result
+}
+
+// relations display equations are broken before, and aligned on
+const RELATIONS: &[&str] = &[
+    r"\leq",
+    r"\geq",
+    r"\neq",
+    r"\le",
+    r"\ge",
+    r"\ne",
+    r"\approx",
+    r"\equiv",
+    r"\sim",
+    r"\simeq",
+    r"\cong",
+    r"\propto",
+    r"\to",
+    r"\implies",
+    r"\iff",
+];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Break {
+    Relation,
+    Operator,
+}
+
+// estimated width of some tex, counting each character and command as one
+fn width(tex: &str) -> usize {
+    let mut width = 0;
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                while chars.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    chars.next();
+                }
+                width += 1;
+            }
+            '{' | '}' | '^' | '_' | '&' => {}
+            c if c.is_whitespace() => {}
+            _ => width += 1,
+        }
+    }
+    width
+}
+
+// split an equation before top-level relations and binary operators
+fn break_points(tex: &str) -> Vec<(usize, Break)> {
+    let mut points = Vec::new();
+    let mut depth = 0usize;
+    // the last character that isn't a space, to tell unary operators apart
+    let mut previous: Option<char> = None;
+    let mut position = 0;
+    while let Some(c) = tex[position..].chars().next() {
+        let rest = &tex[position..];
+        if c == '\\' {
+            let name_length = match rest[1..].find(|c: char| !c.is_ascii_alphabetic()) {
+                // a control symbol, like `\{`
+                Some(0) => rest[1..].chars().next().map_or(0, char::len_utf8),
+                Some(length) => length,
+                None => rest.len() - 1,
+            };
+            let name = &rest[..1 + name_length];
+            match name {
+                r"\left" => depth += 1,
+                r"\right" => depth = depth.saturating_sub(1),
+                _ if depth == 0 && RELATIONS.contains(&name) => {
+                    points.push((position, Break::Relation))
+                }
+                _ => {}
+            }
+            position += name.len();
+            previous = Some('a');
+            continue;
+        }
+        match c {
+            '{' => depth += 1,
+            '}' => depth = depth.saturating_sub(1),
+            '=' | '<' | '>' if depth == 0 => points.push((position, Break::Relation)),
+            '+' | '-' if depth == 0 && previous.is_some_and(|p| !"=<>+-({[^_,".contains(p)) => {
+                points.push((position, Break::Operator))
+            }
+            _ => {}
+        }
+        if !c.is_whitespace() {
+            previous = Some(c);
+        }
+        position += c.len_utf8();
+    }
+    points
+}
+
+// break a display equation estimated wider than `max_width` into lines of
+// an `aligned` environment, aligned on the first relation; equations with
+// their own line breaks or alignment are left alone
+pub fn break_lines(tex: &str, max_width: usize) -> Option<String> {
+    if width(tex) <= max_width
+        || tex.contains(r"\\")
+        || tex.contains('&')
+        || tex.contains(r"\begin")
+    {
+        return None;
+    }
+    let mut pieces = Vec::new();
+    let mut start = 0;
+    let mut kind = None;
+    for (position, point) in break_points(tex) {
+        if position > start {
+            pieces.push((kind, tex[start..position].trim()));
+        }
+        start = position;
+        kind = Some(point);
+    }
+    pieces.push((kind, tex[start..].trim()));
+    if pieces.len() < 2 {
+        return None;
+    }
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut line = String::new();
+    let mut line_width = 0;
+    let mut aligned = false;
+    for (kind, piece) in pieces {
+        let piece_width = width(piece);
+        if line_width > 0 && line_width + piece_width > max_width {
+            lines.push(std::mem::take(&mut line));
+            line_width = 0;
+        }
+        if line_width == 0 && !lines.is_empty() {
+            // continued lines start at the alignment point, operators indented
+            // past relations
+            line.push('&');
+            if kind == Some(Break::Operator) {
+                line.push_str(r"\quad ");
+            }
+        } else if kind == Some(Break::Relation) && !aligned && lines.is_empty() {
+            line.push('&');
+            aligned = true;
+        } else if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(piece);
+        line_width += piece_width;
+    }
+    lines.push(line);
+    if lines.len() < 2 {
+        return None;
+    }
+    // without a relation on the first line, everything is aligned on the left
+    if !aligned {
+        lines[0].insert(0, '&');
+    }
+    Some(format!(
+        "\\begin{{aligned}}{}\\end{{aligned}}",
+        lines.join(r" \\ ")
+    ))
+}
+
+// amsmath environments katex 0.12 doesn't know, starred or not, and the
+// environments they're rendered with
+const AMSMATH_ENVIRONMENTS: &[(&str, &str, &str)] = &[
+    ("equation", "", ""),
+    ("align", r"\begin{aligned}", r"\end{aligned}"),
+    ("flalign", r"\begin{aligned}", r"\end{aligned}"),
+    ("split", r"\begin{aligned}", r"\end{aligned}"),
+    ("alignat", r"\begin{alignedat}", r"\end{alignedat}"),
+    ("gather", r"\begin{gathered}", r"\end{gathered}"),
+    ("multline", r"\begin{gathered}", r"\end{gathered}"),
+    ("eqnarray", r"\begin{array}{rcl}", r"\end{array}"),
+];
+
+// commands leaving an equation without a number, which katex 0.12 doesn't know
+pub const UNNUMBERED: &[&str] = &["\\notag", "\\nonumber"];
+
+// katex 0.12 doesn't know `\label`
+pub fn without_labels(tex: &str) -> String {
+    let mut result = String::from(tex);
+    while let Some(start) = result.find("\\label{") {
+        match result[start..].find('}') {
+            Some(end) => result.replace_range(start..start + end + 1, ""),
+            None => break,
+        }
+    }
+    result
+}
+
+// whether an equation is a starred environment, like `align*`, which latex
+// doesn't number
+pub fn starred_environment(tex: &str) -> bool {
+    tex.trim_start()
+        .strip_prefix("\\begin{")
+        .and_then(|rest| rest.split('}').next())
+        .is_some_and(|name| name.ends_with('*'))
+}
+
+// replace amsmath environments with the ones katex renders them with, as in
+// `align` with `aligned`, without the labels and numbering commands it
+// doesn't know; a single `\tag`, as numbering adds to environments written
+// without delimiters, is moved after the environments, where katex takes it
+pub fn translate_environments(tex: &str) -> Cow<'_, str> {
+    if !tex.contains("\\begin{") {
+        return Cow::Borrowed(tex);
+    }
+    let mut result = String::from(tex);
+    for (name, begin, end) in AMSMATH_ENVIRONMENTS {
+        for name in [String::from(*name), format!("{}*", name)].iter() {
+            result = result
+                .replace(&format!("\\begin{{{}}}", name), begin)
+                .replace(&format!("\\end{{{}}}", name), end);
+        }
+    }
+    if result != tex {
+        result = without_labels(&result);
+        for command in UNNUMBERED {
+            result = result.replace(command, "");
+        }
+    }
+    if let Some(hoisted) = hoist_tag(&result) {
+        result = hoisted;
+    }
+    if result == tex {
+        return Cow::Borrowed(tex);
+    }
+    Cow::Owned(result)
+}
+
+// tex with its only `\tag{...}` moved to the end, if it's inside an
+// environment
+fn hoist_tag(tex: &str) -> Option<String> {
+    if tex.matches("\\tag").count() != 1 {
+        return None;
+    }
+    let start = tex.find("\\tag")?;
+    let end = start + tex[start..].find('}')? + 1;
+    if !tex[end..].contains("\\end{") {
+        return None;
+    }
+    Some(format!(
+        "{}{} {}",
+        tex[..start].trim_end(),
+        &tex[end..],
+        &tex[start..end]
+    ))
+}
+
+// whether an equation ends with a superscript, as in `x^2`, `x^{n+1}`, `x'`,
+// `20^\circ` or the `$^2$` written for `<sup>2</sup>`
+pub fn ends_with_superscript(tex: &str) -> bool {
+    let tex = tex.trim_end();
+    if tex.ends_with('\'') {
+        return true;
+    }
+    let before = if tex.ends_with('}') {
+        // before the group the equation ends with
+        let mut depth = 0;
+        let start = tex.char_indices().rev().find(|(_, c)| {
+            match c {
+                '}' => depth += 1,
+                '{' => depth -= 1,
+                _ => {}
+            }
+            depth == 0
+        });
+        match start {
+            Some((start, _)) => &tex[..start],
+            None => return false,
+        }
+    } else {
+        let letters = tex.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        match letters.strip_suffix('\\') {
+            // a command, like `\circ`
+            Some(before) if letters.len() < tex.len() => before,
+            _ => {
+                let last = tex.chars().next_back().map_or(0, char::len_utf8);
+                &tex[..tex.len() - last]
+            }
+        }
+    };
+    before.trim_end().ends_with('^')
+}
+
+// symbols pasted from datasheets and papers, which katex typesets with a
+// fallback font, and the commands standing for them with `unicode-symbols`
+pub const DEFAULT_SYMBOLS: &[(char, &str)] = &[
+    ('°', r"^\circ"),
+    ('µ', r"\mu"),
+    ('Å', r"\mathring{A}"),
+    ('\u{212b}', r"\mathring{A}"),
+    ('℃', r"{}^\circ\mathrm{C}"),
+    ('\u{2126}', r"\Omega"),
+    ('ℓ', r"\ell"),
+    ('·', r"\cdot"),
+    ('∙', r"\bullet"),
+];
+
+// replace symbols inside an equation with the commands in `symbols`; a space
+// keeps a command apart from a letter after it, as in `20^\circ C`
+pub fn replace_symbols<'a>(tex: &'a str, symbols: &HashMap<char, String>) -> Cow<'a, str> {
+    if !tex.chars().any(|c| symbols.contains_key(&c)) {
+        return Cow::Borrowed(tex);
+    }
+    let mut result = String::with_capacity(tex.len());
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        let replacement = match symbols.get(&c) {
+            Some(replacement) => replacement,
+            None => {
+                result.push(c);
+                continue;
+            }
+        };
+        result.push_str(replacement);
+        let name = replacement.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let ends_command = name.len() < replacement.len() && name.ends_with('\\');
+        if ends_command && chars.peek().is_some_and(|next| next.is_ascii_alphabetic()) {
+            result.push(' ');
+        }
+    }
+    Cow::Owned(result)
+}
+
+// unicode superscripts and subscripts, and the characters they stand for
+const SUPERSCRIPTS: (&str, &str) = ("⁰¹²³⁴⁵⁶⁷⁸⁹⁺⁻⁼⁽⁾ⁱⁿ", "0123456789+-=()in");
+const SUBSCRIPTS: (&str, &str) = ("₀₁₂₃₄₅₆₇₈₉₊₋₌₍₎ₐₑₒₓₕₖₗₘₙₚₛₜ", "0123456789+-=()aeoxhklmnpst");
+
+fn script(c: char) -> Option<(char, char)> {
+    let find = |(scripts, plain): (&str, &str), marker| {
+        let index = scripts.chars().position(|script| script == c)?;
+        plain.chars().nth(index).map(|plain| (marker, plain))
+    };
+    find(SUPERSCRIPTS, '^').or_else(|| find(SUBSCRIPTS, '_'))
+}
+
+// replace unicode superscripts and subscripts, as in `x²` or `H₂O` pasted from
+// web pages, with `^` and `_`; consecutive ones are grouped, as in `x^{10}`
+pub fn normalize_scripts(tex: &str) -> Cow<'_, str> {
+    if !tex.chars().any(|c| script(c).is_some()) {
+        return Cow::Borrowed(tex);
+    }
+    let mut result = String::with_capacity(tex.len());
+    let mut chars = tex.chars().peekable();
+    while let Some(c) = chars.next() {
+        let (marker, plain) = match script(c) {
+            Some(script) => script,
+            None => {
+                result.push(c);
+                continue;
+            }
+        };
+        let mut group = String::from(plain);
+        while let Some((_, next)) = chars
+            .peek()
+            .and_then(|&next| script(next))
+            .filter(|(next_marker, _)| *next_marker == marker)
+        {
+            group.push(next);
+            chars.next();
+        }
+        result.push(marker);
+        if group.chars().count() == 1 {
+            result.push_str(&group);
+        } else {
+            result.push('{');
+            result.push_str(&group);
+            result.push('}');
+        }
+    }
+    Cow::Owned(result)
+}
+
+// what an unclosed group was opened with
+enum Opening {
+    Brace,
+    Left,
+    Begin(String),
+}
+
+impl Opening {
+    fn describe(&self) -> String {
+        match self {
+            Opening::Brace => String::from("`{`"),
+            Opening::Left => String::from(r"`\left`"),
+            Opening::Begin(name) => format!("`\\begin{{{}}}`", name),
+        }
+    }
+}
+
+// `column 17`, or `line 2, column 3` in equations of several lines
+fn position(tex: &str, offset: usize) -> String {
+    let before = &tex[..offset];
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    match before.matches('\n').count() {
+        0 => format!("column {}", column),
+        lines => format!("line {}, column {}", lines + 1, column),
+    }
+}
+
+// the name in braces after `\begin` or `\end`, and where it ends
+fn environment_name(rest: &str) -> Option<(&str, usize)> {
+    let trimmed = rest.trim_start();
+    let inner = trimmed.strip_prefix('{')?;
+    let length = inner.find('}')?;
+    let skipped = rest.len() - trimmed.len();
+    Some((&inner[..length], skipped + length + 2))
+}
+
+// check that braces, `\left` and `\right`, and environments are balanced,
+// for an error pointing at the unclosed one rather than katex's parse error
+pub fn check_balance(tex: &str) -> Result<(), String> {
+    let mut open: Vec<(Opening, usize)> = Vec::new();
+    let mut position_at = 0;
+    while let Some(c) = tex[position_at..].chars().next() {
+        let start = position_at;
+        position_at += c.len_utf8();
+        match c {
+            '\\' => {
+                let rest = &tex[position_at..];
+                let name_length = match rest.find(|c: char| !c.is_ascii_alphabetic()) {
+                    // a control symbol, like `\{`
+                    Some(0) => rest.chars().next().map_or(0, char::len_utf8),
+                    Some(length) => length,
+                    None => rest.len(),
+                };
+                let name = &rest[..name_length];
+                position_at += name_length;
+                match name {
+                    "left" => open.push((Opening::Left, start)),
+                    "right" => match open.pop() {
+                        Some((Opening::Left, _)) => {}
+                        Some((opening, opened)) => {
+                            return Err(unclosed(tex, &opening, opened));
+                        }
+                        None => {
+                            return Err(format!(
+                                "`\\right` at {} without a `\\left`",
+                                position(tex, start)
+                            ))
+                        }
+                    },
+                    "begin" | "end" => {
+                        let (environment, length) = match environment_name(&tex[position_at..]) {
+                            Some(found) => found,
+                            None => continue,
+                        };
+                        position_at += length;
+                        if name == "begin" {
+                            open.push((Opening::Begin(String::from(environment)), start));
+                            continue;
+                        }
+                        match open.pop() {
+                            Some((Opening::Begin(begun), _)) if begun == environment => {}
+                            Some((Opening::Begin(begun), opened)) => {
+                                return Err(format!(
+                                "`\\end{{{}}}` at {} doesn't match `\\begin{{{}}}` opened at {}",
+                                environment,
+                                position(tex, start),
+                                begun,
+                                position(tex, opened)
+                            ))
+                            }
+                            Some((opening, opened)) => {
+                                return Err(unclosed(tex, &opening, opened));
+                            }
+                            None => {
+                                return Err(format!(
+                                    "`\\end{{{}}}` at {} without a `\\begin{{{}}}`",
+                                    environment,
+                                    position(tex, start),
+                                    environment
+                                ))
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            // comments, left to katex, can hold anything
+            '%' => match tex[position_at..].find('\n') {
+                Some(end) => position_at += end,
+                None => position_at = tex.len(),
+            },
+            '{' => open.push((Opening::Brace, start)),
+            '}' => match open.pop() {
+                Some((Opening::Brace, _)) => {}
+                Some((opening, opened)) => return Err(unclosed(tex, &opening, opened)),
+                None => return Err(format!("unmatched `}}` at {}", position(tex, start))),
+            },
+            _ => {}
+        }
+    }
+    match open.pop() {
+        Some((opening, opened)) => Err(unclosed(tex, &opening, opened)),
+        None => Ok(()),
+    }
+}
+
+fn unclosed(tex: &str, opening: &Opening, opened: usize) -> String {
+    format!(
+        "unclosed {} opened at {}",
+        opening.describe(),
+        position(tex, opened)
+    )
+}
@@ -0,0 +1,376 @@
+// plain text approximations of equations, e.g. `x^2/(y+1)` for
+// `\frac{x^2}{y+1}`, for renderers that can't show HTML
+
+// commands written as a single unicode character, or a few ones
+const SYMBOLS: &[(&str, &str)] = &[
+    ("alpha", "α"),
+    ("beta", "β"),
+    ("gamma", "γ"),
+    ("delta", "δ"),
+    ("epsilon", "ε"),
+    ("varepsilon", "ε"),
+    ("zeta", "ζ"),
+    ("eta", "η"),
+    ("theta", "θ"),
+    ("vartheta", "ϑ"),
+    ("iota", "ι"),
+    ("kappa", "κ"),
+    ("lambda", "λ"),
+    ("mu", "μ"),
+    ("nu", "ν"),
+    ("xi", "ξ"),
+    ("pi", "π"),
+    ("rho", "ρ"),
+    ("sigma", "σ"),
+    ("tau", "τ"),
+    ("upsilon", "υ"),
+    ("phi", "φ"),
+    ("varphi", "φ"),
+    ("chi", "χ"),
+    ("psi", "ψ"),
+    ("omega", "ω"),
+    ("Gamma", "Γ"),
+    ("Delta", "Δ"),
+    ("Theta", "Θ"),
+    ("Lambda", "Λ"),
+    ("Xi", "Ξ"),
+    ("Pi", "Π"),
+    ("Sigma", "Σ"),
+    ("Upsilon", "Υ"),
+    ("Phi", "Φ"),
+    ("Psi", "Ψ"),
+    ("Omega", "Ω"),
+    ("cdot", "·"),
+    ("times", "×"),
+    ("div", "÷"),
+    ("pm", "±"),
+    ("mp", "∓"),
+    ("ast", "∗"),
+    ("star", "⋆"),
+    ("circ", "∘"),
+    ("bullet", "•"),
+    ("leq", "≤"),
+    ("le", "≤"),
+    ("geq", "≥"),
+    ("ge", "≥"),
+    ("neq", "≠"),
+    ("ne", "≠"),
+    ("ll", "≪"),
+    ("gg", "≫"),
+    ("approx", "≈"),
+    ("equiv", "≡"),
+    ("sim", "∼"),
+    ("simeq", "≃"),
+    ("cong", "≅"),
+    ("propto", "∝"),
+    ("perp", "⊥"),
+    ("parallel", "∥"),
+    ("mid", "|"),
+    ("vert", "|"),
+    ("lvert", "|"),
+    ("rvert", "|"),
+    ("Vert", "‖"),
+    ("langle", "⟨"),
+    ("rangle", "⟩"),
+    ("lbrace", "{"),
+    ("rbrace", "}"),
+    ("lfloor", "⌊"),
+    ("rfloor", "⌋"),
+    ("lceil", "⌈"),
+    ("rceil", "⌉"),
+    ("infty", "∞"),
+    ("partial", "∂"),
+    ("nabla", "∇"),
+    ("sum", "∑"),
+    ("prod", "∏"),
+    ("int", "∫"),
+    ("iint", "∬"),
+    ("oint", "∮"),
+    ("to", "→"),
+    ("rightarrow", "→"),
+    ("leftarrow", "←"),
+    ("gets", "←"),
+    ("leftrightarrow", "↔"),
+    ("Rightarrow", "⇒"),
+    ("implies", "⇒"),
+    ("Leftarrow", "⇐"),
+    ("Leftrightarrow", "⇔"),
+    ("iff", "⇔"),
+    ("mapsto", "↦"),
+    ("in", "∈"),
+    ("notin", "∉"),
+    ("ni", "∋"),
+    ("subset", "⊂"),
+    ("subseteq", "⊆"),
+    ("supset", "⊃"),
+    ("supseteq", "⊇"),
+    ("cup", "∪"),
+    ("cap", "∩"),
+    ("setminus", "∖"),
+    ("emptyset", "∅"),
+    ("varnothing", "∅"),
+    ("forall", "∀"),
+    ("exists", "∃"),
+    ("neg", "¬"),
+    ("lnot", "¬"),
+    ("land", "∧"),
+    ("wedge", "∧"),
+    ("lor", "∨"),
+    ("vee", "∨"),
+    ("oplus", "⊕"),
+    ("otimes", "⊗"),
+    ("ldots", "…"),
+    ("dots", "…"),
+    ("cdots", "⋯"),
+    ("vdots", "⋮"),
+    ("ddots", "⋱"),
+    ("prime", "′"),
+    ("angle", "∠"),
+    ("degree", "°"),
+    ("ell", "ℓ"),
+    ("hbar", "ℏ"),
+    ("Re", "ℜ"),
+    ("Im", "ℑ"),
+    ("aleph", "ℵ"),
+    // spacing
+    ("quad", " "),
+    ("qquad", " "),
+    ("enspace", " "),
+    ("thinspace", " "),
+];
+
+// commands whose argument is kept as it is, dropping the styling
+const STYLES: &[&str] = &[
+    "text",
+    "textrm",
+    "textit",
+    "textbf",
+    "texttt",
+    "textsf",
+    "textup",
+    "textnormal",
+    "mathrm",
+    "mathit",
+    "mathbf",
+    "mathsf",
+    "mathtt",
+    "mathcal",
+    "mathbb",
+    "mathfrak",
+    "mathscr",
+    "boldsymbol",
+    "bm",
+    "operatorname",
+    "hat",
+    "widehat",
+    "bar",
+    "overline",
+    "underline",
+    "tilde",
+    "widetilde",
+    "vec",
+    "dot",
+    "ddot",
+    "overrightarrow",
+];
+
+// delimiter sizes, and other commands dropped altogether
+const IGNORED: &[&str] = &[
+    "left",
+    "right",
+    "big",
+    "Big",
+    "bigg",
+    "Bigg",
+    "bigl",
+    "bigr",
+    "Bigl",
+    "Bigr",
+    "biggl",
+    "biggr",
+    "Biggl",
+    "Biggr",
+    "middle",
+    "limits",
+    "nolimits",
+    "displaystyle",
+    "textstyle",
+    "scriptstyle",
+    "nonumber",
+    "notag",
+    "mathstrut",
+];
+
+struct Converter<'a> {
+    tex: &'a str,
+    position: usize,
+}
+
+impl<'a> Converter<'a> {
+    fn peek(&self) -> Option<char> {
+        self.tex[self.position..].chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        Some(c)
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(char::is_whitespace) {
+            self.next();
+        }
+    }
+
+    // name of a command, after its backslash
+    fn command_name(&mut self) -> &'a str {
+        let start = self.position;
+        match self.next() {
+            Some(c) if c.is_ascii_alphabetic() => {
+                while self.peek().is_some_and(|c| c.is_ascii_alphabetic()) {
+                    self.next();
+                }
+            }
+            _ => {}
+        }
+        &self.tex[start..self.position]
+    }
+
+    // text of the content up to the end of the current group
+    fn group_content(&mut self) -> String {
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == '}' {
+                self.next();
+                break;
+            }
+            text.push_str(&self.token());
+        }
+        text
+    }
+
+    // text of the argument of a command or script: a group, or a single token
+    fn argument(&mut self) -> String {
+        self.skip_spaces();
+        if self.peek() == Some('{') {
+            self.next();
+            self.group_content()
+        } else {
+            self.token()
+        }
+    }
+
+    // an optional argument in brackets, as in `\sqrt[3]{x}`
+    fn optional_argument(&mut self) -> Option<String> {
+        self.skip_spaces();
+        if self.peek() != Some('[') {
+            return None;
+        }
+        self.next();
+        let mut text = String::new();
+        while let Some(c) = self.peek() {
+            if c == ']' {
+                self.next();
+                break;
+            }
+            text.push_str(&self.token());
+        }
+        Some(text)
+    }
+
+    fn command(&mut self) -> String {
+        let name = self.command_name();
+        if let Some((_, symbol)) = SYMBOLS.iter().find(|(command, _)| *command == name) {
+            return String::from(*symbol);
+        }
+        match name {
+            "frac" | "dfrac" | "tfrac" | "cfrac" => {
+                let numerator = self.argument();
+                let denominator = self.argument();
+                format!(
+                    "{}/{}",
+                    parenthesize(&numerator),
+                    parenthesize(&denominator)
+                )
+            }
+            "binom" => {
+                let n = self.argument();
+                let k = self.argument();
+                format!("C({}, {})", n.trim(), k.trim())
+            }
+            "sqrt" => match self.optional_argument() {
+                Some(index) => format!("root({}, {})", index.trim(), self.argument().trim()),
+                None => format!("sqrt({})", self.argument().trim()),
+            },
+            "begin" | "end" => {
+                self.argument();
+                String::from(" ")
+            }
+            "phantom" | "hphantom" | "vphantom" => {
+                self.argument();
+                String::new()
+            }
+            "\\" | "cr" => String::from("\n"),
+            "," | ":" | ";" | " " => String::from(" "),
+            "!" => String::new(),
+            _ if STYLES.contains(&name) => self.argument(),
+            _ if IGNORED.contains(&name) => {
+                // `\left.` has no delimiter
+                self.skip_spaces();
+                if self.peek() == Some('.') {
+                    self.next();
+                }
+                String::new()
+            }
+            // escaped characters, as in `\{` or `\%`
+            _ if !name.starts_with(|c: char| c.is_ascii_alphabetic()) => String::from(name),
+            // functions like `\sin`, and commands without a better approximation
+            _ => format!("{} ", name),
+        }
+    }
+
+    fn token(&mut self) -> String {
+        let c = match self.next() {
+            Some(c) => c,
+            None => return String::new(),
+        };
+        match c {
+            '\\' => self.command(),
+            '{' => self.group_content(),
+            '}' => String::new(),
+            '^' | '_' => {
+                let script = self.argument();
+                format!("{}{}", c, parenthesize(&script))
+            }
+            '~' | '&' => String::from(" "),
+            c => String::from(c),
+        }
+    }
+}
+
+// some text, in parentheses when it has operators or spaces, as in `(y+1)`
+fn parenthesize(text: &str) -> String {
+    let text = text.trim();
+    if text.chars().count() <= 1 || !text.contains(|c: char| " +-/=<>,·×".contains(c)) {
+        String::from(text)
+    } else {
+        format!("({})", text)
+    }
+}
+
+// a readable plain text approximation of an equation
+pub fn to_text(tex: &str) -> String {
+    let mut converter = Converter { tex, position: 0 };
+    let mut text = String::new();
+    while converter.peek().is_some() {
+        text.push_str(&converter.token());
+    }
+    // spaces are collapsed, keeping line breaks
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>().join(" "))
+        .filter(|line| !line.is_empty())
+        .collect();
+    lines.join("\n")
+}
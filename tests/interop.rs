@@ -0,0 +1,114 @@
+// mdbook-katex chained with other popular preprocessors, in every order, on
+// the fixture book in `tests/fixtures/interop`; preprocessors that aren't
+// installed are left out
+#![cfg(all(feature = "katex", feature = "preprocessor"))]
+
+use std::env;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use mdbook::book::{Book, BookItem};
+use mdbook::MDBook;
+
+const OTHERS: &[&str] = &["mdbook-admonish", "mdbook-mermaid", "mdbook-toc"];
+
+fn find_in_path(name: &str) -> Option<PathBuf> {
+    let paths = env::var_os("PATH")?;
+    env::split_paths(&paths)
+        .map(|dir| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+fn permutations(items: &[PathBuf]) -> Vec<Vec<PathBuf>> {
+    if items.is_empty() {
+        return vec![Vec::new()];
+    }
+    let mut result = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        let mut rest = items.to_vec();
+        rest.remove(index);
+        for mut permutation in permutations(&rest) {
+            permutation.insert(0, item.clone());
+            result.push(permutation);
+        }
+    }
+    result
+}
+
+// run a preprocessor the way mdbook does, on the serialized `[context, book]`
+fn run(preprocessor: &Path, context: &serde_json::Value, book: &Book) -> Book {
+    let mut child = Command::new(preprocessor)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let input = serde_json::to_vec(&(context, book)).unwrap();
+    child.stdin.take().unwrap().write_all(&input).unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{} failed", preprocessor.display());
+    serde_json::from_slice(&output.stdout).unwrap()
+}
+
+fn chapter_content(book: &Book) -> &str {
+    book.iter()
+        .find_map(|item| match item {
+            BookItem::Chapter(chapter) => Some(chapter.content.as_str()),
+            _ => None,
+        })
+        .unwrap()
+}
+
+#[test]
+fn test_interop_with_other_preprocessors() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/interop");
+    let md = MDBook::load(&root).unwrap();
+    let context = serde_json::json!({
+        "root": root,
+        "config": md.config,
+        "renderer": "html",
+        "mdbook_version": mdbook::MDBOOK_VERSION,
+    });
+
+    let mut preprocessors = vec![PathBuf::from(env!("CARGO_BIN_EXE_mdbook-katex"))];
+    for name in OTHERS {
+        match find_in_path(name) {
+            Some(path) => preprocessors.push(path),
+            None => eprintln!("{} isn't installed, leaving it out", name),
+        }
+    }
+    let installed = |name: &str| preprocessors.iter().any(|p| p.ends_with(name));
+
+    for order in permutations(&preprocessors) {
+        let mut book = md.book.clone();
+        for preprocessor in &order {
+            book = run(preprocessor, &context, &book);
+        }
+        let content = chapter_content(&book);
+        let order: Vec<_> = order.iter().map(|p| p.file_name().unwrap()).collect();
+        // every equation is rendered, including those in admonitions
+        for equation in ["$a^2 + b^2$", "$$\\sum", "$\\alpha + \\beta$", "$c^3$"] {
+            assert!(
+                !content.contains(equation),
+                "`{}` isn't rendered with {:?}",
+                equation,
+                order
+            );
+        }
+        // diagrams are left alone
+        assert!(
+            content.contains("A[Costs $100] --> B[Or $200]"),
+            "diagram corrupted with {:?}",
+            order
+        );
+        if installed("mdbook-toc") {
+            assert!(!content.contains("<!-- toc -->"), "{:?}", order);
+        }
+        if installed("mdbook-mermaid") {
+            assert!(content.contains("class=\"mermaid\""), "{:?}", order);
+        }
+        if installed("mdbook-admonish") {
+            assert!(!content.contains("```admonish"), "{:?}", order);
+        }
+    }
+}
@@ -0,0 +1,44 @@
+// renders every function of the KaTeX supported functions list, so that
+// upgrades of the bundled KaTeX that drop or break functions are caught
+#![cfg(feature = "katex")]
+
+use mdbook_katex::scan::Span;
+use mdbook_katex::Scanner;
+
+#[test]
+fn supported_functions_render() {
+    let chapter = include_str!("fixtures/supported/functions.md");
+    let inline_opts = katex::Opts::builder()
+        .display_mode(false)
+        .trust(true)
+        .build()
+        .unwrap();
+    let display_opts = katex::Opts::builder()
+        .display_mode(true)
+        .trust(true)
+        .build()
+        .unwrap();
+    let mut equations = 0;
+    let mut failures = Vec::new();
+    for span in Scanner::default().scan(chapter, &mut |_| {}) {
+        if let Span::Math { tex, display, .. } = span {
+            equations += 1;
+            let opts = if display { &display_opts } else { &inline_opts };
+            if let Err(error) = katex::render_with_opts(tex, opts) {
+                failures.push(format!("`{}`: {}", tex, error));
+            }
+        }
+    }
+    assert!(
+        equations > 300,
+        "only {} equations in the corpus",
+        equations
+    );
+    assert!(
+        failures.is_empty(),
+        "{} of {} equations failed to render:\n{}",
+        failures.len(),
+        equations,
+        failures.join("\n")
+    );
+}